@@ -0,0 +1,7 @@
+fn main() {
+    // Use the vendored protoc, so that building doesn't depend on one
+    // being installed
+    let protoc = protoc_bin_vendored::protoc_bin_path().unwrap();
+    std::env::set_var("PROTOC", protoc);
+    tonic_prost_build::compile_protos("proto/control.proto").unwrap();
+}
@@ -0,0 +1,152 @@
+//! Construction of a [`DynamicFieldFilter`] with its initial state.
+//!
+//! ```
+//! use dynamic_field_filter::DynamicFieldFilter;
+//! use dynamic_field_filter::FieldValue;
+//! use dynamic_field_filter::FilterMode;
+//! use dynamic_field_filter::Matcher;
+//! use tracing::field::Field;
+//! use tracing::Level;
+//!
+//! let filter = DynamicFieldFilter::builder()
+//!     .deny_field("vrf_id", "1")
+//!     .allow_only("peer", ["10.0.0.1", "10.0.0.2"])
+//!     .bypass_level(Level::WARN)
+//!     .build();
+//! assert_eq!(filter.filters().len(), 2);
+//!
+//! // Only keep what the rules match
+//! let filter = DynamicFieldFilter::builder()
+//!     .mode(FilterMode::Allow)
+//!     .deny_field("vrf_id", "2")
+//!     .build();
+//!
+//! // With a matcher of our own, for the rules such as `as_path~private`
+//! let filter = DynamicFieldFilter::builder()
+//!     .matcher("private", |_: &Field, value: &FieldValue<'_>| {
+//!         value.to_text().split(' ').any(|asn| asn.starts_with("645"))
+//!     })
+//!     .rule("as_path", Matcher::Custom("private".into()), Default::default())
+//!     .build();
+//! assert_eq!(filter.matchers(), ["private"]);
+//! ```
+
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+
+use crate::filter::CustomMatchers;
+use crate::filter::DynamicFieldFilter;
+use crate::filter::FilterMode;
+use crate::filter::RuleOptions;
+use crate::matcher::FieldMatcher;
+use crate::matcher::Matcher;
+
+/// A builder of [`DynamicFieldFilter`], see
+/// [`DynamicFieldFilter::builder`]. There is one rule per field, so a
+/// rule replaces the previous ones on the same field.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct Builder {
+    rules: Vec<(String, Matcher, RuleOptions)>,
+    mode: FilterMode,
+    bypass_level: Option<Level>,
+    matchers: CustomMatchers,
+    logger_levels: Vec<(String, LevelFilter)>,
+    rate_limits: Vec<(String, f64)>,
+    dry_run: bool,
+}
+
+impl Builder {
+    /// Add a rule: suppress the spans and events where `field`
+    /// matches
+    pub fn rule(mut self, field: &str, matcher: Matcher, options: RuleOptions) -> Self {
+        self.rules.retain(|(f, _, _)| f != field);
+        self.rules.push((field.to_string(), matcher, options));
+        self
+    }
+
+    /// Suppress the spans and events where `field` has the given value
+    pub fn deny_field(self, field: &str, value: &str) -> Self {
+        let matcher = Matcher::Equals(value.to_string());
+        self.rule(field, matcher, RuleOptions::default())
+    }
+
+    /// Suppress the spans and events where `field` has none of the
+    /// given values
+    pub fn allow_only<I>(self, field: &str, values: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let matcher = Matcher::NotIn(values.into_iter().map(Into::into).collect());
+        self.rule(field, matcher, RuleOptions::default())
+    }
+
+    /// Never suppress the spans and events at the given level, or a
+    /// more severe one, whatever the rules
+    pub fn bypass_level(mut self, level: Level) -> Self {
+        self.bypass_level = Some(level);
+        self
+    }
+
+    /// Choose whether the rules select what is suppressed, the
+    /// default, or what is kept
+    pub fn mode(mut self, mode: FilterMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Register a custom matcher, see
+    /// [`DynamicFieldFilter::register_matcher`]
+    pub fn matcher(mut self, name: &str, matcher: impl FieldMatcher + 'static) -> Self {
+        self.matchers.insert(name, matcher);
+        self
+    }
+
+    /// Set the maximum level of the targets designated by a logger
+    /// name, see [`DynamicFieldFilter::set_logger_level`]
+    pub fn logger_level(mut self, name: &str, level: LevelFilter) -> Self {
+        self.logger_levels.push((name.to_string(), level));
+        self
+    }
+
+    /// Limit the number of events per second of each callsite of the
+    /// targets designated by `name`
+    pub fn rate_limit(mut self, name: &str, rate: f64) -> Self {
+        self.rate_limits.push((name.to_string(), rate));
+        self
+    }
+
+    /// Evaluate and count the rules without suppressing anything
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Build the layer
+    pub fn build(self) -> DynamicFieldFilter {
+        let mut filter = DynamicFieldFilter::default();
+        filter.set_mode(self.mode);
+        filter.set_bypass_level(self.bypass_level);
+        filter.set_dry_run(self.dry_run);
+        filter.set_matchers(self.matchers);
+        for (field, matcher, options) in self.rules {
+            filter.set_rule(&field, matcher, options);
+        }
+        for (name, level) in self.logger_levels {
+            filter.set_logger_level(&name, level);
+        }
+        for (name, rate) in self.rate_limits {
+            filter.set_rate_limit(&name, Some(rate));
+        }
+        filter
+    }
+}
+
+impl DynamicFieldFilter {
+    /// Start building a layer with an initial state, rather than
+    /// changing its default state through a reload handle
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
@@ -0,0 +1,92 @@
+//! Suppression of duplicate events: identical consecutive events from
+//! a callsite within a time window are collapsed into the first one,
+//! followed by a "last message repeated N times" event.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::Metadata;
+
+/// The target of the reports
+pub const TARGET: &str = "filtering::dedup";
+
+/// How often the repeated events are reported
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The last event of a callsite
+#[derive(Debug)]
+struct LastEvent {
+    /// The event's fields, formatted
+    key: String,
+    /// When the event was let through
+    first_seen: Instant,
+    /// Number of times the event was repeated since
+    repeated: u64,
+}
+
+/// The duplicate detection state of a callsite
+#[derive(Debug, Default)]
+pub struct Repeats {
+    last: Mutex<Option<LastEvent>>,
+    /// Repeats of the previous events, not reported yet
+    unreported: AtomicU64,
+}
+
+impl Repeats {
+    /// Return `true` if an event with the given fields repeats the
+    /// last one of the callsite, and was let through less than
+    /// `window` ago
+    pub fn is_repeat(&self, key: String, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        if let Some(last) = last.as_mut() {
+            if last.key == key && now.duration_since(last.first_seen) < window {
+                last.repeated += 1;
+                return true;
+            }
+        }
+        let previous = last.replace(LastEvent {
+            key,
+            first_seen: now,
+            repeated: 0,
+        });
+        if let Some(previous) = previous {
+            self.unreported
+                .fetch_add(previous.repeated, Ordering::Relaxed);
+        }
+        false
+    }
+
+    /// Return the number of repeats to report: those of the events
+    /// that were followed by a different one, and those of the last
+    /// event if its window is over
+    pub fn take_repeats(&self, window: Duration) -> u64 {
+        let mut repeats = self.unreported.swap(0, Ordering::Relaxed);
+        let mut last = self.last.lock().unwrap();
+        if let Some(last) = last.as_mut() {
+            if last.first_seen.elapsed() >= window {
+                repeats += last.repeated;
+                last.repeated = 0;
+            }
+        }
+        repeats
+    }
+}
+
+/// Report the repeated events since the last report, along with how
+/// many times they were repeated. This is to be called every
+/// [`REPORT_INTERVAL`].
+pub fn report(repeats: Vec<(&'static Metadata<'static>, u64)>) {
+    for (metadata, repeated) in repeats {
+        info!(
+            target: TARGET,
+            callsite_target = metadata.target(),
+            callsite = metadata.name(),
+            repeated,
+            "last message repeated {repeated} times"
+        );
+    }
+}
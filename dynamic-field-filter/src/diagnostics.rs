@@ -0,0 +1,46 @@
+//! The layer's own logging.
+//!
+//! What the filter logs about itself, and what the programs using it
+//! log about the filter changes, goes to the `dynamic_filter` target or
+//! to one of its sub-targets, such as `dynamic_filter::slow`. A single
+//! directive, e.g. `dynamic_filter=debug`, sets how verbose it is. The
+//! reports of [`crate::stats`], [`crate::explain`],
+//! [`crate::rate_limit`] and [`crate::dedup`] keep their
+//! `filtering::*` targets, e.g. `filtering::stats`.
+//!
+//! The spans and events of all these targets are never evaluated against
+//! the filter: the rules, logger levels, rate limits, duplicate
+//! suppression and muted callsites don't apply to them. This way the
+//! filter can't suppress its own reports, nor recurse into itself.
+//!
+//! The layer never logs from its own methods: they may run within
+//! [`reload::Handle::modify`](tracing_subscriber::reload::Handle::modify),
+//! where dispatching an event would wait on the lock held by the
+//! modification. The reports are emitted by the reporter threads
+//! instead.
+
+use tracing::Metadata;
+
+use crate::dedup;
+use crate::explain;
+use crate::rate_limit;
+use crate::stats;
+
+/// The target of the layer's own logging
+pub const TARGET: &str = "dynamic_filter";
+
+/// Return `true` if a span or event is part of the layer's own
+/// logging
+pub fn is_own(metadata: &Metadata<'_>) -> bool {
+    let target = metadata.target();
+    target
+        .strip_prefix(TARGET)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        || [
+            stats::TARGET,
+            explain::TARGET,
+            rate_limit::TARGET,
+            dedup::TARGET,
+        ]
+        .contains(&target)
+}
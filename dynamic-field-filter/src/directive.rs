@@ -0,0 +1,299 @@
+//! Directives in the syntax of `EnvFilter`, extended with field
+//! predicates, to give target levels and filter rules in a single
+//! string:
+//!
+//! ```text
+//! router::bgp[add_path{vrf_id=1}]=off,info
+//! ```
+//!
+//! Directives are separated by commas. Each one is either:
+//!
+//! ```text
+//! <level>                          the level of all the targets
+//! <target>[=<level>]               the level of a target, TRACE if
+//!                                  not given
+//! [<target>][[<span>]{<rule>}]=off a filter rule, restricted to a
+//!                                  target and to the spans with a
+//!                                  name, if given
+//! ```
+//!
+//! The rule between braces is a rule expression, see
+//! [`parse_rule`](crate::matcher::parse_rule), e.g. `vrf_id=1` or
+//! `busy_us > 5ms`. The spans and events where it matches are
+//! suppressed, along with everything within the spans, so `off` is
+//! the only level a field predicate takes.
+//!
+//! The levels compile to logger levels, and like those, they can only
+//! make targets quieter than the `EnvFilter` allows.
+//!
+//! ```
+//! use dynamic_field_filter::DynamicFieldFilter;
+//!
+//! let mut filter = DynamicFieldFilter::default();
+//! filter
+//!     .add_directives("router::bgp[add_path{vrf_id=1}]=off,info")
+//!     .unwrap();
+//! assert_eq!(filter.filters()[0].to_string(), "vrf_id=1 TARGET router::bgp SPAN add_path");
+//! assert_eq!(filter.logger_levels().len(), 1);
+//! ```
+
+use std::fmt;
+
+use tracing::level_filters::LevelFilter;
+
+use crate::filter::DynamicFieldFilter;
+use crate::filter::RuleOptions;
+use crate::loggers;
+use crate::matcher;
+use crate::matcher::Matcher;
+
+/// A directive, compiled to what the filter understands
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    /// Set the maximum level of a target, or of all the targets if the
+    /// target is empty
+    Level {
+        /// The target, named like a logger
+        target: String,
+        /// The maximum level
+        level: LevelFilter,
+    },
+    /// Suppress the spans and events where a field matches
+    Rule {
+        /// The field the rule matches on
+        field: String,
+        /// How the rule matches the value of its field
+        matcher: Matcher,
+        /// The target and span the rule is restricted to
+        options: RuleOptions,
+    },
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Directive::Level { target, level } if target.is_empty() => write!(f, "{level}"),
+            Directive::Level { target, level } => write!(f, "{target}={level}"),
+            Directive::Rule {
+                field,
+                matcher,
+                options,
+            } => {
+                let target = options.target.as_deref().unwrap_or_default();
+                let span = options.span.as_deref().unwrap_or_default();
+                write!(f, "{target}[{span}{{{field}{matcher}}}]=off")
+            }
+        }
+    }
+}
+
+/// Parse comma separated directives
+pub fn parse_directives(text: &str) -> Result<Vec<Directive>, String> {
+    split(text)
+        .into_iter()
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(parse_directive)
+        .collect()
+}
+
+/// Split directives on the commas that aren't within brackets, since
+/// rules such as `peer!=a,b` have commas of their own
+fn split(text: &str) -> Vec<&str> {
+    let mut directives = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                directives.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    directives.push(&text[start..]);
+    directives
+}
+
+/// Parse a single directive
+pub fn parse_directive(directive: &str) -> Result<Directive, String> {
+    let Some(open) = directive.find('[') else {
+        return parse_level_directive(directive);
+    };
+    let close = directive
+        .rfind(']')
+        .filter(|&close| close > open)
+        .ok_or_else(|| format!("missing ] in {directive}"))?;
+    let target = directive[..open].trim();
+    match directive[close + 1..].trim().strip_prefix('=') {
+        Some(level) if level.trim().eq_ignore_ascii_case("off") => {}
+        Some(level) => {
+            return Err(format!(
+                "a field predicate only takes the level off, got {}",
+                level.trim()
+            ))
+        }
+        None => return Err(format!("expected =off after ] in {directive}")),
+    }
+    let inner = &directive[open + 1..close];
+    let (span, rule) = match (inner.find('{'), inner.strip_suffix('}')) {
+        (Some(brace), Some(inner)) => (inner[..brace].trim(), &inner[brace + 1..]),
+        _ => {
+            return Err(format!(
+                "expected a field predicate in braces in {directive}"
+            ))
+        }
+    };
+    let (field, matcher) = matcher::parse_rule(rule)?;
+    let options = RuleOptions {
+        target: (!target.is_empty()).then(|| target.to_string()),
+        span: (!span.is_empty()).then(|| span.to_string()),
+        ..RuleOptions::default()
+    };
+    Ok(Directive::Rule {
+        field,
+        matcher,
+        options,
+    })
+}
+
+/// Parse a directive without a field predicate: a level, or a target
+/// with an optional level
+fn parse_level_directive(directive: &str) -> Result<Directive, String> {
+    let level = |level: &str| loggers::parse_level(level.trim()).map_err(|e| e.to_string());
+    let (target, level) = match directive.split_once('=') {
+        Some((target, value)) => (target.trim(), level(value)?),
+        None => match level(directive) {
+            Ok(level) => ("", level),
+            Err(_) => (directive.trim(), LevelFilter::TRACE),
+        },
+    };
+    Ok(Directive::Level {
+        target: target.to_string(),
+        level,
+    })
+}
+
+impl DynamicFieldFilter {
+    /// Apply directives, see [`crate::directive`]. Nothing is applied
+    /// if any of them is invalid.
+    pub fn add_directives(&mut self, text: &str) -> Result<(), String> {
+        for directive in parse_directives(text)? {
+            self.apply_directive(directive);
+        }
+        Ok(())
+    }
+
+    /// Apply a directive, replacing the rule on its field or the level
+    /// of its target
+    pub fn apply_directive(&mut self, directive: Directive) {
+        match directive {
+            Directive::Level { target, level } => self.set_logger_level(&target, level),
+            Directive::Rule {
+                field,
+                matcher,
+                options,
+            } => self.set_rule(&field, matcher, options),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(target: &str, level: LevelFilter) -> Directive {
+        Directive::Level {
+            target: target.to_string(),
+            level,
+        }
+    }
+
+    fn rule(target: Option<&str>, span: Option<&str>, expr: &str) -> Directive {
+        let (field, matcher) = matcher::parse_rule(expr).unwrap();
+        Directive::Rule {
+            field,
+            matcher,
+            options: RuleOptions {
+                target: target.map(str::to_string),
+                span: span.map(str::to_string),
+                ..RuleOptions::default()
+            },
+        }
+    }
+
+    #[test]
+    fn level_directives() {
+        assert_eq!(parse_directive("info"), Ok(level("", LevelFilter::INFO)));
+        assert_eq!(parse_directive("WARNING"), Ok(level("", LevelFilter::WARN)));
+        assert_eq!(
+            parse_directive("router::bgp"),
+            Ok(level("router::bgp", LevelFilter::TRACE))
+        );
+        assert_eq!(
+            parse_directive(" router = off "),
+            Ok(level("router", LevelFilter::OFF))
+        );
+        assert!(parse_directive("router=loud").is_err());
+    }
+
+    #[test]
+    fn rule_directives() {
+        assert_eq!(
+            parse_directive("router::bgp[add_path{vrf_id=1}]=OFF"),
+            Ok(rule(Some("router::bgp"), Some("add_path"), "vrf_id=1"))
+        );
+        assert_eq!(
+            parse_directive("[{vrf_id=1}]=off"),
+            Ok(rule(None, None, "vrf_id=1"))
+        );
+        assert_eq!(
+            parse_directive("router[{busy_us > 5ms}]=off"),
+            Ok(rule(Some("router"), None, "busy_us > 5ms"))
+        );
+    }
+
+    #[test]
+    fn invalid_rule_directives() {
+        // Missing or misplaced brackets
+        assert!(parse_directive("router[{vrf_id=1}").is_err());
+        assert!(parse_directive("router]{vrf_id=1}[=off").is_err());
+        // A level other than off, or none
+        assert!(parse_directive("router[{vrf_id=1}]=info").is_err());
+        assert!(parse_directive("router[{vrf_id=1}]").is_err());
+        // No predicate, or an invalid one
+        assert!(parse_directive("router[add_path]=off").is_err());
+        assert!(parse_directive("router[{vrf_id}]=off").is_err());
+        assert!(parse_directive("router[{busy_us > fast}]=off").is_err());
+    }
+
+    #[test]
+    fn commas_within_brackets_dont_split() {
+        assert_eq!(
+            parse_directives("router::bgp[add_path{peer!=a,b}]=off, info,,"),
+            Ok(vec![
+                rule(Some("router::bgp"), Some("add_path"), "peer!=a,b"),
+                level("", LevelFilter::INFO),
+            ])
+        );
+        assert_eq!(parse_directives(""), Ok(Vec::new()));
+        assert!(parse_directives("info,router=loud").is_err());
+    }
+
+    #[test]
+    fn directives_round_trip() {
+        for text in [
+            "info",
+            "router::bgp=debug",
+            "router::bgp[add_path{vrf_id=1}]=off",
+            "[add_path{peer!=a,b}]=off",
+            "[{prefix within 10.0.0.0/8}]=off",
+        ] {
+            let directive = parse_directive(text).unwrap();
+            assert_eq!(directive.to_string(), text);
+        }
+    }
+}
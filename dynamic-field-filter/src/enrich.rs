@@ -0,0 +1,69 @@
+//! Fields telling where a record comes from, added to every event.
+//!
+//! When enrichment is on, the events get three more fields, which
+//! aren't fields of their callsites:
+//!
+//! - `hostname`, the name of the machine
+//! - `pid`, the ID of the process
+//! - `thread.name`, the name of the thread the event was emitted on,
+//!   or its ID if it has no name
+//!
+//! They are printed by the outputs along with the fields of the event,
+//! see [`crate::format::format_event`], and the filter rules apply to
+//! them like to any other field, except those with a custom matcher.
+
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::thread;
+
+/// The names of the enrichment fields
+pub const FIELDS: &[&str] = &["hostname", "pid", "thread.name"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn enrichment on or off
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Return `true` if enrichment is on
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Return the value of an enrichment field for the current thread, if
+/// there is such a field, whether enrichment is on or not
+pub fn value(name: &str) -> Option<String> {
+    match name {
+        "hostname" => Some(hostname().to_string()),
+        "pid" => Some(process::id().to_string()),
+        "thread.name" => {
+            let thread = thread::current();
+            Some(match thread.name() {
+                Some(name) => name.to_string(),
+                None => format!("{:?}", thread.id()),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Return the enrichment fields and their values for the current
+/// thread, or nothing if enrichment is off
+pub fn values() -> Vec<(&'static str, String)> {
+    if !enabled() {
+        return Vec::new();
+    }
+    FIELDS
+        .iter()
+        .filter_map(|name| Some((*name, value(name)?)))
+        .collect()
+}
+
+/// The name of the machine, looked up once
+fn hostname() -> &'static str {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    HOSTNAME.get_or_init(|| gethostname::gethostname().to_string_lossy().into_owned())
+}
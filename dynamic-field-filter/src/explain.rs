@@ -0,0 +1,111 @@
+//! Explain mode: say why spans and events are suppressed.
+//!
+//! When explain mode is on, the filter layer describes each span or
+//! event it suppresses: which rule matched, and the field value that
+//! triggered it. The descriptions are reported as events of the
+//! `filtering::explain` target, at the INFO level, by
+//! [`run_reporter`]. They are rate limited, so that a rule matching a
+//! lot doesn't flood the output.
+//!
+//! The events are emitted from the reporter's thread rather than from
+//! within the layer, since a subscriber dispatching events to itself
+//! can't be relied on.
+
+use std::fmt;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tracing::Metadata;
+
+use crate::filter::Reason;
+
+/// The target of the explanations
+pub const TARGET: &str = "filtering::explain";
+
+/// The maximum number of explanations per second
+pub const RATE: f64 = 10.0;
+
+/// How many explanations can wait for the reporter, the next ones are
+/// dropped
+const CAPACITY: usize = 64;
+
+/// How often the reporter checks whether it should stop
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Why a span or event was suppressed
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// The callsite of the span or event
+    pub metadata: &'static Metadata<'static>,
+    /// Why it was suppressed
+    pub reason: Reason,
+    /// The value that matched the rule, as `<field>=<value>`, if a
+    /// rule matched
+    pub matched: Option<String>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.metadata.is_span() {
+            "span"
+        } else {
+            "event"
+        };
+        write!(
+            f,
+            "{kind} {} of {} suppressed: {}",
+            self.metadata.name(),
+            self.metadata.target(),
+            self.reason
+        )?;
+        if let Some(matched) = &self.matched {
+            write!(f, " ({matched})")?;
+        }
+        Ok(())
+    }
+}
+
+type Channel = (
+    SyncSender<Explanation>,
+    Mutex<Option<Receiver<Explanation>>>,
+);
+
+static CHANNEL: LazyLock<Channel> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::sync_channel(CAPACITY);
+    (tx, Mutex::new(Some(rx)))
+});
+
+/// Queue an explanation for the reporter, unless too many are waiting
+pub(crate) fn send(explanation: Explanation) {
+    let _ = CHANNEL.0.try_send(explanation);
+}
+
+/// Report the explanations as they come, while `running` returns
+/// `true`. It is checked every [`POLL_INTERVAL`]. Only one reporter
+/// can run at a time, the next ones return right away.
+pub fn run_reporter(running: impl Fn() -> bool) {
+    let Some(rx) = CHANNEL.1.lock().unwrap().take() else {
+        return;
+    };
+    while running() {
+        let explanation = match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(explanation) => explanation,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        info!(
+            target: TARGET,
+            callsite_target = explanation.metadata.target(),
+            callsite = explanation.metadata.name(),
+            reason = %explanation.reason,
+            matched = explanation.matched.as_deref(),
+            "{explanation}"
+        );
+    }
+    *CHANNEL.1.lock().unwrap() = Some(rx);
+}
@@ -0,0 +1,2284 @@
+//! A layer that disables spans (and everything within them) based on
+//! their field values.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Write as _;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::callsite::Identifier;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::level_filters::LevelFilter;
+use tracing::span::Attributes;
+use tracing::span::Record;
+use tracing::subscriber::Interest;
+use tracing::Event;
+use tracing::Id;
+use tracing::Level;
+use tracing::Metadata;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::dedup::Repeats;
+use crate::diagnostics;
+use crate::enrich;
+use crate::explain;
+use crate::explain::Explanation;
+use crate::format;
+use crate::format::FieldValues;
+use crate::hints;
+use crate::loggers;
+use crate::matcher;
+use crate::matcher::FieldMatcher;
+use crate::matcher::FieldValue;
+use crate::matcher::Matcher;
+use crate::panics;
+use crate::quarantine::Quarantine;
+use crate::rate_limit::TokenBucket;
+use crate::replay;
+use crate::replay::HeldEvent;
+use crate::replay::Recorded;
+use crate::ring::RingBuffer;
+use crate::span_set::SpanSet;
+use crate::stats::Stats;
+use crate::window::Window;
+
+/// How many filter changes can be undone
+const UNDO_DEPTH: usize = 32;
+
+/// A limit on the number of matches a rule applies to, written like
+/// `{"limit": 10}` in JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Budget {
+    /// Suppress the first `n` matches, then expire
+    Limit(u64),
+    /// Keep the first `n` matches, and suppress the next ones
+    Capture(u64),
+}
+
+/// What the rules select
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    /// Suppress the spans and events where a field matches its rule
+    #[default]
+    Deny,
+    /// Suppress the spans and events where a field doesn't match its
+    /// rule. Those without any field that is filtered on go through.
+    Allow,
+}
+
+impl FilterMode {
+    /// Return `true` if a span or event must be suppressed, given
+    /// whether one of its fields matched its rule
+    fn suppresses(self, matched: bool) -> bool {
+        match self {
+            FilterMode::Deny => matched,
+            FilterMode::Allow => !matched,
+        }
+    }
+}
+
+impl fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilterMode::Deny => "deny",
+            FilterMode::Allow => "allow",
+        })
+    }
+}
+
+/// Optional settings of a filter rule. The TTL is (de)serialized as
+/// text, e.g. `10m`, and so is the window, e.g. `DAILY 08:00-18:00`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuleOptions {
+    /// How many matches the rule applies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+    /// How long the rule applies
+    #[serde(
+        with = "matcher::duration_text::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ttl: Option<Duration>,
+    /// When the rule applies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<Window>,
+    /// Fraction of the matches that are kept anyway, between 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample: Option<f64>,
+    /// The targets the rule is restricted to, named like loggers, see
+    /// [`loggers::logger_matches`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// The name of the spans the rule is restricted to. The rule then
+    /// doesn't apply to events, only to the spans they are in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span: Option<String>,
+    /// The thread the rule is restricted to, by name or by number, the
+    /// `N` of its `ThreadId(N)`. It is checked on the thread the spans
+    /// and events are created on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+}
+
+impl fmt::Display for RuleOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.budget {
+            Some(Budget::Limit(n)) => write!(f, " LIMIT {n}")?,
+            Some(Budget::Capture(n)) => write!(f, " CAPTURE {n}")?,
+            None => {}
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, " TTL {}", ttl.as_secs())?;
+        }
+        if let Some(window) = self.window {
+            write!(f, " {window}")?;
+        }
+        if let Some(rate) = self.sample {
+            write!(f, " SAMPLE {rate}")?;
+        }
+        if let Some(target) = &self.target {
+            write!(f, " TARGET {target}")?;
+        }
+        if let Some(span) = &self.span {
+            write!(f, " SPAN {span}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A filter rule: spans and events where `field` matches are
+/// disabled
+#[derive(Debug)]
+pub struct Rule {
+    /// The field the rule matches on
+    pub field: String,
+    /// How the rule matches the value of its field
+    pub matcher: Matcher,
+    /// Optional settings of the rule
+    pub options: RuleOptions,
+    /// When the rule expires, if it has a TTL
+    deadline: Option<Instant>,
+    /// Number of spans and events that matched the rule
+    hits: AtomicU64,
+}
+
+impl Rule {
+    fn new(field: &str, matcher: Matcher, options: RuleOptions) -> Self {
+        Self {
+            field: field.to_string(),
+            matcher,
+            deadline: options.ttl.map(|ttl| Instant::now() + ttl),
+            options,
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the number of spans and events that matched the rule
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Count a match of the rule, and return `true` if the span or
+    /// event that matched must be suppressed. Sampling is decided by
+    /// hashing `key`, so that the same key always gets the same
+    /// verdict, or the match number if there is no key.
+    fn hit(&self, key: Option<u64>) -> bool {
+        let previous = self.hits.fetch_add(1, Ordering::Relaxed);
+        let suppress = match self.options.budget {
+            Some(Budget::Limit(n)) => previous < n,
+            Some(Budget::Capture(n)) => previous >= n,
+            None => true,
+        };
+        let key = key.unwrap_or(previous);
+        suppress
+            && !self
+                .options
+                .sample
+                .is_some_and(|rate| is_sampled(key, rate))
+    }
+
+    /// Return the number of matches left in the rule's budget, if
+    /// it has one
+    pub fn remaining(&self) -> Option<u64> {
+        match self.options.budget {
+            Some(Budget::Limit(n)) | Some(Budget::Capture(n)) => {
+                Some(n.saturating_sub(self.hits()))
+            }
+            None => None,
+        }
+    }
+
+    /// Return the time left before the rule expires, if it has a TTL
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Return `true` if the rule used up its budget of suppressed
+    /// matches or outlived its TTL, and no longer applies
+    pub fn is_expired(&self) -> bool {
+        let used_up =
+            matches!(self.options.budget, Some(Budget::Limit(_))) && self.remaining() == Some(0);
+        used_up
+            || self
+                .deadline
+                .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Return `true` if the rule applies now: it didn't expire, and
+    /// it is within its activation window, if any
+    pub fn is_active(&self) -> bool {
+        let in_window = match &self.options.window {
+            Some(window) => window.contains(SystemTime::now()),
+            None => true,
+        };
+        in_window && !self.is_expired()
+    }
+
+    /// Return `true` if every match of the rule is suppressed until
+    /// the rule expires, if it has a TTL
+    fn suppresses_all(&self) -> bool {
+        !self.is_expired()
+            && self.options.budget.is_none()
+            && self.options.window.is_none()
+            && self.options.sample.is_none()
+            && self.options.target.is_none()
+            && self.options.span.is_none()
+            && self.options.thread.is_none()
+    }
+
+    /// Return `true` if the rule applies to the spans or events of a
+    /// callsite, given its target and span restrictions
+    fn applies_to(&self, metadata: &Metadata<'_>) -> bool {
+        let target = self.options.target.as_deref();
+        let span = self.options.span.as_deref();
+        target.is_none_or(|target| loggers::logger_matches(target, metadata.target()))
+            && span.is_none_or(|span| metadata.is_span() && metadata.name() == span)
+    }
+
+    /// Return `true` if the rule applies on the current thread, given
+    /// its thread restriction
+    fn applies_on_current_thread(&self) -> bool {
+        self.options.thread.as_deref().is_none_or(|thread| {
+            CURRENT_THREAD
+                .try_with(|current| current.is(thread))
+                .unwrap_or_else(|_| CurrentThread::get().is(thread))
+        })
+    }
+}
+
+/// The name and number of a thread, which the thread restriction of a
+/// rule can give
+#[derive(Debug)]
+struct CurrentThread {
+    name: Option<String>,
+    /// The number of the thread's ID, e.g. `3` for `ThreadId(3)`
+    number: String,
+}
+
+impl CurrentThread {
+    fn get() -> Self {
+        let current = std::thread::current();
+        let id = format!("{:?}", current.id());
+        let number = id
+            .strip_prefix("ThreadId(")
+            .and_then(|id| id.strip_suffix(')'))
+            .unwrap_or(&id)
+            .to_string();
+        Self {
+            name: current.name().map(str::to_string),
+            number,
+        }
+    }
+
+    /// Return `true` if the thread has the given name or number
+    fn is(&self, thread: &str) -> bool {
+        self.name.as_deref() == Some(thread) || self.number == thread
+    }
+}
+
+thread_local! {
+    /// The current thread, kept so that the rules restricted to a
+    /// thread don't format its ID on every check
+    static CURRENT_THREAD: CurrentThread = CurrentThread::get();
+}
+
+/// Return the value of the most specific name designating the given
+/// target, see [`loggers::logger_matches`]
+fn most_specific<T: Copy>(entries: &[(String, T)], target: &str) -> Option<T> {
+    entries
+        .iter()
+        .filter(|(name, _)| loggers::logger_matches(name, target))
+        .max_by_key(|(name, _)| name.split("::").filter(|s| !s.is_empty()).count())
+        .map(|(_, value)| *value)
+}
+
+/// Return `true` if the given key falls in the sampled fraction
+fn is_sampled(key: u64, rate: f64) -> bool {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() as f64 / u64::MAX as f64) < rate
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(thread) = &self.options.thread {
+            write!(f, "@thread={thread} ")?;
+        }
+        write!(f, "{}{}{}", self.field, self.matcher, self.options)
+    }
+}
+
+/// The fields of a callsite that are filtered on, along with the
+/// rule that applies to each of them.
+type FieldRules = Vec<(Field, Arc<Rule>)>;
+
+/// The rules that apply to a callsite
+#[derive(Debug, Default)]
+struct CallsiteFilters {
+    active: FieldRules,
+    shadow: FieldRules,
+}
+
+impl CallsiteFilters {
+    fn is_empty(&self) -> bool {
+        self.active.is_empty() && self.shadow.is_empty()
+    }
+}
+
+/// The custom matchers registered in the filter, by name
+#[derive(Default)]
+pub(crate) struct CustomMatchers(HashMap<String, Box<dyn FieldMatcher>>);
+
+impl CustomMatchers {
+    pub(crate) fn insert(&mut self, name: &str, matcher: impl FieldMatcher + 'static) {
+        self.0.insert(name.to_string(), Box::new(matcher));
+    }
+}
+
+impl fmt::Debug for CustomMatchers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// A visitor that checks whether any of the given fields matches the
+/// corresponding rule, or doesn't match it in allow mode, and counts
+/// the hits of the shadow rules along the way. Fields are compared by
+/// index rather than by name, since they all come from the same
+/// callsite.
+struct MatchFieldVisitor<'a> {
+    filters: &'a CallsiteFilters,
+    matchers: &'a CustomMatchers,
+    mode: FilterMode,
+    matched: Option<&'a Arc<Rule>>,
+    /// The value that matched, as `<field>=<value>`
+    value: Option<String>,
+}
+
+impl MatchFieldVisitor<'_> {
+    /// Whether the value matches the rule, looking up the custom
+    /// matcher of the rule if it has one
+    fn matches(&self, rule: &Rule, field: &Field, value: &FieldValue<'_>) -> bool {
+        match &rule.matcher {
+            Matcher::Custom(name) => self
+                .matchers
+                .0
+                .get(name)
+                .is_some_and(|matcher| matcher.matches(field, value)),
+            matcher => matcher.matches(field, value),
+        }
+    }
+
+    fn check(&mut self, field: &Field, value: FieldValue<'_>) {
+        // Only look at the values of the fields we're interested in
+        for (_, rule) in self.filters.shadow.iter().filter(|(f, _)| f == field) {
+            if self.matches(rule, field, &value) {
+                rule.hits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if self.matched.is_some() {
+            return;
+        }
+        self.matched = self
+            .filters
+            .active
+            .iter()
+            .find(|(f, rule)| {
+                f == field
+                    && rule.is_active()
+                    && rule.applies_on_current_thread()
+                    && self.mode.suppresses(self.matches(rule, field, &value))
+            })
+            .map(|(_, rule)| rule);
+        if self.matched.is_some() {
+            self.value = Some(format!("{}={}", field.name(), value.to_text()));
+        }
+    }
+}
+
+impl Visit for MatchFieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.check(field, FieldValue::Debug(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.check(field, FieldValue::Str(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.check(field, FieldValue::U64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.check(field, FieldValue::I64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.check(field, FieldValue::F64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.check(field, FieldValue::Bool(value));
+    }
+}
+
+/// Why a span or event was suppressed
+#[derive(Debug, Clone)]
+pub enum Reason {
+    /// The span or event matched a rule, given as text
+    Rule(String),
+    /// The span or event was within a disabled span
+    DisabledSpan,
+    /// The event's callsite was over its rate limit
+    RateLimited,
+    /// The event repeated the previous event of its callsite
+    Duplicate,
+}
+
+/// An event that was suppressed recently
+#[derive(Debug, Clone)]
+pub struct SuppressedEvent {
+    /// The event, formatted like in the shard files
+    pub line: String,
+    /// Why the event was suppressed
+    pub reason: Reason,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Rule(rule) => write!(f, "rule {rule}"),
+            Reason::DisabledSpan => f.write_str("disabled span"),
+            Reason::RateLimited => f.write_str("rate limited"),
+            Reason::Duplicate => f.write_str("duplicate"),
+        }
+    }
+}
+
+impl fmt::Display for SuppressedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.line, self.reason)
+    }
+}
+
+/// A span extension holding the last events suppressed within the
+/// span, for trigger mode
+#[derive(Debug, Default)]
+struct History(VecDeque<HeldEvent>);
+
+/// A filter rule, as saved in a profile or written in a configuration:
+/// its field and matcher, along with its options
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleConfig {
+    /// The field the rule matches on
+    pub field: String,
+    /// How the rule matches the value of its field
+    pub matcher: Matcher,
+    /// Optional settings of the rule, ignored for shadow rules
+    #[serde(flatten)]
+    pub options: RuleOptions,
+}
+
+impl From<&Rule> for RuleConfig {
+    fn from(rule: &Rule) -> Self {
+        Self {
+            field: rule.field.clone(),
+            matcher: rule.matcher.clone(),
+            options: rule.options.clone(),
+        }
+    }
+}
+
+/// A named set of rules, shadow rules, logger levels and rate limits,
+/// along with the mode and the bypass level, that can be saved and
+/// loaded back at once. This is also how the filter state is written
+/// in a configuration, e.g. in JSON:
+///
+/// ```json
+/// {
+///   "mode": "deny",
+///   "bypass_level": "WARN",
+///   "rules": [{"field": "vrf_id", "matcher": {"equals": "1"}, "ttl": "10m"}],
+///   "shadows": [{"field": "busy_us", "matcher": {"duration": [">", "5ms"]}}],
+///   "logger_levels": {"loggingdemo::router": "debug"},
+///   "rate_limits": {"loggingdemo::rib": 100.0}
+/// }
+/// ```
+///
+/// All the fields are optional.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Whether the rules select what is suppressed, or what is kept
+    pub mode: FilterMode,
+    /// The level at which, or above which, nothing is suppressed by
+    /// the rules
+    #[serde(with = "bypass_level", skip_serializing_if = "Option::is_none")]
+    pub bypass_level: Option<Level>,
+    /// The filter rules, at most one per field
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleConfig>,
+    /// The shadow rules
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shadows: Vec<RuleConfig>,
+    /// The maximum levels, by logger name
+    #[serde(with = "level_filters", skip_serializing_if = "Vec::is_empty")]
+    pub logger_levels: Vec<(String, LevelFilter)>,
+    /// The maximum numbers of events per second, by target name
+    #[serde(with = "rate_limits", skip_serializing_if = "Vec::is_empty")]
+    pub rate_limits: Vec<(String, f64)>,
+}
+
+/// (De)serialize the bypass level as text, since `Level` doesn't
+/// implement serde's traits
+mod bypass_level {
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+    use tracing::Level;
+
+    pub fn serialize<S: Serializer>(
+        level: &Option<Level>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match level {
+            Some(level) => serializer.collect_str(level),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Level>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|level| level.parse().map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// (De)serialize logger levels as a map from logger names to levels
+/// as text, since `LevelFilter` doesn't implement serde's traits
+mod level_filters {
+    use std::collections::BTreeMap;
+
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+    use tracing::level_filters::LevelFilter;
+
+    pub fn serialize<S: Serializer>(
+        levels: &[(String, LevelFilter)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(levels.iter().map(|(name, level)| (name, level.to_string())))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, LevelFilter)>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, level)| Ok((name, level.parse().map_err(D::Error::custom)?)))
+            .collect()
+    }
+}
+
+/// (De)serialize rate limits as a map from target names to rates
+mod rate_limits {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(
+        rates: &[(String, f64)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(rates.iter().map(|(name, rate)| (name, rate)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, f64)>, D::Error> {
+        Ok(BTreeMap::<String, f64>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// The filter state that survives restarts: the current rules, shadow
+/// rules, logger levels and rate limits, and the saved profiles. This
+/// is what `SAVE` writes, in JSON or TOML.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SavedFilters {
+    /// The current state of the filter
+    pub current: Profile,
+    /// The saved profiles, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rules, {} shadow rules, {} logger levels, {} rate limits",
+            self.rules.len(),
+            self.shadows.len(),
+            self.logger_levels.len(),
+            self.rate_limits.len()
+        )
+    }
+}
+
+/// A span extension for slow mode: how long the span was entered, and
+/// the events held until it closes
+#[derive(Debug)]
+struct Timing {
+    created: Instant,
+    entered: Option<Instant>,
+    busy: Duration,
+    events: Vec<HeldEvent>,
+}
+
+/// Keeps the open spans tracked, see
+/// [`DynamicFieldFilter::track_live_spans`]
+#[derive(Debug)]
+pub struct SpanTracking(Arc<AtomicUsize>);
+
+impl Drop for SpanTracking {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What the layer knows about a callsite
+#[derive(Debug)]
+struct CallsiteInfo {
+    /// A number identifying the callsite over the TCP connection.
+    /// Callsites are numbered in the order they are registered.
+    number: usize,
+    metadata: &'static Metadata<'static>,
+    /// The fields of the callsite that are filtered on
+    filters: CallsiteFilters,
+    /// The rate limit of the callsite, if it's an event callsite
+    /// with a rate limit
+    bucket: Option<Arc<TokenBucket>>,
+    /// The last events of the callsite, to detect duplicates. This is
+    /// shared with the previous versions of the cache.
+    repeats: Arc<Repeats>,
+}
+
+/// A layer that checks filters spans based their fields values
+#[derive(Debug, Default)]
+pub struct DynamicFieldFilter {
+    /// The filter rules, by field name
+    filters: HashMap<String, Arc<Rule>>,
+    /// Shadow rules, by rule text. They are evaluated and counted
+    /// like the filter rules, but never suppress anything.
+    shadows: HashMap<String, Arc<Rule>>,
+    /// Muted callsites, by number, along with the instant the mute
+    /// expires, if any. Muted callsites are disabled entirely.
+    muted: HashMap<usize, Option<Instant>>,
+    /// Maximum levels by logger name, see [`loggers::logger_matches`]
+    logger_levels: Vec<(String, LevelFilter)>,
+    /// Maximum number of events per second and per callsite, by
+    /// target name. Names are matched like logger names.
+    rate_limits: Vec<(String, f64)>,
+    /// The callsites registered so far. This also caches the fields
+    /// that are filtered on for each callsite. The cache is
+    /// (re)built in `register_callsite`, which is called again for
+    /// every callsite when the layer is modified through its reload
+    /// handle.
+    callsites: RwLock<HashMap<Identifier, CallsiteInfo>>,
+    /// The spans that are disabled, either because they matched a
+    /// filter or because one of their ancestors did. Spans are
+    /// removed when they close.
+    disabled: SpanSet,
+    /// The spans that are currently open, so that they can be listed
+    /// from the TCP connection. They are only tracked while someone
+    /// holds a [`SpanTracking`], since it takes a few locks per span.
+    live: SpanSet,
+    /// The number of [`SpanTracking`] alive
+    trackers: Arc<AtomicUsize>,
+    stats: Stats,
+    /// When set, spans and events are matched against the rules and
+    /// counted, but nothing is suppressed
+    dry_run: bool,
+    /// Whether the rules select what is suppressed, or what is kept
+    mode: FilterMode,
+    /// When set, the suppressed spans and events are explained, at
+    /// the rate of the bucket, see [`crate::explain`]
+    explain: Option<TokenBucket>,
+    /// The matchers provided by the application, for the rules using
+    /// a custom matcher
+    matchers: CustomMatchers,
+    /// The spans and events at this level, or a more severe one, are
+    /// never suppressed by the rules
+    bypass_level: Option<Level>,
+    /// Where the suppressed events go, if anywhere
+    quarantine: Option<Quarantine>,
+    /// The last events that were suppressed
+    suppressed: RingBuffer<SuppressedEvent>,
+    /// When set, the last suppressed events below WARN are kept in
+    /// each span, up to the given number, and printed when a WARN or
+    /// ERROR event occurs within the span
+    trigger: Option<usize>,
+    /// When set, events identical to the previous event of their
+    /// callsite are suppressed for that long
+    dedup: Option<Duration>,
+    /// When set, the events within spans are held until the span
+    /// closes, and only emitted if the span was busy for longer than
+    /// this
+    slow: Option<Duration>,
+    /// The saved profiles, by name
+    profiles: BTreeMap<String, Profile>,
+    /// The rules, shadow rules, logger levels and rate limits before
+    /// each of the last changes, oldest first
+    undo: VecDeque<Profile>,
+    /// The changes undone since the last change, most recent last
+    redo: Vec<Profile>,
+    /// Where the suppressed partitions are published, see
+    /// [`crate::hints`]
+    hints: hints::Publisher,
+}
+
+impl DynamicFieldFilter {
+    /// The environment variable holding the initial rules, in the
+    /// configuration of `loggingdemo`
+    pub const DEFAULT_ENV: &'static str = "FIELD_FILTER";
+
+    /// Add filter rules given as text, e.g. `vrf_id=1`. Invalid rules
+    /// are ignored, and returned along with why they are invalid, for
+    /// the caller to report them: the layer can't log while it is
+    /// being modified, see [`crate::diagnostics`].
+    pub fn add_rules<'a>(
+        &mut self,
+        rules: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<(&'a str, String)> {
+        let mut invalid = Vec::new();
+        for rule in rules
+            .into_iter()
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            match matcher::parse_rule(rule) {
+                Ok((field, matcher)) => self.set_rule(&field, matcher, RuleOptions::default()),
+                Err(e) => invalid.push((rule, e)),
+            }
+        }
+        invalid
+    }
+
+    /// Disable the spans and events where `field` has the given value
+    pub fn set_filter(&mut self, field: &str, value: &str) {
+        self.set_rule(
+            field,
+            Matcher::Equals(value.to_string()),
+            RuleOptions::default(),
+        );
+    }
+
+    /// Disable the spans and events where `field` matches. This
+    /// replaces the existing rule on this field, if any.
+    pub fn set_rule(&mut self, field: &str, matcher: Matcher, options: RuleOptions) {
+        self.filters.insert(
+            field.to_string(),
+            Arc::new(Rule::new(field, matcher, options)),
+        );
+        self.publish_partitions();
+    }
+
+    /// Remove the rule on the given field, if any
+    pub fn remove_rule(&mut self, field: &str) {
+        self.filters.remove(field);
+        self.publish_partitions();
+    }
+
+    /// Remove all the field filters
+    pub fn clear_filters(&mut self) {
+        self.filters.clear();
+        self.publish_partitions();
+    }
+
+    /// Publish the partitions that are suppressed as a whole, for
+    /// [`hints::is_partition_enabled`]: those of the rules matching a
+    /// field by value, until they expire, unless in dry-run or allow
+    /// mode, or when some levels bypass the rules.
+    fn publish_partitions(&self) {
+        let partitions =
+            if self.dry_run || self.mode == FilterMode::Allow || self.bypass_level.is_some() {
+                HashMap::new()
+            } else {
+                self.filters
+                    .values()
+                    .filter(|rule| rule.suppresses_all())
+                    .filter_map(|rule| match &rule.matcher {
+                        Matcher::Equals(value) => {
+                            Some((rule.field.clone(), (value.clone(), rule.deadline)))
+                        }
+                        Matcher::Duration(..)
+                        | Matcher::NotIn(_)
+                        | Matcher::Contains(_)
+                        | Matcher::Within(_)
+                        | Matcher::Custom(_) => None,
+                    })
+                    .collect()
+            };
+        self.hints.publish(partitions);
+    }
+
+    /// Drop the rules whose TTL is over, and return them as text.
+    /// Expired rules are ignored anyway, but this keeps the published
+    /// partitions up to date.
+    pub fn expire_rules(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.filters.retain(|_, rule| {
+            let alive = rule.deadline.is_none_or(|deadline| deadline > now);
+            if !alive {
+                expired.push(rule.to_string());
+            }
+            alive
+        });
+        self.publish_partitions();
+        expired
+    }
+
+    /// Return the field filters, sorted by field name
+    pub fn filters(&self) -> Vec<&Rule> {
+        let mut filters: Vec<&Rule> = self.filters.values().map(|rule| &**rule).collect();
+        filters.sort_by(|a, b| a.field.cmp(&b.field));
+        filters
+    }
+
+    /// Add a shadow rule, which counts the spans and events where
+    /// `field` matches without disabling them. There can be several
+    /// shadow rules on the same field, to compare candidate filters.
+    pub fn add_shadow(&mut self, field: &str, matcher: Matcher) {
+        let rule = Rule::new(field, matcher, RuleOptions::default());
+        self.shadows
+            .entry(rule.to_string())
+            .or_insert_with(|| Arc::new(rule));
+    }
+
+    /// Remove all the shadow rules
+    pub fn clear_shadows(&mut self) {
+        self.shadows.clear();
+    }
+
+    /// Return the shadow rules, sorted by rule text
+    pub fn shadows(&self) -> Vec<&Rule> {
+        let mut shadows: Vec<(&String, &Rule)> = self
+            .shadows
+            .iter()
+            .map(|(text, rule)| (text, &**rule))
+            .collect();
+        shadows.sort_by(|a, b| a.0.cmp(b.0));
+        shadows.into_iter().map(|(_, rule)| rule).collect()
+    }
+
+    /// Save the rules, shadow rules, logger levels and rate limits as
+    /// a profile, replacing the profile with that name if any
+    pub fn save_profile(&mut self, name: &str) {
+        let profile = self.current_profile();
+        self.profiles.insert(name.to_string(), profile);
+    }
+
+    /// Replace the rules, shadow rules, logger levels and rate limits
+    /// with those of a profile. The rules start over: their budgets
+    /// and TTLs are reset. Return `false` if there is no such profile.
+    pub fn load_profile(&mut self, name: &str) -> bool {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            return false;
+        };
+        self.apply_profile(profile);
+        true
+    }
+
+    /// Return the saved profiles, sorted by name
+    pub fn profiles(&self) -> Vec<(&str, &Profile)> {
+        self.profiles
+            .iter()
+            .map(|(name, profile)| (name.as_str(), profile))
+            .collect()
+    }
+
+    /// Return the state to save, e.g. to a file
+    pub fn saved_filters(&self) -> SavedFilters {
+        SavedFilters {
+            current: self.current_profile(),
+            profiles: self.profiles.clone(),
+        }
+    }
+
+    /// Restore a saved state, replacing the rules, shadow rules,
+    /// logger levels, rate limits and profiles
+    pub fn restore(&mut self, saved: SavedFilters) {
+        self.profiles = saved.profiles;
+        self.apply_profile(saved.current);
+    }
+
+    /// Apply a change, and remember the previous rules, shadow rules,
+    /// logger levels and rate limits so that it can be undone, if it
+    /// changed any of them
+    pub fn change(&mut self, change: impl FnOnce(&mut Self)) {
+        let before = self.current_profile();
+        change(self);
+        if self.current_profile() == before {
+            return;
+        }
+        self.push_undo(before);
+        self.redo.clear();
+    }
+
+    /// Remember a state to go back to with [`DynamicFieldFilter::undo`],
+    /// forgetting the oldest one past [`UNDO_DEPTH`]
+    fn push_undo(&mut self, profile: Profile) {
+        if self.undo.len() == UNDO_DEPTH {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(profile);
+    }
+
+    /// Go back to the rules, shadow rules, logger levels and rate
+    /// limits from before the last change. Like with profiles, the
+    /// rules start over. Return `false` if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        let Some(profile) = self.undo.pop_back() else {
+            return false;
+        };
+        self.redo.push(self.current_profile());
+        self.apply_profile(profile);
+        true
+    }
+
+    /// Apply the last undone change again. Return `false` if there is
+    /// nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        let Some(profile) = self.redo.pop() else {
+            return false;
+        };
+        self.push_undo(self.current_profile());
+        self.apply_profile(profile);
+        true
+    }
+
+    /// Return the current state of the filter, as it is saved in a
+    /// profile
+    pub fn current_profile(&self) -> Profile {
+        Profile {
+            mode: self.mode,
+            bypass_level: self.bypass_level,
+            rules: self.filters().into_iter().map(RuleConfig::from).collect(),
+            shadows: self.shadows().into_iter().map(RuleConfig::from).collect(),
+            logger_levels: self.logger_levels.clone(),
+            rate_limits: self.rate_limits.clone(),
+        }
+    }
+
+    /// Replace the state of the filter with that of a profile. Unlike
+    /// [`DynamicFieldFilter::load_profile`], this doesn't record the
+    /// change for undo, see [`DynamicFieldFilter::change`].
+    pub fn apply_profile(&mut self, profile: Profile) {
+        self.mode = profile.mode;
+        self.bypass_level = profile.bypass_level;
+        self.filters = profile
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let field = rule.field.clone();
+                (
+                    field,
+                    Arc::new(Rule::new(&rule.field, rule.matcher, rule.options)),
+                )
+            })
+            .collect();
+        self.shadows = profile
+            .shadows
+            .into_iter()
+            .map(|rule| {
+                let rule = Rule::new(&rule.field, rule.matcher, RuleOptions::default());
+                (rule.to_string(), Arc::new(rule))
+            })
+            .collect();
+        self.logger_levels = profile.logger_levels;
+        self.rate_limits = profile.rate_limits;
+        self.publish_partitions();
+    }
+
+    /// Set the maximum level of the targets designated by a logger
+    /// name. An empty name designates all the targets. When several
+    /// names match a target, the most specific one applies.
+    ///
+    /// Note that this can only make targets quieter than what the
+    /// `EnvFilter` allows.
+    pub fn set_logger_level(&mut self, name: &str, level: LevelFilter) {
+        match self.logger_levels.iter_mut().find(|(n, _)| n == name) {
+            Some((_, current)) => *current = level,
+            None => self.logger_levels.push((name.to_string(), level)),
+        }
+    }
+
+    /// Remove all the logger levels
+    pub fn clear_logger_levels(&mut self) {
+        self.logger_levels.clear();
+    }
+
+    /// Return the logger levels, sorted by logger name
+    pub fn logger_levels(&self) -> Vec<(&str, LevelFilter)> {
+        let mut levels: Vec<_> = self
+            .logger_levels
+            .iter()
+            .map(|(name, level)| (name.as_str(), *level))
+            .collect();
+        levels.sort();
+        levels
+    }
+
+    /// Return the maximum level of the given target, if any logger
+    /// name designates it
+    fn logger_level(&self, target: &str) -> Option<LevelFilter> {
+        most_specific(&self.logger_levels, target)
+    }
+
+    /// Limit the number of events per second of each callsite of the
+    /// targets designated by `name`, or remove the limit with `None`.
+    /// When several names match a target, the most specific one
+    /// applies.
+    pub fn set_rate_limit(&mut self, name: &str, rate: Option<f64>) {
+        self.rate_limits.retain(|(n, _)| n != name);
+        if let Some(rate) = rate {
+            self.rate_limits.push((name.to_string(), rate));
+        }
+    }
+
+    /// Remove all the rate limits
+    pub fn clear_rate_limits(&mut self) {
+        self.rate_limits.clear();
+    }
+
+    /// Return the rate limits, sorted by target name
+    pub fn rate_limits(&self) -> Vec<(&str, f64)> {
+        let mut limits: Vec<_> = self
+            .rate_limits
+            .iter()
+            .map(|(name, rate)| (name.as_str(), *rate))
+            .collect();
+        limits.sort_by(|a, b| a.0.cmp(b.0));
+        limits
+    }
+
+    /// Return the callsites that suppressed events because of their
+    /// rate limit since the last call, along with the number of
+    /// events they suppressed
+    pub fn take_rate_limited(&self) -> Vec<(&'static Metadata<'static>, u64)> {
+        self.callsites
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|info| {
+                let suppressed = info.bucket.as_ref()?.take_suppressed();
+                (suppressed > 0).then_some((info.metadata, suppressed))
+            })
+            .collect()
+    }
+
+    /// Return `true` if an event from the given callsite is over the
+    /// callsite's rate limit
+    fn is_rate_limited(&self, metadata: &'static Metadata<'static>) -> bool {
+        if self.rate_limits.is_empty() {
+            return false;
+        }
+        self.callsites
+            .read()
+            .unwrap()
+            .get(&metadata.callsite())
+            .and_then(|info| info.bucket.as_ref())
+            .is_some_and(|bucket| !bucket.take())
+    }
+
+    /// Turn dry-run mode on or off. In dry-run mode, the rules are
+    /// evaluated and matches are counted, but nothing is suppressed.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+        self.publish_partitions();
+    }
+
+    /// Whether the rules are evaluated without suppressing anything
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Register a custom matcher under the given name, for the rules
+    /// such as `<field>~<name>`. This replaces the matcher registered
+    /// with this name, if any. The rules whose matcher isn't
+    /// registered never match.
+    pub fn register_matcher(&mut self, name: &str, matcher: impl FieldMatcher + 'static) {
+        self.matchers.insert(name, matcher);
+    }
+
+    /// Replace the custom matchers, for the builder
+    pub(crate) fn set_matchers(&mut self, matchers: CustomMatchers) {
+        self.matchers = matchers;
+    }
+
+    /// Return the names of the custom matchers, sorted
+    pub fn matchers(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.matchers.0.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// Turn explain mode on or off, see [`crate::explain`]
+    pub fn set_explain(&mut self, explain: bool) {
+        self.explain = explain.then(|| TokenBucket::new(explain::RATE));
+    }
+
+    /// Return `true` if explain mode is on
+    pub fn explain_mode(&self) -> bool {
+        self.explain.is_some()
+    }
+
+    /// Choose whether the rules select what is suppressed, or what is
+    /// kept
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+        self.publish_partitions();
+    }
+
+    /// Whether the rules select what is suppressed, or what is kept
+    pub fn mode(&self) -> FilterMode {
+        self.mode
+    }
+
+    /// Never suppress the spans and events at the given level, or a
+    /// more severe one, whatever the rules, or stop with `None`
+    pub fn set_bypass_level(&mut self, level: Option<Level>) {
+        self.bypass_level = level;
+        self.publish_partitions();
+    }
+
+    /// The level from which the rules don't apply, if any
+    pub fn bypass_level(&self) -> Option<Level> {
+        self.bypass_level
+    }
+
+    /// Return `true` if the spans or events of a callsite are too
+    /// severe to be suppressed by the rules
+    fn bypasses(&self, metadata: &Metadata<'_>) -> bool {
+        self.bypass_level
+            .is_some_and(|level| *metadata.level() <= level)
+    }
+
+    /// Write the suppressed events to the given quarantine, or
+    /// discard them with `None`
+    pub fn set_quarantine(&mut self, quarantine: Option<Quarantine>) {
+        self.quarantine = quarantine;
+    }
+
+    /// The quarantine of the suppressed events, if any
+    pub fn quarantine(&self) -> Option<&Quarantine> {
+        self.quarantine.as_ref()
+    }
+
+    /// Open the quarantine file again, if events are quarantined to
+    /// a file
+    pub fn reopen_quarantine(&mut self) -> io::Result<()> {
+        match &mut self.quarantine {
+            Some(quarantine) => quarantine.reopen(),
+            None => Ok(()),
+        }
+    }
+
+    /// Turn trigger mode on, keeping up to `depth` events per span, or
+    /// off with `None`. In trigger mode, WARN and ERROR events are
+    /// never suppressed, and they are preceded by the events that
+    /// were suppressed within their spans.
+    pub fn set_trigger(&mut self, depth: Option<usize>) {
+        self.trigger = depth;
+    }
+
+    /// The number of events kept per span in trigger mode, if it is on
+    pub fn trigger(&self) -> Option<usize> {
+        self.trigger
+    }
+
+    /// Suppress the events identical to the previous event of their
+    /// callsite, within the given window, or stop with `None`
+    pub fn set_dedup(&mut self, window: Option<Duration>) {
+        self.dedup = window;
+    }
+
+    /// The window within which identical events are suppressed, if any
+    pub fn dedup(&self) -> Option<Duration> {
+        self.dedup
+    }
+
+    /// Return the callsites whose events were repeated since the last
+    /// call, along with the number of repeats
+    pub fn take_repeats(&self) -> Vec<(&'static Metadata<'static>, u64)> {
+        let Some(window) = self.dedup else {
+            return Vec::new();
+        };
+        self.callsites
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|info| {
+                let repeated = info.repeats.take_repeats(window);
+                (repeated > 0).then_some((info.metadata, repeated))
+            })
+            .collect()
+    }
+
+    /// Return `true` if the event is identical to the previous event
+    /// of its callsite, within the dedup window
+    fn is_duplicate(&self, event: &Event<'_>) -> bool {
+        let Some(window) = self.dedup else {
+            return false;
+        };
+        let Some(repeats) = self
+            .callsites
+            .read()
+            .unwrap()
+            .get(&event.metadata().callsite())
+            .map(|info| info.repeats.clone())
+        else {
+            return false;
+        };
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        let mut key = fields.message.unwrap_or_default();
+        for (name, value) in fields.values {
+            let _ = write!(key, " {name}={value}");
+        }
+        repeats.is_repeat(key, window)
+    }
+
+    /// Only emit the spans that are busy for longer than `threshold`,
+    /// or emit all spans again with `None`
+    pub fn set_slow(&mut self, threshold: Option<Duration>) {
+        self.slow = threshold;
+    }
+
+    /// The threshold under which spans aren't emitted, if any
+    pub fn slow(&self) -> Option<Duration> {
+        self.slow
+    }
+
+    /// Hold an event in its innermost timed span until the span
+    /// closes. Return `false` if the event is not within a timed span.
+    fn defer_event<S>(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(scope) = ctx.event_scope(event) else {
+            return false;
+        };
+        let Some(span_ref) = scope
+            .into_iter()
+            .find(|span_ref| span_ref.extensions().get::<Timing>().is_some())
+        else {
+            return false;
+        };
+        let held = HeldEvent::new(event, ctx);
+        if let Some(timing) = span_ref.extensions_mut().get_mut::<Timing>() {
+            timing.events.push(held);
+        }
+        true
+    }
+
+    /// Decide what to do with the events held in a closing span:
+    /// release them if the span was slow, see [`crate::replay`],
+    /// otherwise hand them over to the parent span, which may turn out
+    /// to be slow, or drop them.
+    fn close_timed_span<S>(&self, id: &Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span_ref) = ctx.span(id) else {
+            return;
+        };
+        let Some(timing) = span_ref.extensions_mut().remove::<Timing>() else {
+            return;
+        };
+        let Timing {
+            created,
+            busy,
+            mut events,
+            ..
+        } = timing;
+        // When slow mode is turned off, let everything through
+        if self.slow.is_none_or(|threshold| busy >= threshold) {
+            let idle = created.elapsed().saturating_sub(busy);
+            events.push(HeldEvent::close(&span_ref, busy, idle));
+            let count = events.iter().filter(|held| held.is_event()).count();
+            let released = replay::send(events);
+            for _ in 0..count {
+                if released {
+                    self.stats.event_passed();
+                } else {
+                    self.stats.event_suppressed();
+                }
+            }
+            return;
+        }
+        if let Some(parent) = span_ref.parent() {
+            if let Some(timing) = parent.extensions_mut().get_mut::<Timing>() {
+                timing.events.append(&mut events);
+                return;
+            }
+        }
+        for _ in events {
+            self.stats.event_suppressed();
+        }
+    }
+
+    /// Return the last `n` suppressed events, oldest first
+    pub fn recently_suppressed(&self, n: usize) -> Vec<SuppressedEvent> {
+        self.suppressed.last(n)
+    }
+
+    /// Return the layer's counters
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Mute a callsite, optionally for a limited time. Return `false`
+    /// if there is no callsite with this number.
+    pub fn mute_callsite(&mut self, number: usize, ttl: Option<Duration>) -> bool {
+        let exists = self
+            .callsites
+            .read()
+            .unwrap()
+            .values()
+            .any(|info| info.number == number);
+        if exists {
+            self.muted
+                .insert(number, ttl.map(|ttl| Instant::now() + ttl));
+        }
+        exists
+    }
+
+    /// Unmute a callsite. Return `false` if it wasn't muted.
+    pub fn unmute_callsite(&mut self, number: usize) -> bool {
+        self.muted.remove(&number).is_some()
+    }
+
+    /// Drop the mutes that expired, and return the numbers of their
+    /// callsites
+    pub fn expire_mutes(&mut self) -> Vec<usize> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.muted.retain(|number, deadline| {
+            let alive = deadline.is_none_or(|deadline| deadline > now);
+            if !alive {
+                expired.push(*number);
+            }
+            alive
+        });
+        expired
+    }
+
+    /// Return when the next rule or callsite mute with a TTL expires,
+    /// if any. This may be in the past, until
+    /// [`DynamicFieldFilter::expire_rules`] or
+    /// [`DynamicFieldFilter::expire_mutes`] drops them.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let rules = self.filters.values().filter_map(|rule| rule.deadline);
+        let mutes = self.muted.values().flatten().copied();
+        rules.chain(mutes).min()
+    }
+
+    /// Return the muted callsites, along with the time left before
+    /// their mute expires
+    pub fn mutes(&self) -> Vec<(usize, Option<Duration>)> {
+        let now = Instant::now();
+        let mut mutes: Vec<_> = self
+            .muted
+            .iter()
+            .map(|(number, deadline)| {
+                (
+                    *number,
+                    deadline.map(|deadline| deadline.saturating_duration_since(now)),
+                )
+            })
+            .collect();
+        mutes.sort();
+        mutes
+    }
+
+    fn is_muted(&self, number: usize) -> bool {
+        match self.muted.get(&number) {
+            Some(Some(deadline)) => *deadline > Instant::now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Return the registered callsites, along with their number and
+    /// whether they are muted, sorted by number
+    pub fn callsites(&self) -> Vec<(usize, &'static Metadata<'static>, bool)> {
+        let mut callsites: Vec<_> = self
+            .callsites
+            .read()
+            .unwrap()
+            .values()
+            .map(|info| (info.number, info.metadata, self.is_muted(info.number)))
+            .collect();
+        callsites.sort_by_key(|(number, _, _)| *number);
+        callsites
+    }
+
+    /// Return `true` if the given span is disabled
+    pub fn is_disabled(&self, id: &Id) -> bool {
+        self.disabled.contains(id)
+    }
+
+    /// Return the IDs of the spans that are currently open, among
+    /// those opened while they were tracked, see
+    /// [`DynamicFieldFilter::track_live_spans`]
+    pub fn live_spans(&self) -> Vec<Id> {
+        self.live.ids()
+    }
+
+    /// Track the open spans, to list them with
+    /// [`DynamicFieldFilter::live_spans`], until the returned value is
+    /// dropped
+    pub fn track_live_spans(&self) -> SpanTracking {
+        self.trackers.fetch_add(1, Ordering::Relaxed);
+        SpanTracking(self.trackers.clone())
+    }
+
+    /// Return the fields of the given callsite that are filtered on
+    fn callsite_filters(&self, metadata: &'static Metadata<'static>) -> CallsiteFilters {
+        let mut filters = CallsiteFilters::default();
+        for field in metadata.fields().iter() {
+            if let Some(rule) = self.filters.get(field.name()) {
+                if rule.applies_to(metadata) {
+                    filters.active.push((field.clone(), rule.clone()));
+                }
+            }
+            for rule in self.shadows.values() {
+                if rule.field == field.name() && rule.applies_to(metadata) {
+                    filters.shadow.push((field.clone(), rule.clone()));
+                }
+            }
+        }
+        filters
+    }
+
+    /// Return the rule matching the values of a span or event, if
+    /// any, along with the value that matched as `<field>=<value>`,
+    /// and count the hits of the shadow rules. `record` must record
+    /// the values with the given visitor.
+    fn match_rule(
+        &self,
+        metadata: &'static Metadata<'static>,
+        record: impl FnOnce(&mut MatchFieldVisitor<'_>),
+    ) -> Option<(Arc<Rule>, String)> {
+        if self.filters.is_empty() && self.shadows.is_empty() {
+            return None;
+        }
+        let callsites = self.callsites.read().unwrap();
+        let uncached;
+        let filters = match callsites.get(&metadata.callsite()) {
+            Some(info) => &info.filters,
+            None => {
+                uncached = self.callsite_filters(metadata);
+                &uncached
+            }
+        };
+        if !filters.is_empty() {
+            let mut visitor = MatchFieldVisitor {
+                filters,
+                matchers: &self.matchers,
+                mode: self.mode,
+                matched: None,
+                value: None,
+            };
+            record(&mut visitor);
+            if let Some(rule) = visitor.matched.cloned() {
+                return Some((rule, visitor.value.unwrap_or_default()));
+            }
+        }
+        self.match_enrichment(metadata)
+    }
+
+    /// Return the rule matching the enrichment fields of an event, if
+    /// any, see [`enrich`], and count the hits of the shadow rules.
+    /// These aren't fields of the callsites, so they are matched as
+    /// text, and custom matchers never match them.
+    fn match_enrichment(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> Option<(Arc<Rule>, String)> {
+        if !metadata.is_event() || !enrich::enabled() {
+            return None;
+        }
+        for name in enrich::FIELDS {
+            let rule = self.filters.get(*name);
+            let shadow = self.shadows.values().filter(|rule| rule.field == *name);
+            if rule.is_none() && shadow.clone().next().is_none() {
+                continue;
+            }
+            let Some(value) = enrich::value(name) else {
+                continue;
+            };
+            for shadow in shadow.filter(|rule| rule.applies_to(metadata)) {
+                if shadow.matcher.matches_text(name, &value) {
+                    shadow.hits.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if let Some(rule) = rule.filter(|rule| {
+                rule.is_active() && rule.applies_to(metadata) && rule.applies_on_current_thread()
+            }) {
+                if self
+                    .mode
+                    .suppresses(rule.matcher.matches_text(name, &value))
+                {
+                    return Some((rule.clone(), format!("{name}={value}")));
+                }
+            }
+        }
+        None
+    }
+
+    /// In trigger mode, release the events that were suppressed within
+    /// the spans of a WARN or ERROR event, followed by the event itself,
+    /// in the order they occurred, see [`crate::replay`]. Return `true`
+    /// if they were released, and the event must wait to be emitted
+    /// after them.
+    fn flush_history<S>(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if self.trigger.is_none() || *event.metadata().level() > Level::WARN {
+            return false;
+        }
+        let Some(scope) = ctx.event_scope(event) else {
+            return false;
+        };
+        let mut events: Vec<HeldEvent> = scope
+            .filter_map(|span_ref| span_ref.extensions_mut().remove::<History>())
+            .flat_map(|History(history)| history)
+            .collect();
+        if events.is_empty() {
+            return false;
+        }
+        events.sort_by_key(|held| held.at);
+        events.push(HeldEvent::new(event, ctx));
+        replay::send(events)
+    }
+
+    /// Disable the given span, and everything within it. In dry-run
+    /// mode the span is tracked the same way, but `enabled` lets
+    /// everything through.
+    fn disable_span(&self, id: &Id) {
+        self.disabled.insert(id);
+        if self.dry_run {
+            self.stats.span_dry_run();
+        } else {
+            self.stats.span_suppressed();
+        }
+    }
+
+    /// Describe why a span or event was suppressed, in explain mode
+    fn explain(
+        &self,
+        metadata: &'static Metadata<'static>,
+        reason: Reason,
+        matched: Option<String>,
+    ) {
+        if self.explain.as_ref().is_some_and(|bucket| bucket.take()) {
+            explain::send(Explanation {
+                metadata,
+                reason,
+                matched,
+            });
+        }
+    }
+
+    /// Drop all the state associated with the given span
+    fn forget_span(&self, id: &Id) {
+        self.disabled.remove(id);
+        self.live.remove(id);
+    }
+}
+
+/// Return `true` if the filter never applies to a callsite: the
+/// layer's own logging, see [`crate::diagnostics`], and the reports of
+/// panics, see [`crate::panics`]
+fn is_exempt(metadata: &Metadata<'_>) -> bool {
+    diagnostics::is_own(metadata) || panics::is_panic(metadata)
+}
+
+/// Return `true` if the filter doesn't apply to a span or event: those
+/// of the exempt callsites, and on the reporter's thread, the held
+/// events released by [`crate::replay`]. Unlike [`is_exempt`], this
+/// depends on the thread, so it must not decide the interest of a
+/// callsite.
+fn is_exempt_here(metadata: &Metadata<'_>) -> bool {
+    is_exempt(metadata) || replay::is_replaying()
+}
+
+impl<S> Layer<S> for DynamicFieldFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // The layer's own logging and the panics are never filtered,
+        // nor listed among the callsites that can be muted
+        if is_exempt(metadata) {
+            return Interest::always();
+        }
+        let filters = self.callsite_filters(metadata);
+        let mut callsites = self.callsites.write().unwrap();
+        let next_number = callsites.len() + 1;
+        let info = callsites
+            .entry(metadata.callsite())
+            .or_insert_with(|| CallsiteInfo {
+                number: next_number,
+                metadata,
+                filters: CallsiteFilters::default(),
+                bucket: None,
+                repeats: Arc::default(),
+            });
+        info.filters = filters;
+        // Keep the current bucket if the rate didn't change, so that
+        // unrelated changes to the layer don't reset it
+        let rate = metadata
+            .is_event()
+            .then(|| most_specific(&self.rate_limits, metadata.target()))
+            .flatten();
+        if info.bucket.as_ref().map(|bucket| bucket.rate()) != rate {
+            info.bucket = rate.map(|rate| Arc::new(TokenBucket::new(rate)));
+        }
+        let too_verbose = self
+            .logger_level(metadata.target())
+            .is_some_and(|level| metadata.level() > &level);
+        if too_verbose || self.is_muted(info.number) {
+            Interest::never()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        if is_exempt_here(metadata) {
+            return true;
+        }
+        let in_disabled_span = match ctx.current_span().id() {
+            Some(id) => self.disabled.contains(id),
+            None => false,
+        };
+        // Events are checked and counted in `event_enabled`, where
+        // their fields are available to format them if they are
+        // suppressed. In dry-run mode, spans get created and are
+        // counted in `on_new_span`. When quarantining, spans within
+        // disabled spans are created too, so that the events within
+        // them are written to the quarantine with their full context.
+        if metadata.is_event()
+            || !in_disabled_span
+            || self.dry_run
+            || self.quarantine.is_some()
+            || self.bypasses(metadata)
+        {
+            return true;
+        }
+        self.stats.span_suppressed();
+        false
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        if is_exempt_here(event.metadata()) {
+            return true;
+        }
+        let span = if event.is_contextual() {
+            ctx.current_span().id().cloned()
+        } else {
+            event.parent().cloned()
+        };
+        let mut matched = None;
+        let reason = if self.bypasses(event.metadata()) {
+            None
+        } else if span.as_ref().is_some_and(|id| self.disabled.contains(id)) {
+            Some(Reason::DisabledSpan)
+        } else {
+            // Sample events by span, so that the events of a span are
+            // all kept or all dropped
+            let key = span.as_ref().map(Id::into_u64);
+            self.match_rule(event.metadata(), |visitor| event.record(visitor))
+                .filter(|(rule, _)| rule.hit(key))
+                .map(|(rule, value)| {
+                    matched = Some(value);
+                    Reason::Rule(rule.to_string())
+                })
+        };
+        let reason = reason.or_else(|| {
+            if self.is_rate_limited(event.metadata()) {
+                Some(Reason::RateLimited)
+            } else if self.is_duplicate(event) {
+                Some(Reason::Duplicate)
+            } else {
+                None
+            }
+        });
+        let Some(reason) = reason else {
+            if self.slow.is_some() && self.defer_event(event, &ctx) {
+                return false;
+            }
+            self.stats.event_passed();
+            return !self.flush_history(event, &ctx);
+        };
+        if self.dry_run {
+            self.stats.event_dry_run();
+            return true;
+        }
+        if self.trigger.is_some() && *event.metadata().level() <= Level::WARN {
+            self.stats.event_passed();
+            return !self.flush_history(event, &ctx);
+        }
+        self.stats.event_suppressed();
+        self.explain(event.metadata(), reason.clone(), matched);
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        let line = format::format_event(event, &fields, &ctx);
+        if let Some(quarantine) = &self.quarantine {
+            quarantine.write(line.clone());
+        }
+        if let Some(depth) = self.trigger {
+            if let Some(span_ref) = ctx.event_span(event) {
+                // Keep the event before locking the span's extensions,
+                // since it reads the fields of its spans
+                let held = HeldEvent::new(event, &ctx);
+                let mut extensions = span_ref.extensions_mut();
+                let History(history) = match extensions.get_mut::<History>() {
+                    Some(history) => history,
+                    None => {
+                        extensions.insert(History::default());
+                        extensions.get_mut::<History>().unwrap()
+                    }
+                };
+                if history.len() >= depth {
+                    history.pop_front();
+                }
+                if depth > 0 {
+                    history.push_back(held);
+                }
+            }
+        }
+        self.suppressed.push(SuppressedEvent { line, reason });
+        false
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if is_exempt_here(attrs.metadata()) {
+            return;
+        }
+        // Span IDs are reused once a span is closed. Normally its
+        // state is dropped in `on_close`, but make sure nothing left
+        // over from a previous span with the same ID applies to this
+        // one.
+        self.forget_span(id);
+        if self.trackers.load(Ordering::Relaxed) > 0 {
+            self.live.insert(id);
+        }
+        self.stats.span_evaluated();
+        // Keep the field values of the span, to emit the events it
+        // holds within a copy of it, see `crate::replay`
+        if self.trigger.is_some() || self.slow.is_some() {
+            if let Some(span_ref) = ctx.span(id) {
+                let mut values = Recorded::new(attrs.metadata());
+                attrs.record(&mut values);
+                span_ref.extensions_mut().replace(values);
+            }
+        }
+        if self.slow.is_some() {
+            if let Some(span_ref) = ctx.span(id) {
+                span_ref.extensions_mut().insert(Timing {
+                    created: Instant::now(),
+                    entered: None,
+                    busy: Duration::ZERO,
+                    events: Vec::new(),
+                });
+            }
+        }
+
+        if self.bypasses(attrs.metadata()) {
+            return;
+        }
+
+        // If the parent span is disabled, disable this span too. Since
+        // the parent's parent was checked the same way when the
+        // parent was created, this covers all the ancestors.
+        let parent = if attrs.is_contextual() {
+            ctx.current_span().id().cloned()
+        } else {
+            attrs.parent().cloned()
+        };
+        if let Some(parent) = parent {
+            if self.disabled.contains(&parent) {
+                self.disable_span(id);
+                if !self.dry_run {
+                    self.explain(attrs.metadata(), Reason::DisabledSpan, None);
+                }
+                return;
+            }
+        }
+
+        // If the parent wasn't disabled or if there was no parent,
+        // check the fields
+        let matched = self.match_rule(attrs.metadata(), |visitor| attrs.record(visitor));
+        if let Some((rule, value)) = matched {
+            if rule.hit(Some(id.into_u64())) {
+                self.disable_span(id);
+                if !self.dry_run {
+                    self.explain(
+                        attrs.metadata(),
+                        Reason::Rule(rule.to_string()),
+                        Some(value),
+                    );
+                }
+            }
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            if let Some(recorded) = span_ref.extensions_mut().get_mut::<Recorded>() {
+                values.record(recorded);
+            }
+        }
+    }
+
+    // A span following from a disabled span is disabled too, like a
+    // span within one, e.g. when BGP handles an update the RIB sent
+    // from a disabled span
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, S>) {
+        if !self.disabled.contains(follows) || self.disabled.contains(id) {
+            return;
+        }
+        let Some(span_ref) = ctx.span(id) else {
+            return;
+        };
+        if is_exempt_here(span_ref.metadata()) || self.bypasses(span_ref.metadata()) {
+            return;
+        }
+        self.disable_span(id);
+        if !self.dry_run {
+            self.explain(span_ref.metadata(), Reason::DisabledSpan, None);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            if let Some(timing) = span_ref.extensions_mut().get_mut::<Timing>() {
+                timing.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            if let Some(timing) = span_ref.extensions_mut().get_mut::<Timing>() {
+                if let Some(entered) = timing.entered.take() {
+                    timing.busy += entered.elapsed();
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.close_timed_span(&id, &ctx);
+        self.forget_span(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use tracing::info;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::reload;
+    use tracing_subscriber::Registry;
+
+    use super::*;
+
+    /// How long the rules with a TTL last in the tests
+    const TTL: Duration = Duration::from_millis(50);
+
+    fn vrf_1() -> Matcher {
+        Matcher::Equals("1".to_string())
+    }
+
+    fn with_ttl() -> RuleOptions {
+        RuleOptions {
+            ttl: Some(TTL),
+            ..RuleOptions::default()
+        }
+    }
+
+    /// A profile with a single rule on `vrf_id`, with a TTL
+    fn profile_with_ttl() -> Profile {
+        Profile {
+            rules: vec![RuleConfig {
+                field: "vrf_id".to_string(),
+                matcher: vrf_1(),
+                options: with_ttl(),
+            }],
+            ..Profile::default()
+        }
+    }
+
+    /// A layer recording the `n` field of the events it sees
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<u64>>>);
+
+    impl Visit for Recorder {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            if field.name() == "n" {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+    }
+
+    impl<S: Subscriber> Layer<S> for Recorder {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            event.record(&mut self.clone());
+        }
+    }
+
+    /// Emit `count` events numbered from 0, alternately on `vrf_id=1`
+    /// and `vrf_id=2`, through a filter with a rule on `vrf_id=1`, and
+    /// return the numbers of those that went through
+    fn filter_events(options: RuleOptions, count: u64) -> Vec<u64> {
+        let mut layer = DynamicFieldFilter::default();
+        layer.set_rule("vrf_id", vrf_1(), options);
+        let recorder = Recorder::default();
+        let subscriber = Registry::default().with(layer).with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for n in 0..count {
+                info!(vrf_id = n % 2 + 1, n, "update");
+            }
+        });
+        let recorded = recorder.0.lock().unwrap().clone();
+        recorded
+    }
+
+    #[test]
+    fn limits_suppress_the_first_matches() {
+        let limit = RuleOptions {
+            budget: Some(Budget::Limit(3)),
+            ..RuleOptions::default()
+        };
+        let recorded = filter_events(limit.clone(), 20);
+        // The first 3 matches are 0, 2 and 4
+        let kept: Vec<u64> = (0..20).filter(|n| n % 2 == 1 || *n > 4).collect();
+        assert_eq!(recorded, kept);
+
+        let mut layer = DynamicFieldFilter::default();
+        layer.set_rule("vrf_id", vrf_1(), limit);
+        let rule = &layer.filters()[0];
+        for remaining in (0..3).rev() {
+            assert!(rule.hit(None));
+            assert_eq!(rule.remaining(), Some(remaining));
+        }
+        assert!(rule.is_expired());
+        assert!(!rule.is_active());
+        assert!(!rule.hit(None));
+    }
+
+    #[test]
+    fn captures_keep_the_first_matches() {
+        let capture = RuleOptions {
+            budget: Some(Budget::Capture(3)),
+            ..RuleOptions::default()
+        };
+        let recorded = filter_events(capture.clone(), 20);
+        let kept: Vec<u64> = (0..20).filter(|n| n % 2 == 1 || *n <= 4).collect();
+        assert_eq!(recorded, kept);
+
+        let mut layer = DynamicFieldFilter::default();
+        layer.set_rule("vrf_id", vrf_1(), capture);
+        let rule = &layer.filters()[0];
+        for remaining in (0..3).rev() {
+            assert!(!rule.hit(None));
+            assert_eq!(rule.remaining(), Some(remaining));
+        }
+        // Capturing goes on, unlike limits
+        assert!(!rule.is_expired());
+        assert!(rule.hit(None));
+        assert_eq!(rule.remaining(), Some(0));
+    }
+
+    #[test]
+    fn sampling_keeps_the_given_fraction() {
+        let sample = RuleOptions {
+            sample: Some(0.25),
+            ..RuleOptions::default()
+        };
+        // 400 matches, of which about a quarter are kept
+        let recorded = filter_events(sample.clone(), 800);
+        let kept = recorded.iter().filter(|n| *n % 2 == 0).count();
+        assert!((80..=120).contains(&kept), "{kept} kept");
+        assert_eq!(recorded.len() - kept, 400);
+        // The same matches are kept every time
+        assert_eq!(filter_events(sample, 800), recorded);
+    }
+
+    #[test]
+    fn sampling_keeps_the_events_of_a_span_together() {
+        let mut layer = DynamicFieldFilter::default();
+        let sample = RuleOptions {
+            sample: Some(0.5),
+            ..RuleOptions::default()
+        };
+        layer.set_rule("vrf_id", vrf_1(), sample);
+        let recorder = Recorder::default();
+        let subscriber = Registry::default().with(layer).with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for span in 0..100u64 {
+                let _entered = info_span!("update", vrf_id = 2).entered();
+                for event in 0..5 {
+                    info!(vrf_id = 1, n = span * 5 + event, "update");
+                }
+            }
+        });
+        let mut events = [0; 100];
+        for n in recorder.0.lock().unwrap().iter() {
+            events[*n as usize / 5] += 1;
+        }
+        assert!(events.iter().all(|count| *count == 0 || *count == 5));
+        let kept = events.iter().filter(|count| **count == 5).count();
+        assert!((30..=70).contains(&kept), "{kept} spans kept");
+    }
+
+    /// Set a rule on the field `field_<n>`, as a change that can be
+    /// undone
+    fn change_rule(layer: &mut DynamicFieldFilter, n: usize) {
+        layer
+            .change(|layer| layer.set_rule(&format!("field_{n}"), vrf_1(), RuleOptions::default()));
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip() {
+        let mut layer = DynamicFieldFilter::default();
+        let mut states = vec![layer.current_profile()];
+        for n in 0..3 {
+            change_rule(&mut layer, n);
+            states.push(layer.current_profile());
+        }
+        // Changes that change nothing are not recorded
+        change_rule(&mut layer, 2);
+        for state in states.iter().rev().skip(1) {
+            assert!(layer.undo());
+            assert_eq!(&layer.current_profile(), state);
+        }
+        assert!(!layer.undo());
+        for state in states.iter().skip(1) {
+            assert!(layer.redo());
+            assert_eq!(&layer.current_profile(), state);
+        }
+        assert!(!layer.redo());
+        // A new change can't be followed by the undone ones
+        assert!(layer.undo());
+        change_rule(&mut layer, 3);
+        assert!(!layer.redo());
+    }
+
+    #[test]
+    fn undo_history_is_capped() {
+        let mut layer = DynamicFieldFilter::default();
+        let mut states = Vec::new();
+        for n in 0..UNDO_DEPTH + 5 {
+            states.push(layer.current_profile());
+            change_rule(&mut layer, n);
+        }
+        let mut undone = 0;
+        while layer.undo() {
+            undone += 1;
+        }
+        assert_eq!(undone, UNDO_DEPTH);
+        assert_eq!(layer.current_profile(), states[5]);
+    }
+
+    #[test]
+    fn undo_history_is_capped_when_redoing() {
+        let mut layer = DynamicFieldFilter::default();
+        let mut states = Vec::new();
+        for n in 0..UNDO_DEPTH + 5 {
+            states.push(layer.current_profile());
+            change_rule(&mut layer, n);
+        }
+        for _ in 0..3 {
+            assert!(layer.undo());
+        }
+        while layer.redo() {}
+        assert_eq!(layer.undo.len(), UNDO_DEPTH);
+        while layer.undo() {}
+        assert_eq!(layer.current_profile(), states[5]);
+    }
+
+    /// A way of setting a rule, for the tests
+    type Setter = fn(&mut DynamicFieldFilter);
+
+    #[test]
+    fn rules_expire_however_they_are_set() {
+        let set: &[(&str, Setter)] = &[
+            ("set", |layer| layer.set_rule("vrf_id", vrf_1(), with_ttl())),
+            ("profile", |layer| layer.apply_profile(profile_with_ttl())),
+            ("load", |layer| {
+                layer.profiles.insert("ttl".to_string(), profile_with_ttl());
+                assert!(layer.load_profile("ttl"));
+            }),
+            ("restore", |layer| {
+                layer.restore(SavedFilters {
+                    current: profile_with_ttl(),
+                    profiles: BTreeMap::new(),
+                })
+            }),
+            ("undo", |layer| {
+                layer.change(|layer| layer.set_rule("vrf_id", vrf_1(), with_ttl()));
+                layer.change(|layer| layer.remove_rule("vrf_id"));
+                assert!(layer.undo());
+            }),
+            ("redo", |layer| {
+                layer.change(|layer| layer.set_rule("vrf_id", vrf_1(), with_ttl()));
+                assert!(layer.undo());
+                assert!(layer.redo());
+            }),
+        ];
+        let mut layers = Vec::new();
+        for (path, set) in set {
+            let mut layer = DynamicFieldFilter::default();
+            set(&mut layer);
+            let rule = &layer.filters()[0];
+            assert!(rule.is_active(), "{path}");
+            assert!(rule.suppresses_all(), "{path}");
+            assert!(layer.next_deadline().is_some(), "{path}");
+            layers.push((path, layer));
+        }
+        thread::sleep(TTL);
+        for (path, mut layer) in layers {
+            let rule = &layer.filters()[0];
+            assert!(!rule.is_active(), "{path}");
+            assert!(!rule.suppresses_all(), "{path}");
+            assert!(
+                layer
+                    .next_deadline()
+                    .is_some_and(|deadline| deadline <= Instant::now()),
+                "{path}"
+            );
+            assert_eq!(layer.expire_rules(), ["vrf_id=1 TTL 0"], "{path}");
+            assert!(layer.filters().is_empty(), "{path}");
+            assert_eq!(layer.next_deadline(), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn staged_rules_expire_from_when_they_are_applied() {
+        let staged = |layer: &mut DynamicFieldFilter| layer.set_rule("vrf_id", vrf_1(), with_ttl());
+        thread::sleep(TTL);
+        let mut layer = DynamicFieldFilter::default();
+        layer.change(staged);
+        assert!(layer.filters()[0].is_active());
+        assert!(layer.expire_rules().is_empty());
+    }
+
+    #[test]
+    fn closed_spans_are_forgotten() {
+        let mut layer = DynamicFieldFilter::default();
+        layer.set_rule("vrf_id", vrf_1(), RuleOptions::default());
+        let (layer, handle) = reload::Layer::new(layer);
+        let tracking = handle
+            .with_current(|layer| layer.track_live_spans())
+            .unwrap();
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let disabled = info_span!("update", vrf_id = 1);
+            let id = disabled.id().unwrap();
+            handle
+                .with_current(|layer| {
+                    assert!(layer.is_disabled(&id));
+                    assert_eq!(layer.live_spans(), std::slice::from_ref(&id));
+                })
+                .unwrap();
+            drop(disabled);
+            handle
+                .with_current(|layer| {
+                    assert!(!layer.is_disabled(&id));
+                    assert!(layer.live_spans().is_empty());
+                })
+                .unwrap();
+            // The registry reuses the slot of the closed span, with a
+            // new generation in the ID, and the span in it must not
+            // inherit the state of the closed one
+            let enabled = info_span!("update", vrf_id = 2);
+            let reused = enabled.id().unwrap();
+            let slot = |id: &Id| id.into_u64() & ((1 << 51) - 1);
+            assert_eq!(slot(&reused), slot(&id));
+            handle
+                .with_current(|layer| {
+                    assert!(!layer.is_disabled(&id));
+                    assert!(!layer.is_disabled(&reused));
+                    assert_eq!(layer.live_spans(), [reused]);
+                })
+                .unwrap();
+        });
+        drop(tracking);
+    }
+}
@@ -0,0 +1,190 @@
+//! Formatting of records for the outputs that don't go through the
+//! fmt layer, because they need to look at the field values of each
+//! record.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::Write;
+
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::span::Record;
+use tracing::Event;
+use tracing::Id;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::time::SystemTime;
+use tracing_subscriber::fmt::FormattedFields;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::Scope;
+
+use crate::enrich;
+use crate::redact;
+use crate::redact::RedactingFields;
+
+/// Field values recorded as strings, in the order they were recorded
+#[derive(Debug, Default)]
+pub struct FieldValues {
+    /// The message of the event, if any
+    pub message: Option<String>,
+    /// The other fields, by name
+    pub values: Vec<(&'static str, String)>,
+}
+
+impl FieldValues {
+    /// Return the value of the given field, if it was recorded
+    pub fn get(&self, name: &str) -> Option<&str> {
+        if name == "message" {
+            return self.message.as_deref();
+        }
+        self.values
+            .iter()
+            .rev()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn push(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = Some(value);
+        } else {
+            self.values.push((field.name(), value));
+        }
+    }
+}
+
+impl Visit for FieldValues {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+}
+
+/// A span extension holding the span's field values
+#[derive(Debug, Default)]
+pub struct SpanFields(pub FieldValues);
+
+/// Record the fields of a new span in a `SpanFields` extension
+pub fn record_span_fields<S>(attrs: &Attributes<'_>, id: &Id, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span_ref) = ctx.span(id) else {
+        return;
+    };
+    let mut fields = FieldValues::default();
+    attrs.record(&mut fields);
+    span_ref.extensions_mut().replace(SpanFields(fields));
+}
+
+/// Add the values recorded after a span's creation to its
+/// `SpanFields` extension
+pub fn update_span_fields<S>(id: &Id, values: &Record<'_>, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span_ref) = ctx.span(id) else {
+        return;
+    };
+    let mut extensions = span_ref.extensions_mut();
+    if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+        values.record(fields);
+    }
+}
+
+/// Look up a field value for the given event, first in the event
+/// itself and then in its spans, from the innermost to the outermost.
+pub fn lookup_field<S>(
+    name: &str,
+    event: &Event<'_>,
+    fields: &FieldValues,
+    ctx: &Context<'_, S>,
+) -> Option<String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if let Some(value) = fields.get(name) {
+        return Some(value.to_string());
+    }
+    ctx.event_scope(event)?.find_map(|span_ref| {
+        span_ref
+            .extensions()
+            .get::<SpanFields>()
+            .and_then(|SpanFields(fields)| fields.get(name).map(str::to_string))
+    })
+}
+
+/// Format an event on a single line, in a way that resembles the
+/// compact fmt output:
+///
+/// ```text
+/// <timestamp> <level> <span>:<span>: <target>: <message> <event fields> <enrichment fields> <span fields>
+/// ```
+///
+/// The enrichment fields are there if enrichment is on, see
+/// [`crate::enrich`].
+pub fn format_event<S>(event: &Event<'_>, fields: &FieldValues, ctx: &Context<'_, S>) -> String
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut line = String::new();
+    let _ = SystemTime.format_time(&mut Writer::new(&mut line));
+    let metadata = event.metadata();
+    let _ = write!(line, " {:>5} ", metadata.level());
+
+    let mut span_fields = String::new();
+    if let Some(scope) = ctx.event_scope(event) {
+        write_scope(&mut line, &mut span_fields, scope);
+    }
+
+    let _ = write!(line, "{}:", metadata.target());
+    if let Some(message) = fields.message.as_ref() {
+        let _ = write!(line, " {message}");
+    }
+    for (name, value) in fields.values.iter() {
+        let _ = write!(line, " {name}={}", redacted(name, value));
+    }
+    for (name, value) in enrich::values() {
+        let _ = write!(line, " {name}={}", redacted(name, &value));
+    }
+    line.push_str(&span_fields);
+    line
+}
+
+/// Write the names of the spans of a scope, from the root, to `line`,
+/// and their fields to `span_fields`
+fn write_scope<S>(line: &mut String, span_fields: &mut String, scope: Scope<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    for span_ref in scope.from_root() {
+        let _ = write!(line, "{}:", span_ref.name());
+        let extensions = span_ref.extensions();
+        if let Some(SpanFields(fields)) = extensions.get::<SpanFields>() {
+            for (name, value) in fields.values.iter() {
+                let _ = write!(span_fields, " {name}={}", redacted(name, value));
+            }
+        } else if let Some(fields) = extensions.get::<FormattedFields<RedactingFields>>() {
+            // The fields as recorded by the fmt layer, for the spans
+            // created before `SpanFields` were recorded
+            if !fields.is_empty() {
+                let _ = write!(span_fields, " {fields}");
+            }
+        }
+    }
+    line.push(' ');
+}
+
+/// Return a field value as it must be written, see [`redact`]
+pub fn redacted<'a>(name: &str, value: &'a str) -> Cow<'a, str> {
+    match redact::redaction(name) {
+        Some(redaction) => Cow::Owned(redaction.apply(value)),
+        None => Cow::Borrowed(value),
+    }
+}
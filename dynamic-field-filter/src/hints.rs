@@ -0,0 +1,108 @@
+//! Filtering hints for instrumented code.
+//!
+//! Hot code can ask whether a partition of the traffic (all the spans
+//! and events where a field has a given value, e.g. `vrf_id=3`) is
+//! currently suppressed, and skip building expensive field values if
+//! so:
+//!
+//! ```ignore
+//! if hints::is_partition_enabled("vrf_id", "3") {
+//!     info!(table = ?expensive_dump(), "table updated");
+//! }
+//! ```
+//!
+//! The filter layer publishes a snapshot of its rules every time they
+//! change, and bumps an epoch counter. Each thread caches the last
+//! snapshot it saw, so checking a hint only costs an atomic load as
+//! long as the rules don't change.
+//!
+//! The hints are process-wide, so only one filter layer can publish
+//! them: the first one to publish owns them until it is dropped, and
+//! the snapshots of the other layers are ignored.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Instant;
+
+/// The suppressed partitions: field name to value, along with when
+/// the partition stops being suppressed, if the rule has a TTL
+pub(crate) type Partitions = HashMap<String, (String, Option<Instant>)>;
+
+/// Incremented every time a new snapshot is published
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+static SNAPSHOT: RwLock<Option<Arc<Partitions>>> = RwLock::new(None);
+/// The publisher owning the hints, or 0 if none does
+static OWNER: AtomicU64 = AtomicU64::new(0);
+/// The identifier of the last publisher created
+static PUBLISHERS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The epoch and snapshot this thread last looked at
+    static CACHE: RefCell<(u64, Option<Arc<Partitions>>)> = const { RefCell::new((0, None)) };
+}
+
+/// The right of a filter layer to publish the hints, see the module
+/// documentation
+#[derive(Debug)]
+pub(crate) struct Publisher(u64);
+
+impl Default for Publisher {
+    fn default() -> Self {
+        Self(PUBLISHERS.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+impl Publisher {
+    /// Publish the partitions that are currently suppressed, unless
+    /// another publisher owns the hints
+    pub(crate) fn publish(&self, partitions: Partitions) {
+        let owner = OWNER
+            .compare_exchange(0, self.0, Ordering::AcqRel, Ordering::Acquire)
+            .unwrap_or_else(|owner| owner);
+        if owner == self.0 || owner == 0 {
+            replace(Some(Arc::new(partitions)));
+        }
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        if OWNER.load(Ordering::Acquire) == self.0 {
+            replace(None);
+            OWNER.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Replace the published snapshot
+fn replace(partitions: Option<Arc<Partitions>>) {
+    let mut snapshot = SNAPSHOT.write().unwrap();
+    *snapshot = partitions;
+    // Bump the epoch while holding the lock, so that a thread that
+    // sees the new epoch also sees the new snapshot.
+    EPOCH.fetch_add(1, Ordering::Release);
+}
+
+/// Return `false` if the spans and events where `field` is `value`
+/// are currently suppressed. This is only a hint: a partition that
+/// is enabled may still be suppressed by other means (levels, muted
+/// callsites, disabled parent spans).
+pub fn is_partition_enabled(field: &str, value: &str) -> bool {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let epoch = EPOCH.load(Ordering::Acquire);
+        if cache.0 != epoch {
+            *cache = (epoch, SNAPSHOT.read().unwrap().clone());
+        }
+        match &cache.1 {
+            Some(partitions) => partitions.get(field).is_none_or(|(v, deadline)| {
+                v != value || deadline.is_some_and(|deadline| deadline <= Instant::now())
+            }),
+            None => true,
+        }
+    })
+}
@@ -0,0 +1,86 @@
+//! A [`tracing_subscriber`] layer that filters spans and events on the
+//! values of their fields, with rules that can change at runtime.
+//!
+//! [`DynamicFieldFilter`] suppresses the spans where a field matches a
+//! rule, e.g. `vrf_id=1` or `busy_us > 5ms`, along with everything
+//! that happens within them. Wrapped in a
+//! [`reload::Layer`](tracing_subscriber::reload::Layer), its rules can
+//! be changed while the program runs:
+//!
+//! ```
+//! use dynamic_field_filter::DynamicFieldFilter;
+//! use dynamic_field_filter::Matcher;
+//! use dynamic_field_filter::RuleOptions;
+//! use tracing_subscriber::prelude::*;
+//! use tracing_subscriber::reload;
+//!
+//! let (filter, handle) = reload::Layer::new(DynamicFieldFilter::default());
+//! let _guard = tracing_subscriber::registry()
+//!     .with(filter)
+//!     .with(tracing_subscriber::fmt::layer())
+//!     .set_default();
+//!
+//! let (field, matcher) = dynamic_field_filter::parse_rule("vrf_id=1").unwrap();
+//! handle
+//!     .modify(|filter| filter.set_rule(&field, matcher, RuleOptions::default()))
+//!     .unwrap();
+//! ```
+//!
+//! Rules and target levels can also be given as directives, in the
+//! syntax of `EnvFilter`, see [`directive`].
+//!
+//! The initial state can be given with [`DynamicFieldFilter::builder`]
+//! instead. Application-specific matching logic can be plugged in
+//! with a [`FieldMatcher`], see
+//! [`DynamicFieldFilter::register_matcher`].
+//!
+//! The layer's own logging goes to the `dynamic_filter` target, which
+//! the filter never applies to, see [`diagnostics`]. Neither does it
+//! apply to the panics reported by [`panics::install_hook`].
+//!
+//! The [`protocol`] module has the types of the JSON control protocol
+//! of the `loggingdemo` server, for the clients that change the rules
+//! remotely.
+
+#![warn(missing_docs)]
+
+#[macro_use]
+extern crate tracing;
+
+pub mod builder;
+pub mod dedup;
+pub mod diagnostics;
+pub mod directive;
+pub mod enrich;
+pub mod explain;
+pub mod filter;
+pub mod format;
+pub mod hints;
+pub mod loggers;
+pub mod matcher;
+pub mod panics;
+pub mod protocol;
+pub mod quarantine;
+pub mod rate_limit;
+pub mod redact;
+pub mod replay;
+pub mod stats;
+pub mod window;
+
+mod ring;
+mod span_set;
+
+pub use builder::Builder;
+pub use filter::Budget;
+pub use filter::DynamicFieldFilter;
+pub use filter::FilterMode;
+pub use filter::Profile;
+pub use filter::RuleConfig;
+pub use filter::RuleOptions;
+pub use filter::SavedFilters;
+pub use matcher::parse_rule;
+pub use matcher::Cidr;
+pub use matcher::CmpOp;
+pub use matcher::FieldMatcher;
+pub use matcher::FieldValue;
+pub use matcher::Matcher;
@@ -0,0 +1,184 @@
+//! An adapter for the "logger name + level" idiom used by the dynamic
+//! logging endpoints of proxies such as Envoy, or by gRPC-style
+//! `SetLogLevel(name, level)` calls. Requests are translated into
+//! target level rules for the filter layer.
+//!
+//! The accepted shapes are those of Envoy's `/logging` admin
+//! endpoint, either as a query string or as space separated pairs:
+//!
+//! ```text
+//! level=<level>                    set the level of all the loggers
+//! <name>=<level>                   set the level of one logger
+//! paths=<name>:<level>,...         set the level of several loggers
+//! ```
+
+use std::fmt;
+
+use tracing::level_filters::LevelFilter;
+
+/// A level change requested for one or all loggers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoggerLevel {
+    /// Set the level of all the loggers
+    All(LevelFilter),
+    /// Set the level of the named logger
+    Named(String, LevelFilter),
+}
+
+/// An invalid logger level request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Parse a level name. On top of the tracing levels, this accepts
+/// the spdlog names used by Envoy (`warning`, `critical`).
+pub fn parse_level(level: &str) -> Result<LevelFilter, ParseError> {
+    match level.to_ascii_lowercase().as_str() {
+        "trace" => Ok(LevelFilter::TRACE),
+        "debug" => Ok(LevelFilter::DEBUG),
+        "info" => Ok(LevelFilter::INFO),
+        "warn" | "warning" => Ok(LevelFilter::WARN),
+        "error" | "critical" => Ok(LevelFilter::ERROR),
+        "off" => Ok(LevelFilter::OFF),
+        _ => Err(ParseError(format!("unknown level {level}"))),
+    }
+}
+
+/// Parse a logging request, made of `&` or whitespace separated
+/// `key=value` pairs.
+pub fn parse_request(request: &str) -> Result<Vec<LoggerLevel>, ParseError> {
+    let mut changes = Vec::new();
+    for pair in request.split(|c: char| c == '&' || c.is_whitespace()) {
+        if pair.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = pair.split_once('=') else {
+            return Err(ParseError(format!("expected <name>=<level>, got {pair}")));
+        };
+        match key {
+            "level" => changes.push(LoggerLevel::All(parse_level(value)?)),
+            "paths" => {
+                for path in value.split(',') {
+                    let Some((name, level)) = path.split_once(':') else {
+                        return Err(ParseError(format!("expected <name>:<level>, got {path}")));
+                    };
+                    changes.push(LoggerLevel::Named(logger_target(name), parse_level(level)?));
+                }
+            }
+            name => changes.push(LoggerLevel::Named(logger_target(name), parse_level(value)?)),
+        }
+    }
+    if changes.is_empty() {
+        return Err(ParseError("no logger level given".to_string()));
+    }
+    Ok(changes)
+}
+
+/// Translate a logger name into a target path: Envoy and gRPC use
+/// dots or slashes where tracing targets use `::`.
+pub fn logger_target(name: &str) -> String {
+    name.split(['.', '/'])
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// Return `true` if the logger name designates the given target, i.e.
+/// if the segments of the name appear, in order and contiguously, in
+/// the segments of the target. For instance, `router` matches
+/// `loggingdemo::router`, and `loggingdemo` matches
+/// `loggingdemo::control`.
+pub fn logger_matches(name: &str, target: &str) -> bool {
+    if name.is_empty() {
+        return true;
+    }
+    let name: Vec<&str> = name.split("::").collect();
+    let target: Vec<&str> = target.split("::").collect();
+    target.windows(name.len()).any(|window| window == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str, level: LevelFilter) -> LoggerLevel {
+        LoggerLevel::Named(name.to_string(), level)
+    }
+
+    #[test]
+    fn envoy_level_names() {
+        assert_eq!(parse_level("warning"), Ok(LevelFilter::WARN));
+        assert_eq!(parse_level("critical"), Ok(LevelFilter::ERROR));
+        assert_eq!(parse_level("DEBUG"), Ok(LevelFilter::DEBUG));
+        assert_eq!(parse_level("off"), Ok(LevelFilter::OFF));
+        assert!(parse_level("loud").is_err());
+    }
+
+    #[test]
+    fn query_string_requests() {
+        assert_eq!(
+            parse_request("level=info&router=trace"),
+            Ok(vec![
+                LoggerLevel::All(LevelFilter::INFO),
+                named("router", LevelFilter::TRACE),
+            ])
+        );
+        assert_eq!(
+            parse_request("paths=router.bgp:debug,grpc/client:warning"),
+            Ok(vec![
+                named("router::bgp", LevelFilter::DEBUG),
+                named("grpc::client", LevelFilter::WARN),
+            ])
+        );
+    }
+
+    #[test]
+    fn space_separated_requests() {
+        assert_eq!(
+            parse_request(" level=off  upstream.pool=critical "),
+            Ok(vec![
+                LoggerLevel::All(LevelFilter::OFF),
+                named("upstream::pool", LevelFilter::ERROR),
+            ])
+        );
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(parse_request("").is_err());
+        assert!(parse_request(" & ").is_err());
+        assert!(parse_request("router").is_err());
+        assert!(parse_request("router=loud").is_err());
+        assert!(parse_request("paths=router:debug,bgp").is_err());
+        assert!(parse_request("paths=router:loud").is_err());
+    }
+
+    #[test]
+    fn logger_names_as_targets() {
+        assert_eq!(logger_target("router.bgp"), "router::bgp");
+        assert_eq!(logger_target("grpc/client"), "grpc::client");
+        assert_eq!(logger_target("/a..b/"), "a::b");
+    }
+
+    #[test]
+    fn logger_names_match_targets() {
+        assert!(logger_matches("", "loggingdemo::router"));
+        assert!(logger_matches("router", "loggingdemo::router"));
+        assert!(logger_matches("loggingdemo", "loggingdemo::control"));
+        assert!(logger_matches(
+            "router::bgp",
+            "loggingdemo::router::bgp::peer"
+        ));
+        assert!(!logger_matches(
+            "loggingdemo::bgp",
+            "loggingdemo::router::bgp"
+        ));
+        assert!(!logger_matches("route", "loggingdemo::router"));
+        assert!(!logger_matches("router::bgp::peer", "router::bgp"));
+    }
+}
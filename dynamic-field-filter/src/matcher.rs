@@ -0,0 +1,703 @@
+//! Matching of field values against filter rules.
+//!
+//! Values are matched either as text, the way the fmt layer would
+//! print them, or as typed values. Durations are the only typed
+//! values for now: a rule such as `busy_us > 5ms` compares the
+//! duration recorded in a field to a threshold, regardless of the
+//! unit the field is recorded in.
+//!
+//! A rule such as `MESSAGE contains New path` matches the values
+//! containing a substring, here the messages of the events, which are
+//! recorded in their `message` field.
+//!
+//! A rule such as `prefix within 10.0.0.0/8` matches the addresses and
+//! the prefixes inside a network, IPv4 or IPv6, here the prefixes of
+//! the routes. The values of the other address family never match.
+//!
+//! Applications can plug in their own matching logic with the
+//! [`FieldMatcher`] trait: a matcher registered in the filter under a
+//! name is used by the rules such as `as_path~private_as`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::field::Field;
+
+/// A field value, as recorded by a visitor
+#[derive(Clone, Copy)]
+pub enum FieldValue<'a> {
+    /// A string
+    Str(&'a str),
+    /// An unsigned integer
+    U64(u64),
+    /// A signed integer
+    I64(i64),
+    /// A floating point number
+    F64(f64),
+    /// A boolean
+    Bool(bool),
+    /// Any other value, printed with `Debug`
+    Debug(&'a dyn fmt::Debug),
+}
+
+impl FieldValue<'_> {
+    /// Return the value as text, the way the fmt layer prints it
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            FieldValue::Str(s) => Cow::Borrowed(s),
+            FieldValue::U64(n) => Cow::Owned(n.to_string()),
+            FieldValue::I64(n) => Cow::Owned(n.to_string()),
+            FieldValue::F64(n) => Cow::Owned(n.to_string()),
+            FieldValue::Bool(b) => Cow::Owned(b.to_string()),
+            FieldValue::Debug(d) => Cow::Owned(format!("{d:?}")),
+        }
+    }
+
+    /// Decode the value of the given field as a duration.
+    ///
+    /// Numbers are interpreted according to the unit suffix of the
+    /// field name (`busy_us`, `elapsed_ms`, ...), and are not
+    /// durations if the name has no such suffix. Text is parsed
+    /// either as a `Duration`'s `Debug` output (`1.5ms`, `12µs`) or
+    /// as a humantime duration (`5ms`, `1m 30s`).
+    pub fn as_duration(&self, field: &Field) -> Option<Duration> {
+        self.as_duration_of(field.name())
+    }
+
+    /// Decode the value of the field with the given name as a
+    /// duration, see [`FieldValue::as_duration`]
+    fn as_duration_of(&self, name: &str) -> Option<Duration> {
+        let unit = unit_of(name);
+        // Negative, NaN and too large values are not durations
+        let from_number =
+            |n: f64| -> Option<Duration> { Duration::try_from_secs_f64(n * unit?).ok() };
+        match self {
+            FieldValue::U64(n) => from_number(*n as f64),
+            FieldValue::I64(n) => from_number(*n as f64),
+            FieldValue::F64(n) => from_number(*n),
+            FieldValue::Bool(_) => None,
+            FieldValue::Str(_) | FieldValue::Debug(_) => {
+                let text = self.to_text();
+                match text.parse::<f64>() {
+                    Ok(n) => from_number(n),
+                    Err(_) => parse_duration(&text),
+                }
+            }
+        }
+    }
+}
+
+/// Return the unit, in seconds, of a numeric field given its name
+fn unit_of(name: &str) -> Option<f64> {
+    let (_, suffix) = name.rsplit_once(['_', '.'])?;
+    match suffix {
+        "ns" | "nanos" => Some(1e-9),
+        "us" | "micros" => Some(1e-6),
+        "ms" | "millis" => Some(1e-3),
+        "s" | "secs" => Some(1.0),
+        _ => None,
+    }
+}
+
+/// Parse a duration, either in the format of `Duration`'s `Debug`
+/// implementation or in the humantime format
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let unit = match unit {
+        "ns" => Some(1e-9),
+        "µs" | "us" => Some(1e-6),
+        "ms" => Some(1e-3),
+        "s" => Some(1.0),
+        _ => None,
+    };
+    if let (Ok(number), Some(unit)) = (number.parse::<f64>(), unit) {
+        return Duration::try_from_secs_f64(number * unit).ok();
+    }
+    humantime::parse_duration(text).ok()
+}
+
+/// Matching logic provided by the application, e.g. a regex on an AS
+/// path or a lookup in a prefix trie. Custom matchers are registered
+/// in the filter under a name, see
+/// [`DynamicFieldFilter::register_matcher`](crate::DynamicFieldFilter::register_matcher),
+/// and used by the [`Matcher::Custom`] rules with that name.
+///
+/// Closures taking a field and its value implement this trait.
+pub trait FieldMatcher: Send + Sync {
+    /// Return `true` if the value recorded for the field matches
+    fn matches(&self, field: &Field, value: &FieldValue<'_>) -> bool;
+}
+
+impl<F> FieldMatcher for F
+where
+    F: Fn(&Field, &FieldValue<'_>) -> bool + Send + Sync,
+{
+    fn matches(&self, field: &Field, value: &FieldValue<'_>) -> bool {
+        self(field, value)
+    }
+}
+
+/// (De)serialize durations as text, e.g. `5ms` or `1m 30s`, rather
+/// than as seconds and nanoseconds
+pub(crate) mod duration_text {
+    use std::time::Duration;
+
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_duration(&text)
+            .ok_or_else(|| D::Error::custom(format!("invalid duration {text}")))
+    }
+
+    /// The same, for optional durations
+    pub mod option {
+        use std::time::Duration;
+
+        use serde::Deserialize;
+        use serde::Deserializer;
+        use serde::Serializer;
+
+        pub fn serialize<S: Serializer>(
+            duration: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(duration) => super::serialize(duration, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            #[derive(Deserialize)]
+            struct Text(#[serde(with = "super")] Duration);
+
+            let duration = Option::<Text>::deserialize(deserializer)?;
+            Ok(duration.map(|Text(duration)| duration))
+        }
+    }
+}
+
+/// A comparison operator, (de)serialized as its symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CmpOp {
+    /// `<`
+    #[serde(rename = "<")]
+    Lt,
+    /// `<=`
+    #[serde(rename = "<=")]
+    Le,
+    /// `>`
+    #[serde(rename = ">")]
+    Gt,
+    /// `>=`
+    #[serde(rename = ">=")]
+    Ge,
+}
+
+impl CmpOp {
+    fn apply<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        })
+    }
+}
+
+/// An IPv4 or IPv6 network, such as `10.0.0.0/8` or `2001:db8::/32`,
+/// (de)serialized as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    /// Return `true` if the address or the prefix, such as `10.1.2.3`
+    /// or `10.1.0.0/16`, is inside the network
+    pub fn contains(&self, text: &str) -> bool {
+        let Ok(other) = text.trim().parse::<Cidr>() else {
+            return false;
+        };
+        other.len >= self.len && mask(other.addr, self.len) == mask(self.addr, self.len)
+    }
+}
+
+/// The bits of an address in the first `len`, the others being zero
+fn mask(addr: IpAddr, len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let bits = u32::from(addr)
+                .checked_shr(32 - u32::from(len))
+                .unwrap_or(0);
+            IpAddr::V4(bits.checked_shl(32 - u32::from(len)).unwrap_or(0).into())
+        }
+        IpAddr::V6(addr) => {
+            let bits = u128::from(addr)
+                .checked_shr(128 - u32::from(len))
+                .unwrap_or(0);
+            IpAddr::V6(bits.checked_shl(128 - u32::from(len)).unwrap_or(0).into())
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse a network, or a single address as a network of its own
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match text.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid network {text}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("invalid network {text}"))?,
+            None => max,
+        };
+        Ok(Self { addr, len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// The kinds of matchers, as advertised by `HELLO`. This must list
+/// every variant of [`Matcher`].
+pub const MATCHERS: &[&str] = &[
+    "equals", "duration", "not_in", "contains", "within", "custom",
+];
+
+/// How a rule matches the value of its field. In JSON, a matcher is
+/// written like `{"equals": "1"}`, `{"duration": [">", "5ms"]}`,
+/// `{"not_in": ["1", "2"]}`, `{"contains": "New path"}`,
+/// `{"within": "10.0.0.0/8"}` or `{"custom": "private_as"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Matcher {
+    /// The value, as text, is equal to the given string
+    Equals(String),
+    /// The value is a duration that compares to the given one
+    Duration(CmpOp, #[serde(with = "duration_text")] Duration),
+    /// The value, as text, is none of the given strings
+    NotIn(Vec<String>),
+    /// The value, as text, contains the given string
+    Contains(String),
+    /// The value is an address or a prefix inside the given network
+    Within(Cidr),
+    /// The custom matcher registered in the filter under the given
+    /// name matches the value
+    Custom(String),
+}
+
+impl Matcher {
+    /// Return `true` if the value recorded for the field matches.
+    /// Custom matchers are only known to the filter, so they never
+    /// match here.
+    pub fn matches(&self, field: &Field, value: &FieldValue<'_>) -> bool {
+        match self {
+            Matcher::Equals(expected) => value.to_text() == expected.as_str(),
+            Matcher::Duration(op, threshold) => value
+                .as_duration(field)
+                .is_some_and(|duration| op.apply(duration, *threshold)),
+            Matcher::NotIn(values) => !values.iter().any(|v| value.to_text() == v.as_str()),
+            Matcher::Contains(part) => value.to_text().contains(part.as_str()),
+            Matcher::Within(network) => network.contains(&value.to_text()),
+            Matcher::Custom(_) => false,
+        }
+    }
+
+    /// Match a value already recorded as text, as in
+    /// [`crate::format::FieldValues`]
+    pub fn matches_text(&self, name: &str, text: &str) -> bool {
+        match self {
+            Matcher::Equals(expected) => text == expected,
+            Matcher::Duration(op, threshold) => FieldValue::Str(text)
+                .as_duration_of(name)
+                .is_some_and(|duration| op.apply(duration, *threshold)),
+            Matcher::NotIn(values) => !values.iter().any(|v| text == v),
+            Matcher::Contains(part) => text.contains(part.as_str()),
+            Matcher::Within(network) => network.contains(text),
+            Matcher::Custom(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Equals(value) => write!(f, "={value}"),
+            Matcher::Duration(op, threshold) => {
+                write!(f, " {op} {}", humantime::format_duration(*threshold))
+            }
+            Matcher::NotIn(values) => write!(f, "!={}", values.join(",")),
+            Matcher::Contains(part) => write!(f, " contains {part}"),
+            Matcher::Within(network) => write!(f, " within {network}"),
+            Matcher::Custom(name) => write!(f, "~{name}"),
+        }
+    }
+}
+
+/// Parse a rule expression: `<field>=<value>`, `<field>!=<value>,...`,
+/// `<field> <op> <duration>` where `<op>` is one of `<`, `<=`, `>`,
+/// `>=`, `<field> contains <text>`, `<field> within <network>`, or
+/// `<field>~<matcher>` for a custom matcher. Spaces around the operator
+/// are optional, except around `contains` and `within`. The `MESSAGE`
+/// field is the `message` of the events.
+pub fn parse_rule(expr: &str) -> Result<(String, Matcher), String> {
+    if let Some((field, network)) = expr.trim().split_once(" within ") {
+        let (field, network) = (field.trim(), network.trim());
+        if field.is_empty() || network.is_empty() || field.contains(char::is_whitespace) {
+            return Err(format!("missing field or network in {expr}"));
+        }
+        return Ok((field.to_string(), Matcher::Within(network.parse()?)));
+    }
+    if let Some((field, part)) = expr.trim().split_once(" contains ") {
+        let (field, part) = (field.trim(), part.trim());
+        if field.is_empty() || part.is_empty() || field.contains(char::is_whitespace) {
+            return Err(format!("missing field or text in {expr}"));
+        }
+        let field = if field == "MESSAGE" { "message" } else { field };
+        return Ok((field.to_string(), Matcher::Contains(part.to_string())));
+    }
+    let Some(start) = expr.find(['<', '>', '=', '~']) else {
+        return Err(format!(
+            "expected <field>=<value>, <field>!=<value>,..., <field> <op> <duration>, <field> contains <text>, <field> within <network> or <field>~<matcher>, got {expr}"
+        ));
+    };
+    let rest = &expr[start..];
+    let field = expr[..start].trim();
+    let (field, negated) = match field.strip_suffix('!') {
+        Some(field) if rest.starts_with('=') => (field.trim(), true),
+        _ => (field, false),
+    };
+    let (op, value) = if let Some(value) = rest.strip_prefix("<=") {
+        (Some(CmpOp::Le), value)
+    } else if let Some(value) = rest.strip_prefix(">=") {
+        (Some(CmpOp::Ge), value)
+    } else if let Some(value) = rest.strip_prefix('<') {
+        (Some(CmpOp::Lt), value)
+    } else if let Some(value) = rest.strip_prefix('>') {
+        (Some(CmpOp::Gt), value)
+    } else if let Some(name) = rest.strip_prefix('~') {
+        let name = name.trim();
+        if field.is_empty() || name.is_empty() {
+            return Err(format!("missing field or matcher in {expr}"));
+        }
+        return Ok((field.to_string(), Matcher::Custom(name.to_string())));
+    } else {
+        (None, &rest[1..])
+    };
+    let value = value.trim();
+    if field.is_empty() || value.is_empty() {
+        return Err(format!("missing field or value in {expr}"));
+    }
+    let matcher = match op {
+        Some(op) => match parse_duration(value) {
+            Some(threshold) => Matcher::Duration(op, threshold),
+            None => return Err(format!("invalid duration {value}")),
+        },
+        None if negated => Matcher::NotIn(value.split(',').map(|v| v.trim().to_string()).collect()),
+        None => Matcher::Equals(value.to_string()),
+    };
+    Ok((field.to_string(), matcher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        let cases: &[(&str, Option<Duration>)] = &[
+            ("5ms", Some(Duration::from_millis(5))),
+            ("1.5ms", Some(Duration::from_micros(1500))),
+            ("12µs", Some(Duration::from_micros(12))),
+            ("12us", Some(Duration::from_micros(12))),
+            ("250ns", Some(Duration::from_nanos(250))),
+            ("2s", Some(Duration::from_secs(2))),
+            (" 3s ", Some(Duration::from_secs(3))),
+            ("1m 30s", Some(Duration::from_secs(90))),
+            ("", None),
+            ("ms", None),
+            ("5", None),
+            ("fast", None),
+            ("1e30", None),
+            ("99999999999999999999999s", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_duration(text), *expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn field_values_as_durations() {
+        let cases: &[(&str, FieldValue<'_>, Option<Duration>)] = &[
+            (
+                "busy_us",
+                FieldValue::U64(1500),
+                Some(Duration::from_micros(1500)),
+            ),
+            (
+                "elapsed_ms",
+                FieldValue::I64(3),
+                Some(Duration::from_millis(3)),
+            ),
+            (
+                "elapsed.s",
+                FieldValue::F64(0.5),
+                Some(Duration::from_millis(500)),
+            ),
+            (
+                "wait_nanos",
+                FieldValue::Str("40"),
+                Some(Duration::from_nanos(40)),
+            ),
+            (
+                "busy",
+                FieldValue::Str("5ms"),
+                Some(Duration::from_millis(5)),
+            ),
+            ("busy", FieldValue::U64(5), None),
+            ("busy_us", FieldValue::Bool(true), None),
+            ("busy_ms", FieldValue::I64(-1), None),
+            ("busy_s", FieldValue::U64(u64::MAX), None),
+            ("busy_s", FieldValue::F64(f64::NAN), None),
+            ("busy_s", FieldValue::F64(f64::INFINITY), None),
+            ("busy_s", FieldValue::Str("1e30"), None),
+        ];
+        for (name, value, expected) in cases {
+            assert_eq!(
+                value.as_duration_of(name),
+                *expected,
+                "{name} {}",
+                value.to_text()
+            );
+        }
+    }
+
+    #[test]
+    fn parse_rule_rejects_huge_durations() {
+        assert!(parse_rule("busy_us > 99999999999999999999999s").is_err());
+        assert_eq!(
+            parse_rule("busy_us > 5ms"),
+            Ok((
+                "busy_us".to_string(),
+                Matcher::Duration(CmpOp::Gt, Duration::from_millis(5))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_duration_rules() {
+        let cases: &[(&str, Option<Matcher>)] = &[
+            (
+                "busy_us > 5ms",
+                Some(Matcher::Duration(CmpOp::Gt, Duration::from_millis(5))),
+            ),
+            (
+                "busy_us<=1s",
+                Some(Matcher::Duration(CmpOp::Le, Duration::from_secs(1))),
+            ),
+            (
+                "busy_us >= 1m 30s",
+                Some(Matcher::Duration(CmpOp::Ge, Duration::from_secs(90))),
+            ),
+            (
+                "busy_us < 250ns",
+                Some(Matcher::Duration(CmpOp::Lt, Duration::from_nanos(250))),
+            ),
+            ("busy_us > fast", None),
+            ("busy_us >", None),
+            ("> 5ms", None),
+        ];
+        for (expr, expected) in cases {
+            let parsed = parse_rule(expr).ok().map(|(field, matcher)| {
+                assert_eq!(field, "busy_us", "{expr:?}");
+                matcher
+            });
+            assert_eq!(parsed, *expected, "{expr:?}");
+        }
+    }
+
+    #[test]
+    fn duration_rules_round_trip() {
+        for expr in ["busy_us > 5ms", "busy_us <= 1m 30s", "busy_us < 250ns"] {
+            let (field, matcher) = parse_rule(expr).unwrap();
+            assert_eq!(format!("{field}{matcher}"), expr);
+        }
+    }
+
+    #[test]
+    fn parse_networks() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("10.0.0.0/8", Some("10.0.0.0/8")),
+            ("10.1.2.3", Some("10.1.2.3/32")),
+            ("0.0.0.0/0", Some("0.0.0.0/0")),
+            ("2001:db8::/32", Some("2001:db8::/32")),
+            ("fe80::1", Some("fe80::1/128")),
+            ("::/0", Some("::/0")),
+            ("10.0.0.0/33", None),
+            ("2001:db8::/129", None),
+            ("10.0.0.0/", None),
+            ("10.0.0.0/x", None),
+            ("10.0.0/8", None),
+            ("", None),
+        ];
+        for (text, expected) in cases {
+            let parsed = text.parse::<Cidr>().ok().map(|cidr| cidr.to_string());
+            assert_eq!(parsed.as_deref(), *expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn networks_contain_prefixes() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("10.0.0.0/8", "10.1.2.3", true),
+            ("10.0.0.0/8", "10.1.0.0/16", true),
+            ("10.0.0.0/8", "10.0.0.0/8", true),
+            ("10.0.0.0/8", "10.0.0.0/7", false),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("10.1.2.3", "10.1.2.3", true),
+            ("10.1.2.3", "10.1.2.4", false),
+            ("0.0.0.0/0", "192.0.2.1", true),
+            ("0.0.0.0/0", "2001:db8::1", false),
+            ("2001:db8:a0::/44", "2001:db8:af::/48", true),
+            ("2001:db8:a0::/44", "2001:db8:b0::/48", false),
+            ("::/0", "fe80::1", true),
+            ("::/0", "10.0.0.1", false),
+            ("10.0.0.0/8", "not an address", false),
+            ("10.0.0.0/8", " 10.0.0.1 ", true),
+        ];
+        for (network, value, expected) in cases {
+            let network: Cidr = network.parse().unwrap();
+            assert_eq!(network.contains(value), *expected, "{network} {value}");
+        }
+    }
+
+    #[test]
+    fn parse_within_rules() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("prefix within 10.0.0.0/8", Some("10.0.0.0/8")),
+            (" next_hop within 2001:db8::/32 ", Some("2001:db8::/32")),
+            ("prefix within 10.0.0.0/33", None),
+            ("prefix within nowhere", None),
+            ("a b within 10.0.0.0/8", None),
+            (" within 10.0.0.0/8", None),
+        ];
+        for (expr, expected) in cases {
+            let parsed = match parse_rule(expr) {
+                Ok((_, Matcher::Within(network))) => Some(network.to_string()),
+                _ => None,
+            };
+            assert_eq!(parsed.as_deref(), *expected, "{expr:?}");
+        }
+        for expr in ["prefix within 10.0.0.0/8", "next_hop within 2001:db8::/32"] {
+            let (field, matcher) = parse_rule(expr).unwrap();
+            assert_eq!(format!("{field}{matcher}"), expr);
+        }
+    }
+
+    #[test]
+    fn advertised_matchers_parse() {
+        for name in MATCHERS {
+            let value = match *name {
+                "equals" | "contains" => r#""1""#,
+                "duration" => r#"[">", "5ms"]"#,
+                "not_in" => r#"["1", "2"]"#,
+                "within" => r#""10.0.0.0/8""#,
+                "custom" => r#""private_as""#,
+                _ => panic!("no example for the {name} matcher"),
+            };
+            let json = format!(r#"{{"{name}": {value}}}"#);
+            let matcher: Matcher = serde_json::from_str(&json).unwrap();
+            let serialized = serde_json::to_value(&matcher).unwrap();
+            assert!(serialized.get(name).is_some(), "{name}: {serialized}");
+        }
+    }
+
+    #[test]
+    fn rules_round_trip() {
+        for expr in [
+            "vrf_id=1",
+            "peer!=a,b",
+            "busy_us > 5ms",
+            "message contains New path",
+            "prefix within 10.0.0.0/8",
+            "as_path~private_as",
+        ] {
+            let (field, matcher) = parse_rule(expr).unwrap();
+            assert_eq!(format!("{field}{matcher}"), expr);
+        }
+        assert_eq!(
+            parse_rule(" peer != a, b "),
+            Ok((
+                "peer".to_string(),
+                Matcher::NotIn(vec!["a".to_string(), "b".to_string()])
+            ))
+        );
+        assert_eq!(
+            parse_rule("MESSAGE contains New path"),
+            Ok((
+                "message".to_string(),
+                Matcher::Contains("New path".to_string())
+            ))
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Panics reported as events.
+//!
+//! [`install_hook`] makes every panic emit an ERROR event of the
+//! `panic` target, with the message, the thread and the location of
+//! the panic, before the panic hook that was installed before, so that
+//! a panic shows in the same output as the rest of the logs, within
+//! the spans it happened in. The filter never applies to this target.
+//!
+//! ```
+//! dynamic_field_filter::panics::install_hook();
+//! ```
+
+use std::panic;
+use std::panic::PanicHookInfo;
+use std::thread;
+
+use tracing::Metadata;
+
+/// The target of the events that report panics
+pub const TARGET: &str = "panic";
+
+/// Report the panics as events, then run the current panic hook
+pub fn install_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        report(info);
+        previous(info);
+    }));
+}
+
+/// Return `true` if an event reports a panic
+pub fn is_panic(metadata: &Metadata<'_>) -> bool {
+    metadata.target() == TARGET
+}
+
+fn report(info: &PanicHookInfo<'_>) {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    let thread = thread::current();
+    let location = info.location().map(ToString::to_string);
+    error!(
+        target: "panic",
+        thread = thread.name().unwrap_or("<unnamed>"),
+        location,
+        "panicked: {message}"
+    );
+}
@@ -0,0 +1,213 @@
+//! The JSON mode of the control connection, for programs rather than
+//! humans.
+//!
+//! The `JSON` command switches a connection to this mode. From then
+//! on, each request is a JSON object on its own line, and so is each
+//! response:
+//!
+//! ```text
+//! {"command":"filter","rule":"vrf_id=1","options":{"ttl":"600"}}
+//! {"status":"ok","output":[]}
+//! {"command":"list"}
+//! {"status":"ok","output":["filter vrf_id=1"]}
+//! {"command":"raw","line":"DEDUP 5"}
+//! {"status":"ok","output":[]}
+//! ```
+//!
+//! The changes made by the other clients arrive as
+//! `{"status":"notify","peer":...,"action":...,"detail":...}`, and
+//! `{"command":"text"}` goes back to the text mode.
+//!
+//! These types are shared with `filterctl`, which speaks this mode.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A change made by a client, or by the configuration file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    /// The client that made the change
+    pub peer: String,
+    /// The kind of change, e.g. `set_filter`
+    pub action: String,
+    /// What changed, e.g. the rule that was set
+    pub detail: String,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NOTIFY filters-changed {} {}", self.peer, self.action)?;
+        if !self.detail.is_empty() {
+            write!(f, " {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// A request, in JSON mode
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    /// Describe the protocol, like `HELLO`
+    Hello,
+    /// Authenticate, to be allowed to change anything
+    Auth {
+        /// The token configured on the server
+        token: String,
+    },
+    /// Set a filter rule, with options named like those of the FILTER
+    /// command, e.g. `{"limit": "10"}`
+    Filter {
+        /// The rule, e.g. `vrf_id=1` or `busy_us > 5ms`
+        rule: String,
+        /// The options, by name
+        #[serde(default)]
+        options: BTreeMap<String, String>,
+    },
+    /// Remove the filter rule on a field
+    Unfilter {
+        /// The field of the rule
+        field: String,
+    },
+    /// Filter on `vrf_id`
+    Vrf {
+        /// The VRF to filter
+        id: u32,
+    },
+    /// Remove all the filter rules
+    Clear,
+    /// List the filter rules
+    List,
+    /// Add a level directive, or show the directives
+    Level {
+        /// The directive, in the syntax of `RUST_LOG`
+        directive: Option<String>,
+    },
+    /// Report the filter counters
+    Stats,
+    /// Stage the following changes
+    Begin,
+    /// Apply the staged changes
+    Commit,
+    /// Discard the staged changes
+    Abort,
+    /// Revert the last change
+    Undo,
+    /// Apply the last reverted change again
+    Redo,
+    /// Stream the events whose fields have the given values, as
+    /// `{"status":"event","line":...}`
+    Subscribe {
+        /// The values the fields must have
+        #[serde(default)]
+        fields: BTreeMap<String, String>,
+    },
+    /// Stop streaming the events
+    Unsubscribe,
+    /// Check that the server is alive
+    Ping,
+    /// Close the connection, after an `ok` response
+    Quit,
+    /// Any command, in the syntax of the text mode
+    Raw {
+        /// The command, e.g. `DEDUP 5`
+        line: String,
+    },
+    /// Go back to the text mode
+    Text,
+}
+
+impl Request {
+    /// Return the request as a command of the text mode, or `None`
+    /// for `Text`
+    pub fn to_command(&self) -> Option<String> {
+        let command = match self {
+            Request::Hello => "HELLO".to_string(),
+            Request::Auth { token } => format!("AUTH {token}"),
+            Request::Filter { rule, options } => {
+                let mut command = format!("FILTER {rule}");
+                for (option, value) in options {
+                    command.push_str(&format!(" {} {value}", option.to_uppercase()));
+                }
+                command
+            }
+            Request::Unfilter { field } => format!("UNFILTER {field}"),
+            Request::Vrf { id } => format!("VRF {id}"),
+            Request::Clear => "CLEAR".to_string(),
+            Request::List => "LIST".to_string(),
+            Request::Level { directive } => match directive {
+                Some(directive) => format!("LEVEL {directive}"),
+                None => "LEVEL".to_string(),
+            },
+            Request::Stats => "STATS".to_string(),
+            Request::Begin => "BEGIN".to_string(),
+            Request::Commit => "COMMIT".to_string(),
+            Request::Abort => "ABORT".to_string(),
+            Request::Undo => "UNDO".to_string(),
+            Request::Redo => "REDO".to_string(),
+            Request::Subscribe { fields } => {
+                let mut command = "SUBSCRIBE".to_string();
+                for (field, value) in fields {
+                    command.push_str(&format!(" {field}={value}"));
+                }
+                command
+            }
+            Request::Unsubscribe => "UNSUBSCRIBE".to_string(),
+            Request::Ping => "PING".to_string(),
+            Request::Quit => "QUIT".to_string(),
+            Request::Raw { line } => line.clone(),
+            Request::Text => return None,
+        };
+        Some(command)
+    }
+}
+
+/// A response, or a notification, in JSON mode
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    /// The request succeeded. The output is the reply of the text
+    /// mode, line by line.
+    Ok {
+        /// The reply, line by line
+        output: Vec<String>,
+    },
+    /// The request failed
+    Error {
+        /// Why the request failed
+        message: String,
+    },
+    /// A change made by another client
+    Notify(Notification),
+    /// An event, after SUBSCRIBE
+    Event {
+        /// The sequence number of the event, for this client. A gap
+        /// means that events were dropped, the client being behind.
+        seq: u64,
+        /// The event, formatted like in the shard files
+        line: String,
+    },
+}
+
+impl From<Result<String, String>> for Response {
+    fn from(result: Result<String, String>) -> Self {
+        match result {
+            Ok(reply) => Response::Ok {
+                output: reply.lines().map(str::to_string).collect(),
+            },
+            Err(message) => Response::Error { message },
+        }
+    }
+}
+
+impl Response {
+    /// Serialize the response as a line
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
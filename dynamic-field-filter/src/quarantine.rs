@@ -0,0 +1,87 @@
+//! A secondary output for the events suppressed by the filter layer,
+//! so that filtering never loses anything: suppressed events are
+//! formatted and written either to a file or to an in-memory buffer
+//! that can be read from the TCP connection.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::ring::RingBuffer;
+
+/// Default number of lines kept by an in-memory quarantine
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+/// Where the suppressed events go
+#[derive(Debug)]
+pub enum Quarantine {
+    /// Append the suppressed events to a file
+    File {
+        /// Where the file is
+        path: PathBuf,
+        /// The file, open for appending
+        file: File,
+    },
+    /// Keep the last suppressed events in memory
+    Memory(RingBuffer<String>),
+}
+
+impl Quarantine {
+    /// Quarantine suppressed events to the given file
+    pub fn file(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Quarantine::File {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Open the quarantine file again, e.g. after it was rotated
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Quarantine::File { path, .. } = self {
+            *self = Quarantine::file(&path.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Quarantine the last `capacity` suppressed events in memory
+    pub fn memory(capacity: usize) -> Self {
+        Quarantine::Memory(RingBuffer::new(capacity))
+    }
+
+    /// Write a formatted event, without a trailing newline
+    pub fn write(&self, line: String) {
+        match self {
+            Quarantine::File { file, .. } => {
+                if let Err(e) = writeln!(&*file, "{line}") {
+                    eprintln!("failed to write to the quarantine file: {e}");
+                }
+            }
+            Quarantine::Memory(lines) => lines.push(line),
+        }
+    }
+
+    /// Return the events held in memory, oldest first. File
+    /// quarantines don't hold anything.
+    pub fn lines(&self) -> Vec<String> {
+        match self {
+            Quarantine::File { .. } => Vec::new(),
+            Quarantine::Memory(lines) => lines.last(lines.capacity()),
+        }
+    }
+
+    /// Return a one-line description of the quarantine
+    pub fn describe(&self) -> String {
+        match self {
+            Quarantine::File { path, .. } => format!("quarantine to {}", path.display()),
+            Quarantine::Memory(lines) => format!(
+                "quarantine in memory ({}/{} lines)",
+                lines.len(),
+                lines.capacity()
+            ),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Rate limiting of events, with a token bucket per callsite.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::Metadata;
+
+/// The target of the reports
+pub const TARGET: &str = "filtering::rate_limit";
+
+/// How often the number of events suppressed by rate limiting is
+/// reported
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A token bucket refilled at `rate` tokens per second, holding up to
+/// one second worth of tokens (and at least one token)
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    /// The tokens available, as of the given instant
+    tokens: Mutex<(f64, Instant)>,
+    /// Number of events suppressed since the last report
+    suppressed: AtomicU64,
+}
+
+impl TokenBucket {
+    /// Create a bucket refilled at `rate` tokens per second
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: Mutex::new((rate.max(1.0), Instant::now())),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of tokens added per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Take a token. Return `false`, and count a suppressed event, if
+    /// there is none left.
+    pub fn take(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (available, refilled_at) = &mut *tokens;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+        *available = (*available + elapsed * self.rate).min(self.rate.max(1.0));
+        *refilled_at = now;
+        if *available >= 1.0 {
+            *available -= 1.0;
+            true
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Return the number of events suppressed since the last call
+    pub fn take_suppressed(&self) -> u64 {
+        self.suppressed.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Report the events suppressed by rate limiting since the last
+/// report, as returned by the rate limiter of each callsite. This is
+/// to be called every [`REPORT_INTERVAL`].
+pub fn report(summaries: Vec<(&'static Metadata<'static>, u64)>) {
+    for (metadata, suppressed) in summaries {
+        warn!(
+            target: TARGET,
+            callsite_target = metadata.target(),
+            callsite = metadata.name(),
+            suppressed,
+            "{suppressed} messages suppressed"
+        );
+    }
+}
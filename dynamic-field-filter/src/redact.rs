@@ -0,0 +1,179 @@
+//! Redaction of sensitive field values in the output.
+//!
+//! [`RedactingFields`] formats fields for the fmt layer, masking or
+//! hashing the values of the configured fields. The same redactions
+//! apply to the records formatted by [`crate::format`]. Span fields
+//! are formatted when spans are created, so changing the redactions
+//! only affects new spans.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::sync::RwLock;
+
+use serde_json::Map;
+use serde_json::Value;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Record;
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::field::VisitOutput;
+use tracing_subscriber::fmt::format::DefaultVisitor;
+use tracing_subscriber::fmt::format::JsonVisitor;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::fmt::FormattedFields;
+
+/// How a field value is redacted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redaction {
+    /// Replace the value with the given text
+    Mask(String),
+    /// Replace the value with a hash of it, so that equal values can
+    /// still be correlated
+    Hash,
+}
+
+impl Redaction {
+    /// Return the redacted version of a value
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Redaction::Mask(mask) => mask.clone(),
+            Redaction::Hash => {
+                let mut hasher = DefaultHasher::new();
+                value.hash(&mut hasher);
+                format!("#{:016x}", hasher.finish())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redaction::Mask(mask) => write!(f, "mask {mask}"),
+            Redaction::Hash => f.write_str("hash"),
+        }
+    }
+}
+
+/// The redactions, by field name
+static REDACTIONS: RwLock<Option<HashMap<String, Redaction>>> = RwLock::new(None);
+
+/// Redact the given field, or stop redacting it with `None`
+pub fn set_redaction(field: &str, redaction: Option<Redaction>) {
+    let mut redactions = REDACTIONS.write().unwrap();
+    let redactions = redactions.get_or_insert_with(HashMap::new);
+    match redaction {
+        Some(redaction) => {
+            redactions.insert(field.to_string(), redaction);
+        }
+        None => {
+            redactions.remove(field);
+        }
+    }
+}
+
+/// Stop redacting all fields
+pub fn clear_redactions() {
+    *REDACTIONS.write().unwrap() = None;
+}
+
+/// Return the redactions, sorted by field name
+pub fn redactions() -> Vec<(String, Redaction)> {
+    let redactions = REDACTIONS.read().unwrap();
+    let mut redactions: Vec<_> = redactions
+        .iter()
+        .flatten()
+        .map(|(field, redaction)| (field.clone(), redaction.clone()))
+        .collect();
+    redactions.sort_by(|a, b| a.0.cmp(&b.0));
+    redactions
+}
+
+/// Return the redaction of the given field, if it is redacted
+pub fn redaction(field: &str) -> Option<Redaction> {
+    let redactions = REDACTIONS.read().unwrap();
+    redactions.as_ref()?.get(field).cloned()
+}
+
+/// A field formatter for the fmt layer, which formats fields like the
+/// default one, or like the JSON one, except for the redacted ones
+#[derive(Debug, Default)]
+pub struct RedactingFields {
+    json: bool,
+}
+
+impl RedactingFields {
+    /// Format the fields as a JSON object, for the JSON output
+    pub fn json() -> Self {
+        Self { json: true }
+    }
+}
+
+impl<'a> FormatFields<'a> for RedactingFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'_>, fields: R) -> fmt::Result {
+        if self.json {
+            let mut visitor = RedactingVisitor(JsonVisitor::new(&mut writer));
+            fields.record(&mut visitor);
+            visitor.0.finish()
+        } else {
+            let mut visitor = RedactingVisitor(DefaultVisitor::new(writer, true));
+            fields.record(&mut visitor);
+            visitor.0.finish()
+        }
+    }
+
+    fn add_fields(
+        &self,
+        current: &'a mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        if !self.json {
+            if !current.fields.is_empty() {
+                current.fields.push(' ');
+            }
+            return self.format_fields(current.as_writer(), fields);
+        }
+        // Merge the new fields into the JSON object, rather than
+        // appending a second object
+        let mut new = String::new();
+        self.format_fields(Writer::new(&mut new), fields)?;
+        let Ok(mut merged) = serde_json::from_str::<Map<String, Value>>(&current.fields) else {
+            current.fields = new;
+            return Ok(());
+        };
+        let new = serde_json::from_str::<Map<String, Value>>(&new).map_err(|_| fmt::Error)?;
+        merged.extend(new);
+        current.fields = serde_json::to_string(&merged).map_err(|_| fmt::Error)?;
+        Ok(())
+    }
+}
+
+/// Wraps the visitor of a field formatter, and redacts the values it
+/// records
+struct RedactingVisitor<V>(V);
+
+impl<V: Visit> Visit for RedactingVisitor<V> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match redaction(field.name()) {
+            Some(redaction) => {
+                let redacted = redaction.apply(value);
+                self.0.record_debug(field, &format_args!("{redacted}"))
+            }
+            None => self.0.record_str(field, value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match redaction(field.name()) {
+            Some(redaction) => {
+                let redacted = redaction.apply(&format!("{value:?}"));
+                self.0.record_debug(field, &format_args!("{redacted}"))
+            }
+            None => self.0.record_debug(field, value),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Releasing the events the filter held back.
+//!
+//! In trigger mode, see [`DynamicFieldFilter::set_trigger`], the
+//! events suppressed within a span are kept until a WARN or ERROR
+//! event happens within it. They are then released, along with the
+//! WARN or ERROR event. In slow mode, see
+//! [`DynamicFieldFilter::set_slow`], the events within a span are held
+//! until it closes, and released if it was slow, followed by an event
+//! of the [`CLOSE_TARGET`] target with its busy and idle times.
+//!
+//! The released events are emitted again through the subscriber by
+//! [`run_reporter`], so that they go to the same outputs, formatted
+//! the same way, as the other events.
+//!
+//! The events are emitted from the reporter's thread rather than from
+//! within the layer, since a subscriber dispatching events to itself
+//! can't be relied on. Their spans may be closed by then, so a held
+//! event carries the metadata and field values of its spans, and it
+//! is emitted within copies of them. The filter lets the events and
+//! spans emitted by the reporter through. Their timestamps are those
+//! of their release.
+//!
+//! [`DynamicFieldFilter::set_trigger`]: crate::DynamicFieldFilter::set_trigger
+//! [`DynamicFieldFilter::set_slow`]: crate::DynamicFieldFilter::set_slow
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::field;
+use tracing::field::DisplayValue;
+use tracing::field::Field;
+use tracing::field::FieldSet;
+use tracing::field::ValueSet;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::Dispatch;
+use tracing::Event;
+use tracing::Id;
+use tracing::Metadata;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::Scope;
+use tracing_subscriber::registry::SpanRef;
+
+/// The target of the events reporting that a slow span closed
+pub const CLOSE_TARGET: &str = "dynamic_filter::slow";
+
+/// How many releases can wait for the reporter, the next ones are
+/// dropped
+const CAPACITY: usize = 64;
+
+/// How often the reporter checks whether it should stop
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// Whether this thread is the reporter's
+    static REPLAYING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Return `true` on the reporter's thread, whose spans and events the
+/// filter never applies to
+pub(crate) fn is_replaying() -> bool {
+    REPLAYING.get()
+}
+
+/// A field value, kept to be recorded again
+#[derive(Debug, Clone, PartialEq)]
+enum OwnedValue {
+    Str(String),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    /// A value recorded as `Debug` or `Display`, already formatted
+    Text(String),
+}
+
+/// The field values of a span or event, by field index
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Recorded(Vec<Option<OwnedValue>>);
+
+impl Recorded {
+    /// No values for the fields of the given callsite
+    pub(crate) fn new(metadata: &Metadata<'_>) -> Self {
+        Recorded(vec![None; metadata.fields().len()])
+    }
+
+    fn set(&mut self, field: &Field, value: OwnedValue) {
+        if let Some(slot) = self.0.get_mut(field.index()) {
+            *slot = Some(value);
+        }
+    }
+
+    /// Call `f` with the values, as the value set of the given fields
+    fn with_value_set<R>(&self, fields: &FieldSet, f: impl FnOnce(&ValueSet<'_>) -> R) -> R {
+        let texts: Vec<Option<DisplayValue<&str>>> = self
+            .0
+            .iter()
+            .map(|value| match value {
+                Some(OwnedValue::Text(text)) => Some(field::display(text.as_str())),
+                _ => None,
+            })
+            .collect();
+        let values: Vec<Option<&dyn field::Value>> = self
+            .0
+            .iter()
+            .zip(&texts)
+            .map(|(value, text)| match (value, text) {
+                (_, Some(text)) => Some(text as &dyn field::Value),
+                (Some(OwnedValue::Str(value)), _) => Some(value as &dyn field::Value),
+                (Some(OwnedValue::U64(value)), _) => Some(value as &dyn field::Value),
+                (Some(OwnedValue::I64(value)), _) => Some(value as &dyn field::Value),
+                (Some(OwnedValue::F64(value)), _) => Some(value as &dyn field::Value),
+                (Some(OwnedValue::Bool(value)), _) => Some(value as &dyn field::Value),
+                _ => None,
+            })
+            .collect();
+        f(&fields.value_set_all(&values))
+    }
+}
+
+impl Visit for Recorded {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, OwnedValue::Str(value.to_string()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, OwnedValue::U64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, OwnedValue::I64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, OwnedValue::F64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, OwnedValue::Bool(value));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.set(field, OwnedValue::Text(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, OwnedValue::Text(format!("{value:?}")));
+    }
+}
+
+/// A span of a held event: its callsite, and its field values when
+/// the event was held
+#[derive(Debug, Clone, PartialEq)]
+struct HeldSpan {
+    metadata: &'static Metadata<'static>,
+    values: Recorded,
+}
+
+impl HeldSpan {
+    /// Create a copy of the span, within the given parent. Return
+    /// `None` if the subscriber disables it.
+    fn open(&self, dispatch: &Dispatch, parent: Option<Id>) -> Option<Id> {
+        if !dispatch.enabled(self.metadata) {
+            return None;
+        }
+        let id = self
+            .values
+            .with_value_set(self.metadata.fields(), |values| match parent {
+                Some(parent) => {
+                    dispatch.new_span(&Attributes::child_of(parent, self.metadata, values))
+                }
+                None => dispatch.new_span(&Attributes::new_root(self.metadata, values)),
+            });
+        Some(id)
+    }
+}
+
+/// Keep the spans of a scope, from the root, with the current values
+/// of their fields if the layer recorded them in a [`Recorded`]
+/// extension
+fn held_spans<S>(scope: Scope<'_, S>) -> Vec<HeldSpan>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    scope
+        .from_root()
+        .map(|span_ref| HeldSpan {
+            metadata: span_ref.metadata(),
+            values: span_ref
+                .extensions()
+                .get::<Recorded>()
+                .cloned()
+                .unwrap_or_else(|| Recorded::new(span_ref.metadata())),
+        })
+        .collect()
+}
+
+/// What a held event reports
+#[derive(Debug)]
+enum Record {
+    /// An event of the program
+    Event {
+        metadata: &'static Metadata<'static>,
+        values: Recorded,
+    },
+    /// The closing of a slow span, the innermost of the held spans
+    Close { busy: Duration, idle: Duration },
+}
+
+/// An event held by the filter, to be emitted when it is released
+#[derive(Debug)]
+pub(crate) struct HeldEvent {
+    /// When the event happened, to release events in order
+    pub(crate) at: Instant,
+    record: Record,
+    /// The spans of the event, from the root
+    spans: Vec<HeldSpan>,
+}
+
+impl HeldEvent {
+    /// Keep an event, along with its spans
+    pub(crate) fn new<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut values = Recorded::new(event.metadata());
+        event.record(&mut values);
+        HeldEvent {
+            at: Instant::now(),
+            record: Record::Event {
+                metadata: event.metadata(),
+                values,
+            },
+            spans: ctx.event_scope(event).map(held_spans).unwrap_or_default(),
+        }
+    }
+
+    /// Report the closing of a slow span
+    pub(crate) fn close<S>(span_ref: &SpanRef<'_, S>, busy: Duration, idle: Duration) -> Self
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        HeldEvent {
+            at: Instant::now(),
+            record: Record::Close { busy, idle },
+            spans: held_spans(span_ref.scope()),
+        }
+    }
+
+    /// Return `true` if this is an event of the program, rather than
+    /// the closing of a span
+    pub(crate) fn is_event(&self) -> bool {
+        matches!(self.record, Record::Event { .. })
+    }
+
+    fn emit(&self, dispatch: &Dispatch, parent: Option<Id>) {
+        match &self.record {
+            Record::Event { metadata, values } => {
+                if !dispatch.enabled(metadata) {
+                    return;
+                }
+                values.with_value_set(metadata.fields(), |values| {
+                    dispatch.event(&Event::new_child_of(parent, metadata, values))
+                });
+            }
+            Record::Close { busy, idle } => {
+                info!(
+                    target: CLOSE_TARGET,
+                    parent: parent,
+                    busy = ?busy,
+                    idle = ?idle,
+                    "close"
+                );
+            }
+        }
+    }
+}
+
+type Channel = (
+    SyncSender<Vec<HeldEvent>>,
+    Mutex<Option<Receiver<Vec<HeldEvent>>>>,
+);
+
+static CHANNEL: LazyLock<Channel> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::sync_channel(CAPACITY);
+    (tx, Mutex::new(Some(rx)))
+});
+
+/// Queue released events for the reporter. Return `false` if too many
+/// are waiting, and they were dropped.
+pub(crate) fn send(events: Vec<HeldEvent>) -> bool {
+    CHANNEL.0.try_send(events).is_ok()
+}
+
+/// Emit the released events as they come, while `running` returns
+/// `true`. It is checked every [`POLL_INTERVAL`]. Only one reporter
+/// can run at a time, the next ones return right away.
+pub fn run_reporter(running: impl Fn() -> bool) {
+    let Some(rx) = CHANNEL.1.lock().unwrap().take() else {
+        return;
+    };
+    REPLAYING.set(true);
+    while running() {
+        let events = match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(events) => events,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let dispatch = tracing::dispatcher::get_default(Dispatch::clone);
+        // The copies of the spans of the last event, reused by the next
+        // events while they are within the same spans
+        let mut open: Vec<(&HeldSpan, Option<Id>)> = Vec::new();
+        for event in &events {
+            let shared = open
+                .iter()
+                .zip(&event.spans)
+                .take_while(|((open, _), span)| *open == *span)
+                .count();
+            close(&dispatch, &mut open, shared);
+            for span in &event.spans[shared..] {
+                let id = span.open(&dispatch, innermost(&open));
+                open.push((span, id));
+            }
+            event.emit(&dispatch, innermost(&open));
+        }
+        close(&dispatch, &mut open, 0);
+    }
+    // The thread may run other code next
+    REPLAYING.set(false);
+    *CHANNEL.1.lock().unwrap() = Some(rx);
+}
+
+/// The innermost span copy that was created, if any
+fn innermost(open: &[(&HeldSpan, Option<Id>)]) -> Option<Id> {
+    open.iter().rev().find_map(|(_, id)| id.clone())
+}
+
+/// Close the span copies past the first `keep`
+fn close(dispatch: &Dispatch, open: &mut Vec<(&HeldSpan, Option<Id>)>, keep: usize) {
+    while open.len() > keep {
+        if let Some((_, Some(id))) = open.pop() {
+            dispatch.try_close(id);
+        }
+    }
+}
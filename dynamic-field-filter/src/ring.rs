@@ -0,0 +1,61 @@
+//! A bounded buffer that keeps the most recent items.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Default capacity of a [`RingBuffer`]
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// A thread-safe buffer of the last `capacity` items pushed into it
+#[derive(Debug)]
+pub struct RingBuffer<T> {
+    capacity: usize,
+    items: Mutex<VecDeque<T>>,
+}
+
+impl<T> RingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Add an item, dropping the oldest one if the buffer is full
+    pub fn push(&self, item: T) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut items = self.items.lock().unwrap();
+        if items.len() == self.capacity {
+            items.pop_front();
+        }
+        items.push_back(item);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<T: Clone> RingBuffer<T> {
+    /// Return the last `n` items, oldest first
+    pub fn last(&self, n: usize) -> Vec<T> {
+        let items = self.items.lock().unwrap();
+        items
+            .iter()
+            .skip(items.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<T> Default for RingBuffer<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
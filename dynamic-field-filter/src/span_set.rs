@@ -0,0 +1,69 @@
+//! A concurrent set of span IDs.
+
+use std::collections::HashSet;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
+
+use tracing::Id;
+
+/// Number of shards in a [`SpanSet`]. Spans are spread across shards
+/// by ID, so threads working on different spans rarely contend on the
+/// same lock.
+const SHARDS: usize = 32;
+
+/// A set of span IDs, sharded to reduce contention. The set also
+/// keeps track of its length, so that lookups in an empty set (the
+/// common case when nothing is filtered) don't touch any lock.
+#[derive(Debug, Default)]
+pub struct SpanSet {
+    shards: [RwLock<HashSet<u64>>; SHARDS],
+    len: AtomicUsize,
+}
+
+impl SpanSet {
+    fn shard(&self, id: &Id) -> &RwLock<HashSet<u64>> {
+        &self.shards[id.into_u64() as usize % SHARDS]
+    }
+
+    /// Add a span to the set
+    pub fn insert(&self, id: &Id) {
+        if self.shard(id).write().unwrap().insert(id.into_u64()) {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Remove a span from the set. Return `true` if it was present.
+    pub fn remove(&self, id: &Id) -> bool {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let removed = self.shard(id).write().unwrap().remove(&id.into_u64());
+        if removed {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Return `true` if the span is in the set
+    pub fn contains(&self, id: &Id) -> bool {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        self.shard(id).read().unwrap().contains(&id.into_u64())
+    }
+
+    /// Return the IDs of the spans in the set
+    pub fn ids(&self) -> Vec<Id> {
+        let mut ids: Vec<Id> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard.iter().map(|id| Id::from_u64(*id)).collect::<Vec<_>>()
+            })
+            .collect();
+        ids.sort_by_key(|id| id.into_u64());
+        ids
+    }
+}
@@ -0,0 +1,187 @@
+//! Counters of what the filter layer did.
+
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Serialize;
+
+/// Counters updated by the filter layer as it evaluates spans and
+/// events
+#[derive(Debug, Default)]
+pub struct Stats {
+    spans_evaluated: AtomicU64,
+    spans_suppressed: AtomicU64,
+    events_passed: AtomicU64,
+    events_suppressed: AtomicU64,
+    spans_dry_run: AtomicU64,
+    events_dry_run: AtomicU64,
+}
+
+impl Stats {
+    /// Count a span evaluated by the rules
+    pub fn span_evaluated(&self) {
+        self.spans_evaluated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a span suppressed by the rules
+    pub fn span_suppressed(&self) {
+        self.spans_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an event that went through
+    pub fn event_passed(&self) {
+        self.events_passed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a suppressed event
+    pub fn event_suppressed(&self) {
+        self.events_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a span that would have been suppressed, in dry-run mode
+    pub fn span_dry_run(&self) {
+        self.spans_dry_run.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an event that would have been suppressed, in dry-run mode
+    pub fn event_dry_run(&self) {
+        self.events_dry_run.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Return the current value of the counters
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            spans_evaluated: self.spans_evaluated.load(Ordering::Relaxed),
+            spans_suppressed: self.spans_suppressed.load(Ordering::Relaxed),
+            events_passed: self.events_passed.load(Ordering::Relaxed),
+            events_suppressed: self.events_suppressed.load(Ordering::Relaxed),
+            spans_dry_run: self.spans_dry_run.load(Ordering::Relaxed),
+            events_dry_run: self.events_dry_run.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The value of the [`Stats`] counters at a given time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StatsSnapshot {
+    /// Number of new spans checked against the filters
+    pub spans_evaluated: u64,
+    /// Number of spans disabled, because they matched a filter or
+    /// because they were within a disabled span
+    pub spans_suppressed: u64,
+    /// Number of events that went through the filters
+    pub events_passed: u64,
+    /// Number of events disabled, because they matched a filter or
+    /// because they were within a disabled span
+    pub events_suppressed: u64,
+    /// Number of spans that would have been disabled, if dry-run mode
+    /// had been off
+    pub spans_dry_run: u64,
+    /// Number of events that would have been disabled, if dry-run
+    /// mode had been off
+    pub events_dry_run: u64,
+}
+
+impl StatsSnapshot {
+    /// Return the difference between these counters and an earlier
+    /// snapshot
+    pub fn since(&self, earlier: &StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            spans_evaluated: self.spans_evaluated.wrapping_sub(earlier.spans_evaluated),
+            spans_suppressed: self.spans_suppressed.wrapping_sub(earlier.spans_suppressed),
+            events_passed: self.events_passed.wrapping_sub(earlier.events_passed),
+            events_suppressed: self
+                .events_suppressed
+                .wrapping_sub(earlier.events_suppressed),
+            spans_dry_run: self.spans_dry_run.wrapping_sub(earlier.spans_dry_run),
+            events_dry_run: self.events_dry_run.wrapping_sub(earlier.events_dry_run),
+        }
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "spans_evaluated {}", self.spans_evaluated)?;
+        writeln!(f, "spans_suppressed {}", self.spans_suppressed)?;
+        writeln!(f, "events_passed {}", self.events_passed)?;
+        writeln!(f, "events_suppressed {}", self.events_suppressed)?;
+        writeln!(f, "spans_dry_run {}", self.spans_dry_run)?;
+        writeln!(f, "events_dry_run {}", self.events_dry_run)
+    }
+}
+
+/// The target of the reports
+pub const TARGET: &str = "filtering::stats";
+
+/// Periodically reports the filter counters as events, under the
+/// [`TARGET`] target. Reporting is off until an interval is set.
+#[derive(Debug, Default)]
+pub struct StatsReporter {
+    interval: Mutex<Option<Duration>>,
+    /// When the last report was made, or reporting started, and the
+    /// counters then
+    last: Mutex<Option<(Instant, StatsSnapshot)>>,
+}
+
+impl StatsReporter {
+    /// How often [`StatsReporter::tick`] is meant to be called.
+    /// Reports are late by up to that much.
+    pub const TICK: Duration = Duration::from_secs(1);
+
+    /// Set the reporting interval, or turn reporting off with `None`
+    pub fn set_interval(&self, interval: Option<Duration>) {
+        *self.interval.lock().unwrap() = interval;
+        // Start over, rather than report early
+        *self.last.lock().unwrap() = None;
+    }
+
+    /// Return the reporting interval, if reporting is on
+    pub fn interval(&self) -> Option<Duration> {
+        *self.interval.lock().unwrap()
+    }
+
+    /// Report the counters returned by `snapshot` if the interval
+    /// elapsed since the last report. This is to be called every
+    /// [`StatsReporter::TICK`]. Each report covers the period since
+    /// the previous one.
+    pub fn tick(&self, snapshot: impl FnOnce() -> Option<StatsSnapshot>) {
+        let mut last = self.last.lock().unwrap();
+        // Don't include what happened while reporting was off in the
+        // next report
+        let Some(period) = self.interval() else {
+            *last = None;
+            return;
+        };
+        let Some(current) = snapshot() else {
+            return;
+        };
+        match &*last {
+            Some((at, earlier)) if at.elapsed() >= period => {
+                let delta = current.since(earlier);
+                *last = Some((Instant::now(), current));
+                drop(last);
+                report(&delta, period);
+            }
+            Some(_) => {}
+            None => *last = Some((Instant::now(), current)),
+        }
+    }
+}
+
+fn report(delta: &StatsSnapshot, period: Duration) {
+    info!(
+        target: TARGET,
+        period_secs = period.as_secs(),
+        spans_evaluated = delta.spans_evaluated,
+        spans_suppressed = delta.spans_suppressed,
+        events_passed = delta.events_passed,
+        events_suppressed = delta.events_suppressed,
+        spans_dry_run = delta.spans_dry_run,
+        events_dry_run = delta.events_dry_run,
+        "filter statistics"
+    );
+}
@@ -0,0 +1,216 @@
+//! Activation windows, which restrict when a filter rule applies.
+//!
+//! ```text
+//! BETWEEN <start> <end>     between two instants, in RFC 3339 format
+//! DAILY <HH:MM>-<HH:MM>     every day between two times, in UTC
+//! ```
+//!
+//! Windows are (de)serialized in the same format.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::de::Error;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a rule applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// From the first instant, included, to the second, excluded
+    Between(SystemTime, SystemTime),
+    /// Every day, between two times given in seconds since midnight
+    /// UTC. The window spans midnight if the end is before the start.
+    Daily {
+        /// When the window opens
+        start: u64,
+        /// When the window closes
+        end: u64,
+    },
+}
+
+impl Window {
+    /// Return `true` if the window is open at the given time
+    pub fn contains(&self, now: SystemTime) -> bool {
+        match *self {
+            Window::Between(start, end) => start <= now && now < end,
+            Window::Daily { start, end } => {
+                let secs = now
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs() % SECS_PER_DAY);
+                if start <= end {
+                    start <= secs && secs < end
+                } else {
+                    secs >= start || secs < end
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Window::Between(start, end) => write!(
+                f,
+                "BETWEEN {} {}",
+                humantime::format_rfc3339_seconds(start),
+                humantime::format_rfc3339_seconds(end)
+            ),
+            Window::Daily { start, end } => write!(
+                f,
+                "DAILY {:02}:{:02}-{:02}:{:02}",
+                start / 3600,
+                start % 3600 / 60,
+                end / 3600,
+                end % 3600 / 60
+            ),
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["BETWEEN", start, end] => parse_between(start, end),
+            ["DAILY", times] => parse_daily(times),
+            _ => Err(format!(
+                "expected BETWEEN <start> <end> or DAILY <HH:MM>-<HH:MM>, got {text}"
+            )),
+        }
+    }
+}
+
+impl Serialize for Window {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Window {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Parse the instants of a `BETWEEN` window
+pub fn parse_between(start: &str, end: &str) -> Result<Window, String> {
+    let parse = |instant: &str| {
+        humantime::parse_rfc3339_weak(instant)
+            .map_err(|e| format!("invalid instant {instant} ({e})"))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if end <= start {
+        return Err("the window ends before it starts".to_string());
+    }
+    Ok(Window::Between(start, end))
+}
+
+/// Parse the times of a `DAILY` window: `<HH:MM>-<HH:MM>`
+pub fn parse_daily(times: &str) -> Result<Window, String> {
+    let parse = |time: &str| -> Option<u64> {
+        let (hours, minutes) = time.split_once(':')?;
+        let (hours, minutes) = (hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?);
+        (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+    };
+    let window = times.split_once('-').and_then(|(start, end)| {
+        Some(Window::Daily {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    });
+    window.ok_or_else(|| format!("expected <HH:MM>-<HH:MM>, got {times}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// The given time of the given day since the epoch
+    fn at(day: u64, hours: u64, minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(day * SECS_PER_DAY + hours * 3600 + minutes * 60)
+    }
+
+    fn daily(text: &str) -> Window {
+        parse_daily(text).unwrap()
+    }
+
+    #[test]
+    fn daily_windows() {
+        assert_eq!(
+            "DAILY 09:00-17:30".parse(),
+            Ok(Window::Daily {
+                start: 9 * 3600,
+                end: 17 * 3600 + 30 * 60,
+            })
+        );
+        let office = daily("09:00-17:00");
+        assert!(office.contains(at(0, 9, 0)));
+        assert!(office.contains(at(3, 16, 59)));
+        assert!(!office.contains(at(0, 17, 0)));
+        assert!(!office.contains(at(0, 8, 59)));
+    }
+
+    #[test]
+    fn daily_windows_span_midnight() {
+        let night = daily("22:00-06:00");
+        assert!(night.contains(at(0, 22, 0)));
+        assert!(night.contains(at(1, 0, 0)));
+        assert!(night.contains(at(2, 5, 59)));
+        assert!(!night.contains(at(2, 6, 0)));
+        assert!(!night.contains(at(2, 12, 0)));
+    }
+
+    #[test]
+    fn between_windows() {
+        let window: Window = "BETWEEN 1970-01-02T00:00:00Z 1970-01-03T00:00:00Z"
+            .parse()
+            .unwrap();
+        assert_eq!(window, Window::Between(at(1, 0, 0), at(2, 0, 0)));
+        assert!(!window.contains(at(0, 23, 59)));
+        assert!(window.contains(at(1, 0, 0)));
+        assert!(window.contains(at(1, 23, 59)));
+        assert!(!window.contains(at(2, 0, 0)));
+    }
+
+    #[test]
+    fn windows_round_trip() {
+        for text in [
+            "DAILY 09:00-17:30",
+            "DAILY 23:45-00:15",
+            "BETWEEN 2026-01-01T00:00:00Z 2026-01-02T12:30:00Z",
+        ] {
+            let window: Window = text.parse().unwrap();
+            assert_eq!(window.to_string(), text);
+            let json = serde_json::to_string(&window).unwrap();
+            assert_eq!(serde_json::from_str::<Window>(&json).unwrap(), window);
+        }
+    }
+
+    #[test]
+    fn invalid_windows() {
+        assert!(parse_daily("09:00-24:00").is_err());
+        assert!(parse_daily("09:60-17:00").is_err());
+        assert!(parse_daily("09:00").is_err());
+        assert!(parse_daily("9h-17h").is_err());
+        assert!(parse_between("2026-01-02T00:00:00Z", "2026-01-01T00:00:00Z").is_err());
+        assert!(parse_between("2026-01-01T00:00:00Z", "2026-01-01T00:00:00Z").is_err());
+        assert!(parse_between("yesterday", "tomorrow").is_err());
+        assert!("WEEKLY 09:00-17:00".parse::<Window>().is_err());
+        assert!("DAILY".parse::<Window>().is_err());
+        assert!("BETWEEN 2026-01-01T00:00:00Z".parse::<Window>().is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! An append-only audit log of the control plane.
+//!
+//! Every command accepted on a control connection is appended to the
+//! audit file, one line per command: the time, the peer address, the
+//! number of filter rules once the command is applied, and the command
+//! itself. The same is logged as an INFO event with the
+//! `control::audit` target.
+//!
+//! The file is set with the `AUDIT_LOG` environment variable. Without
+//! it, the commands are only logged.
+
+use std::env;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::time::SystemTime;
+
+/// The target of the audit events
+pub const TARGET: &str = "control::audit";
+
+/// Open a file for appending
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(open_file(path)?),
+        })
+    }
+
+    /// Open the file again, e.g. after it was rotated
+    pub fn reopen(&self) -> io::Result<()> {
+        *self.file.lock().unwrap() = open_file(&self.path)?;
+        Ok(())
+    }
+
+    pub fn record(&self, peer: &str, command: &str, filters: usize) -> io::Result<()> {
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        let line = format!("{timestamp} {peer} filters={filters} {command}\n");
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+}
+
+static LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// Open the file given by the `AUDIT_LOG` environment variable, if it
+/// is set
+pub fn init_from_env() -> io::Result<()> {
+    let Some(path) = env::var_os("AUDIT_LOG") else {
+        return Ok(());
+    };
+    let log = AuditLog::open(Path::new(&path))?;
+    let _ = LOG.set(log);
+    Ok(())
+}
+
+/// Open the audit file again
+pub fn reopen() -> io::Result<()> {
+    match LOG.get() {
+        Some(log) => log.reopen(),
+        None => Ok(()),
+    }
+}
+
+/// Record a command accepted from `peer`, which left `filters` filter
+/// rules
+pub fn record(peer: &str, command: &str, filters: usize) {
+    info!(target: TARGET, peer, filters, "{command}");
+    if let Some(log) = LOG.get() {
+        if let Err(e) = log.record(peer, command, filters) {
+            error!("failed to write to the audit log: {e}");
+        }
+    }
+}
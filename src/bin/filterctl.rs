@@ -0,0 +1,231 @@
+//! A command-line client of the control connection, for scripts and
+//! for those who'd rather not type the commands in netcat:
+//!
+//! ```text
+//! filterctl filter vrf_id=1 --ttl 600
+//! filterctl filter busy_us '>' 5ms
+//! filterctl unfilter vrf_id
+//! filterctl list
+//! filterctl clear
+//! filterctl tail --vrf 2
+//! filterctl ping
+//! ```
+//!
+//! It speaks the JSON mode of the protocol, see
+//! [`dynamic_field_filter::protocol`].
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpStream;
+use std::process::ExitCode;
+
+use clap::Parser;
+use clap::Subcommand;
+use dynamic_field_filter::protocol::Request;
+use dynamic_field_filter::protocol::Response;
+
+/// Change the filters of a running loggingdemo
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Args {
+    /// Where the control connection listens
+    #[arg(long, env = "CONTROL_LISTEN", default_value = "127.0.0.1:8888")]
+    connect: String,
+    /// The token to authenticate with, if the server requires one
+    #[arg(long, env = "CONTROL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Set a filter rule, e.g. `vrf_id=1` or `busy_us > 5ms`
+    Filter {
+        #[arg(required = true)]
+        rule: Vec<String>,
+        /// Suppress this many matches, then expire
+        #[arg(long, conflicts_with = "capture")]
+        limit: Option<u64>,
+        /// Keep this many matches, then suppress the next ones
+        #[arg(long)]
+        capture: Option<u64>,
+        /// Expire after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Keep this fraction of the matches anyway
+        #[arg(long)]
+        sample: Option<f64>,
+    },
+    /// Remove the filter rule on a field
+    Unfilter { field: String },
+    /// List the filters
+    List,
+    /// Remove all the filters
+    Clear,
+    /// Print the filter counters
+    Stats,
+    /// Check that the server is alive, and print its uptime
+    Ping,
+    /// Print the events that go through the filters, as they happen
+    Tail {
+        /// Only the events of this VRF
+        #[arg(long)]
+        vrf: Option<u32>,
+        /// Other values the fields of the events must have, as
+        /// `<field>=<value>`
+        fields: Vec<String>,
+    },
+}
+
+/// A connection to the server, in JSON mode
+struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: &str) -> Result<Self, String> {
+        let stream =
+            TcpStream::connect(addr).map_err(|e| format!("failed to connect to {addr}: {e}"))?;
+        let reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let mut client = Self {
+            writer: stream,
+            reader,
+        };
+        client.write("JSON\n")?;
+        client.reply()?;
+        Ok(client)
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), String> {
+        self.writer
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("failed to send the request: {e}"))
+    }
+
+    /// Read the next response, notifications and events included
+    fn next(&mut self) -> Result<Response, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err("connection closed by the server".to_string()),
+            Ok(_) => serde_json::from_str(&line).map_err(|e| format!("invalid response: {e}")),
+            Err(e) => Err(format!("failed to read the response: {e}")),
+        }
+    }
+
+    /// Read the reply to the last request, skipping what the server
+    /// sent in the meantime
+    fn reply(&mut self) -> Result<Vec<String>, String> {
+        loop {
+            match self.next()? {
+                Response::Ok { output } => return Ok(output),
+                Response::Error { message } => return Err(message),
+                Response::Notify(_) | Response::Event { .. } => {}
+            }
+        }
+    }
+
+    /// Send a request, and return its output
+    fn send(&mut self, request: &Request) -> Result<Vec<String>, String> {
+        let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+        line.push('\n');
+        self.write(&line)?;
+        self.reply()
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("filterctl: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let mut client = Client::connect(&args.connect)?;
+    if let Some(token) = args.token {
+        client.send(&Request::Auth { token })?;
+    }
+    let request = match args.command {
+        Command::Filter {
+            rule,
+            limit,
+            capture,
+            ttl,
+            sample,
+        } => {
+            let mut options = BTreeMap::new();
+            let values = [
+                ("limit", limit.map(|n| n.to_string())),
+                ("capture", capture.map(|n| n.to_string())),
+                ("ttl", ttl.map(|secs| secs.to_string())),
+                ("sample", sample.map(|rate| rate.to_string())),
+            ];
+            for (option, value) in values {
+                if let Some(value) = value {
+                    options.insert(option.to_string(), value);
+                }
+            }
+            Request::Filter {
+                rule: rule.join(" "),
+                options,
+            }
+        }
+        Command::Unfilter { field } => Request::Unfilter { field },
+        Command::List => Request::List,
+        Command::Clear => Request::Clear,
+        Command::Stats => Request::Stats,
+        Command::Ping => Request::Ping,
+        Command::Tail { vrf, fields } => return tail(&mut client, vrf, &fields),
+    };
+    let output = client.send(&request)?;
+    client.send(&Request::Quit)?;
+    let mut stdout = io::stdout().lock();
+    for line in output {
+        // Stop quietly if the output is closed, e.g. by `head`
+        if writeln!(stdout, "{line}").is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Print the matching events until the connection or the output is
+/// closed
+fn tail(client: &mut Client, vrf: Option<u32>, fields: &[String]) -> Result<(), String> {
+    let mut query = BTreeMap::new();
+    if let Some(vrf) = vrf {
+        query.insert("vrf_id".to_string(), vrf.to_string());
+    }
+    for field in fields {
+        let Some((name, value)) = field.split_once('=') else {
+            return Err(format!(
+                "invalid field value {field}, expected <field>=<value>"
+            ));
+        };
+        query.insert(name.to_string(), value.to_string());
+    }
+    client.send(&Request::Subscribe { fields: query })?;
+    let mut stdout = io::stdout().lock();
+    let mut last = 0;
+    loop {
+        if let Response::Event { seq, line } = client.next()? {
+            // The events skipped were dropped, this client being behind
+            if seq > last + 1 {
+                eprintln!("{} events missed", seq - last - 1);
+            }
+            last = seq;
+            if writeln!(stdout, "{line}").is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Notifications of the filter changes to the control clients.
+//!
+//! Every change recorded in the SIEM changelog is also sent to the
+//! other connected control clients, as a line such as:
+//!
+//! ```text
+//! NOTIFY filters-changed 127.0.0.1:51234 set_filter vrf_id=1
+//! ```
+//!
+//! so that operators sharing a box see each other's changes as they
+//! happen.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+use dynamic_field_filter::protocol::Notification;
+
+/// Identifies a control client. Unlike its peer name, which clients
+/// over pipes or HTTP may share, this is unique.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientId(u64);
+
+impl ClientId {
+    /// Return a new, unique ID
+    pub fn unique() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ClientId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The connected clients, with where their notifications go
+static CLIENTS: Mutex<Vec<(ClientId, Sender<Notification>)>> = Mutex::new(Vec::new());
+
+/// The notifications of a client, until it is dropped
+#[derive(Debug)]
+pub struct Subscription {
+    client: ClientId,
+    notifications: Receiver<Notification>,
+}
+
+impl Subscription {
+    /// Return the notifications received since the last call
+    pub fn pending(&self) -> impl Iterator<Item = Notification> + '_ {
+        self.notifications.try_iter()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        CLIENTS
+            .lock()
+            .unwrap()
+            .retain(|(client, _)| *client != self.client);
+    }
+}
+
+/// Start receiving the changes made by the other clients
+pub fn subscribe(client: ClientId) -> Subscription {
+    let (tx, rx) = mpsc::channel();
+    CLIENTS.lock().unwrap().push((client, tx));
+    Subscription {
+        client,
+        notifications: rx,
+    }
+}
+
+/// Notify all the clients but `from`, if given, of a change made by
+/// `peer`
+pub fn send(from: Option<ClientId>, peer: &str, action: &str, detail: &str) {
+    let notification = Notification {
+        peer: peer.to_string(),
+        action: action.to_string(),
+        detail: detail.to_string(),
+    };
+    for (client, tx) in CLIENTS.lock().unwrap().iter() {
+        if Some(*client) != from {
+            let _ = tx.send(notification.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_with_the_same_peer_are_told_apart() {
+        let (first, second) = (ClientId::unique(), ClientId::unique());
+        let first_subscription = subscribe(first);
+        let second_subscription = subscribe(second);
+        send(Some(first), "pipe", "set_filter", "vrf_id=1");
+        assert_eq!(first_subscription.pending().count(), 0);
+        assert_eq!(second_subscription.pending().count(), 1);
+        drop(first_subscription);
+        send(None, "pipe", "remove_filter", "vrf_id");
+        let notifications: Vec<_> = second_subscription.pending().collect();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].action, "remove_filter");
+    }
+}
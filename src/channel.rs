@@ -0,0 +1,183 @@
+//! A bounded channel, with a choice of what to do when it is full.
+//!
+//! The RIB sends its route updates to BGP through it. When BGP falls
+//! behind and the channel fills up, the RIB either waits for room,
+//! drops the oldest update still queued to make some, or drops the one
+//! it was sending, depending on the [`Overflow`] policy. A single task
+//! sends and a single task receives.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+/// What to do with an item sent to a full channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Wait until the receiver makes room
+    Block,
+    /// Drop the oldest item in the channel, and queue the new one
+    DropOldest,
+    /// Drop the new item
+    DropNewest,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overflow::Block => write!(f, "block"),
+            Overflow::DropOldest => write!(f, "drop_oldest"),
+            Overflow::DropNewest => write!(f, "drop_newest"),
+        }
+    }
+}
+
+/// What happened to a sent item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sent {
+    /// It was queued
+    Queued,
+    /// It was queued, and the oldest item dropped to make room
+    DroppedOldest,
+    /// It was dropped, the channel being full
+    DroppedNewest,
+}
+
+/// The receiver is gone, and the item with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// Whether the sender or the receiver was dropped
+    closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    overflow: Overflow,
+    /// Signaled when an item is queued, or the sender dropped
+    readable: Notify,
+    /// Signaled when an item is received, or the receiver dropped
+    writable: Notify,
+}
+
+/// Create a channel holding at most `capacity` items, which must not
+/// be 0
+pub fn bounded<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a channel needs room for an item");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        capacity,
+        overflow,
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The sending half of a channel
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("capacity", &self.shared.capacity)
+            .field("overflow", &self.shared.overflow)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send an item, applying the overflow policy if the channel is
+    /// full
+    pub async fn send(&self, item: T) -> Result<Sent, Closed> {
+        loop {
+            let writable = self.shared.writable.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return Err(Closed);
+                }
+                let sent = if state.items.len() < self.shared.capacity {
+                    Some(Sent::Queued)
+                } else {
+                    match self.shared.overflow {
+                        Overflow::Block => None,
+                        Overflow::DropOldest => {
+                            state.items.pop_front();
+                            Some(Sent::DroppedOldest)
+                        }
+                        Overflow::DropNewest => return Ok(Sent::DroppedNewest),
+                    }
+                };
+                if let Some(sent) = sent {
+                    state.items.push_back(item);
+                    self.shared.readable.notify_one();
+                    return Ok(sent);
+                }
+            }
+            writable.await;
+        }
+    }
+
+    /// The overflow policy of the channel
+    pub fn overflow(&self) -> Overflow {
+        self.shared.overflow
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.readable.notify_one();
+    }
+}
+
+/// The receiving half of a channel
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next item, or `None` once the sender is gone and the
+    /// channel is empty
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let readable = self.shared.readable.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    self.shared.writable.notify_one();
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.writable.notify_one();
+    }
+}
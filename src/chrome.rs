@@ -0,0 +1,288 @@
+//! Recording the span timelines in the Chrome trace event format, on
+//! demand, to explore the timings of the router in `chrome://tracing`
+//! or in the Perfetto UI.
+//!
+//! `TRACE START <path>` starts writing to a file, and `TRACE STOP`
+//! finishes it. In between, each time a span is entered and exited, a
+//! duration event begins and ends on the timeline of the thread, with
+//! the span fields as arguments, and each event is an instant event:
+//!
+//! ```json
+//! {"traceEvents":[
+//! {"name":"add_path","cat":"loggingdemo::router","ph":"B","ts":12.5,"pid":4242,"tid":3,"args":{"vrf_id":"2"}},
+//! {"name":"New path","cat":"loggingdemo::router","ph":"i","s":"t","ts":40.1,"pid":4242,"tid":3,"args":{"next_hop":"10.10.10.10"}},
+//! {"name":"add_path","cat":"loggingdemo::router","ph":"E","ts":52.0,"pid":4242,"tid":3}
+//! ]}
+//! ```
+//!
+//! The trace layer comes after the field filter, so the suppressed spans
+//! and events aren't recorded. The span fields are those recorded by
+//! [`crate::live::LiveEvents`], redacted like in the other outputs. A
+//! trace still being written at shutdown is finished.
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use dynamic_field_filter::format;
+use dynamic_field_filter::format::FieldValues;
+use dynamic_field_filter::format::SpanFields;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use tracing::Event;
+use tracing::Id;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// The trace being written, if any
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+/// Whether a trace is being written, to skip the spans and events
+/// otherwise
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// The number of the last trace started, to tell the spans entered
+/// during the current trace from those entered before it
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The last thread number given out
+static THREADS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The number of the current thread on the timelines, given out
+    /// when it first records something
+    static THREAD: Cell<u64> = const { Cell::new(0) };
+}
+
+struct Trace {
+    path: PathBuf,
+    output: BufWriter<File>,
+    started: Instant,
+    generation: u64,
+    events: u64,
+    /// Whether anything was written, events or thread names
+    written: bool,
+    /// The threads whose name was written
+    threads: BTreeSet<u64>,
+}
+
+impl Trace {
+    /// Write a trace event, along with the name of its thread the first
+    /// time the thread records something
+    fn write(&mut self, mut event: Map<String, Value>) -> io::Result<()> {
+        let tid = thread_number();
+        if self.threads.insert(tid) {
+            let thread = thread::current();
+            let name = thread.name().unwrap_or("unnamed");
+            let metadata = json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": process::id(),
+                "tid": tid,
+                "args": { "name": name },
+            });
+            self.separator()?;
+            serde_json::to_writer(&mut self.output, &metadata)?;
+        }
+        let ts = self.started.elapsed().as_nanos() as f64 / 1000.0;
+        event.insert("ts".to_string(), ts.into());
+        event.insert("pid".to_string(), process::id().into());
+        event.insert("tid".to_string(), tid.into());
+        self.separator()?;
+        serde_json::to_writer(&mut self.output, &event)?;
+        self.events += 1;
+        Ok(())
+    }
+
+    /// Separate a trace event from the previous one, if any
+    fn separator(&mut self) -> io::Result<()> {
+        if self.written {
+            self.output.write_all(b",\n")?;
+        }
+        self.written = true;
+        Ok(())
+    }
+}
+
+/// Start writing a trace to a file
+pub fn start(path: &Path) -> Result<(), String> {
+    let mut trace = TRACE.lock().unwrap();
+    if let Some(trace) = trace.as_ref() {
+        return Err(format!("already tracing to {}", trace.path.display()));
+    }
+    let file =
+        File::create(path).map_err(|e| format!("failed to create {}: {e}", path.display()))?;
+    let mut output = BufWriter::new(file);
+    output
+        .write_all(b"{\"traceEvents\":[\n")
+        .map_err(|e| format!("failed to write to {}: {e}", path.display()))?;
+    *trace = Some(Trace {
+        path: path.to_path_buf(),
+        output,
+        started: Instant::now(),
+        generation: GENERATION.fetch_add(1, Ordering::Relaxed) + 1,
+        events: 0,
+        written: false,
+        threads: BTreeSet::new(),
+    });
+    TRACING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Finish the trace being written, and return its path and how many
+/// events it has, or nothing if there is no trace
+pub fn stop() -> Result<Option<(PathBuf, u64)>, String> {
+    let Some(mut trace) = TRACE.lock().unwrap().take() else {
+        return Ok(None);
+    };
+    TRACING.store(false, Ordering::Relaxed);
+    trace
+        .output
+        .write_all(b"\n]}\n")
+        .and_then(|()| trace.output.flush())
+        .map_err(|e| format!("failed to write to {}: {e}", trace.path.display()))?;
+    Ok(Some((trace.path, trace.events)))
+}
+
+/// Write a trace event built from the current trace, if any
+fn record(event: impl FnOnce(u64) -> Option<Map<String, Value>>) {
+    if !TRACING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut trace = TRACE.lock().unwrap();
+    let Some(trace) = trace.as_mut() else {
+        return;
+    };
+    if let Some(event) = event(trace.generation) {
+        // There is nowhere to report the failure to, and the file is
+        // checked when the trace stops
+        let _ = trace.write(event);
+    }
+}
+
+/// The number of the current thread on the timelines
+fn thread_number() -> u64 {
+    THREAD.with(|number| {
+        if number.get() == 0 {
+            number.set(THREADS.fetch_add(1, Ordering::Relaxed) + 1);
+        }
+        number.get()
+    })
+}
+
+/// A span extension counting how many times a span was entered and not
+/// exited yet during a trace, so that the spans entered before the
+/// trace started don't end on its timelines
+struct Entered {
+    generation: u64,
+    depth: usize,
+}
+
+/// A layer writing the span timelines to the current trace
+#[derive(Debug, Default)]
+pub struct ChromeTrace;
+
+impl<S> Layer<S> for ChromeTrace
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span_ref) = ctx.span(id) else {
+            return;
+        };
+        record(|generation| {
+            let mut extensions = span_ref.extensions_mut();
+            match extensions.get_mut::<Entered>() {
+                Some(entered) if entered.generation == generation => entered.depth += 1,
+                _ => {
+                    extensions.replace(Entered {
+                        generation,
+                        depth: 1,
+                    });
+                }
+            }
+            let mut args = Map::new();
+            if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+                for (name, value) in fields.values.iter() {
+                    args.insert(name.to_string(), format::redacted(name, value).into());
+                }
+            }
+            Some(trace_event(
+                span_ref.name(),
+                span_ref.metadata().target(),
+                "B",
+                args,
+            ))
+        });
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span_ref) = ctx.span(id) else {
+            return;
+        };
+        record(|generation| {
+            let mut extensions = span_ref.extensions_mut();
+            let entered = extensions.get_mut::<Entered>()?;
+            if entered.generation != generation || entered.depth == 0 {
+                return None;
+            }
+            entered.depth -= 1;
+            Some(trace_event(
+                span_ref.name(),
+                span_ref.metadata().target(),
+                "E",
+                Map::new(),
+            ))
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        record(|_| {
+            let mut fields = FieldValues::default();
+            event.record(&mut fields);
+            let metadata = event.metadata();
+            let mut args = Map::new();
+            for (name, value) in fields.values.iter() {
+                args.insert(name.to_string(), format::redacted(name, value).into());
+            }
+            let name = fields
+                .message
+                .unwrap_or_else(|| metadata.name().to_string());
+            let mut instant = trace_event(&name, metadata.target(), "i", args);
+            instant.insert("s".to_string(), "t".into());
+            Some(instant)
+        });
+    }
+}
+
+/// A trace event of a span or event, without its time and thread
+fn trace_event(
+    name: &str,
+    target: &str,
+    phase: &str,
+    args: Map<String, Value>,
+) -> Map<String, Value> {
+    let mut event = Map::new();
+    event.insert("name".to_string(), name.into());
+    event.insert("cat".to_string(), target.into());
+    event.insert("ph".to_string(), phase.into());
+    if !args.is_empty() {
+        event.insert("args".to_string(), Value::Object(args));
+    }
+    event
+}
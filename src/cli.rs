@@ -0,0 +1,118 @@
+//! The command-line arguments. They override the settings of the
+//! configuration file and of the environment, see [`crate::config`].
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::config::Config;
+use crate::config::Format;
+use crate::config::Source;
+
+/// A fake router that logs a lot, to try out filtering
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    /// The TOML configuration file
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Where the TCP control connection listens
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// Where the gRPC control service listens
+    #[arg(long)]
+    pub grpc: Option<SocketAddr>,
+    /// Where the HTTP admin API listens
+    #[arg(long)]
+    pub http: Option<SocketAddr>,
+    /// A field filter rule, e.g. `vrf_id=1` (repeatable)
+    #[arg(long = "filter", value_name = "RULE")]
+    pub filters: Vec<String>,
+    /// Target levels and filter rules, as directives, e.g.
+    /// `router::bgp[add_path{vrf_id=1}]=off,info`
+    #[arg(long)]
+    pub directives: Option<String>,
+    /// The maximum level of the filter's own logging, e.g. `debug`
+    #[arg(long, value_name = "LEVEL")]
+    pub diagnostics: Option<String>,
+    /// How the records are printed
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+    /// Seed of the simulator's random number generator, to reproduce
+    /// the same route updates
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Number of route updates per second
+    #[arg(long, value_parser = parse_rate)]
+    pub rate: Option<f64>,
+    /// A scenario of route updates to replay, rather than random ones,
+    /// see `src/scenario.rs`
+    #[arg(long)]
+    pub scenario: Option<PathBuf>,
+}
+
+static ARGS: OnceLock<Args> = OnceLock::new();
+
+/// Return the command-line arguments, parsed on the first call. This
+/// exits with a usage message if they are invalid.
+pub fn args() -> &'static Args {
+    ARGS.get_or_init(Args::parse)
+}
+
+impl Args {
+    /// Override the settings of the configuration file with those
+    /// given on the command line
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+            config.set_source("listen", Source::Cli);
+        }
+        if let Some(grpc) = self.grpc {
+            config.control.grpc = Some(grpc);
+            config.set_source("control.grpc", Source::Cli);
+        }
+        if let Some(http) = self.http {
+            config.control.http = Some(http);
+            config.set_source("control.http", Source::Cli);
+        }
+        if !self.filters.is_empty() {
+            config.filters.extend(self.filters.iter().cloned());
+            config.set_source("filters", Source::Cli);
+        }
+        if let Some(directives) = &self.directives {
+            config.directives = Some(directives.clone());
+            config.set_source("directives", Source::Cli);
+        }
+        if let Some(level) = &self.diagnostics {
+            config.diagnostics = Some(level.clone());
+            config.set_source("diagnostics", Source::Cli);
+        }
+        if let Some(format) = self.format {
+            config.fmt.format = format;
+            config.set_source("fmt.format", Source::Cli);
+        }
+        if let Some(seed) = self.seed {
+            config.simulator.seed = Some(seed);
+            config.set_source("simulator.seed", Source::Cli);
+        }
+        if let Some(rate) = self.rate {
+            config.simulator.interval = Duration::from_secs_f64(1.0 / rate);
+            config.set_source("simulator.interval", Source::Cli);
+        }
+        if let Some(scenario) = &self.scenario {
+            config.simulator.scenario = Some(scenario.clone());
+            config.set_source("simulator.scenario", Source::Cli);
+        }
+    }
+}
+
+/// Parse a number of events per second
+pub fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate {rate}")),
+    }
+}
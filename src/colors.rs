@@ -0,0 +1,141 @@
+//! Coloring the printed records by the value of a field, `vrf_id` by
+//! default, so that the records of each VRF stand out when they are
+//! interleaved.
+//!
+//! When the colors are on, [`ColorBy`] looks the field up in the event,
+//! then in its spans from the innermost, and prints the whole record in
+//! the color of the value. A value gets the color it is given in
+//! `fmt.colors`, if any, and otherwise one from `fmt.palette`: numbers
+//! pick the color at their index modulo the palette size, and other
+//! values a color by hash, so that the same value always has the same
+//! color. The records without the field are printed as usual.
+//!
+//! ```toml
+//! [fmt]
+//! ansi = true
+//! color_by = "vrf_id"                  # or "" to turn this off
+//! palette = [32, 33, 34, 35, 36]       # ANSI SGR color codes
+//! colors = { "0" = 37 }
+//! ```
+//!
+//! The span fields are those recorded by [`crate::live::LiveEvents`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use dynamic_field_filter::format::FieldValues;
+use dynamic_field_filter::format::SpanFields;
+use tracing::Event;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::fmt::FormatEvent;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::FmtConfig;
+
+/// The colors picked from by default, leaving out red, which is that
+/// of the errors
+pub const DEFAULT_PALETTE: &[u8] = &[32, 33, 34, 35, 36, 92, 93, 94, 95, 96];
+
+/// The escape sequence resetting the styles
+const RESET: &str = "\x1b[0m";
+
+/// An event formatter coloring the records of another one by the value
+/// of a field
+#[derive(Debug, Clone)]
+pub struct ColorBy<F> {
+    inner: F,
+    field: String,
+    palette: Vec<u8>,
+    colors: BTreeMap<String, u8>,
+}
+
+impl<F> ColorBy<F> {
+    pub fn new(inner: F, config: &FmtConfig) -> Self {
+        Self {
+            inner,
+            field: config.color_by.clone(),
+            palette: config.palette.clone(),
+            colors: config.colors.clone(),
+        }
+    }
+
+    /// The color of a value, if there is one
+    fn color(&self, value: &str) -> Option<u8> {
+        if let Some(color) = self.colors.get(value) {
+            return Some(*color);
+        }
+        if self.palette.is_empty() {
+            return None;
+        }
+        let index = match value.parse::<u64>() {
+            Ok(number) => number,
+            Err(_) => fnv1a(value),
+        };
+        Some(self.palette[(index % self.palette.len() as u64) as usize])
+    }
+
+    /// The value of the field for an event, looked up in the event and
+    /// then in its spans
+    fn lookup<S, N>(&self, ctx: &FmtContext<'_, S, N>, event: &Event<'_>) -> Option<String>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        N: for<'a> FormatFields<'a> + 'static,
+    {
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        if let Some(value) = fields.get(&self.field) {
+            return Some(value.to_string());
+        }
+        ctx.event_scope()?.find_map(|span_ref| {
+            span_ref
+                .extensions()
+                .get::<SpanFields>()
+                .and_then(|SpanFields(fields)| fields.get(&self.field).map(str::to_string))
+        })
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for ColorBy<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if !writer.has_ansi_escapes() || self.field.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+        let Some(color) = self.lookup(ctx, event).and_then(|value| self.color(&value)) else {
+            return self.inner.format_event(ctx, writer, event);
+        };
+        // Formatted without the usual styles, which would reset the
+        // color on the way. The fields may have been formatted with
+        // them already, so the color is set again after each reset.
+        let mut record = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut record), event)?;
+        let (record, newline) = match record.strip_suffix('\n') {
+            Some(record) => (record, "\n"),
+            None => (record.as_str(), ""),
+        };
+        let start = format!("\x1b[{color}m");
+        let record = record.replace(RESET, &format!("{RESET}{start}"));
+        write!(writer, "{start}{record}{RESET}{newline}")
+    }
+}
+
+/// The FNV-1a hash of a value, which unlike the std hashers is
+/// guaranteed to stay the same across Rust versions
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
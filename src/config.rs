@@ -0,0 +1,1387 @@
+//! The startup settings, and where they come from.
+//!
+//! Each setting comes from the first of these that sets it:
+//!
+//! 1. the control commands, at runtime (e.g. `LEVEL`, `FORMAT` or
+//!    `DISPLAY`)
+//! 2. the command-line arguments (see [`crate::cli`])
+//! 3. the environment variables: `RUST_LOG`, `FIELD_FILTER`,
+//!    `CONTROL_LISTEN`, `CONTROL_TOKEN`, `LOG_FORMAT`, `SIM_SEED` and
+//!    `SIM_RATE`
+//! 4. the configuration file
+//! 5. the defaults
+//!
+//! Filter rules are the exception: those of all the sources apply.
+//! `CONFIG SHOW` prints the settings along with their source.
+//!
+//! The configuration file is given with `--config <path>`, in TOML. It
+//! is watched, and the filter and fmt settings are applied again when
+//! it changes, see [`crate::hot_reload`]. All the settings are
+//! optional:
+//!
+//! ```toml
+//! listen = "127.0.0.1:8888"            # the TCP control connection
+//! env_filter = "info,loggingdemo::router=debug"
+//! filters = ["vrf_id=1", "busy_us > 5ms"]
+//! directives = "router::bgp[add_path{vrf_id=2}]=off,info"
+//! diagnostics = "info"                 # the filter's own logging
+//! enrich = true                        # hostname, pid, thread.name
+//!
+//! [filter]                             # the filter state, in the
+//! mode = "deny"                        # schema of SAVE, applied
+//! bypass_level = "WARN"                # before the filters above
+//! rules = [{ field = "peer", matcher = { not_in = ["10.0.0.1"] }, ttl = "1h" }]
+//! logger_levels = { "loggingdemo::router" = "debug" }
+//!
+//! [routes]                             # the targets printed to
+//! "router::bgp" = "bgp.log"            # files rather than stdout
+//!
+//! [rotation]                           # of the files above
+//! max_size = "10MB"                    # to roll past, none if unset
+//! keep = 5                             # rotated files, as bgp.log.1
+//! compress = true                      # with gzip, as bgp.log.1.gz
+//!
+//! [journald]                           # see crate::journald
+//! enabled = true
+//! identifier = "loggingdemo"
+//!
+//! [syslog]                             # see crate::syslog
+//! address = "udp://127.0.0.1:514"
+//! facility = "local0"
+//! structured_data = true
+//!
+//! [gelf]                               # see crate::gelf
+//! address = "127.0.0.1:12201"
+//! chunk_size = 1420
+//!
+//! [otlp]                               # see crate::otlp
+//! endpoint = "http://localhost:4318/v1/traces"
+//! service_name = "loggingdemo"
+//!
+//! [metrics]                            # see crate::metrics
+//! fields = ["vrf_id"]
+//! max_values = 100
+//!
+//! [fmt]
+//! ansi = false
+//! line_numbers = true
+//! span_path = false                    # before each record
+//! targets = true
+//! timestamps = true
+//! format = "compact"                   # or "pretty", "json", "tree"
+//! tee = "loggingdemo.log"              # also printed to, without
+//! tee_ansi = false                     # colors unless this is set
+//! color_by = "vrf_id"                  # the records, when ansi is
+//! palette = [32, 33, 34, 35, 36]       # on, by the value of a field
+//! colors = { "0" = 37 }                # with a color of their own
+//!
+//! [control]
+//! token = "secret"                     # required by AUTH, if set
+//! cert = "control.crt"                 # to use TLS, in PEM, along
+//! key = "control.key"                  # with the private key
+//! pipe = '\\.\pipe\tracing-filter'    # Windows only
+//! max_clients = 16                     # open at once
+//! idle_timeout = "5m"                  # or "0s" for none
+//! grpc = "127.0.0.1:50051"             # the gRPC control service
+//! http = "127.0.0.1:8080"              # the HTTP admin API
+//!
+//! [simulator]
+//! interval = "1s"                      # between two route updates
+//! seed = 42                            # to repeat the same updates
+//! vrf_ids = [0, 1, 2, 3]
+//! vrf_names = { mgmt = 0, cust-a = 1 } # see crate::vrfs
+//! prefixes = ["1.0.0.0/8", "2001:db8:10::/48"]
+//! next_hops = ["1.1.1.1", "2001:db8::1"] # of the family of the prefix
+//! asns = [65001, 65002, 3356]          # making up the AS paths
+//! scenario = "flap.json"               # replayed rather than random
+//! scenario_loop = false                # updates, see crate::scenario
+//! speed = 1.0
+//! capacity = 64                        # route updates queued for BGP
+//! overflow = "block"                   # when they are, or
+//!                                      # "drop_oldest", "drop_newest"
+//!
+//! [[simulator.vrfs]]                   # rather than vrf_ids, VRFs
+//! id = 1                               # with their own pools, those
+//! name = "cust-a"                      # above by default, and their
+//! prefixes = ["10.1.0.0/16"]           # own mix of route updates
+//! next_hops = ["10.1.0.1"]
+//! weight = 3                           # updated 3 times as often
+//! withdraw = 0.2                       # as a VRF of weight 1, with
+//!                                      # 20% of route withdrawals
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use dynamic_field_filter::diagnostics;
+use dynamic_field_filter::directive;
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use dynamic_field_filter::filter::Profile;
+use dynamic_field_filter::loggers;
+use dynamic_field_filter::panics;
+use dynamic_field_filter::redact::RedactingFields;
+use ipnetwork::IpNetwork;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::ServerConfig;
+use serde::de::Error;
+use serde::Deserialize;
+use serde::Deserializer;
+use tracing_subscriber::fmt;
+use tracing_subscriber::fmt::format::DefaultFields;
+use tracing_subscriber::fmt::format::Format as EventFormat;
+use tracing_subscriber::fmt::format::Full;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+use tracing_subscriber::Registry;
+use tracing_tree::time::Uptime;
+use tracing_tree::HierarchicalLayer;
+
+use crate::channel::Overflow;
+use crate::cli;
+use crate::colors;
+use crate::colors::ColorBy;
+use crate::console;
+use crate::enrichment::Enrich;
+use crate::nonblocking::NonBlocking;
+use crate::routes::RoutingWriter;
+use crate::span_path::SpanPath;
+use crate::tee::Tee;
+
+/// Where a setting comes from, from the lowest precedence to the
+/// highest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Default,
+    File,
+    Env,
+    Cli,
+    Runtime,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Source::Default => "default",
+            Source::File => "file",
+            Source::Env => "env",
+            Source::Cli => "cli",
+            Source::Runtime => "runtime",
+        })
+    }
+}
+
+/// The settings, from all the sources
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The file the settings were read from, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Where each setting comes from, by name, if not from the
+    /// defaults
+    #[serde(skip)]
+    sources: BTreeMap<&'static str, Source>,
+    /// Where the TCP control connection listens
+    pub listen: SocketAddr,
+    /// The `EnvFilter` directives
+    pub env_filter: Option<String>,
+    /// Field filter rules, in the syntax of `FIELD_FILTER`
+    pub filters: Vec<String>,
+    /// Target levels and filter rules, as directives, see
+    /// [`dynamic_field_filter::directive`]
+    pub directives: Option<String>,
+    /// The maximum level of the filter's own logging, see
+    /// [`dynamic_field_filter::diagnostics`]. When unset, the
+    /// `EnvFilter` directives decide.
+    pub diagnostics: Option<String>,
+    /// Whether the events get the `hostname`, `pid` and `thread.name`
+    /// fields, see [`dynamic_field_filter::enrich`]
+    pub enrich: bool,
+    /// The filter state, in the schema of the files written by SAVE
+    pub filter: Option<Profile>,
+    /// The files the records of some targets are printed to, by
+    /// logger name, see [`crate::routes`]
+    pub routes: BTreeMap<String, PathBuf>,
+    /// When the files of the routes roll
+    pub rotation: RotationConfig,
+    /// Whether the records are sent to the systemd journal
+    pub journald: JournaldConfig,
+    /// Whether the records are sent to a syslog server
+    pub syslog: SyslogConfig,
+    /// Whether the records are sent to Graylog
+    pub gelf: GelfConfig,
+    /// Whether the spans are exported to an OpenTelemetry collector
+    pub otlp: OtlpConfig,
+    /// Which fields the events are counted by
+    pub metrics: MetricsConfig,
+    pub fmt: FmtConfig,
+    pub control: ControlConfig,
+    pub simulator: SimulatorConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: None,
+            sources: BTreeMap::new(),
+            listen: SocketAddr::from(([127, 0, 0, 1], 8888)),
+            env_filter: None,
+            filters: Vec::new(),
+            directives: None,
+            diagnostics: None,
+            enrich: false,
+            filter: None,
+            routes: BTreeMap::new(),
+            rotation: RotationConfig::default(),
+            journald: JournaldConfig::default(),
+            syslog: SyslogConfig::default(),
+            gelf: GelfConfig::default(),
+            otlp: OtlpConfig::default(),
+            metrics: MetricsConfig::default(),
+            fmt: FmtConfig::default(),
+            control: ControlConfig::default(),
+            simulator: SimulatorConfig::default(),
+        }
+    }
+}
+
+/// The fmt layer, boxed so that its format can change when it is
+/// reloaded
+pub type FmtLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// The display options of the fmt layer, which can be turned on and
+/// off at runtime with `DISPLAY`
+pub const DISPLAY_OPTIONS: &[&str] =
+    &["ansi", "line_numbers", "span_path", "targets", "timestamps"];
+
+/// How the fmt layer prints the records
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FmtConfig {
+    pub ansi: bool,
+    pub line_numbers: bool,
+    /// Whether the records start with their span path, see
+    /// [`crate::span_path`]
+    pub span_path: bool,
+    pub targets: bool,
+    pub timestamps: bool,
+    pub format: Format,
+    /// A file the records are copied to, see [`crate::tee`]
+    pub tee: Option<PathBuf>,
+    /// Whether the colors are kept in the copy
+    pub tee_ansi: bool,
+    /// The field whose values give the records their color, see
+    /// [`crate::colors`], or nothing if empty
+    pub color_by: String,
+    /// The colors given to the values, as ANSI SGR codes
+    pub palette: Vec<u8>,
+    /// The colors of some values, rather than one of the palette
+    pub colors: BTreeMap<String, u8>,
+}
+
+impl Default for FmtConfig {
+    fn default() -> Self {
+        Self {
+            ansi: false,
+            line_numbers: true,
+            span_path: false,
+            targets: true,
+            timestamps: true,
+            format: Format::Compact,
+            tee: None,
+            tee_ansi: false,
+            color_by: "vrf_id".to_string(),
+            palette: colors::DEFAULT_PALETTE.to_vec(),
+            colors: BTreeMap::new(),
+        }
+    }
+}
+
+impl FmtConfig {
+    /// Build the fmt layer
+    pub fn layer(&self) -> FmtLayer {
+        if self.timestamps {
+            self.formatted(fmt::layer())
+        } else {
+            self.formatted(fmt::layer().without_time())
+        }
+    }
+
+    /// Return whether a display option is on, or `None` if there is
+    /// no such option, see [`DISPLAY_OPTIONS`]
+    pub fn display(&self, option: &str) -> Option<bool> {
+        match option {
+            "ansi" => Some(self.ansi),
+            "line_numbers" => Some(self.line_numbers),
+            "span_path" => Some(self.span_path),
+            "targets" => Some(self.targets),
+            "timestamps" => Some(self.timestamps),
+            _ => None,
+        }
+    }
+
+    /// Apply the display options and the format to a fmt layer, whose
+    /// timer depends on whether timestamps are on
+    fn formatted<T>(
+        &self,
+        layer: fmt::Layer<Registry, DefaultFields, EventFormat<Full, T>>,
+    ) -> FmtLayer
+    where
+        T: FormatTime + Send + Sync + 'static,
+    {
+        // Formatted with colors if either side of the tee keeps them
+        let tee_ansi = self.tee.is_some() && self.tee_ansi;
+        let layer = layer
+            .with_line_number(self.line_numbers)
+            .with_target(self.targets)
+            .with_ansi(self.ansi || tee_ansi)
+            .with_writer(NonBlocking(Tee::new(RoutingWriter, self.ansi, tee_ansi)));
+        match self.format {
+            Format::Compact => layer
+                .compact()
+                .fmt_fields(RedactingFields::default())
+                .map_event_format(|format| {
+                    ColorBy::new(Enrich::text(SpanPath::new(format, self.span_path)), self)
+                })
+                .boxed(),
+            Format::Pretty => layer
+                .pretty()
+                .fmt_fields(RedactingFields::default())
+                .map_event_format(|format| {
+                    ColorBy::new(Enrich::text(SpanPath::new(format, self.span_path)), self)
+                })
+                .boxed(),
+            Format::Json => layer
+                .json()
+                .fmt_fields(RedactingFields::json())
+                .map_event_format(Enrich::json)
+                .boxed(),
+            Format::Tree => self.tree(),
+        }
+    }
+
+    /// Build the layer of the tree format, which prints the spans as
+    /// they open and close, indented by depth, and the time elapsed in
+    /// them if timestamps are on
+    fn tree(&self) -> FmtLayer {
+        let tee_ansi = self.tee.is_some() && self.tee_ansi;
+        let layer = HierarchicalLayer::new(2)
+            .with_ansi(self.ansi || tee_ansi)
+            .with_targets(self.targets)
+            .with_indent_lines(true)
+            .with_verbose_exit(true)
+            .with_writer(NonBlocking(Tee::new(RoutingWriter, self.ansi, tee_ansi)));
+        if self.timestamps {
+            layer.with_timer(Uptime::default()).boxed()
+        } else {
+            layer.boxed()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Compact,
+    Pretty,
+    Json,
+    /// The spans as a tree, with `tracing-tree`. The records all go to
+    /// stdout, whatever the routes, and their fields aren't redacted.
+    Tree,
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Compact => "compact",
+            Format::Pretty => "pretty",
+            Format::Json => "json",
+            Format::Tree => "tree",
+        })
+    }
+}
+
+/// Who may use the control connection, and how
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The token that clients must give with `AUTH` before changing
+    /// anything. When unset, all the clients may change everything.
+    /// There is no command-line argument for it, so that it doesn't
+    /// show in the process list.
+    pub token: Option<String>,
+    /// The certificate chain of the control connection, in PEM. The
+    /// connection uses TLS when this and the key are set.
+    pub cert: Option<PathBuf>,
+    /// The private key of the certificate, in PEM
+    pub key: Option<PathBuf>,
+    /// A named pipe to also accept control connections on, on Windows
+    pub pipe: Option<String>,
+    /// The maximum number of control connections open at once. The
+    /// connections over the limit are closed right away.
+    pub max_clients: usize,
+    /// How long a TCP control connection may stay without a command
+    /// before it is closed. Zero means forever.
+    #[serde(deserialize_with = "duration")]
+    pub idle_timeout: Duration,
+    /// Where the gRPC control service listens, if it is enabled, see
+    /// [`crate::grpc`]
+    pub grpc: Option<SocketAddr>,
+    /// Where the HTTP admin API listens, if it is enabled, see
+    /// [`crate::http`]
+    pub http: Option<SocketAddr>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            cert: None,
+            key: None,
+            pipe: None,
+            max_clients: 16,
+            idle_timeout: Duration::from_secs(300),
+            grpc: None,
+            http: None,
+        }
+    }
+}
+
+impl ControlConfig {
+    /// Build the TLS settings of the control connection, if it uses
+    /// TLS
+    pub fn tls(&self) -> Result<Option<Arc<ServerConfig>>, String> {
+        let (cert, key) = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => return Err("control.cert and control.key must be set together".to_string()),
+        };
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(Iterator::collect)
+            .map_err(|e| format!("invalid certificate {}: {e}", cert.display()))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| format!("invalid key {}: {e}", key.display()))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("invalid certificate or key: {e}"))?;
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+/// What the fake router does
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatorConfig {
+    /// Time between two route updates
+    #[serde(deserialize_with = "duration")]
+    pub interval: Duration,
+    pub vrf_ids: Vec<u32>,
+    /// The VRF IDs, by name, see [`crate::vrfs`]
+    pub vrf_names: BTreeMap<String, u32>,
+    /// The prefixes of the routes, IPv4 or IPv6
+    pub prefixes: Vec<IpNetwork>,
+    /// The next hops of the routes. Those of the address family of the
+    /// prefix are picked, if there are any.
+    pub next_hops: Vec<IpAddr>,
+    /// The ASNs the AS paths of the routes are made of
+    pub asns: Vec<u32>,
+    /// The VRFs, with their own pools and mix of route updates. When
+    /// there are none, those of `vrf_ids` are updated evenly, with the
+    /// pools above.
+    pub vrfs: Vec<VrfConfig>,
+    /// Seed of the random number generator, if the updates must be
+    /// the same from one run to the next. Otherwise, the RIB picks one
+    /// and logs it.
+    pub seed: Option<u64>,
+    /// The scenario replayed rather than generating random route
+    /// updates, see [`crate::scenario`]
+    pub scenario: Option<PathBuf>,
+    /// Whether the scenario starts over at the end
+    pub scenario_loop: bool,
+    /// How much faster than written the scenario is replayed
+    pub speed: f64,
+    /// How many route updates can wait for BGP
+    pub capacity: usize,
+    /// What to do with a route update when that many are waiting
+    pub overflow: Overflow,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            vrf_ids: vec![0, 1, 2, 3],
+            vrf_names: BTreeMap::from([
+                ("mgmt".to_string(), 0),
+                ("cust-a".to_string(), 1),
+                ("cust-b".to_string(), 2),
+                ("lab".to_string(), 3),
+            ]),
+            prefixes: vec![
+                "1.0.0.0/8".parse().unwrap(),
+                "192.168.1.1/32".parse().unwrap(),
+                "10.10.1.0/24".parse().unwrap(),
+                "3.3.240.0/16".parse().unwrap(),
+                "2001:db8:10::/48".parse().unwrap(),
+                "2001:db8:a0::/44".parse().unwrap(),
+            ],
+            next_hops: vec![
+                "1.1.1.1".parse().unwrap(),
+                "11.22.33.44".parse().unwrap(),
+                "10.10.10.10".parse().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+                "fe80::1".parse().unwrap(),
+            ],
+            asns: vec![65001, 65002, 65003, 64512, 3356, 174],
+            vrfs: Vec::new(),
+            seed: None,
+            scenario: None,
+            scenario_loop: false,
+            speed: 1.0,
+            capacity: 64,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+impl SimulatorConfig {
+    /// The VRFs updated, with their pools filled in
+    pub fn topology(&self) -> Vec<VrfConfig> {
+        let vrfs = if self.vrfs.is_empty() {
+            self.vrf_ids
+                .iter()
+                .map(|id| VrfConfig {
+                    id: *id,
+                    ..VrfConfig::default()
+                })
+                .collect()
+        } else {
+            self.vrfs.clone()
+        };
+        vrfs.into_iter()
+            .map(|mut vrf| {
+                if vrf.prefixes.is_empty() {
+                    vrf.prefixes = self.prefixes.clone();
+                }
+                if vrf.next_hops.is_empty() {
+                    vrf.next_hops = self.next_hops.clone();
+                }
+                vrf
+            })
+            .collect()
+    }
+
+    /// The VRF IDs by name, those of `vrf_names` and those of the VRFs
+    /// with a name
+    pub fn names(&self) -> BTreeMap<String, u32> {
+        let mut names = self.vrf_names.clone();
+        for vrf in &self.vrfs {
+            if let Some(name) = &vrf.name {
+                names.insert(name.clone(), vrf.id);
+            }
+        }
+        names
+    }
+}
+
+/// A VRF of the fake router
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VrfConfig {
+    pub id: u32,
+    /// The name of the VRF, see [`crate::vrfs`]
+    pub name: Option<String>,
+    /// The prefixes of its routes, those of the simulator if empty
+    pub prefixes: Vec<IpNetwork>,
+    /// The next hops of its routes, those of the simulator if empty
+    pub next_hops: Vec<IpAddr>,
+    /// How often the VRF is updated, relative to the others
+    pub weight: u32,
+    /// The fraction of its updates withdrawing a route, when there is
+    /// one to withdraw
+    pub withdraw: f64,
+}
+
+impl Default for VrfConfig {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            name: None,
+            prefixes: Vec::new(),
+            next_hops: Vec::new(),
+            weight: 1,
+            withdraw: 0.5,
+        }
+    }
+}
+
+/// When the files the records are printed to roll, see
+/// [`crate::rotate`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    /// The size past which a file rolls, if it does
+    #[serde(deserialize_with = "size")]
+    pub max_size: Option<u64>,
+    /// How many rotated generations are kept
+    pub keep: usize,
+    /// Whether the rotated generations are compressed with gzip
+    pub compress: bool,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            keep: 5,
+            compress: false,
+        }
+    }
+}
+
+/// Whether and how the records are sent to the systemd journal, see
+/// [`crate::journald`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournaldConfig {
+    pub enabled: bool,
+    /// The `SYSLOG_IDENTIFIER` of the entries, the name of the binary
+    /// if unset
+    pub identifier: Option<String>,
+    /// The prefix of the names of the event fields, none if empty
+    pub field_prefix: String,
+}
+
+impl Default for JournaldConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            identifier: None,
+            field_prefix: "F".to_string(),
+        }
+    }
+}
+
+/// Where and how the records are sent to syslog, see [`crate::syslog`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// The server, as `udp://<host>:<port>` or `unix://<path>`, none
+    /// if unset
+    pub address: Option<String>,
+    /// The facility of the messages, by name, e.g. `user` or `local0`
+    pub facility: String,
+    /// The APP-NAME of the messages
+    pub app_name: String,
+    /// Whether the span fields are sent as structured data
+    pub structured_data: bool,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            facility: "user".to_string(),
+            app_name: "loggingdemo".to_string(),
+            structured_data: false,
+        }
+    }
+}
+
+/// Where and how the records are sent to Graylog, see [`crate::gelf`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GelfConfig {
+    /// The GELF UDP input, as `<host>:<port>`, none if unset
+    pub address: Option<String>,
+    /// The size of the datagrams, past which the messages are split
+    /// into chunks
+    pub chunk_size: usize,
+}
+
+impl Default for GelfConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            chunk_size: 1420,
+        }
+    }
+}
+
+/// Where the spans are exported to over OTLP, see [`crate::otlp`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// The OTLP/HTTP traces endpoint of the collector, none if unset
+    pub endpoint: Option<String>,
+    /// The `service.name` of the exported spans
+    pub service_name: String,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "loggingdemo".to_string(),
+        }
+    }
+}
+
+/// Which fields the events are counted by, see [`crate::metrics`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The fields whose values the events are counted by
+    pub fields: Vec<String>,
+    /// The number of values counted for each field, past which the
+    /// events are counted under `__other__`
+    pub max_values: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            fields: Vec::new(),
+            max_values: 100,
+        }
+    }
+}
+
+/// Deserialize a size in bytes, either a number or written like
+/// `512KB`, `10MB` or `1GB`, the units being powers of 1024
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    let text = match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => return Ok(Some(bytes)),
+        Size::Text(text) => text,
+    };
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| D::Error::custom(format!("invalid size {text}")))?;
+    let unit = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(D::Error::custom(format!("invalid size unit in {text}"))),
+    };
+    Ok(Some(number * unit))
+}
+
+/// Deserialize a duration written like `1s` or `500ms`
+pub(crate) fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text).map_err(D::Error::custom)
+}
+
+impl Config {
+    /// Build the `EnvFilter`, with the level of the filter's own
+    /// logging on top of the directives. The panics always go through.
+    pub fn env_filter(&self) -> EnvFilter {
+        let filter = EnvFilter::new(self.env_filter.as_deref().unwrap_or_default());
+        let filter = console::add_directives(filter);
+        let filter = match format!("{}=error", panics::TARGET).parse() {
+            Ok(directive) => filter.add_directive(directive),
+            Err(_) => filter,
+        };
+        let own = self
+            .diagnostics
+            .as_deref()
+            .and_then(|level| loggers::parse_level(level).ok())
+            .and_then(|level| format!("{}={level}", diagnostics::TARGET).parse().ok());
+        match own {
+            Some(directive) => filter.add_directive(directive),
+            None => filter,
+        }
+    }
+
+    /// Gather the settings from the configuration file given with
+    /// `--config`, if any, then from the environment and from the
+    /// command-line arguments
+    pub fn from_args() -> Result<Self, String> {
+        let args = cli::args();
+        let mut config = match &args.config {
+            Some(path) => {
+                let mut config = Self::load(path)?;
+                config.set_source("config", Source::Cli);
+                config
+            }
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        args.apply(&mut config);
+        if let Some(directives) = &config.directives {
+            directive::parse_directives(directives)
+                .map_err(|e| format!("invalid directives {directives}: {e}"))?;
+        }
+        if let Some(level) = &config.diagnostics {
+            loggers::parse_level(level)
+                .map_err(|e| format!("invalid diagnostics level {level}: {e}"))?;
+        }
+        Ok(config)
+    }
+
+    /// Read a configuration file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let invalid = |e: toml::de::Error| format!("invalid {}: {e}", path.display());
+        let table: toml::Table = toml::from_str(&text).map_err(invalid)?;
+        let mut sources = BTreeMap::new();
+        set_sources(&mut sources, TOP_SETTINGS, &table);
+        if let Some(section) = table.get("rotation").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, ROTATION_SETTINGS, section);
+        }
+        if let Some(section) = table.get("journald").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, JOURNALD_SETTINGS, section);
+        }
+        if let Some(section) = table.get("syslog").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, SYSLOG_SETTINGS, section);
+        }
+        if let Some(section) = table.get("gelf").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, GELF_SETTINGS, section);
+        }
+        if let Some(section) = table.get("otlp").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, OTLP_SETTINGS, section);
+        }
+        if let Some(section) = table.get("metrics").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, METRICS_SETTINGS, section);
+        }
+        if let Some(section) = table.get("fmt").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, FMT_SETTINGS, section);
+        }
+        if let Some(section) = table.get("control").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, CONTROL_SETTINGS, section);
+        }
+        if let Some(section) = table.get("simulator").and_then(toml::Value::as_table) {
+            set_sources(&mut sources, SIMULATOR_SETTINGS, section);
+        }
+        let mut config: Self = table.try_into().map_err(invalid)?;
+        config.path = Some(path.to_path_buf());
+        config.sources = sources;
+        let simulator = &config.simulator;
+        if simulator.vrf_ids.is_empty()
+            || simulator.prefixes.is_empty()
+            || simulator.next_hops.is_empty()
+        {
+            return Err(format!(
+                "invalid {}: the simulator needs VRFs, prefixes and next hops",
+                path.display()
+            ));
+        }
+        if simulator.vrfs.iter().all(|vrf| vrf.weight == 0) && !simulator.vrfs.is_empty() {
+            return Err(format!(
+                "invalid {}: the simulator needs a VRF with a weight",
+                path.display()
+            ));
+        }
+        if simulator
+            .vrfs
+            .iter()
+            .any(|vrf| !(0.0..=1.0).contains(&vrf.withdraw))
+        {
+            return Err(format!(
+                "invalid {}: the withdrawals of a VRF must be between 0 and 1",
+                path.display()
+            ));
+        }
+        if !(simulator.speed > 0.0 && simulator.speed.is_finite()) {
+            return Err(format!(
+                "invalid {}: the scenario speed must be positive",
+                path.display()
+            ));
+        }
+        if simulator.capacity == 0 {
+            return Err(format!(
+                "invalid {}: the simulator needs room for a route update",
+                path.display()
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Apply the environment variables
+    fn apply_env(&mut self) -> Result<(), String> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+        if let Some(directives) = var(EnvFilter::DEFAULT_ENV) {
+            self.env_filter = Some(directives);
+            self.set_source("env_filter", Source::Env);
+        }
+        if let Some(rules) = var(DynamicFieldFilter::DEFAULT_ENV) {
+            self.filters
+                .extend(rules.split(',').map(|rule| rule.trim().to_string()));
+            self.set_source("filters", Source::Env);
+        }
+        if let Some(listen) = var("CONTROL_LISTEN") {
+            self.listen = listen
+                .parse()
+                .map_err(|e| format!("invalid CONTROL_LISTEN {listen}: {e}"))?;
+            self.set_source("listen", Source::Env);
+        }
+        if let Some(token) = var("CONTROL_TOKEN") {
+            self.control.token = Some(token);
+            self.set_source("control.token", Source::Env);
+        }
+        if let Some(format) = var("LOG_FORMAT") {
+            self.fmt.format = Format::from_str(&format, true)
+                .map_err(|e| format!("invalid LOG_FORMAT {format}: {e}"))?;
+            self.set_source("fmt.format", Source::Env);
+        }
+        if let Some(seed) = var("SIM_SEED") {
+            self.simulator.seed = Some(
+                seed.parse()
+                    .map_err(|e| format!("invalid SIM_SEED {seed}: {e}"))?,
+            );
+            self.set_source("simulator.seed", Source::Env);
+        }
+        if let Some(rate) = var("SIM_RATE") {
+            let rate = cli::parse_rate(&rate).map_err(|e| format!("SIM_RATE: {e}"))?;
+            self.simulator.interval = Duration::from_secs_f64(1.0 / rate);
+            self.set_source("simulator.interval", Source::Env);
+        }
+        Ok(())
+    }
+
+    /// Record where a setting comes from
+    pub fn set_source(&mut self, setting: &'static str, source: Source) {
+        self.sources.insert(setting, source);
+    }
+
+    /// Return where a setting comes from
+    pub fn source(&self, setting: &str) -> Source {
+        self.sources
+            .get(setting)
+            .copied()
+            .unwrap_or(Source::Default)
+    }
+
+    /// Change the `EnvFilter` directives from a control command
+    pub fn set_runtime_env_filter(&mut self, directives: String) {
+        self.env_filter = Some(directives);
+        self.set_source("env_filter", Source::Runtime);
+    }
+
+    /// Turn a display option of the fmt layer on or off from a
+    /// control command
+    pub fn set_runtime_display(&mut self, option: &str, on: bool) -> Result<(), String> {
+        let setting = match option {
+            "ansi" => {
+                self.fmt.ansi = on;
+                "fmt.ansi"
+            }
+            "line_numbers" => {
+                self.fmt.line_numbers = on;
+                "fmt.line_numbers"
+            }
+            "span_path" => {
+                self.fmt.span_path = on;
+                "fmt.span_path"
+            }
+            "targets" => {
+                self.fmt.targets = on;
+                "fmt.targets"
+            }
+            "timestamps" => {
+                self.fmt.timestamps = on;
+                "fmt.timestamps"
+            }
+            _ => {
+                return Err(format!(
+                    "unknown display option {option}, expected one of {}",
+                    DISPLAY_OPTIONS.join(", ")
+                ))
+            }
+        };
+        self.set_source(setting, Source::Runtime);
+        Ok(())
+    }
+
+    /// Change the routes from a control command
+    pub fn set_runtime_routes(&mut self, routes: BTreeMap<String, PathBuf>) {
+        self.routes = routes;
+        self.set_source("routes", Source::Runtime);
+    }
+
+    /// Change the output format from a control command
+    pub fn set_runtime_format(&mut self, format: Format) {
+        self.fmt.format = format;
+        self.set_source("fmt.format", Source::Runtime);
+    }
+
+    /// Drop the `EnvFilter` directives set at runtime, and go back to
+    /// those of the other sources
+    pub fn reset_env_filter(&mut self) -> Result<(), String> {
+        let fresh = Self::from_args()?;
+        self.set_source("env_filter", fresh.source("env_filter"));
+        self.env_filter = fresh.env_filter;
+        Ok(())
+    }
+
+    /// Keep the settings that were changed at runtime in `old`, since
+    /// they take precedence over everything else
+    pub fn keep_runtime_settings(&mut self, old: &Config) {
+        if old.source("env_filter") == Source::Runtime {
+            self.env_filter = old.env_filter.clone();
+            self.set_source("env_filter", Source::Runtime);
+        }
+        if old.source("routes") == Source::Runtime {
+            self.routes = old.routes.clone();
+            self.set_source("routes", Source::Runtime);
+        }
+        for option in DISPLAY_OPTIONS {
+            if old.source(&format!("fmt.{option}")) == Source::Runtime {
+                if let Some(on) = old.fmt.display(option) {
+                    let _ = self.set_runtime_display(option, on);
+                }
+            }
+        }
+        if old.source("fmt.format") == Source::Runtime {
+            self.fmt.format = old.fmt.format;
+            self.set_source("fmt.format", Source::Runtime);
+        }
+    }
+
+    /// Describe the effective settings, along with their source
+    pub fn show(&self) -> String {
+        let mut out = String::new();
+        let mut line = |setting: &str, value: String| {
+            let _ = writeln!(out, "{setting} = {value} ({})", self.source(setting));
+        };
+        let list = |values: Vec<String>| format!("[{}]", values.join(", "));
+        if let Some(path) = &self.path {
+            line("config", path.display().to_string());
+        }
+        line("listen", self.listen.to_string());
+        line("env_filter", self.env_filter.clone().unwrap_or_default());
+        line("filters", list(self.filters.clone()));
+        line(
+            "directives",
+            self.directives
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line(
+            "diagnostics",
+            self.diagnostics
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line("enrich", self.enrich.to_string());
+        line(
+            "filter",
+            self.filter
+                .as_ref()
+                .map_or_else(|| "none".to_string(), Profile::to_string),
+        );
+        line(
+            "routes",
+            list(
+                self.routes
+                    .iter()
+                    .map(|(target, path)| format!("{target}: {}", path.display()))
+                    .collect(),
+            ),
+        );
+        line(
+            "rotation.max_size",
+            self.rotation
+                .max_size
+                .map_or_else(|| "none".to_string(), |size| size.to_string()),
+        );
+        line("rotation.keep", self.rotation.keep.to_string());
+        line("rotation.compress", self.rotation.compress.to_string());
+        line("journald.enabled", self.journald.enabled.to_string());
+        line(
+            "journald.identifier",
+            self.journald
+                .identifier
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line("journald.field_prefix", self.journald.field_prefix.clone());
+        line(
+            "syslog.address",
+            self.syslog
+                .address
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line("syslog.facility", self.syslog.facility.clone());
+        line("syslog.app_name", self.syslog.app_name.clone());
+        line(
+            "syslog.structured_data",
+            self.syslog.structured_data.to_string(),
+        );
+        line(
+            "gelf.address",
+            self.gelf
+                .address
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line("gelf.chunk_size", self.gelf.chunk_size.to_string());
+        line(
+            "otlp.endpoint",
+            self.otlp
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line("otlp.service_name", self.otlp.service_name.clone());
+        line("metrics.fields", list(self.metrics.fields.clone()));
+        line("metrics.max_values", self.metrics.max_values.to_string());
+        line("fmt.ansi", self.fmt.ansi.to_string());
+        line("fmt.line_numbers", self.fmt.line_numbers.to_string());
+        line("fmt.span_path", self.fmt.span_path.to_string());
+        line("fmt.targets", self.fmt.targets.to_string());
+        line("fmt.timestamps", self.fmt.timestamps.to_string());
+        line("fmt.format", self.fmt.format.to_string());
+        line(
+            "fmt.tee",
+            self.fmt
+                .tee
+                .as_ref()
+                .map_or_else(|| "none".to_string(), |path| path.display().to_string()),
+        );
+        line("fmt.tee_ansi", self.fmt.tee_ansi.to_string());
+        line("fmt.color_by", self.fmt.color_by.clone());
+        line(
+            "fmt.palette",
+            list(self.fmt.palette.iter().map(u8::to_string).collect()),
+        );
+        line(
+            "fmt.colors",
+            list(
+                self.fmt
+                    .colors
+                    .iter()
+                    .map(|(value, color)| format!("{value}: {color}"))
+                    .collect(),
+            ),
+        );
+        // The token itself is a secret
+        line(
+            "control.token",
+            match self.control.token {
+                Some(_) => "<hidden>",
+                None => "none",
+            }
+            .to_string(),
+        );
+        let path = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map_or_else(|| "none".to_string(), |path| path.display().to_string())
+        };
+        line("control.cert", path(&self.control.cert));
+        line("control.key", path(&self.control.key));
+        line(
+            "control.pipe",
+            self.control
+                .pipe
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+        );
+        line("control.max_clients", self.control.max_clients.to_string());
+        line(
+            "control.idle_timeout",
+            humantime::format_duration(self.control.idle_timeout).to_string(),
+        );
+        line(
+            "control.grpc",
+            self.control
+                .grpc
+                .map_or_else(|| "none".to_string(), |grpc| grpc.to_string()),
+        );
+        line(
+            "control.http",
+            self.control
+                .http
+                .map_or_else(|| "none".to_string(), |http| http.to_string()),
+        );
+        let simulator = &self.simulator;
+        line(
+            "simulator.interval",
+            humantime::format_duration(simulator.interval).to_string(),
+        );
+        line(
+            "simulator.vrf_ids",
+            list(simulator.vrf_ids.iter().map(u32::to_string).collect()),
+        );
+        line(
+            "simulator.vrf_names",
+            list(
+                simulator
+                    .vrf_names
+                    .iter()
+                    .map(|(name, id)| format!("{name}={id}"))
+                    .collect(),
+            ),
+        );
+        line(
+            "simulator.prefixes",
+            list(
+                simulator
+                    .prefixes
+                    .iter()
+                    .map(IpNetwork::to_string)
+                    .collect(),
+            ),
+        );
+        line(
+            "simulator.next_hops",
+            list(simulator.next_hops.iter().map(IpAddr::to_string).collect()),
+        );
+        line(
+            "simulator.asns",
+            list(simulator.asns.iter().map(u32::to_string).collect()),
+        );
+        line(
+            "simulator.vrfs",
+            list(
+                simulator
+                    .vrfs
+                    .iter()
+                    .map(|vrf| {
+                        format!(
+                            "{}{} (weight {}, withdraw {}, {} prefixes, {} next hops)",
+                            vrf.id,
+                            vrf.name
+                                .as_ref()
+                                .map(|name| format!(" {name}"))
+                                .unwrap_or_default(),
+                            vrf.weight,
+                            vrf.withdraw,
+                            vrf.prefixes.len(),
+                            vrf.next_hops.len()
+                        )
+                    })
+                    .collect(),
+            ),
+        );
+        line(
+            "simulator.seed",
+            simulator
+                .seed
+                .map_or_else(|| "none".to_string(), |seed| seed.to_string()),
+        );
+        line(
+            "simulator.scenario",
+            simulator
+                .scenario
+                .as_ref()
+                .map_or_else(|| "none".to_string(), |path| path.display().to_string()),
+        );
+        line(
+            "simulator.scenario_loop",
+            simulator.scenario_loop.to_string(),
+        );
+        line("simulator.speed", simulator.speed.to_string());
+        line("simulator.capacity", simulator.capacity.to_string());
+        line("simulator.overflow", simulator.overflow.to_string());
+        out
+    }
+}
+
+/// The settings at the top of the configuration file, and in its
+/// sections
+const TOP_SETTINGS: &[&str] = &[
+    "listen",
+    "env_filter",
+    "filters",
+    "directives",
+    "diagnostics",
+    "enrich",
+    "filter",
+    "routes",
+];
+const ROTATION_SETTINGS: &[&str] = &["rotation.max_size", "rotation.keep", "rotation.compress"];
+const JOURNALD_SETTINGS: &[&str] = &[
+    "journald.enabled",
+    "journald.identifier",
+    "journald.field_prefix",
+];
+const SYSLOG_SETTINGS: &[&str] = &[
+    "syslog.address",
+    "syslog.facility",
+    "syslog.app_name",
+    "syslog.structured_data",
+];
+const GELF_SETTINGS: &[&str] = &["gelf.address", "gelf.chunk_size"];
+const OTLP_SETTINGS: &[&str] = &["otlp.endpoint", "otlp.service_name"];
+const METRICS_SETTINGS: &[&str] = &["metrics.fields", "metrics.max_values"];
+const FMT_SETTINGS: &[&str] = &[
+    "fmt.ansi",
+    "fmt.line_numbers",
+    "fmt.span_path",
+    "fmt.targets",
+    "fmt.timestamps",
+    "fmt.format",
+    "fmt.tee",
+    "fmt.tee_ansi",
+    "fmt.color_by",
+    "fmt.palette",
+    "fmt.colors",
+];
+const CONTROL_SETTINGS: &[&str] = &[
+    "control.token",
+    "control.cert",
+    "control.key",
+    "control.pipe",
+    "control.max_clients",
+    "control.idle_timeout",
+    "control.grpc",
+    "control.http",
+];
+const SIMULATOR_SETTINGS: &[&str] = &[
+    "simulator.interval",
+    "simulator.vrf_ids",
+    "simulator.vrf_names",
+    "simulator.prefixes",
+    "simulator.next_hops",
+    "simulator.asns",
+    "simulator.vrfs",
+    "simulator.seed",
+    "simulator.scenario",
+    "simulator.scenario_loop",
+    "simulator.speed",
+    "simulator.capacity",
+    "simulator.overflow",
+];
+
+/// Record the settings of a table of the configuration file as coming
+/// from the file
+fn set_sources(
+    sources: &mut BTreeMap<&'static str, Source>,
+    settings: &[&'static str],
+    table: &toml::Table,
+) {
+    for setting in settings {
+        let key = setting.rsplit('.').next().unwrap_or(setting);
+        if table.contains_key(key) {
+            sources.insert(setting, Source::File);
+        }
+    }
+}
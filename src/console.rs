@@ -0,0 +1,80 @@
+//! Task diagnostics for tokio-console, with the `console` feature.
+//!
+//! The console layer is composed into the same registry as the other
+//! layers. It sees tokio's own spans and events, of the `tokio` and
+//! `runtime` targets, at the TRACE level, and only those: it has its
+//! own filter. The env filter lets them through, since it filters for
+//! all the layers, and the layers writing the logs filter them out in
+//! turn, see [`hide_tasks`], so that the logs read the same with or
+//! without the console.
+//!
+//! tokio only instruments its tasks when built with `tokio_unstable`:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg tokio_unstable" cargo run --features console
+//! tokio-console
+//! ```
+
+use tracing::Metadata;
+use tracing::Subscriber;
+use tracing_subscriber::filter;
+use tracing_subscriber::filter::FilterFn;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+
+/// The directives letting tokio's instrumentation through the env
+/// filter
+#[cfg(feature = "console")]
+const DIRECTIVES: [&str; 2] = ["tokio=trace", "runtime=trace"];
+
+/// The console layer, which serves the diagnostics on
+/// `127.0.0.1:6669`, or on `TOKIO_CONSOLE_BIND`
+#[cfg(feature = "console")]
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    console_subscriber::spawn()
+}
+
+/// Nothing, without the `console` feature
+#[cfg(not(feature = "console"))]
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_subscriber::layer::Identity::new()
+}
+
+/// Let tokio's instrumentation through an env filter, for the console
+#[cfg(feature = "console")]
+pub fn add_directives(filter: EnvFilter) -> EnvFilter {
+    DIRECTIVES
+        .iter()
+        .filter_map(|directive| directive.parse().ok())
+        .fold(filter, EnvFilter::add_directive)
+}
+
+/// Leave an env filter as it is, without the `console` feature
+#[cfg(not(feature = "console"))]
+pub fn add_directives(filter: EnvFilter) -> EnvFilter {
+    filter
+}
+
+/// A per-layer filter for the layers writing the logs, hiding tokio's
+/// instrumentation. Without the `console` feature, there is none to
+/// hide.
+pub fn hide_tasks() -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+    filter::filter_fn(|metadata| !is_task_instrumentation(metadata))
+}
+
+/// Return `true` for the spans and events of tokio's instrumentation
+fn is_task_instrumentation(metadata: &Metadata<'_>) -> bool {
+    let target = metadata.target();
+    ["tokio", "runtime"].iter().any(|prefix| {
+        target
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    })
+}
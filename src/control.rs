@@ -0,0 +1,2472 @@
+//! The TCP control connection, used to change the filters at runtime.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use dynamic_field_filter::diagnostics;
+use dynamic_field_filter::directive;
+use dynamic_field_filter::filter::Budget;
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use dynamic_field_filter::filter::FilterMode;
+use dynamic_field_filter::filter::Profile;
+use dynamic_field_filter::filter::RuleOptions;
+use dynamic_field_filter::filter::SavedFilters;
+use dynamic_field_filter::filter::SpanTracking;
+use dynamic_field_filter::loggers;
+use dynamic_field_filter::loggers::LoggerLevel;
+use dynamic_field_filter::matcher;
+use dynamic_field_filter::matcher::Matcher;
+use dynamic_field_filter::protocol::Request;
+use dynamic_field_filter::protocol::Response;
+use dynamic_field_filter::quarantine;
+use dynamic_field_filter::quarantine::Quarantine;
+use dynamic_field_filter::redact;
+use dynamic_field_filter::redact::Redaction;
+use dynamic_field_filter::stats::StatsReporter;
+use dynamic_field_filter::window;
+use ipnetwork::IpNetwork;
+use rustls::ServerConfig;
+use rustls::ServerConnection;
+use rustls::StreamOwned;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time;
+use tracing::Id;
+use tracing_subscriber::filter::Directive;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Registry;
+
+use crate::audit;
+use crate::broadcast;
+use crate::broadcast::ClientId;
+use crate::chrome;
+use crate::config::Config;
+use crate::config::FmtConfig;
+use crate::config::FmtLayer;
+use crate::config::Format;
+use crate::config::DISPLAY_OPTIONS;
+use crate::flame;
+use crate::inspect;
+use crate::interactive;
+use crate::live;
+use crate::live::SessionFilters;
+use crate::metrics;
+use crate::nonblocking;
+use crate::persist;
+use crate::retry;
+use crate::router;
+use crate::router::AsPath;
+use crate::router::PathAttributes;
+use crate::router::RouteUpdate;
+use crate::routes;
+use crate::shutdown;
+use crate::siem;
+use crate::siem::SiemEvent;
+use crate::sink::ShardedSink;
+use crate::vrfs;
+
+/// The version of the control protocol, advertised by `HELLO`. This
+/// must change whenever a command changes in a backward incompatible
+/// way.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// A command of the text protocol, as described by `HELP`
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// The arguments, as in the usage of the command
+    pub args: &'static str,
+    pub description: &'static str,
+}
+
+impl CommandSpec {
+    const fn new(name: &'static str, args: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            args,
+            description,
+        }
+    }
+
+    /// The usage of the command, e.g. `UNFILTER <field>`
+    pub fn usage(&self) -> String {
+        format!("{} {}", self.name, self.args)
+            .trim_end()
+            .to_string()
+    }
+}
+
+/// The commands, as advertised by `HELLO` and described by `HELP`, in
+/// alphabetical order. Every command must be declared here.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new("ABORT", "", "Discard the changes staged since BEGIN"),
+    CommandSpec::new(
+        "ADDROUTE",
+        "<vrf> <prefix> <next-hop> [<asn>,...]",
+        "Have the simulated RIB send a route to BGP, the VRF being given by ID or by name, with \
+         the AS path given if any",
+    ),
+    CommandSpec::new(
+        "AUTH",
+        "<token>",
+        "Authenticate, to be allowed to change anything when a token is configured",
+    ),
+    CommandSpec::new(
+        "BEGIN",
+        "",
+        "Stage the following filter changes, to apply them all at once with COMMIT",
+    ),
+    CommandSpec::new("CLEAR", "", "Remove all the filters"),
+    CommandSpec::new("COMMIT", "", "Apply the changes staged since BEGIN"),
+    CommandSpec::new(
+        "CONFIG",
+        "SHOW",
+        "Print the effective settings, and where they come from",
+    ),
+    CommandSpec::new(
+        "DEDUP",
+        "<window-secs>|OFF",
+        "Collapse identical consecutive events of a callsite",
+    ),
+    CommandSpec::new(
+        "DELROUTE",
+        "<vrf> <prefix> [<next-hop>]",
+        "Have the simulated RIB withdraw a route from BGP, the VRF being given by ID or by name, \
+         or only a next hop of the route",
+    ),
+    CommandSpec::new(
+        "DIRECTIVE",
+        "<directive>,...",
+        "Set target levels and filter rules with EnvFilter-style directives, e.g. \
+         router::bgp[add_path{vrf_id=1}]=off,info",
+    ),
+    CommandSpec::new(
+        "DISPLAY",
+        "[ansi|line_numbers|span_path|targets|timestamps on|off]",
+        "Turn a display option of the output on or off, or show them",
+    ),
+    CommandSpec::new(
+        "DRYRUN",
+        "on|off",
+        "Evaluate the rules without suppressing anything",
+    ),
+    CommandSpec::new("DUMP", "[n]", "Print the last suppressed events"),
+    CommandSpec::new(
+        "EXPLAIN",
+        "on|off",
+        "Report why each span and event is suppressed, under the filtering::explain target",
+    ),
+    CommandSpec::new(
+        "FILTER",
+        "[@thread=<name>] <field>=<value>|<field> <op> <duration>|<field> contains <text>|\
+         <field> within <network> [LIMIT <n>|CAPTURE <n>] [TTL <secs>] [BETWEEN <start> <end>|\
+         DAILY <HH:MM>-<HH:MM>] [SAMPLE <rate>] [TARGET <target>] [SPAN <name>]",
+        "Filter on any field, by value, by duration, by part of its text or by network, e.g. \
+         FILTER busy_us > 5ms, FILTER MESSAGE contains New path, MESSAGE being the message of the \
+         events, or FILTER prefix within 2001:db8::/32. LIMIT \
+         suppresses n matches then expires, CAPTURE keeps n matches then suppresses, SAMPLE keeps \
+         that fraction of the matching spans, TARGET and SPAN restrict the rule to a target and \
+         to the spans with a name, @thread to the spans and events created on a thread",
+    ),
+    CommandSpec::new(
+        "FLAME",
+        "START / FLAME STOP <path>",
+        "Record the time spent in the spans, and write it as folded stacks for a flamegraph",
+    ),
+    CommandSpec::new(
+        "FORMAT",
+        "[compact|pretty|json|tree]",
+        "Change how the records are printed, e.g. to JSON to pipe them into jq, or show it",
+    ),
+    CommandSpec::new(
+        "HELLO",
+        "",
+        "Describe the protocol, so that client tools can tell which features are available",
+    ),
+    CommandSpec::new(
+        "HELP",
+        "[command]",
+        "List the commands, or describe one of them",
+    ),
+    CommandSpec::new(
+        "INTERACTIVE",
+        "[OFF]",
+        "Prompt for the commands and explain the errors, for netcat and telnet",
+    ),
+    CommandSpec::new(
+        "JSON",
+        "",
+        "Switch to JSON requests and responses, one per line",
+    ),
+    CommandSpec::new(
+        "LEVEL",
+        "[<directive>|CLEAR]",
+        "Change the level and target filtering with RUST_LOG directives, or show the current \
+         directives",
+    ),
+    CommandSpec::new(
+        "LIST",
+        "[CALLSITES]",
+        "List the filters and muted callsites, or the callsites",
+    ),
+    CommandSpec::new("LOAD", "[path]", "Replace the filters with those of a file"),
+    CommandSpec::new(
+        "LOGGING",
+        "[<name>=<level>...|level=<level>|paths=<name>:<level>,...|RESET]",
+        "Set logger levels, like the dynamic logging endpoints of proxies, or list them",
+    ),
+    CommandSpec::new(
+        "MUTE",
+        "CALLSITE <n> [ttl-secs]",
+        "Disable a callsite entirely, optionally for a limited time",
+    ),
+    CommandSpec::new(
+        "PING",
+        "",
+        "Check that the server is alive, it replies PONG and its uptime in seconds",
+    ),
+    CommandSpec::new(
+        "PROFILE",
+        "SAVE|LOAD <name> / PROFILE LIST",
+        "Switch between named sets of rules, shadow rules, logger levels and rate limits",
+    ),
+    CommandSpec::new(
+        "QUARANTINE",
+        "[FILE <path>|MEMORY [lines]|OFF]",
+        "Keep the suppressed events instead of discarding them, or print those held in memory",
+    ),
+    CommandSpec::new("QUIT", "", "Close the connection"),
+    CommandSpec::new(
+        "RATE",
+        "[<target> <events-per-sec>|<target> OFF|RESET]",
+        "Limit the number of events per second of each callsite of a target, or list the rate \
+         limits",
+    ),
+    CommandSpec::new(
+        "REDACT",
+        "[<field> <mask>|<field> HASH|<field> OFF|CLEAR]",
+        "Mask or hash the values of a field in the output, or list the redacted fields",
+    ),
+    CommandSpec::new("REDO", "", "Apply the last undone change again"),
+    CommandSpec::new(
+        "ROUTE",
+        "[<target> <path>|<target> OFF]",
+        "Print the records of a target to a file rather than stdout, stop, or list the routes",
+    ),
+    CommandSpec::new("SAVE", "[path]", "Save the filters to a file"),
+    CommandSpec::new(
+        "SESSION",
+        "FILTER <rule> / SESSION UNFILTER <field> / SESSION CLEAR|LIST",
+        "Filter the events streamed to this connection only",
+    ),
+    CommandSpec::new(
+        "SHADOW",
+        "<rule>|CLEAR",
+        "Evaluate a candidate rule alongside the filters, without enforcing it",
+    ),
+    CommandSpec::new(
+        "SHOW",
+        "SPANS / SHOW SPAN <id>",
+        "List the live spans, tracked from the first SHOW SPANS on, or describe one of them",
+    ),
+    CommandSpec::new(
+        "SIM",
+        "[PAUSE|RESUME|RATE <updates-per-sec>]",
+        "Pause or resume the route updates of the simulator, change their rate, or show it",
+    ),
+    CommandSpec::new(
+        "SINK",
+        "[SHARD <field> <count> [dir]|OFF]",
+        "Shard the records across files, stop, or show the sharding",
+    ),
+    CommandSpec::new(
+        "SLOW",
+        "<duration>|OFF",
+        "Only emit the spans busy for longer than a threshold, along with their events",
+    ),
+    CommandSpec::new(
+        "STATS",
+        "[REPORT <interval-secs>|OFF]",
+        "Report the filter and output counters, or turn periodic reporting on or off",
+    ),
+    CommandSpec::new(
+        "SUBSCRIBE",
+        "[<field>=<value>...]",
+        "Tail the events whose fields, or those of their spans, have the given values, each \
+         after its sequence number, e.g. seq=42, a gap meaning that events were dropped",
+    ),
+    CommandSpec::new(
+        "TRACE",
+        "START <path> / TRACE STOP",
+        "Record the span timelines to a file in the Chrome trace event format, or finish it",
+    ),
+    CommandSpec::new(
+        "TRIGGER",
+        "on [depth]|off",
+        "Keep the events suppressed within each span, and print them when a WARN or ERROR event \
+         occurs in the span",
+    ),
+    CommandSpec::new(
+        "UNDO",
+        "",
+        "Revert the last change to the rules, shadow rules, logger levels and rate limits",
+    ),
+    CommandSpec::new("UNFILTER", "<field>", "Remove the filter on a field"),
+    CommandSpec::new("UNMUTE", "CALLSITE <n>", "Enable a muted callsite again"),
+    CommandSpec::new("UNSUBSCRIBE", "", "Stop tailing the events"),
+    CommandSpec::new(
+        "VRF",
+        "<id>|<name>",
+        "Filter on vrf_id, the VRF being given by ID or by name",
+    ),
+];
+
+/// When the program started, for the uptime reported by PING
+pub static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// The reply to QUIT, before the connection is closed
+const GOODBYE: &str = "BYE";
+
+/// The error of the commands that need AUTH first
+pub const NOT_AUTHENTICATED: &str = "not authenticated";
+
+/// The options of the FILTER command
+pub const RULE_OPTIONS: &[&str] = &[
+    "LIMIT", "CAPTURE", "TTL", "BETWEEN", "DAILY", "SAMPLE", "TARGET", "SPAN",
+];
+
+/// How often a connection waiting for a command checks for the
+/// notifications of the changes made by the other clients
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Default number of events kept per span in trigger mode
+const DEFAULT_TRIGGER_DEPTH: usize = 32;
+
+/// A change to the field filter, staged until the transaction it
+/// belongs to is committed
+type Change = Box<dyn FnOnce(&mut DynamicFieldFilter) + Send>;
+
+/// What to log and notify about a change, once it is made
+type Announcement = Box<dyn FnOnce() + Send>;
+
+/// A change staged in a transaction
+enum Staged {
+    Change(Change),
+    /// Replace the filter state with that of a file, which is read
+    /// at COMMIT
+    Load(PathBuf),
+}
+
+/// A transaction opened with BEGIN
+#[derive(Default)]
+struct Transaction {
+    /// The changes to the field filter, made at COMMIT
+    changes: Vec<Staged>,
+    /// What to log and notify, once the changes are made
+    announcements: Vec<Announcement>,
+    /// The fields with a rule, once the changes are made. The
+    /// commands are checked against this rather than the current
+    /// filters.
+    fields: BTreeSet<String>,
+    /// The saved profiles once the changes are made, and the fields
+    /// of their rules
+    profiles: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Transaction {
+    /// Start a transaction from the current state of the filter
+    fn new(layer: &DynamicFieldFilter) -> Self {
+        Self {
+            fields: layer
+                .filters()
+                .iter()
+                .map(|rule| rule.field.clone())
+                .collect(),
+            profiles: layer
+                .profiles()
+                .into_iter()
+                .map(|(name, profile)| (name.to_string(), profile_fields(profile)))
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Stage replacing the filter state with the given one
+    fn restore(&mut self, saved: &SavedFilters) {
+        self.fields = profile_fields(&saved.current);
+        self.profiles = saved
+            .profiles
+            .iter()
+            .map(|(name, profile)| (name.clone(), profile_fields(profile)))
+            .collect();
+    }
+}
+
+/// The fields of the rules of a profile
+fn profile_fields(profile: &Profile) -> BTreeSet<String> {
+    profile
+        .rules
+        .iter()
+        .map(|rule| rule.field.clone())
+        .collect()
+}
+
+/// The commands that cannot be part of a transaction: those that
+/// change something else than the field filter, and those that go
+/// through the undo history
+const UNSTAGED_COMMANDS: &[&str] = &[
+    "LEVEL", "FORMAT", "DISPLAY", "ROUTE", "REDACT", "SINK", "TRACE", "FLAME", "MUTE", "UNMUTE",
+    "UNDO", "REDO",
+];
+
+/// The number of control connections open, up to a maximum
+#[derive(Debug)]
+pub struct Clients {
+    open: AtomicUsize,
+    max: usize,
+}
+
+/// A place among the open control connections, given back when this
+/// is dropped
+#[derive(Debug)]
+pub struct ClientSlot(Arc<Clients>);
+
+impl Clients {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            open: AtomicUsize::new(0),
+            max,
+        })
+    }
+
+    /// Take a place for a new connection, unless the maximum number
+    /// of connections are already open
+    pub fn add(self: &Arc<Self>) -> Option<ClientSlot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| ClientSlot(self.clone()))
+    }
+
+    /// Wait until all the connections are closed, for at most
+    /// `timeout`. Return `false` if some are still open.
+    pub async fn wait_closed(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        while self.open.load(Ordering::Acquire) > 0 {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+        true
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A control connection. Reading it times out after
+/// [`POLL_INTERVAL`], so that the notifications and the shutdown are
+/// noticed while the client is quiet.
+pub enum Connection {
+    /// A plaintext TCP connection
+    Tcp(TcpStream),
+    /// A connection whose I/O blocks, over TLS or a named pipe. It is
+    /// read and written with [`task::block_in_place`], so that the
+    /// other tasks move to another worker meanwhile.
+    Blocking(Box<dyn BlockingStream>),
+}
+
+/// A stream whose I/O blocks
+pub trait BlockingStream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> BlockingStream for T {}
+
+impl Connection {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => match time::timeout(POLL_INTERVAL, stream.read(buf)).await {
+                Ok(result) => result,
+                Err(_) => Err(ErrorKind::TimedOut.into()),
+            },
+            Connection::Blocking(stream) => task::block_in_place(|| stream.read(buf)),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.write_all(buf).await,
+            Connection::Blocking(stream) => task::block_in_place(|| stream.write_all(buf)),
+        }
+    }
+}
+
+/// Run the commands of a TCP control connection in `session`, over
+/// TLS if `tls` is set
+pub async fn handle_tcp_client<S: 'static, T, U>(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    session: Session<S, T, U>,
+) {
+    let connection = match tls {
+        Some(tls) => match blocking_tls(stream, tls) {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("TLS connection failed ({e})");
+                return;
+            }
+        },
+        None => Connection::Tcp(stream),
+    };
+    handle_client(connection, session).await;
+}
+
+/// Set up TLS on a TCP connection. rustls only works on blocking
+/// streams, so the connection goes back to blocking mode, with a read
+/// timeout. The handshake happens on the first read.
+fn blocking_tls(stream: TcpStream, tls: Arc<ServerConfig>) -> io::Result<Connection> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let connection = ServerConnection::new(tls).map_err(io::Error::other)?;
+    Ok(Connection::Blocking(Box::new(StreamOwned::new(
+        connection, stream,
+    ))))
+}
+
+/// Run the commands of a control connection in `session`, whatever
+/// its transport, until it is closed
+pub async fn handle_client<S: 'static, T, U>(
+    mut stream: Connection,
+    mut session: Session<S, T, U>,
+) {
+    let notifications = broadcast::subscribe(session.client);
+    let idle_timeout = session.config.read().unwrap().control.idle_timeout;
+    let mut last_command = Instant::now();
+    // Whether the connection is in JSON mode, see [`dynamic_field_filter::protocol`]
+    let mut json = false;
+    // The command being typed, if the connection is in interactive
+    // mode, see [`crate::interactive`]
+    let mut interactive: Option<interactive::Input> = None;
+    // What was read of the next line
+    let mut pending = String::new();
+    // The events the client subscribed to, if any
+    let mut events: Option<mpsc::Receiver<live::StreamedEvent>> = None;
+    // The filters of the client's own stream of events
+    let session_filters = SessionFilters::default();
+    'connection: loop {
+        let mut interrupted = false;
+        let pending_notifications: Vec<_> = notifications.pending().collect();
+        for notification in pending_notifications {
+            let _ = if json {
+                stream
+                    .write_all(Response::Notify(notification).to_line().as_bytes())
+                    .await
+            } else {
+                stream
+                    .write_all(format!("{notification}\n").as_bytes())
+                    .await
+            };
+            interrupted = true;
+        }
+        while let Some(Ok(live::StreamedEvent { seq, line })) =
+            events.as_mut().map(mpsc::Receiver::try_recv)
+        {
+            let _ = if json {
+                stream
+                    .write_all(Response::Event { seq, line }.to_line().as_bytes())
+                    .await
+            } else {
+                stream
+                    .write_all(format!("seq={seq} {line}\n").as_bytes())
+                    .await
+            };
+            interrupted = true;
+        }
+        // Prompt again after what was printed over the prompt
+        if interrupted && interactive.is_some() {
+            let _ = stream.write_all(interactive::PROMPT.as_bytes()).await;
+        }
+        let mut read_buf = [0_u8; 1024];
+        match stream.read(&mut read_buf[..]).await {
+            Ok(0) => {
+                info!("control connection closed");
+                break;
+            }
+            Ok(n) => {
+                last_command = Instant::now();
+                pending.push_str(&String::from_utf8_lossy(&read_buf[..n]));
+                // In text mode, what was read is a command even
+                // without a final newline, for clients that don't
+                // send any
+                let complete = if json || interactive.is_some() {
+                    pending.rfind('\n').map_or(0, |end| end + 1)
+                } else {
+                    pending.len()
+                };
+                let lines: String = pending.drain(..complete).collect();
+                for line in lines.lines() {
+                    let command = match (json, line.trim()) {
+                        (false, "JSON") => {
+                            json = true;
+                            interactive = None;
+                            session.record("JSON", &Ok(String::new()));
+                            let ok = Response::from(Ok(String::new()));
+                            let _ = stream.write_all(ok.to_line().as_bytes()).await;
+                            continue;
+                        }
+                        (false, _) => match interactive.as_mut() {
+                            Some(input) => match input.push(line) {
+                                Some(command) => command,
+                                None => {
+                                    let prompt = interactive::CONTINUATION_PROMPT;
+                                    let _ = stream.write_all(prompt.as_bytes()).await;
+                                    continue;
+                                }
+                            },
+                            None => line.to_string(),
+                        },
+                        (true, "") => continue,
+                        (true, _) => match serde_json::from_str::<Request>(line) {
+                            Ok(request) => match request.to_command() {
+                                Some(command) => command,
+                                None => {
+                                    json = false;
+                                    session.record(line.trim(), &Ok(String::new()));
+                                    let ok = Response::from(Ok(String::new()));
+                                    let _ = stream.write_all(ok.to_line().as_bytes()).await;
+                                    continue;
+                                }
+                            },
+                            Err(e) => {
+                                let error = Response::Error {
+                                    message: format!("invalid request: {e}"),
+                                };
+                                let _ = stream.write_all(error.to_line().as_bytes()).await;
+                                continue;
+                            }
+                        },
+                    };
+                    // Streaming the events is up to the connection,
+                    // the other commands are up to the session
+                    let mut words = command.split_whitespace();
+                    let handled = match words.next() {
+                        // Tail the events whose fields, or those of
+                        // their spans, have the given values:
+                        // SUBSCRIBE [<field>=<value>...] / UNSUBSCRIBE
+                        Some("SUBSCRIBE") => Some(parse_query(words).map(|query| {
+                            info!("streaming events to {}", session.peer);
+                            events = Some(live::subscribe(query, session_filters.clone()));
+                            String::new()
+                        })),
+                        Some("UNSUBSCRIBE") => Some(match events.take() {
+                            Some(_) => Ok(String::new()),
+                            None => Err("not subscribed".to_string()),
+                        }),
+                        // Filter the events of this connection only:
+                        // SESSION FILTER <rule> / SESSION UNFILTER
+                        // <field> / SESSION CLEAR / SESSION LIST
+                        Some("SESSION") => Some(session_filter(words, &session_filters)),
+                        // Say goodbye and close the connection: QUIT
+                        Some("QUIT") => {
+                            session.record(command.trim(), &Ok(String::new()));
+                            let goodbye = if json {
+                                Response::from(Ok(GOODBYE.to_string())).to_line()
+                            } else {
+                                format!("{GOODBYE}\n")
+                            };
+                            let _ = stream.write_all(goodbye.as_bytes()).await;
+                            info!("control connection closed by the client");
+                            break 'connection;
+                        }
+                        // Prompt for the commands, and explain the
+                        // errors: INTERACTIVE / INTERACTIVE OFF
+                        Some("INTERACTIVE") if !json => Some(match words.next() {
+                            None => {
+                                interactive = Some(interactive::Input::default());
+                                Ok(interactive::WELCOME.to_string())
+                            }
+                            Some("OFF") => {
+                                interactive = None;
+                                Ok(String::new())
+                            }
+                            Some(_) => Err("usage: INTERACTIVE [OFF]".to_string()),
+                        }),
+                        _ => None,
+                    };
+                    let result = match handled {
+                        Some(result) => {
+                            session.record(command.trim(), &result);
+                            result
+                        }
+                        None => session.execute(&command),
+                    };
+                    let reply = match (json, result) {
+                        (true, result) => Response::from(result).to_line(),
+                        (false, Ok(reply)) => reply,
+                        (false, Err(e)) if interactive.is_some() => {
+                            interactive::explain(&command, &e)
+                        }
+                        (false, Err(e)) => format!("{e}\n"),
+                    };
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                    if interactive.is_some() {
+                        let _ = stream.write_all(interactive::PROMPT.as_bytes()).await;
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if shutdown::requested() {
+                    info!("control connection closed for the shutdown");
+                    break;
+                }
+                // A client tailing the events isn't idle
+                if !idle_timeout.is_zero()
+                    && events.is_none()
+                    && last_command.elapsed() >= idle_timeout
+                {
+                    info!("control connection closed after being idle");
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("control connection closed ({e})");
+                break;
+            }
+        }
+    }
+    if let Some(transaction) = session.staged {
+        let count = transaction.changes.len();
+        info!(target: diagnostics::TARGET, "transaction of {count} changes discarded");
+        rule_change(session.client, &session.peer, "abort", count.to_string());
+    }
+}
+
+/// The state of a control connection, and what its commands act on
+pub struct Session<S, T, U> {
+    peer: String,
+    /// Tells the client apart from those with the same peer name
+    client: ClientId,
+    layer_handle: Handle<DynamicFieldFilter, S>,
+    level_handle: Handle<EnvFilter, U>,
+    sink_handle: Handle<ShardedSink, T>,
+    fmt_handle: Handle<FmtLayer, Registry>,
+    reporter: Arc<StatsReporter>,
+    config: Arc<RwLock<Config>>,
+    /// The changes staged since BEGIN, if a transaction is open
+    staged: Option<Transaction>,
+    /// Whether the client gave the right token with AUTH
+    authenticated: bool,
+    /// Keeps the open spans tracked from the first SHOW SPANS on
+    span_tracking: Option<SpanTracking>,
+}
+
+impl<S: 'static, T, U> Session<S, T, U> {
+    pub fn new(
+        peer: String,
+        layer_handle: Handle<DynamicFieldFilter, S>,
+        level_handle: Handle<EnvFilter, U>,
+        sink_handle: Handle<ShardedSink, T>,
+        fmt_handle: Handle<FmtLayer, Registry>,
+        reporter: Arc<StatsReporter>,
+        config: Arc<RwLock<Config>>,
+    ) -> Self {
+        Self {
+            peer,
+            client: ClientId::unique(),
+            layer_handle,
+            level_handle,
+            sink_handle,
+            fmt_handle,
+            reporter,
+            config,
+            staged: None,
+            authenticated: false,
+            span_tracking: None,
+        }
+    }
+
+    /// Run a command, and return the reply to send back, or the error
+    /// to report. The accepted commands are recorded in the audit log.
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let command = line.split_whitespace().next();
+        if !self.authenticated
+            && command != Some("AUTH")
+            && !is_read_only(line)
+            && self.config.read().unwrap().control.token.is_some()
+        {
+            return Err(NOT_AUTHENTICATED.to_string());
+        }
+        // Keep the token out of the changelog and the audit log. The
+        // attempt itself is recorded by AUTH.
+        let text = match command {
+            Some("AUTH") => "AUTH <hidden>",
+            _ => {
+                siem::record(SiemEvent::AdminCommand {
+                    peer: &self.peer,
+                    command: line.trim(),
+                });
+                line.trim()
+            }
+        };
+        let reply = self.run(line)?;
+        if !text.is_empty() {
+            audit::record(&self.peer, text, self.filters_count());
+        }
+        Ok(reply)
+    }
+
+    /// Record a command handled by the connection rather than the
+    /// session, like those the session runs: in the changelog, and in
+    /// the audit log if it succeeded
+    pub fn record(&self, command: &str, result: &Result<String, String>) {
+        siem::record(SiemEvent::AdminCommand {
+            peer: &self.peer,
+            command,
+        });
+        if result.is_ok() {
+            audit::record(&self.peer, command, self.filters_count());
+        }
+    }
+
+    /// The number of filters, once the staged changes are made if a
+    /// transaction is open, for the audit log
+    fn filters_count(&self) -> usize {
+        match &self.staged {
+            Some(transaction) => transaction.fields.len(),
+            None => {
+                retry::read(&self.layer_handle, |layer| layer.filters().len()).unwrap_or_default()
+            }
+        }
+    }
+
+    /// Replace the filter state, as LOAD does with a file, for the
+    /// clients that send the state itself. The change is recorded like
+    /// a command.
+    pub fn restore(&mut self, saved: SavedFilters, source: &str) -> Result<(), String> {
+        if !self.authenticated && self.config.read().unwrap().control.token.is_some() {
+            return Err(NOT_AUTHENTICATED.to_string());
+        }
+        let command = format!("LOAD {source}");
+        siem::record(SiemEvent::AdminCommand {
+            peer: &self.peer,
+            command: &command,
+        });
+        if let Some(transaction) = &mut self.staged {
+            transaction.restore(&saved);
+        }
+        apply(&self.layer_handle, &mut self.staged, move |layer| {
+            layer.restore(saved)
+        })?;
+        let source = source.to_string();
+        announce(
+            &mut self.staged,
+            self.client,
+            &self.peer,
+            move |client, peer| {
+                info!(target: diagnostics::TARGET, "filters loaded from {source}");
+                rule_change(client, peer, "load_filters", source);
+            },
+        );
+        audit::record(&self.peer, &command, self.filters_count());
+        Ok(())
+    }
+
+    fn run(&mut self, line: &str) -> Result<String, String> {
+        let Self {
+            peer,
+            client,
+            layer_handle,
+            level_handle,
+            sink_handle,
+            fmt_handle,
+            reporter,
+            config,
+            staged,
+            authenticated,
+            span_tracking,
+        } = self;
+        let mut words = line.split_whitespace();
+        let command = words.next();
+        if staged.is_some() && command.is_some_and(|c| UNSTAGED_COMMANDS.contains(&c)) {
+            return Err("not allowed in a transaction".to_string());
+        }
+        match command {
+            // Describe the protocol, so that client tools can
+            // tell which features are available: HELLO
+            Some("HELLO") => {
+                let auth = match config.read().unwrap().control.token {
+                    Some(_) if !*authenticated => "required",
+                    _ => "no",
+                };
+                let mut reply = String::new();
+                let _ = writeln!(reply, "HELLO loggingdemo {}", env!("CARGO_PKG_VERSION"));
+                let _ = writeln!(reply, "protocol {PROTOCOL_VERSION}");
+                let _ = writeln!(reply, "auth {auth}");
+                let names: Vec<&str> = COMMANDS.iter().map(|command| command.name).collect();
+                let _ = writeln!(reply, "commands {}", names.join(" "));
+                let _ = writeln!(reply, "matchers {}", matcher::MATCHERS.join(" "));
+                let _ = writeln!(reply, "options {}", RULE_OPTIONS.join(" "));
+                return Ok(reply);
+            }
+            // Check that the server is alive: PING
+            Some("PING") => return Ok(format!("PONG {}\n", STARTED.elapsed().as_secs())),
+            // List the commands, or describe one of them:
+            // HELP [command]
+            Some("HELP") => return help(words.next()),
+            // Authenticate, to be allowed to change anything
+            // when a token is configured: AUTH <token>
+            Some("AUTH") => {
+                let Some(token) = words.next() else {
+                    return Err("usage: AUTH <token>".to_string());
+                };
+                let expected = config.read().unwrap().control.token.clone();
+                let success = expected.is_none_or(|expected| same_token(&expected, token));
+                siem::record(SiemEvent::AuthAttempt { peer, success });
+                if !success {
+                    warn!("authentication failed");
+                    return Err("invalid token".to_string());
+                }
+                *authenticated = true;
+                info!("client authenticated");
+            }
+            // Stage the following filter changes, and apply
+            // them all at once: BEGIN, then COMMIT or ABORT
+            Some("BEGIN") => {
+                if staged.is_some() {
+                    return Err("transaction already open".to_string());
+                }
+                *staged = Some(retry::read(layer_handle, Transaction::new)?);
+                rule_change(*client, peer, "begin", String::new());
+            }
+            Some("COMMIT") => {
+                let Some(transaction) = staged.take() else {
+                    return Err("no transaction open".to_string());
+                };
+                let count = transaction.changes.len();
+                // Read the files to load before changing anything, so
+                // that the transaction applies as a whole or not at all
+                let mut changes: Vec<Change> = Vec::with_capacity(count);
+                for change in transaction.changes {
+                    match change {
+                        Staged::Change(change) => changes.push(change),
+                        Staged::Load(path) => match persist::load(&path) {
+                            Ok(saved) => changes.push(Box::new(move |layer| layer.restore(saved))),
+                            Err(e) => {
+                                info!(target: diagnostics::TARGET, "transaction of {count} changes aborted");
+                                rule_change(*client, peer, "abort", count.to_string());
+                                return Err(format!(
+                                    "failed to load {}: {e}, transaction aborted",
+                                    path.display()
+                                ));
+                            }
+                        },
+                    }
+                }
+                retry::modify(layer_handle, |layer| {
+                    layer.change(|layer| {
+                        for change in changes {
+                            change(layer);
+                        }
+                    })
+                })?;
+                for announcement in transaction.announcements {
+                    announcement();
+                }
+                info!(target: diagnostics::TARGET, "transaction of {count} changes committed");
+                rule_change(*client, peer, "commit", count.to_string());
+            }
+            Some("ABORT") => {
+                let Some(transaction) = staged.take() else {
+                    return Err("no transaction open".to_string());
+                };
+                let count = transaction.changes.len();
+                info!(target: diagnostics::TARGET, "transaction of {count} changes aborted");
+                rule_change(*client, peer, "abort", count.to_string());
+            }
+            // Revert the last changes to the rules, shadow rules,
+            // logger levels and rate limits, or apply them again
+            Some("UNDO") => {
+                let mut undone = false;
+                retry::modify(layer_handle, |layer| undone = layer.undo())?;
+                if !undone {
+                    return Err("nothing to undo".to_string());
+                }
+                info!(target: diagnostics::TARGET, "last filter change undone");
+                rule_change(*client, peer, "undo", String::new());
+            }
+            Some("REDO") => {
+                let mut redone = false;
+                retry::modify(layer_handle, |layer| redone = layer.redo())?;
+                if !redone {
+                    return Err("nothing to redo".to_string());
+                }
+                info!(target: diagnostics::TARGET, "last undone filter change redone");
+                rule_change(*client, peer, "redo", String::new());
+            }
+            Some("CLEAR") => {
+                if let Some(transaction) = staged {
+                    transaction.fields.clear();
+                }
+                apply(layer_handle, staged, |layer| layer.clear_filters())?;
+                announce(staged, *client, peer, |client, peer| {
+                    rule_change(client, peer, "clear_filters", String::new())
+                });
+            }
+            // Have the RIB send a route update to BGP, to produce
+            // the spans a filter applies to:
+            // ADDROUTE <vrf> <prefix> <next-hop> [<asn>,...] /
+            // DELROUTE <vrf> <prefix> [<next-hop>]
+            Some("ADDROUTE") => {
+                let (Some(vrf), Some(prefix), Some(next_hop), as_path, None) = (
+                    words.next(),
+                    words.next(),
+                    words.next(),
+                    words.next(),
+                    words.next(),
+                ) else {
+                    return Err("usage: ADDROUTE <vrf> <prefix> <next-hop> [<asn>,...]".to_string());
+                };
+                let (vrf_id, prefix) = parse_route(vrf, prefix)?;
+                let next_hop = next_hop
+                    .parse()
+                    .map_err(|_| format!("invalid next hop {next_hop}"))?;
+                let mut attributes = PathAttributes::default();
+                if let Some(as_path) = as_path {
+                    let asns = as_path
+                        .split(',')
+                        .map(|asn| asn.parse().map_err(|_| format!("invalid ASN {asn}")))
+                        .collect::<Result<_, _>>()?;
+                    attributes.as_path = AsPath(asns);
+                }
+                router::inject(RouteUpdate::RedistAdd(vrf_id, prefix, next_hop, attributes))?;
+            }
+            Some("DELROUTE") => {
+                let (Some(vrf), Some(prefix), next_hop, None) =
+                    (words.next(), words.next(), words.next(), words.next())
+                else {
+                    return Err("usage: DELROUTE <vrf> <prefix> [<next-hop>]".to_string());
+                };
+                let (vrf_id, prefix) = parse_route(vrf, prefix)?;
+                let next_hop = next_hop
+                    .map(|next_hop| {
+                        next_hop
+                            .parse()
+                            .map_err(|_| format!("invalid next hop {next_hop}"))
+                    })
+                    .transpose()?;
+                router::inject(RouteUpdate::RedistDel(vrf_id, prefix, next_hop))?;
+            }
+            // Pause or resume the route updates the RIB generates, or
+            // change their rate, or show how they are generated:
+            // SIM [PAUSE|RESUME|RATE <updates-per-sec>]
+            Some("SIM") => match (words.next(), words.next()) {
+                (None, _) => {
+                    let pace = router::pace();
+                    let state = if pace.paused { "paused" } else { "running" };
+                    return Ok(format!(
+                        "{state} rate {}\n",
+                        1.0 / pace.interval.as_secs_f64()
+                    ));
+                }
+                (Some("PAUSE"), None) => {
+                    router::set_paused(true);
+                    info!("route updates paused");
+                }
+                (Some("RESUME"), None) => {
+                    router::set_paused(false);
+                    info!("route updates resumed");
+                }
+                (Some("RATE"), Some(rate)) => match rate.parse::<f64>() {
+                    Ok(rate) if rate > 0.0 && rate.is_finite() => {
+                        router::set_interval(Duration::from_secs_f64(1.0 / rate));
+                        info!("route updates at {rate} per second");
+                    }
+                    _ => {
+                        return Err(format!("invalid rate {rate}"));
+                    }
+                },
+                _ => {
+                    return Err("usage: SIM [PAUSE|RESUME|RATE <updates-per-sec>]".to_string());
+                }
+            },
+            // Filter on vrf_id=id, the VRF being given by ID or by
+            // name: VRF <id>|<name>
+            Some("VRF") => {
+                let Some(vrf) = words.next() else {
+                    return Err("usage: VRF <id>|<name>".to_string());
+                };
+                let Some(id) = vrfs::resolve(vrf) else {
+                    return Err(format!("no VRF named {vrf}"));
+                };
+                if let Some(transaction) = staged {
+                    transaction.fields.insert("vrf_id".to_string());
+                }
+                let value = id.to_string();
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_filter("vrf_id", &value)
+                })?;
+                // Don't log from within `modify`: the layer is
+                // write-locked, so logging would deadlock.
+                announce(staged, *client, peer, move |client, peer| {
+                    error!(target: diagnostics::TARGET, "setting filter for vrf_id = {id}");
+                    rule_change(client, peer, "set_filter", format!("vrf_id={id}"));
+                });
+            }
+            // Filter on any field, by value, by duration, by part of
+            // its text or by network: FILTER <field>=<value> /
+            // FILTER <field> <op> <duration> / FILTER <field> contains
+            // <text> / FILTER <field> within <network>, e.g.
+            // FILTER busy_us > 5ms, FILTER MESSAGE contains New path or
+            // FILTER prefix within 10.0.0.0/8, optionally restricted to a
+            // thread, by name or number: FILTER @thread=bgp vrf_id=1,
+            // and optionally followed by
+            // LIMIT <n> (suppress n matches, then expire) or
+            // CAPTURE <n> (keep n matches, then suppress),
+            // TTL <secs> (expire after that time), and an
+            // activation window: BETWEEN <start> <end> or
+            // DAILY <HH:MM>-<HH:MM>, and SAMPLE <rate> (keep
+            // that fraction of the matching spans)
+            Some("FILTER") => {
+                let args: Vec<&str> = words.collect();
+                let (field, matcher, options) = match parse_filter(&args) {
+                    Ok(rule) => rule,
+                    Err(e) => {
+                        return Err(format!("invalid rule: {e}"));
+                    }
+                };
+                check_matcher(layer_handle, &matcher)?;
+                let rule = match &options.thread {
+                    Some(thread) => format!("@thread={thread} {field}{matcher}{options}"),
+                    None => format!("{field}{matcher}{options}"),
+                };
+                if let Some(transaction) = staged {
+                    transaction.fields.insert(field.clone());
+                }
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_rule(&field, matcher, options)
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "setting filter {rule}");
+                    rule_change(client, peer, "set_filter", rule);
+                });
+            }
+            // Remove the filter on a field: UNFILTER <field>
+            Some("UNFILTER") => {
+                let Some(field) = words.next() else {
+                    return Err("usage: UNFILTER <field>".to_string());
+                };
+                let exists = match staged {
+                    Some(transaction) => transaction.fields.remove(field),
+                    None => retry::read(layer_handle, |layer| {
+                        layer.filters().iter().any(|rule| rule.field == field)
+                    })?,
+                };
+                if !exists {
+                    return Err(format!("no filter on {field}"));
+                }
+                let name = field.to_string();
+                apply(layer_handle, staged, move |layer| layer.remove_rule(&name))?;
+                let field = field.to_string();
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "removing filter on {field}");
+                    rule_change(client, peer, "remove_filter", field);
+                });
+            }
+            // Evaluate a candidate rule alongside the filters,
+            // without enforcing it: SHADOW <rule> / SHADOW CLEAR
+            Some("SHADOW") => {
+                let expr = words.collect::<Vec<_>>().join(" ");
+                if expr == "CLEAR" {
+                    apply(layer_handle, staged, |layer| layer.clear_shadows())?;
+                    announce(staged, *client, peer, |client, peer| {
+                        info!(target: diagnostics::TARGET, "shadow rules cleared");
+                        rule_change(client, peer, "clear_shadows", String::new());
+                    });
+                    return Ok(String::new());
+                }
+                let (field, matcher) = match matcher::parse_rule(&expr) {
+                    Ok(rule) => rule,
+                    Err(e) => {
+                        return Err(format!("invalid rule: {e}"));
+                    }
+                };
+                check_matcher(layer_handle, &matcher)?;
+                let rule = format!("{field}{matcher}");
+                apply(layer_handle, staged, move |layer| {
+                    layer.add_shadow(&field, matcher)
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "adding shadow rule {rule}");
+                    rule_change(client, peer, "add_shadow", rule);
+                });
+            }
+            // Evaluate the rules without suppressing anything:
+            // DRYRUN on|off
+            Some("DRYRUN") => {
+                let dry_run = match words.next() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => {
+                        return Err("usage: DRYRUN on|off".to_string());
+                    }
+                };
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_dry_run(dry_run)
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, dry_run, "dry-run mode changed");
+                    rule_change(client, peer, "set_dry_run", dry_run.to_string());
+                });
+            }
+            // Report why the spans and events are suppressed:
+            // EXPLAIN on|off
+            Some("EXPLAIN") => {
+                let explain = match words.next() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => {
+                        return Err("usage: EXPLAIN on|off".to_string());
+                    }
+                };
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_explain(explain)
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, explain, "explain mode changed");
+                    rule_change(client, peer, "set_explain", explain.to_string());
+                });
+            }
+            // List the filters and muted callsites, or the
+            // callsites: LIST / LIST CALLSITES
+            Some("LIST") => {
+                let reply = retry::read(layer_handle, |layer| match words.next() {
+                    Some("CALLSITES") => list_callsites(layer),
+                    _ => list(layer),
+                })?;
+                return Ok(reply);
+            }
+            // Set logger levels, with the "name=level" requests
+            // of proxies' dynamic logging endpoints:
+            // LOGGING <name>=<level>... / LOGGING level=<level>
+            // LOGGING paths=<name>:<level>,... / LOGGING RESET
+            // Without arguments, list the logger levels.
+            Some("LOGGING") => {
+                let request = words.collect::<Vec<_>>().join(" ");
+                if request.is_empty() {
+                    let reply = retry::read(layer_handle, list_logger_levels)?;
+                    return Ok(reply);
+                }
+                if request == "RESET" {
+                    apply(layer_handle, staged, |layer| layer.clear_logger_levels())?;
+                    announce(staged, *client, peer, |client, peer| {
+                        info!(target: diagnostics::TARGET, "logger levels reset");
+                        rule_change(client, peer, "reset_logger_levels", String::new());
+                    });
+                    return Ok(String::new());
+                }
+                let changes = match loggers::parse_request(&request) {
+                    Ok(changes) => changes,
+                    Err(e) => {
+                        return Err(format!("invalid logging request: {e}"));
+                    }
+                };
+                apply(layer_handle, staged, move |layer| {
+                    for change in changes {
+                        match change {
+                            LoggerLevel::All(level) => {
+                                layer.clear_logger_levels();
+                                layer.set_logger_level("", level);
+                            }
+                            LoggerLevel::Named(name, level) => layer.set_logger_level(&name, level),
+                        }
+                    }
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "logger levels updated: {request}");
+                    rule_change(client, peer, "set_logger_levels", request);
+                });
+            }
+            // Set target levels and filter rules at once, with
+            // directives in the syntax of `RUST_LOG` extended with
+            // field predicates: DIRECTIVE <directive>,...
+            // e.g. DIRECTIVE router::bgp[add_path{vrf_id=1}]=off,info
+            Some("DIRECTIVE") => {
+                let text = words.collect::<Vec<_>>().join(" ");
+                if text.is_empty() {
+                    return Err("usage: DIRECTIVE <directive>,...".to_string());
+                }
+                let directives = match directive::parse_directives(&text) {
+                    Ok(directives) => directives,
+                    Err(e) => {
+                        return Err(format!("invalid directive: {e}"));
+                    }
+                };
+                for directive in &directives {
+                    if let directive::Directive::Rule { matcher, .. } = directive {
+                        check_matcher(layer_handle, matcher)?;
+                    }
+                }
+                if let Some(transaction) = staged {
+                    for directive in &directives {
+                        if let directive::Directive::Rule { field, .. } = directive {
+                            transaction.fields.insert(field.clone());
+                        }
+                    }
+                }
+                apply(layer_handle, staged, move |layer| {
+                    for directive in directives {
+                        layer.apply_directive(directive);
+                    }
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "applying directives {text}");
+                    rule_change(client, peer, "directive", text);
+                });
+            }
+            // Change the level and target filtering, with
+            // `RUST_LOG` directives: LEVEL <directive> /
+            // LEVEL CLEAR. Without arguments, show the current
+            // directives.
+            Some("LEVEL") => match words.next() {
+                None => {
+                    let reply = retry::read(level_handle, |filter| format!("{filter}\n"))?;
+                    return Ok(reply);
+                }
+                Some("CLEAR") => {
+                    let mut config = config.write().unwrap();
+                    if let Err(e) = config.reset_env_filter() {
+                        return Err(format!("{e}"));
+                    }
+                    retry::reload(level_handle, config.env_filter())?;
+                    drop(config);
+                    info!(target: diagnostics::TARGET, "level directives reset");
+                    rule_change(*client, peer, "reset_level", String::new());
+                }
+                Some(directive) => {
+                    let directive = match directive.parse::<Directive>() {
+                        Ok(directive) => directive,
+                        Err(e) => {
+                            return Err(format!("invalid directive {directive}: {e}"));
+                        }
+                    };
+                    let detail = directive.to_string();
+                    // Hold the configuration until it records the
+                    // change, so that concurrent changes from other
+                    // clients can't get in between
+                    let mut config = config.write().unwrap();
+                    retry::modify(level_handle, |filter| {
+                        *filter = std::mem::take(filter).add_directive(directive)
+                    })?;
+                    let directives = retry::read(level_handle, |filter| filter.to_string())?;
+                    config.set_runtime_env_filter(directives);
+                    drop(config);
+                    info!(target: diagnostics::TARGET, "level directive added: {detail}");
+                    rule_change(*client, peer, "set_level", detail);
+                }
+            },
+            // Change how the records are printed, or show it:
+            // FORMAT [compact|pretty|json]
+            Some("FORMAT") => match words.next() {
+                None => {
+                    let reply = format!("{}\n", config.read().unwrap().fmt.format);
+                    return Ok(reply);
+                }
+                Some(name) => {
+                    let Ok(format) = Format::from_str(name, true) else {
+                        return Err(format!(
+                            "unknown format {name}, expected compact, pretty, json or tree"
+                        ));
+                    };
+                    let mut config = config.write().unwrap();
+                    let fmt = FmtConfig {
+                        format,
+                        ..config.fmt.clone()
+                    };
+                    retry::reload(fmt_handle, fmt.layer())?;
+                    config.set_runtime_format(format);
+                    drop(config);
+                    info!(target: diagnostics::TARGET, "output format set to {format}");
+                    rule_change(*client, peer, "set_format", format.to_string());
+                }
+            },
+            // Turn a display option of the output on or off, or
+            // show them: DISPLAY [<option> on|off]
+            Some("DISPLAY") => match (words.next(), words.next()) {
+                (None, _) => {
+                    let fmt = config.read().unwrap().fmt.clone();
+                    let mut reply = String::new();
+                    for option in DISPLAY_OPTIONS {
+                        let on = fmt.display(option).unwrap_or_default();
+                        let _ = writeln!(reply, "{option} {}", if on { "on" } else { "off" });
+                    }
+                    return Ok(reply);
+                }
+                (Some(option), Some(value @ ("on" | "off"))) => {
+                    let on = value == "on";
+                    let mut config = config.write().unwrap();
+                    let mut changed = config.clone();
+                    changed.set_runtime_display(option, on)?;
+                    retry::reload(fmt_handle, changed.fmt.layer())?;
+                    *config = changed;
+                    drop(config);
+                    info!(target: diagnostics::TARGET, "display option {option} turned {value}");
+                    rule_change(*client, peer, "set_display", format!("{option} {value}"));
+                }
+                _ => {
+                    return Err("usage: DISPLAY [<option> on|off]".to_string());
+                }
+            },
+            // Print the records of a target to a file, stop, or
+            // list the routes: ROUTE [<target> <path>|<target> OFF]
+            Some("ROUTE") => match (words.next(), words.next()) {
+                (None, _) => {
+                    let mut reply = String::new();
+                    for (target, path) in routes::list() {
+                        let _ = writeln!(reply, "{target} {}", path.display());
+                    }
+                    return Ok(reply);
+                }
+                (Some(target), Some("OFF")) => {
+                    let mut config = config.write().unwrap();
+                    if !routes::remove(target) {
+                        return Err(format!("no route for {target}"));
+                    }
+                    config.set_runtime_routes(routes::list());
+                    drop(config);
+                    info!(target: diagnostics::TARGET, "route of {target} removed");
+                    rule_change(*client, peer, "remove_route", target.to_string());
+                }
+                (Some(target), Some(path)) => {
+                    let mut config = config.write().unwrap();
+                    if let Err(e) = routes::set(target, Path::new(path)) {
+                        return Err(format!("failed to open {path}: {e}"));
+                    }
+                    config.set_runtime_routes(routes::list());
+                    drop(config);
+                    info!(target: diagnostics::TARGET, "{target} routed to {path}");
+                    rule_change(*client, peer, "set_route", format!("{target} {path}"));
+                }
+                _ => {
+                    return Err("usage: ROUTE [<target> <path>|<target> OFF]".to_string());
+                }
+            },
+            // Record the span timelines for a timeline UI:
+            // TRACE START <path> / TRACE STOP
+            Some("TRACE") => match (words.next(), words.next()) {
+                (Some("START"), Some(path)) => {
+                    chrome::start(Path::new(path))?;
+                    info!(target: diagnostics::TARGET, "tracing to {path}");
+                    rule_change(*client, peer, "start_trace", path.to_string());
+                }
+                (Some("STOP"), None) => {
+                    let Some((path, events)) = chrome::stop()? else {
+                        return Err("not tracing".to_string());
+                    };
+                    info!(
+                        target: diagnostics::TARGET,
+                        "trace written to {}: {events} events",
+                        path.display()
+                    );
+                    rule_change(*client, peer, "stop_trace", path.display().to_string());
+                }
+                _ => {
+                    return Err("usage: TRACE START <path> / TRACE STOP".to_string());
+                }
+            },
+            // Capture a flamegraph of the spans: FLAME START /
+            // FLAME STOP <path>
+            Some("FLAME") => match (words.next(), words.next()) {
+                (Some("START"), None) => {
+                    flame::start()?;
+                    info!(target: diagnostics::TARGET, "recording the span stacks");
+                    rule_change(*client, peer, "start_flame", String::new());
+                }
+                (Some("STOP"), Some(path)) => {
+                    let stacks = flame::stop(Path::new(path))?;
+                    info!(
+                        target: diagnostics::TARGET,
+                        "span stacks written to {path}: {stacks} stacks"
+                    );
+                    rule_change(*client, peer, "stop_flame", path.to_string());
+                }
+                _ => {
+                    return Err("usage: FLAME START / FLAME STOP <path>".to_string());
+                }
+            },
+            // Print the effective settings, and where they
+            // come from: CONFIG SHOW
+            Some("CONFIG") => match words.next() {
+                Some("SHOW") | None => {
+                    let reply = config.read().unwrap().show();
+                    return Ok(reply);
+                }
+                Some(_) => {
+                    return Err("usage: CONFIG SHOW".to_string());
+                }
+            },
+            // Switch between named sets of rules, shadow rules,
+            // logger levels and rate limits: PROFILE SAVE <name>
+            // / PROFILE LOAD <name> / PROFILE LIST
+            Some("PROFILE") => match (words.next(), words.next()) {
+                (Some("SAVE"), Some(name)) => {
+                    if let Some(transaction) = staged {
+                        let fields = transaction.fields.clone();
+                        transaction.profiles.insert(name.to_string(), fields);
+                    }
+                    let profile = name.to_string();
+                    apply(layer_handle, staged, move |layer| {
+                        layer.save_profile(&profile)
+                    })?;
+                    let name = name.to_string();
+                    announce(staged, *client, peer, move |client, peer| {
+                        info!(target: diagnostics::TARGET, "profile {name} saved");
+                        rule_change(client, peer, "save_profile", name);
+                    });
+                }
+                (Some("LOAD"), Some(name)) => {
+                    let exists = match staged {
+                        Some(transaction) => match transaction.profiles.get(name) {
+                            Some(fields) => {
+                                transaction.fields = fields.clone();
+                                true
+                            }
+                            None => false,
+                        },
+                        None => retry::read(layer_handle, |layer| {
+                            layer.profiles().iter().any(|(profile, _)| *profile == name)
+                        })?,
+                    };
+                    if !exists {
+                        return Err(format!("unknown profile {name}"));
+                    }
+                    let profile = name.to_string();
+                    apply(layer_handle, staged, move |layer| {
+                        layer.load_profile(&profile);
+                    })?;
+                    let name = name.to_string();
+                    announce(staged, *client, peer, move |client, peer| {
+                        info!(target: diagnostics::TARGET, "profile {name} loaded");
+                        rule_change(client, peer, "load_profile", name);
+                    });
+                }
+                (Some("LIST") | None, None) => {
+                    let reply = retry::read(layer_handle, list_profiles)?;
+                    return Ok(reply);
+                }
+                _ => {
+                    return Err("usage: PROFILE SAVE|LOAD <name> / PROFILE LIST".to_string());
+                }
+            },
+            // Save the filters to a file, or replace them with
+            // those of a file: SAVE [path] / LOAD [path]
+            Some("SAVE") => {
+                let path = words.next().map_or_else(persist::default_path, Into::into);
+                let saved = retry::read(layer_handle, |layer| layer.saved_filters())?;
+                match persist::save(&path, &saved) {
+                    Ok(()) => {
+                        info!(target: diagnostics::TARGET, "filters saved to {}", path.display())
+                    }
+                    Err(e) => {
+                        return Err(format!("failed to save {}: {e}", path.display()));
+                    }
+                }
+            }
+            Some("LOAD") => {
+                let path = words.next().map_or_else(persist::default_path, Into::into);
+                let saved = match persist::load(&path) {
+                    Ok(saved) => saved,
+                    Err(e) => {
+                        return Err(format!("failed to load {}: {e}", path.display()));
+                    }
+                };
+                // In a transaction, the file is only checked now, and
+                // read again at COMMIT
+                let source = path.display().to_string();
+                match staged {
+                    Some(transaction) => {
+                        transaction.restore(&saved);
+                        transaction.changes.push(Staged::Load(path));
+                    }
+                    None => retry::modify(layer_handle, |layer| {
+                        layer.change(|layer| layer.restore(saved))
+                    })?,
+                }
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "filters loaded from {source}");
+                    rule_change(client, peer, "load_filters", source);
+                });
+            }
+            // Limit the number of events per second of each
+            // callsite of a target: RATE <target> <events-per-sec>
+            // RATE <target> OFF / RATE RESET. Without
+            // arguments, list the rate limits.
+            Some("RATE") => {
+                let (target, rate) = match (words.next(), words.next()) {
+                    (None, _) => {
+                        let reply = retry::read(layer_handle, list_rate_limits)?;
+                        return Ok(reply);
+                    }
+                    (Some("RESET"), None) => {
+                        apply(layer_handle, staged, |layer| layer.clear_rate_limits())?;
+                        announce(staged, *client, peer, |client, peer| {
+                            info!(target: diagnostics::TARGET, "rate limits reset");
+                            rule_change(client, peer, "reset_rate_limits", String::new());
+                        });
+                        return Ok(String::new());
+                    }
+                    (Some(target), Some("OFF")) => (target, None),
+                    (Some(target), Some(rate)) => match rate.parse::<f64>() {
+                        Ok(rate) if rate > 0.0 => (target, Some(rate)),
+                        _ => {
+                            return Err(format!("invalid rate {rate}"));
+                        }
+                    },
+                    (Some(_), None) => {
+                        return Err("usage: RATE <target> <events-per-sec>|OFF".to_string());
+                    }
+                };
+                let target = if target == "*" { "" } else { target };
+                let name = target.to_string();
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_rate_limit(&name, rate)
+                })?;
+                let detail = match rate {
+                    Some(rate) => format!("{} {rate}/s", logger_name(target)),
+                    None => format!("{} off", logger_name(target)),
+                };
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "rate limit set: {detail}");
+                    rule_change(client, peer, "set_rate_limit", detail);
+                });
+            }
+            // Collapse identical consecutive events of a
+            // callsite: DEDUP <window-secs> / DEDUP OFF
+            Some("DEDUP") => {
+                let window = match words.next() {
+                    Some("OFF") => None,
+                    Some(secs) => match secs.parse::<u64>() {
+                        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                        _ => {
+                            return Err("invalid window".to_string());
+                        }
+                    },
+                    None => {
+                        return Err("usage: DEDUP <window-secs>|OFF".to_string());
+                    }
+                };
+                apply(layer_handle, staged, move |layer| layer.set_dedup(window))?;
+                let detail = match window {
+                    Some(window) => format!("{}s", window.as_secs()),
+                    None => "off".to_string(),
+                };
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "duplicate suppression: {detail}");
+                    rule_change(client, peer, "set_dedup", detail);
+                });
+            }
+            // Mask or hash the values of a field in the output:
+            // REDACT <field> <mask> / REDACT <field> HASH /
+            // REDACT <field> OFF / REDACT CLEAR. Without
+            // arguments, list the redacted fields.
+            Some("REDACT") => {
+                let (field, redaction) = match (words.next(), words.next()) {
+                    (None, _) => {
+                        let mut reply = String::new();
+                        for (field, redaction) in redact::redactions() {
+                            let _ = writeln!(reply, "{field} {redaction}");
+                        }
+                        return Ok(reply);
+                    }
+                    (Some("CLEAR"), None) => {
+                        redact::clear_redactions();
+                        info!(target: diagnostics::TARGET, "redactions cleared");
+                        rule_change(*client, peer, "clear_redactions", String::new());
+                        return Ok(String::new());
+                    }
+                    (Some(field), Some("OFF")) => (field, None),
+                    (Some(field), Some("HASH")) => (field, Some(Redaction::Hash)),
+                    (Some(field), Some(mask)) => (field, Some(Redaction::Mask(mask.to_string()))),
+                    (Some(_), None) => {
+                        return Err("usage: REDACT <field> <mask>|HASH|OFF".to_string());
+                    }
+                };
+                let detail = match &redaction {
+                    Some(redaction) => format!("{field} {redaction}"),
+                    None => format!("{field} off"),
+                };
+                redact::set_redaction(field, redaction);
+                info!(target: diagnostics::TARGET, "redaction set: {detail}");
+                rule_change(*client, peer, "set_redaction", detail);
+            }
+            // Only emit the spans that are busy for longer than
+            // a threshold, along with their events:
+            // SLOW <duration> / SLOW OFF
+            Some("SLOW") => {
+                let threshold = match words.next() {
+                    Some("OFF") => None,
+                    Some(threshold) => match matcher::parse_duration(threshold) {
+                        Some(threshold) => Some(threshold),
+                        None => {
+                            return Err(format!("invalid duration {threshold}"));
+                        }
+                    },
+                    None => {
+                        return Err("usage: SLOW <duration>|OFF".to_string());
+                    }
+                };
+                apply(layer_handle, staged, move |layer| layer.set_slow(threshold))?;
+                let detail = match threshold {
+                    Some(threshold) => format!("{threshold:?}"),
+                    None => "off".to_string(),
+                };
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "slow span threshold: {detail}");
+                    rule_change(client, peer, "set_slow", detail);
+                });
+            }
+            // Report the filter counters, or turn periodic
+            // reporting on or off:
+            // STATS / STATS REPORT <interval-secs>|OFF
+            Some("STATS") => match (words.next(), words.next()) {
+                (Some("REPORT"), Some("OFF")) => {
+                    reporter.set_interval(None);
+                    info!(target: diagnostics::TARGET, "periodic statistics reporting disabled");
+                }
+                (Some("REPORT"), Some(secs)) => match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        reporter.set_interval(Some(Duration::from_secs(secs)));
+                        info!(target: diagnostics::TARGET, "reporting statistics every {secs}s");
+                    }
+                    _ => {
+                        return Err("invalid interval".to_string());
+                    }
+                },
+                _ => {
+                    let reply = retry::read(layer_handle, stats)?;
+                    return Ok(reply);
+                }
+            },
+            // Disable a callsite entirely, optionally for a
+            // limited time: MUTE CALLSITE <n> [ttl-secs]
+            Some("MUTE") if words.next() == Some("CALLSITE") => {
+                let number = words.next().and_then(|n| n.parse::<usize>().ok());
+                let ttl = match words.next().map(|ttl| ttl.parse::<u64>()) {
+                    Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                    Some(Err(_)) => {
+                        return Err("invalid TTL".to_string());
+                    }
+                    None => None,
+                };
+                let Some(number) = number else {
+                    return Err("invalid callsite number".to_string());
+                };
+                let mut muted = false;
+                retry::modify(layer_handle, |layer| {
+                    muted = layer.mute_callsite(number, ttl)
+                })?;
+                if !muted {
+                    return Err(format!("unknown callsite {number}"));
+                }
+                info!(
+                    target: audit::TARGET,
+                    callsite = number,
+                    ttl_secs = ttl.map(|ttl| ttl.as_secs()),
+                    "callsite muted"
+                );
+                let detail = match ttl {
+                    Some(ttl) => format!("{number} ttl={}s", ttl.as_secs()),
+                    None => number.to_string(),
+                };
+                rule_change(*client, peer, "mute_callsite", detail);
+            }
+            // UNMUTE CALLSITE <n>
+            Some("UNMUTE") if words.next() == Some("CALLSITE") => {
+                let Some(number) = words.next().and_then(|n| n.parse::<usize>().ok()) else {
+                    return Err("invalid callsite number".to_string());
+                };
+                let mut unmuted = false;
+                retry::modify(layer_handle, |layer| {
+                    unmuted = layer.unmute_callsite(number)
+                })?;
+                if unmuted {
+                    info!(target: audit::TARGET, callsite = number, "callsite unmuted");
+                    rule_change(*client, peer, "unmute_callsite", number.to_string());
+                } else {
+                    return Err(format!("callsite {number} is not muted"));
+                }
+            }
+            // Shard records across files, stop, or show the sharding:
+            // SINK [SHARD <field> <count> [dir]|OFF]
+            Some("SINK") => match words.next() {
+                None => {
+                    let reply = retry::read(sink_handle, |sink| format!("{}\n", sink.describe()))?;
+                    return Ok(reply);
+                }
+                Some("SHARD") => {
+                    let field = words.next();
+                    let count = words.next().and_then(|n| n.parse::<usize>().ok());
+                    let dir = words.next().unwrap_or("shards");
+                    let (Some(field), Some(count)) = (field, count) else {
+                        return Err("usage: SINK SHARD <field> <count> [dir]".to_string());
+                    };
+                    let mut res = Ok(());
+                    retry::modify(sink_handle, |sink| {
+                        res = sink.enable(field, count, Path::new(dir))
+                    })?;
+                    if let Err(e) = res {
+                        return Err(format!("failed to enable sharding: {e}"));
+                    }
+                    info!(
+                        target: diagnostics::TARGET,
+                        "sharding records on {field} across {count} files in {dir}"
+                    );
+                    rule_change(
+                        *client,
+                        peer,
+                        "shard_sink",
+                        format!("{field} {count} {dir}"),
+                    );
+                }
+                Some("OFF") => {
+                    retry::modify(sink_handle, |sink| sink.disable())?;
+                    info!(target: diagnostics::TARGET, "sharding disabled");
+                    rule_change(*client, peer, "unshard_sink", String::new());
+                }
+                _ => return Err("usage: SINK [SHARD <field> <count> [dir]|OFF]".to_string()),
+            },
+            // Keep the suppressed events instead of discarding
+            // them: QUARANTINE FILE <path> / QUARANTINE MEMORY
+            // [lines] / QUARANTINE OFF. Without arguments,
+            // print the events held in memory.
+            Some("QUARANTINE") => {
+                let quarantine = match (words.next(), words.next()) {
+                    (Some("FILE"), Some(path)) => match Quarantine::file(Path::new(path)) {
+                        Ok(quarantine) => Some(quarantine),
+                        Err(e) => {
+                            return Err(format!("failed to open {path}: {e}"));
+                        }
+                    },
+                    (Some("MEMORY"), lines) => {
+                        match lines.map_or(Ok(quarantine::DEFAULT_CAPACITY), str::parse) {
+                            Ok(lines) => Some(Quarantine::memory(lines)),
+                            Err(_) => {
+                                return Err("invalid line count".to_string());
+                            }
+                        }
+                    }
+                    (Some("OFF"), _) => None,
+                    _ => {
+                        let reply = retry::read(layer_handle, |layer| match layer.quarantine() {
+                            Some(quarantine) => quarantine
+                                .lines()
+                                .into_iter()
+                                .map(|line| line + "\n")
+                                .collect(),
+                            None => "quarantine disabled\n".to_string(),
+                        })?;
+                        return Ok(reply);
+                    }
+                };
+                let detail = match &quarantine {
+                    Some(quarantine) => quarantine.describe(),
+                    None => "quarantine disabled".to_string(),
+                };
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_quarantine(quarantine)
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, "{detail}");
+                    rule_change(client, peer, "set_quarantine", detail);
+                });
+            }
+            // Keep the events suppressed within each span, and
+            // print them when a WARN or ERROR event occurs in
+            // the span: TRIGGER on [depth] / TRIGGER off
+            Some("TRIGGER") => {
+                let trigger = match (words.next(), words.next()) {
+                    (Some("on"), None) => Some(DEFAULT_TRIGGER_DEPTH),
+                    (Some("on"), Some(depth)) => match depth.parse::<usize>() {
+                        Ok(depth) => Some(depth),
+                        Err(_) => {
+                            return Err("invalid depth".to_string());
+                        }
+                    },
+                    (Some("off"), _) => None,
+                    _ => {
+                        return Err("usage: TRIGGER on [depth]|off".to_string());
+                    }
+                };
+                apply(layer_handle, staged, move |layer| {
+                    layer.set_trigger(trigger)
+                })?;
+                announce(staged, *client, peer, move |client, peer| {
+                    info!(target: diagnostics::TARGET, depth = trigger, "trigger mode changed");
+                    let detail = match trigger {
+                        Some(depth) => format!("on depth={depth}"),
+                        None => "off".to_string(),
+                    };
+                    rule_change(client, peer, "set_trigger", detail);
+                });
+            }
+            // Print the last suppressed events: DUMP [n]
+            Some("DUMP") => {
+                let n = match words.next().map(str::parse::<usize>) {
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => {
+                        return Err("invalid count".to_string());
+                    }
+                    None => usize::MAX,
+                };
+                let reply: String = retry::read(layer_handle, |layer| {
+                    layer
+                        .recently_suppressed(n)
+                        .iter()
+                        .map(|event| format!("{event}\n"))
+                        .collect()
+                })?;
+                return Ok(reply);
+            }
+            // List the live spans, or describe one of them:
+            // SHOW SPANS / SHOW SPAN <id>. The open spans are only
+            // tracked from the first SHOW SPANS on.
+            Some("SHOW") => {
+                let reply = retry::read(layer_handle, |layer| {
+                    let is_disabled = |id: &Id| layer.is_disabled(id);
+                    match (words.next(), words.next()) {
+                        (Some("SPANS"), _) => {
+                            span_tracking.get_or_insert_with(|| layer.track_live_spans());
+                            layer
+                                .live_spans()
+                                .iter()
+                                .filter_map(|id| inspect::summarize_span(id, is_disabled))
+                                .map(|summary| summary + "\n")
+                                .collect()
+                        }
+                        (Some("SPAN"), Some(id)) => match id.parse::<u64>() {
+                            Ok(id) if id != 0 => {
+                                inspect::describe_span(&Id::from_u64(id), is_disabled)
+                                    .unwrap_or_else(|| format!("no live span {id}\n"))
+                            }
+                            _ => format!("invalid span ID {id}\n"),
+                        },
+                        _ => String::new(),
+                    }
+                })?;
+                return Ok(reply);
+            }
+            Some(command) => return Err(format!("unknown command {command}")),
+            None => {}
+        }
+        Ok(String::new())
+    }
+}
+
+/// Find a command by name
+pub fn command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Run a HELP command: list the commands, or describe one of them
+fn help(name: Option<&str>) -> Result<String, String> {
+    let mut reply = String::new();
+    match name {
+        Some(name) => {
+            let command =
+                command(&name.to_uppercase()).ok_or_else(|| format!("unknown command {name}"))?;
+            let _ = writeln!(reply, "usage: {}", command.usage());
+            let _ = writeln!(reply, "{}", command.description);
+        }
+        None => {
+            let width = COMMANDS.iter().map(|command| command.name.len()).max();
+            for command in COMMANDS {
+                let (name, description) = (command.name, command.description);
+                let _ = writeln!(
+                    reply,
+                    "{name:<width$}  {description}",
+                    width = width.unwrap_or(0)
+                );
+            }
+        }
+    }
+    Ok(reply)
+}
+
+/// Whether a command only reads the state, and so is allowed before
+/// AUTH
+fn is_read_only(command: &str) -> bool {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next()) {
+        (
+            Some(
+                "HELLO" | "HELP" | "INTERACTIVE" | "JSON" | "PING" | "QUIT" | "LIST" | "SHOW"
+                | "DUMP",
+            ),
+            _,
+        ) => true,
+        (Some("CONFIG"), None | Some("SHOW")) => true,
+        (Some("FORMAT" | "DISPLAY" | "ROUTE" | "SINK"), None) => true,
+        (Some("PROFILE"), None | Some("LIST")) => true,
+        (Some("SIM"), None) => true,
+        (Some("STATS" | "LEVEL" | "LOGGING" | "RATE" | "REDACT"), None) => true,
+        _ => false,
+    }
+}
+
+/// Run a SESSION command, changing the filters of the connection's own
+/// stream of events
+fn session_filter<'a>(
+    mut args: impl Iterator<Item = &'a str>,
+    filters: &SessionFilters,
+) -> Result<String, String> {
+    let mut filters = filters.write().unwrap();
+    match args.next() {
+        Some("FILTER") => {
+            let expr = args.collect::<Vec<_>>().join(" ");
+            let (field, matcher) =
+                matcher::parse_rule(&expr).map_err(|e| format!("invalid rule: {e}"))?;
+            // The custom matchers are only registered in the filter
+            if let Matcher::Custom(_) = matcher {
+                return Err(
+                    "invalid rule: custom matchers only apply to FILTER and SHADOW".to_string(),
+                );
+            }
+            filters.insert(field, matcher);
+        }
+        Some("UNFILTER") => {
+            let field = args.next().ok_or("usage: SESSION UNFILTER <field>")?;
+            if filters.remove(field).is_none() {
+                return Err(format!("no session filter on {field}"));
+            }
+        }
+        Some("CLEAR") => filters.clear(),
+        Some("LIST") | None => {
+            let mut reply = String::new();
+            for (field, matcher) in filters.iter() {
+                let _ = writeln!(reply, "session filter {field}{matcher}");
+            }
+            return Ok(reply);
+        }
+        Some(_) => {
+            return Err(
+                "usage: SESSION FILTER <rule> / SESSION UNFILTER <field> / SESSION CLEAR|LIST"
+                    .to_string(),
+            )
+        }
+    }
+    Ok(String::new())
+}
+
+/// Parse the `<field>=<value>` arguments of SUBSCRIBE
+fn parse_query<'a>(args: impl Iterator<Item = &'a str>) -> Result<live::Query, String> {
+    args.map(|arg| match arg.split_once('=') {
+        Some((field, value)) if !field.is_empty() => Ok((field.to_string(), value.to_string())),
+        _ => Err(format!(
+            "invalid field value {arg}, expected <field>=<value>"
+        )),
+    })
+    .collect()
+}
+
+/// Compare two tokens, in a time that doesn't depend on where they
+/// differ
+fn same_token(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Parse the VRF, by ID or by name, and the prefix of a route
+fn parse_route(vrf: &str, prefix: &str) -> Result<(u32, IpNetwork), String> {
+    let vrf_id = vrfs::resolve(vrf).ok_or_else(|| format!("no VRF named {vrf}"))?;
+    let prefix = prefix
+        .parse()
+        .map_err(|_| format!("invalid prefix {prefix}"))?;
+    Ok((vrf_id, prefix))
+}
+
+/// Parse the arguments of a FILTER command: a rule expression,
+/// preceded by a scope such as `@thread=bgp` and followed by options
+fn parse_filter(mut args: &[&str]) -> Result<(String, Matcher, RuleOptions), String> {
+    let mut thread = None;
+    while let Some(scope) = args.first().and_then(|word| word.strip_prefix('@')) {
+        match scope.split_once('=') {
+            Some(("thread", name)) if !name.is_empty() => thread = Some(name.to_string()),
+            _ => return Err(format!("invalid scope @{scope}, expected @thread=<name>")),
+        }
+        args = &args[1..];
+    }
+    let start = args
+        .iter()
+        .position(|word| RULE_OPTIONS.contains(word))
+        .unwrap_or(args.len());
+    let (field, matcher) = matcher::parse_rule(&args[..start].join(" "))?;
+    let mut options = RuleOptions {
+        thread,
+        ..RuleOptions::default()
+    };
+    let mut rest = args[start..].iter();
+    while let Some(keyword) = rest.next() {
+        let mut value = || {
+            rest.next()
+                .ok_or_else(|| format!("missing value for {keyword}"))
+        };
+        let count = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid count {value}"))
+        };
+        match *keyword {
+            "LIMIT" => options.budget = Some(Budget::Limit(count(value()?)?)),
+            "CAPTURE" => options.budget = Some(Budget::Capture(count(value()?)?)),
+            "TTL" => options.ttl = Some(Duration::from_secs(count(value()?)?)),
+            "BETWEEN" => {
+                let (start, end) = (value()?, value()?);
+                options.window = Some(window::parse_between(start, end)?);
+            }
+            "DAILY" => options.window = Some(window::parse_daily(value()?)?),
+            "TARGET" => options.target = Some(value()?.to_string()),
+            "SPAN" => options.span = Some(value()?.to_string()),
+            "SAMPLE" => {
+                let rate = value()?;
+                match rate.parse::<f64>() {
+                    Ok(rate) if (0.0..=1.0).contains(&rate) => options.sample = Some(rate),
+                    _ => return Err(format!("invalid sample rate {rate}")),
+                }
+            }
+            _ => return Err(format!("unknown option {keyword}")),
+        }
+    }
+    Ok((field, matcher, options))
+}
+
+/// Check that the custom matcher of a rule, if it has one, is
+/// registered in the field filter
+fn check_matcher<S>(
+    handle: &Handle<DynamicFieldFilter, S>,
+    matcher: &Matcher,
+) -> Result<(), String> {
+    let Matcher::Custom(name) = matcher else {
+        return Ok(());
+    };
+    let names = retry::read(handle, |layer| layer.matchers().join(" "))?;
+    if names.split(' ').any(|known| known == name) {
+        return Ok(());
+    }
+    if names.is_empty() {
+        Err(format!(
+            "invalid rule: no custom matcher {name}, none is registered"
+        ))
+    } else {
+        Err(format!(
+            "invalid rule: no custom matcher {name}, expected one of {names}"
+        ))
+    }
+}
+
+/// Apply a change to the field filter, or stage it if a transaction is
+/// open
+fn apply<S>(
+    handle: &Handle<DynamicFieldFilter, S>,
+    staged: &mut Option<Transaction>,
+    change: impl FnOnce(&mut DynamicFieldFilter) + Send + 'static,
+) -> Result<(), String> {
+    match staged {
+        Some(transaction) => {
+            transaction.changes.push(Staged::Change(Box::new(change)));
+            Ok(())
+        }
+        None => retry::modify(handle, |layer| layer.change(change)),
+    }
+}
+
+/// Log and notify a change made by a client, or, if a transaction is
+/// open, once it is committed
+fn announce(
+    staged: &mut Option<Transaction>,
+    client: ClientId,
+    peer: &str,
+    announcement: impl FnOnce(ClientId, &str) + Send + 'static,
+) {
+    match staged {
+        Some(transaction) => {
+            let peer = peer.to_string();
+            transaction
+                .announcements
+                .push(Box::new(move || announcement(client, &peer)));
+        }
+        None => announcement(client, peer),
+    }
+}
+
+/// Record a rule change made by a client in the SIEM changelog, and
+/// notify the other clients
+fn rule_change(client: ClientId, peer: &str, action: &str, detail: String) {
+    broadcast::send(Some(client), peer, action, &detail);
+    siem::record(SiemEvent::RuleChange {
+        peer,
+        action,
+        detail,
+    });
+}
+
+/// Describe the field filters, the shadow rules and the muted
+/// callsites
+fn list(layer: &DynamicFieldFilter) -> String {
+    let mut out = String::new();
+    if layer.dry_run() {
+        out.push_str("dry-run on\n");
+    }
+    if layer.explain_mode() {
+        out.push_str("explain on\n");
+    }
+    if layer.mode() == FilterMode::Allow {
+        out.push_str("mode allow\n");
+    }
+    if let Some(level) = layer.bypass_level() {
+        let _ = writeln!(out, "bypass {level}");
+    }
+    if let Some(depth) = layer.trigger() {
+        let _ = writeln!(out, "trigger on (depth {depth})");
+    }
+    if let Some(threshold) = layer.slow() {
+        let _ = writeln!(out, "slow {threshold:?}");
+    }
+    if let Some(window) = layer.dedup() {
+        let _ = writeln!(out, "dedup {}s", window.as_secs());
+    }
+    if let Some(quarantine) = layer.quarantine() {
+        let _ = writeln!(out, "{}", quarantine.describe());
+    }
+    for rule in layer.filters() {
+        let _ = write!(out, "filter {rule}");
+        if rule.is_expired() {
+            out.push_str(" (expired)");
+        } else {
+            if !rule.is_active() {
+                out.push_str(" (inactive)");
+            }
+            if let Some(remaining) = rule.remaining() {
+                let _ = write!(out, " ({remaining} left)");
+            }
+            if let Some(ttl) = rule.time_left() {
+                let _ = write!(out, " ({}s left)", ttl.as_secs());
+            }
+        }
+        out.push('\n');
+    }
+    for rule in layer.shadows() {
+        let _ = writeln!(out, "shadow {rule}");
+    }
+    for (name, level) in layer.logger_levels() {
+        let _ = writeln!(out, "logger {} {level}", logger_name(name));
+    }
+    for (name, rate) in layer.rate_limits() {
+        let _ = writeln!(out, "rate {} {rate}/s", logger_name(name));
+    }
+    for (number, ttl) in layer.mutes() {
+        match ttl {
+            Some(ttl) => {
+                let _ = writeln!(out, "mute callsite {number} ({}s left)", ttl.as_secs());
+            }
+            None => {
+                let _ = writeln!(out, "mute callsite {number}");
+            }
+        }
+    }
+    out
+}
+
+/// Return the name to display for a logger: the empty name stands for
+/// all the loggers
+fn logger_name(name: &str) -> &str {
+    if name.is_empty() {
+        "*"
+    } else {
+        name
+    }
+}
+
+/// Describe the logger levels, one `<name>: <level>` per line, like
+/// Envoy's `/logging` endpoint does
+fn list_logger_levels(layer: &DynamicFieldFilter) -> String {
+    let mut out = String::new();
+    for (name, level) in layer.logger_levels() {
+        let _ = writeln!(out, "{}: {}", logger_name(name), level);
+    }
+    out
+}
+
+/// Describe the rate limits, one `<target> <events-per-sec>/s` per
+/// line
+fn list_rate_limits(layer: &DynamicFieldFilter) -> String {
+    let mut out = String::new();
+    for (name, rate) in layer.rate_limits() {
+        let _ = writeln!(out, "{} {rate}/s", logger_name(name));
+    }
+    out
+}
+
+/// List the saved profiles, with what they hold
+fn list_profiles(layer: &DynamicFieldFilter) -> String {
+    let mut out = String::new();
+    for (name, profile) in layer.profiles() {
+        let _ = writeln!(out, "{name}: {profile}");
+    }
+    out
+}
+
+/// Report the filter counters, including the number of hits of each
+/// rule and shadow rule, and the busy times of the spans
+fn stats(layer: &DynamicFieldFilter) -> String {
+    let mut out = layer.stats().snapshot().to_string();
+    let _ = writeln!(out, "lines_dropped {}", nonblocking::dropped());
+    for rule in layer.filters() {
+        let _ = writeln!(out, "rule {rule} hits {}", rule.hits());
+    }
+    for rule in layer.shadows() {
+        let _ = writeln!(out, "shadow {rule} hits {}", rule.hits());
+    }
+    for (name, histogram) in metrics::span_busy_times() {
+        let _ = write!(out, "span {name} count {}", histogram.count);
+        for (quantile, quantile_name) in metrics::QUANTILES {
+            let _ = write!(out, " {quantile_name} {:?}", histogram.quantile(quantile));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Describe all the registered callsites
+fn list_callsites(layer: &DynamicFieldFilter) -> String {
+    let mut out = String::new();
+    for (number, metadata, muted) in layer.callsites() {
+        let kind = if metadata.is_span() { "span" } else { "event" };
+        let _ = write!(
+            out,
+            "{number} {kind} {} {} {}",
+            metadata.level(),
+            metadata.target(),
+            metadata.name()
+        );
+        if muted {
+            out.push_str(" (muted)");
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::reload;
+
+    use super::*;
+
+    #[test]
+    fn commands_are_sorted() {
+        for pair in COMMANDS.windows(2) {
+            assert!(
+                pair[0].name < pair[1].name,
+                "{} must come after {}",
+                pair[0].name,
+                pair[1].name
+            );
+        }
+    }
+
+    #[test]
+    fn transactions_apply_and_announce_at_commit() {
+        let (_layer, layer_handle) =
+            reload::Layer::<_, Registry>::new(DynamicFieldFilter::default());
+        let (_level, level_handle) = reload::Layer::<_, Registry>::new(EnvFilter::default());
+        let (_sink, sink_handle) = reload::Layer::<_, Registry>::new(ShardedSink::default());
+        let config = Config::default();
+        let (_fmt, fmt_handle) = reload::Layer::new(config.fmt.layer());
+        let mut session = Session::new(
+            "transaction test".to_string(),
+            layer_handle.clone(),
+            level_handle,
+            sink_handle,
+            fmt_handle,
+            Arc::new(StatsReporter::default()),
+            Arc::new(RwLock::new(config)),
+        );
+        let subscription = broadcast::subscribe(ClientId::unique());
+        let announced = || {
+            subscription
+                .pending()
+                .filter(|notification| notification.peer == "transaction test")
+                .map(|notification| notification.action)
+                .collect::<Vec<_>>()
+        };
+        let filters = || -> Vec<String> {
+            layer_handle
+                .with_current(|layer| {
+                    layer
+                        .filters()
+                        .iter()
+                        .map(|rule| rule.to_string())
+                        .collect()
+                })
+                .unwrap()
+        };
+
+        session.execute("BEGIN").unwrap();
+        session.execute("FILTER vrf_id=1").unwrap();
+        session.execute("PROFILE SAVE one").unwrap();
+        // Checked against the staged changes rather than the filters
+        session.execute("UNFILTER vrf_id").unwrap();
+        assert!(session.execute("UNFILTER vrf_id").is_err());
+        session.execute("PROFILE LOAD one").unwrap();
+        assert!(session.execute("PROFILE LOAD two").is_err());
+        assert_eq!(announced(), ["begin"]);
+        assert!(filters().is_empty());
+
+        session.execute("COMMIT").unwrap();
+        assert_eq!(
+            announced(),
+            [
+                "set_filter",
+                "save_profile",
+                "remove_filter",
+                "load_profile",
+                "commit"
+            ]
+        );
+        assert_eq!(filters(), ["vrf_id=1"]);
+
+        // Nothing is announced for the aborted changes
+        session.execute("BEGIN").unwrap();
+        session.execute("CLEAR").unwrap();
+        session.execute("ABORT").unwrap();
+        assert_eq!(announced(), ["begin", "abort"]);
+        assert_eq!(filters(), ["vrf_id=1"]);
+    }
+}
@@ -0,0 +1,93 @@
+//! Printing the enrichment fields, `hostname`, `pid` and
+//! `thread.name`, in the records of the fmt layer, see
+//! [`dynamic_field_filter::enrich`].
+//!
+//! They can't be added to the events themselves, so [`Enrich`] prints
+//! them around the record of the format it wraps: before it in the
+//! text formats, so that the styles of the record are kept, and as the
+//! first keys of the object in JSON. Enrichment is turned on with
+//! `enrich = true` in the configuration.
+
+use std::fmt;
+
+use dynamic_field_filter::enrich;
+use dynamic_field_filter::redact;
+use serde_json::Value;
+use tracing::Event;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::fmt::FormatEvent;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::registry::LookupSpan;
+
+/// An event formatter adding the enrichment fields to the records of
+/// another one, if enrichment is on
+#[derive(Debug, Clone)]
+pub struct Enrich<F> {
+    inner: F,
+    /// Whether the records of the inner format are JSON objects
+    json: bool,
+}
+
+impl<F> Enrich<F> {
+    pub fn text(inner: F) -> Self {
+        Self { inner, json: false }
+    }
+
+    pub fn json(inner: F) -> Self {
+        Self { inner, json: true }
+    }
+}
+
+/// The enrichment fields, redacted like the other fields
+fn values() -> Vec<(&'static str, String)> {
+    enrich::values()
+        .into_iter()
+        .map(|(name, value)| match redact::redaction(name) {
+            Some(redaction) => (name, redaction.apply(&value)),
+            None => (name, value),
+        })
+        .collect()
+}
+
+impl<S, N, F> FormatEvent<S, N> for Enrich<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let values = values();
+        if values.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+        if !self.json {
+            for (name, value) in values {
+                write!(writer, "{name}={value} ")?;
+            }
+            return self.inner.format_event(ctx, writer, event);
+        }
+        let mut record = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut record), event)?;
+        let Some(rest) = record.strip_prefix('{') else {
+            return writer.write_str(&record);
+        };
+        writer.write_char('{')?;
+        for (name, value) in values {
+            // The PID is a number
+            let value = match value.parse::<u64>() {
+                Ok(number) if name == "pid" => Value::from(number),
+                _ => Value::from(value),
+            };
+            write!(writer, "{}:{value},", Value::from(name))?;
+        }
+        writer.write_str(rest)
+    }
+}
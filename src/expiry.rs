@@ -0,0 +1,85 @@
+//! Expiry of the filter rules and callsite mutes with a TTL.
+//!
+//! The filter ignores the rules that outlived their TTL by itself,
+//! however they were set: from a control connection, a profile, an
+//! undo, the configuration file or a saved state. The expired rules
+//! and mutes still have to be dropped though, so that they are no
+//! longer listed, and so that the interest of the muted callsites is
+//! evaluated again. This checks the deadlines periodically and drops
+//! what expired, until the shutdown.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use tokio::time;
+use tokio::time::MissedTickBehavior;
+use tracing_subscriber::reload::Handle;
+
+use crate::audit;
+use crate::broadcast;
+use crate::retry;
+use crate::shutdown;
+use crate::siem;
+use crate::siem::SiemEvent;
+
+/// How often the deadlines are checked. TTLs are given in seconds.
+const PERIOD: Duration = Duration::from_secs(1);
+
+/// Who the expirations are attributed to, in the notifications and
+/// the SIEM changelog
+const PEER: &str = "TTL";
+
+/// Drop the rules and mutes of the field filter once they expire,
+/// until the shutdown
+pub async fn run<S>(handle: Handle<DynamicFieldFilter, S>) {
+    let mut interval = time::interval(PERIOD);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = shutdown::wait() => break,
+        }
+        // Only modify the layer when something expired, since this
+        // registers all the callsites again
+        let now = Instant::now();
+        let due = retry::read(&handle, |layer| layer.next_deadline())
+            .ok()
+            .flatten()
+            .is_some_and(|deadline| deadline <= now);
+        if due {
+            expire(&handle);
+        }
+    }
+}
+
+/// Drop the rules and mutes that expired, and report them
+fn expire<S>(handle: &Handle<DynamicFieldFilter, S>) {
+    let mut rules = Vec::new();
+    let mut mutes = Vec::new();
+    let changed = retry::modify(handle, |layer| {
+        rules = layer.expire_rules();
+        mutes = layer.expire_mutes();
+    });
+    if changed.is_err() {
+        return;
+    }
+    for rule in rules {
+        info!(target: audit::TARGET, "filter {rule} expired");
+        expiry_change("expire_rules", rule);
+    }
+    for number in mutes {
+        info!(target: audit::TARGET, callsite = number, "callsite mute expired");
+        expiry_change("expire_mutes", number.to_string());
+    }
+}
+
+/// Record an expiration in the SIEM changelog, and notify the clients
+fn expiry_change(action: &str, detail: String) {
+    broadcast::send(None, PEER, action, &detail);
+    siem::record(SiemEvent::RuleChange {
+        peer: PEER,
+        action,
+        detail,
+    });
+}
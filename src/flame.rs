@@ -0,0 +1,143 @@
+//! Capturing a flamegraph of the time spent in the spans, on demand.
+//!
+//! `FLAME START` starts recording, and `FLAME STOP <path>` writes what
+//! was recorded since to a file, in the folded stacks format of
+//! `inferno` and `flamegraph.pl`: the spans entered, from the
+//! outermost, and how long they were the innermost one entered, in
+//! nanoseconds:
+//!
+//! ```text
+//! loggingdemo::router::add_path;loggingdemo::router::add_route 35200
+//! ```
+//!
+//! ```text
+//! inferno-flamegraph < router.folded > router.svg
+//! ```
+//!
+//! Like with `tracing-flame`, the time is measured between the moments
+//! the spans are entered and exited on each thread. The time spent
+//! outside of any span isn't recorded. The flame layer comes after the
+//! field filter, so the suppressed spans aren't recorded: their time
+//! goes to the spans around them.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tracing::Id;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::SpanRef;
+use tracing_subscriber::Layer;
+
+/// The time spent in each stack since the recording started, if it did
+static RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+
+/// Whether a recording is going on, to skip the spans otherwise
+static RECORDING_ON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// When the spans entered on the current thread last changed
+    static LAST_CHANGE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+struct Recording {
+    started: Instant,
+    /// The nanoseconds spent in each stack, by stack
+    stacks: BTreeMap<String, u64>,
+}
+
+/// Start recording
+pub fn start() -> Result<(), String> {
+    let mut recording = RECORDING.lock().unwrap();
+    if recording.is_some() {
+        return Err("already recording".to_string());
+    }
+    *recording = Some(Recording {
+        started: Instant::now(),
+        stacks: BTreeMap::new(),
+    });
+    RECORDING_ON.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop recording, and write the stacks recorded to a file. Return how
+/// many stacks there were.
+pub fn stop(path: &Path) -> Result<usize, String> {
+    let Some(recording) = RECORDING.lock().unwrap().take() else {
+        return Err("not recording".to_string());
+    };
+    RECORDING_ON.store(false, Ordering::Relaxed);
+    let mut folded = String::new();
+    for (stack, nanos) in &recording.stacks {
+        let _ = writeln!(folded, "{stack} {nanos}");
+    }
+    fs::write(path, folded).map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    Ok(recording.stacks.len())
+}
+
+/// Add the time since the last change on the current thread to a
+/// stack, and make now the last change
+fn record<S>(stack: Option<SpanRef<'_, S>>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let now = Instant::now();
+    let last = LAST_CHANGE.with(|last| last.replace(Some(now)));
+    let mut recording = RECORDING.lock().unwrap();
+    let Some(recording) = recording.as_mut() else {
+        return;
+    };
+    let Some(innermost) = stack else {
+        return;
+    };
+    // The changes made before the recording started don't count
+    let since = last.map_or(recording.started, |last| last.max(recording.started));
+    let mut frames = String::new();
+    for span_ref in innermost.scope().from_root() {
+        if !frames.is_empty() {
+            frames.push(';');
+        }
+        let _ = write!(
+            frames,
+            "{}::{}",
+            span_ref.metadata().target(),
+            span_ref.name()
+        );
+    }
+    *recording.stacks.entry(frames).or_default() += now.duration_since(since).as_nanos() as u64;
+}
+
+/// A layer recording the time spent in the spans
+#[derive(Debug, Default)]
+pub struct FlameGraph;
+
+impl<S> Layer<S> for FlameGraph
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // Until now, the time was spent in the parent span
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if !RECORDING_ON.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(span_ref) = ctx.span(id) {
+            record(span_ref.parent());
+        }
+    }
+
+    // Until now, the time was spent in the span
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if !RECORDING_ON.load(Ordering::Relaxed) {
+            return;
+        }
+        record(ctx.span(id));
+    }
+}
@@ -0,0 +1,238 @@
+//! Shipping the records to Graylog, as GELF messages over UDP, with
+//! the `[gelf]` settings:
+//!
+//! ```toml
+//! [gelf]
+//! address = "127.0.0.1:12201"          # the GELF UDP input
+//! chunk_size = 1420                    # or 8154 on a LAN
+//! ```
+//!
+//! Each event is a message of its own, with its message, level and
+//! target, and the fields of the event and of its spans flattened as
+//! additional fields. A field of the event hides those of its spans
+//! with the same name, and a field of a span those of the outer spans:
+//!
+//! ```json
+//! {"version":"1.1","host":"router1","short_message":"New path","timestamp":1684316832.345,"level":6,"_target":"loggingdemo::router","_spans":"add_path:add_route","_vrf_id":2,"_prefix":"10.10.1.0/24","_seq":7}
+//! ```
+//!
+//! `_seq` counts the messages from 1, so that the messages dropped can
+//! be told, and hides a `seq` field.
+//!
+//! The messages larger than `chunk_size` are split into chunks, up to
+//! 128 of them, and the larger ones are dropped. The field values are
+//! redacted like those of the other outputs.
+//!
+//! The GELF layer comes after the field filter, so Graylog only gets
+//! the records that went through the filters. The span fields are
+//! those recorded by [`crate::live::LiveEvents`]. A record is dropped
+//! rather than holding up the logging thread when the socket can't
+//! take it. The layer is set up at startup, and these settings only
+//! apply after a restart.
+
+use std::io;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use dynamic_field_filter::enrich;
+use dynamic_field_filter::format;
+use dynamic_field_filter::format::FieldValues;
+use dynamic_field_filter::format::SpanFields;
+use serde_json::Map;
+use serde_json::Value;
+use tracing::Event;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::GelfConfig;
+use crate::syslog;
+
+/// The magic bytes starting each chunk
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+
+/// The size of the header of each chunk: the magic bytes, the ID of
+/// the message, and the sequence number and count
+const CHUNK_HEADER: usize = 12;
+
+/// The most chunks a message can be split into
+const MAX_CHUNKS: usize = 128;
+
+/// The GELF layer, if an address is configured
+pub fn layer(config: &GelfConfig) -> Result<Option<Gelf>, String> {
+    let Some(address) = &config.address else {
+        return Ok(None);
+    };
+    if config.chunk_size <= CHUNK_HEADER {
+        return Err(format!(
+            "the GELF chunk size must be more than {CHUNK_HEADER} bytes"
+        ));
+    }
+    let socket =
+        connect(address).map_err(|e| format!("failed to connect to GELF at {address}: {e}"))?;
+    Ok(Some(Gelf {
+        socket,
+        chunk_size: config.chunk_size,
+        host: enrich::value("hostname").unwrap_or_default(),
+        seq: AtomicU64::new(0),
+    }))
+}
+
+/// Connect a UDP socket to an address, as `<host>:<port>`
+fn connect(address: &str) -> io::Result<UdpSocket> {
+    let server = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected <host>:<port>"))?;
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// A layer sending the events to Graylog
+#[derive(Debug)]
+pub struct Gelf {
+    socket: UdpSocket,
+    chunk_size: usize,
+    host: String,
+    /// The number of messages sent, or dropped
+    seq: AtomicU64,
+}
+
+impl Gelf {
+    /// Send a message, in chunks if it doesn't fit in one datagram
+    fn send(&self, message: &[u8]) {
+        // There is nowhere to report the failures to
+        if message.len() <= self.chunk_size {
+            let _ = self.socket.send(message);
+            return;
+        }
+        let chunks = message.chunks(self.chunk_size - CHUNK_HEADER);
+        let count = chunks.len();
+        if count > MAX_CHUNKS {
+            return;
+        }
+        let id = rand::random::<u64>().to_be_bytes();
+        let mut datagram = Vec::with_capacity(self.chunk_size);
+        for (sequence, chunk) in chunks.enumerate() {
+            datagram.clear();
+            datagram.extend_from_slice(&CHUNK_MAGIC);
+            datagram.extend_from_slice(&id);
+            datagram.push(sequence as u8);
+            datagram.push(count as u8);
+            datagram.extend_from_slice(chunk);
+            let _ = self.socket.send(&datagram);
+        }
+    }
+}
+
+impl<S> Layer<S> for Gelf
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |elapsed| (elapsed.as_millis() as f64) / 1000.0);
+
+        let mut message = Map::new();
+        message.insert("version".to_string(), "1.1".into());
+        message.insert("host".to_string(), self.host.clone().into());
+        message.insert(
+            "short_message".to_string(),
+            fields.message.clone().unwrap_or_default().into(),
+        );
+        message.insert("timestamp".to_string(), timestamp.into());
+        message.insert(
+            "level".to_string(),
+            syslog::severity(metadata.level()).into(),
+        );
+        message.insert("_target".to_string(), metadata.target().into());
+        if let Some(file) = metadata.file() {
+            message.insert("_file".to_string(), file.into());
+        }
+        if let Some(line) = metadata.line() {
+            message.insert("_line".to_string(), line.into());
+        }
+
+        // The fields of the outermost spans first, so that those of the
+        // inner spans and of the event replace them
+        let mut additional = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            let mut spans = Vec::new();
+            for span_ref in scope.from_root() {
+                spans.push(span_ref.name());
+                if let Some(SpanFields(fields)) = span_ref.extensions().get::<SpanFields>() {
+                    additional.extend(
+                        fields
+                            .values
+                            .iter()
+                            .map(|(name, value)| (name.to_string(), value.clone())),
+                    );
+                }
+            }
+            message.insert("_spans".to_string(), spans.join(":").into());
+        }
+        additional.extend(
+            fields
+                .values
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone())),
+        );
+        additional.extend(
+            enrich::values()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value)),
+        );
+        for (name, value) in additional {
+            let value = format::redacted(&name, &value);
+            message.insert(field_name(&name), field_value(&value));
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        message.insert("_seq".to_string(), seq.into());
+
+        if let Ok(message) = serde_json::to_vec(&Value::Object(message)) {
+            self.send(&message);
+        }
+    }
+}
+
+/// The name of an additional field: a field name with an underscore
+/// before it, made of letters, digits, `_`, `.` and `-` only. `_id` is
+/// reserved.
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') => c,
+            _ => '_',
+        })
+        .collect();
+    if name == "id" {
+        return "__id".to_string();
+    }
+    format!("_{name}")
+}
+
+/// The value of an additional field, a number if it is an integer so
+/// that Graylog can compare them, and a string otherwise
+fn field_value(value: &str) -> Value {
+    match value.parse::<i64>() {
+        Ok(number) => number.into(),
+        Err(_) => value.into(),
+    }
+}
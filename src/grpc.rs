@@ -0,0 +1,178 @@
+//! The gRPC control service, for programs, on its own port.
+//!
+//! The service is described in `proto/control.proto`. The changes go
+//! through the commands of the text protocol, see
+//! [`crate::control::Session`], so they are checked, recorded and
+//! notified the same way. When a token is configured, the clients
+//! give it in the `authorization` metadata, as `Bearer <token>`.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use dynamic_field_filter::protocol;
+use dynamic_field_filter::stats::StatsReporter;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tokio_stream::StreamExt;
+use tonic::transport::Server;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Registry;
+
+use crate::config::Config;
+use crate::config::FmtLayer;
+use crate::control;
+use crate::control::Session;
+use crate::live;
+use crate::sink::ShardedSink;
+
+use proto::filter_control_server::FilterControl;
+use proto::filter_control_server::FilterControlServer;
+
+pub mod proto {
+    tonic::include_proto!("filtering.v1");
+}
+
+/// The gRPC control service, acting on the same layers as the TCP
+/// control connection
+pub struct ControlService<S, T, U> {
+    pub layer_handle: Handle<DynamicFieldFilter, S>,
+    pub level_handle: Handle<EnvFilter, U>,
+    pub sink_handle: Handle<ShardedSink, T>,
+    pub fmt_handle: Handle<FmtLayer, Registry>,
+    pub reporter: Arc<StatsReporter>,
+    pub config: Arc<RwLock<Config>>,
+}
+
+impl<S: 'static, T: 'static, U: 'static> ControlService<S, T, U> {
+    /// Serve the requests on `addr`, until the server fails
+    pub async fn serve(self, addr: SocketAddr) {
+        info!("gRPC control service listening on {addr}");
+        let server = Server::builder()
+            .add_service(FilterControlServer::new(self))
+            .serve(addr);
+        if let Err(e) = server.await {
+            error!("gRPC control service failed: {e}");
+        }
+    }
+
+    /// Run a command of the text protocol for a request, as a client
+    /// of its own
+    fn execute<R>(&self, request: &Request<R>, command: &str) -> Result<proto::Reply, Status> {
+        let peer = match request.remote_addr() {
+            Some(addr) => format!("grpc {addr}"),
+            None => "grpc".to_string(),
+        };
+        let mut session = Session::new(
+            peer,
+            self.layer_handle.clone(),
+            self.level_handle.clone(),
+            self.sink_handle.clone(),
+            self.fmt_handle.clone(),
+            self.reporter.clone(),
+            self.config.clone(),
+        );
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(token) = token {
+            session
+                .execute(&format!("AUTH {token}"))
+                .map_err(Status::unauthenticated)?;
+        }
+        match session.execute(command) {
+            Ok(reply) => Ok(proto::Reply {
+                output: reply.lines().map(str::to_string).collect(),
+            }),
+            Err(e) if e == control::NOT_AUTHENTICATED => Err(Status::unauthenticated(e)),
+            Err(e) => Err(Status::invalid_argument(e)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: 'static, T: 'static, U: 'static> FilterControl for ControlService<S, T, U> {
+    async fn set_filter(
+        &self,
+        request: Request<proto::SetFilterRequest>,
+    ) -> Result<Response<proto::Reply>, Status> {
+        let filter = protocol::Request::Filter {
+            rule: request.get_ref().rule.clone(),
+            options: BTreeMap::from_iter(request.get_ref().options.clone()),
+        };
+        let command = filter.to_command().unwrap_or_default();
+        self.execute(&request, &command).map(Response::new)
+    }
+
+    async fn remove_filter(
+        &self,
+        request: Request<proto::RemoveFilterRequest>,
+    ) -> Result<Response<proto::Reply>, Status> {
+        let command = format!("UNFILTER {}", request.get_ref().field);
+        self.execute(&request, &command).map(Response::new)
+    }
+
+    async fn list_filters(
+        &self,
+        _: Request<proto::ListFiltersRequest>,
+    ) -> Result<Response<proto::ListFiltersReply>, Status> {
+        let filters = self
+            .layer_handle
+            .with_current(|layer| {
+                layer
+                    .filters()
+                    .into_iter()
+                    .map(|rule| proto::Filter {
+                        rule: rule.to_string(),
+                        field: rule.field.clone(),
+                        active: rule.is_active(),
+                        expired: rule.is_expired(),
+                        hits: rule.hits(),
+                        remaining: rule.remaining(),
+                        ttl_left_secs: rule.time_left().map(|ttl| ttl.as_secs()),
+                    })
+                    .collect()
+            })
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(proto::ListFiltersReply { filters }))
+    }
+
+    async fn stats(
+        &self,
+        _: Request<proto::StatsRequest>,
+    ) -> Result<Response<proto::StatsReply>, Status> {
+        let stats = self
+            .layer_handle
+            .with_current(|layer| layer.stats().snapshot())
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(proto::StatsReply {
+            spans_evaluated: stats.spans_evaluated,
+            spans_suppressed: stats.spans_suppressed,
+            events_passed: stats.events_passed,
+            events_suppressed: stats.events_suppressed,
+            spans_dry_run: stats.spans_dry_run,
+            events_dry_run: stats.events_dry_run,
+        }))
+    }
+
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn stream_events(
+        &self,
+        request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let query = request.into_inner().fields.into_iter().collect();
+        let events = ReceiverStream::new(live::subscribe(query, Default::default()))
+            .map(|live::StreamedEvent { seq, line }| Ok(proto::Event { line, seq }));
+        Ok(Response::new(Box::pin(events)))
+    }
+}
@@ -0,0 +1,334 @@
+//! Hot reloading of the configuration file.
+//!
+//! When the file changes on disk, the filter, routes and fmt settings
+//! are applied again, through the reload handles for the layers, and
+//! what changed is logged. The other settings only apply at startup.
+
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use dynamic_field_filter::diagnostics;
+use dynamic_field_filter::directive;
+use dynamic_field_filter::directive::Directive;
+use dynamic_field_filter::enrich;
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use dynamic_field_filter::matcher;
+use notify::EventKind;
+use notify::RecursiveMode;
+use notify::Watcher;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::EnvFilter;
+
+use crate::broadcast;
+use crate::config::Config;
+use crate::config::ControlConfig;
+use crate::config::FmtLayer;
+use crate::retry;
+use crate::routes;
+use crate::shutdown;
+use crate::siem;
+use crate::siem::SiemEvent;
+use crate::tee;
+
+/// How long to wait for an editor to finish writing the file, so that
+/// a save is applied once
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// The reload handles of the layers that the configuration file sets
+pub struct Handles<S, T, U> {
+    pub filter: Handle<DynamicFieldFilter, S>,
+    pub level: Handle<EnvFilter, T>,
+    pub fmt: Handle<FmtLayer, U>,
+}
+
+/// Watch the configuration file, and apply it again whenever it
+/// changes, until the shutdown
+pub async fn watch<S, T, U>(config: &RwLock<Config>, handles: &Handles<S, T, U>) {
+    let Some(path) = config.read().unwrap().path.clone() else {
+        return;
+    };
+    // Watch the directory rather than the file itself: editors often
+    // replace the file instead of writing to it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // The watcher calls this from its own thread
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!(target: diagnostics::TARGET, "failed to watch {}: {e}", path.display());
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        error!(target: diagnostics::TARGET, "failed to watch {}: {e}", path.display());
+        return;
+    }
+    loop {
+        let event = tokio::select! {
+            Some(event) = rx.recv() => event,
+            () = shutdown::wait() => break,
+        };
+        let Ok(event) = event else {
+            continue;
+        };
+        let changed = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == path.file_name());
+        if !changed {
+            continue;
+        }
+        time::sleep(SETTLE_TIME).await;
+        while rx.try_recv().is_ok() {}
+        reload(config, handles);
+    }
+}
+
+/// Read the configuration file again, and apply what changed. If a
+/// layer fails to change, the reload stops there and the previous
+/// configuration is kept, so that the next reload tries again.
+pub fn reload<S, T, U>(config: &RwLock<Config>, handles: &Handles<S, T, U>) {
+    // Hold the configuration until the end, so that the runtime
+    // changes made meanwhile from the control connections aren't lost
+    let mut config = config.write().unwrap();
+    if config.path.is_none() {
+        return;
+    }
+    // The environment and the command-line arguments still override
+    // the file, and so do the runtime changes
+    let old = config.clone();
+    let new = match Config::from_args() {
+        Ok(mut new) => {
+            new.keep_runtime_settings(&old);
+            new
+        }
+        Err(e) => {
+            error!(target: diagnostics::TARGET, "not reloading the configuration: {e}");
+            return;
+        }
+    };
+    if new == old {
+        return;
+    }
+
+    // The filter state of the file replaces the rules, the filters
+    // given as text are added again on top of it
+    if let Some(profile) = new.filter.clone().filter(|_| new.filter != old.filter) {
+        let summary = profile.to_string();
+        let mut invalid = Vec::new();
+        if retry::modify(&handles.filter, |layer| {
+            layer.change(|layer| {
+                layer.apply_profile(profile);
+                invalid = layer.add_rules(new.filters.iter().map(String::as_str));
+            })
+        })
+        .is_err()
+        {
+            return;
+        }
+        warn_invalid_rules(&invalid);
+        info!(target: diagnostics::TARGET, "config: filter state applied ({summary})");
+        config_change("load_filters", summary);
+    }
+
+    // The rules of the old directives are removed, those of the new
+    // ones replace them
+    if new.directives != old.directives {
+        let parse = |directives: &Option<String>| {
+            directives
+                .as_deref()
+                .map(|directives| directive::parse_directives(directives).unwrap_or_default())
+                .unwrap_or_default()
+        };
+        let (removed, added) = (parse(&old.directives), parse(&new.directives));
+        if retry::modify(&handles.filter, |layer| {
+            layer.change(|layer| {
+                for directive in removed {
+                    if let Directive::Rule { field, .. } = directive {
+                        layer.remove_rule(&field);
+                    }
+                }
+                for directive in added {
+                    layer.apply_directive(directive);
+                }
+            })
+        })
+        .is_err()
+        {
+            return;
+        }
+        let directives = new.directives.clone().unwrap_or_default();
+        info!(
+            target: diagnostics::TARGET,
+            "config: directives changed from {:?} to {directives:?}",
+            old.directives.as_deref().unwrap_or_default()
+        );
+        config_change("directive", directives);
+    }
+
+    let removed: Vec<&str> = old
+        .filters
+        .iter()
+        .filter(|rule| !new.filters.contains(rule))
+        .map(String::as_str)
+        .collect();
+    let added: Vec<&str> = new
+        .filters
+        .iter()
+        .filter(|rule| !old.filters.contains(rule))
+        .map(String::as_str)
+        .collect();
+    if !removed.is_empty() || !added.is_empty() {
+        let removed_fields: Vec<String> = removed
+            .iter()
+            .filter_map(|rule| matcher::parse_rule(rule).ok())
+            .map(|(field, _)| field)
+            .collect();
+        let mut invalid = Vec::new();
+        if retry::modify(&handles.filter, |layer| {
+            for field in &removed_fields {
+                layer.remove_rule(field);
+            }
+            invalid = layer.add_rules(added.iter().copied());
+        })
+        .is_err()
+        {
+            return;
+        }
+        warn_invalid_rules(&invalid);
+        for rule in &removed {
+            info!(target: diagnostics::TARGET, "config: filter {rule} removed");
+            config_change("remove_filter", rule.to_string());
+        }
+        for rule in &added {
+            info!(target: diagnostics::TARGET, "config: filter {rule} added");
+            config_change("set_filter", rule.to_string());
+        }
+    }
+
+    if new.env_filter != old.env_filter {
+        let directives = new.env_filter.clone().unwrap_or_default();
+        if retry::reload(&handles.level, new.env_filter()).is_err() {
+            return;
+        }
+        info!(
+            target: diagnostics::TARGET,
+            "config: env_filter changed from {:?} to {directives:?}",
+            old.env_filter.as_deref().unwrap_or_default()
+        );
+        config_change("set_level", directives);
+    }
+
+    if new.diagnostics != old.diagnostics {
+        if retry::reload(&handles.level, new.env_filter()).is_err() {
+            return;
+        }
+        info!(
+            target: diagnostics::TARGET,
+            "config: diagnostics changed from {:?} to {:?}",
+            old.diagnostics.as_deref().unwrap_or("none"),
+            new.diagnostics.as_deref().unwrap_or("none")
+        );
+    }
+
+    if new.enrich != old.enrich {
+        enrich::set_enabled(new.enrich);
+        info!(
+            target: diagnostics::TARGET,
+            "config: enrich changed from {} to {}",
+            old.enrich,
+            new.enrich
+        );
+    }
+
+    if new.routes != old.routes {
+        if let Err(e) = routes::replace(&new.routes) {
+            error!(target: diagnostics::TARGET, "config: failed to change the routes: {e}");
+            return;
+        }
+        info!(
+            target: diagnostics::TARGET,
+            "config: routes changed from {:?} to {:?}",
+            old.routes,
+            new.routes
+        );
+    }
+
+    if new.rotation != old.rotation {
+        routes::set_rotation(new.rotation.clone());
+        info!(
+            target: diagnostics::TARGET,
+            "config: rotation changed from {:?} to {:?}",
+            old.rotation,
+            new.rotation
+        );
+    }
+
+    if new.fmt != old.fmt {
+        if new.fmt.tee != old.fmt.tee {
+            if let Err(e) = tee::set(new.fmt.tee.as_deref()) {
+                error!(target: diagnostics::TARGET, "config: failed to open the tee file: {e}");
+                return;
+            }
+        }
+        if retry::reload(&handles.fmt, new.fmt.layer()).is_err() {
+            return;
+        }
+        info!(target: diagnostics::TARGET, "config: fmt changed from {:?} to {:?}", old.fmt, new.fmt);
+    }
+
+    // The token is checked on every AUTH, the rest of the control
+    // settings only apply to the listeners started at startup
+    let control_changed = ControlConfig {
+        token: None,
+        ..new.control.clone()
+    } != ControlConfig {
+        token: None,
+        ..old.control.clone()
+    };
+    if new.listen != old.listen
+        || control_changed
+        || new.simulator != old.simulator
+        || new.journald != old.journald
+        || new.syslog != old.syslog
+        || new.gelf != old.gelf
+        || new.otlp != old.otlp
+        || new.metrics != old.metrics
+    {
+        warn!(
+            target: diagnostics::TARGET,
+            "config: the listen address, control, simulator and output settings only apply \
+             after a restart"
+        );
+    }
+
+    *config = new;
+}
+
+/// Record a change made by the configuration file in the SIEM
+/// changelog, and notify the control clients
+fn config_change(action: &str, detail: String) {
+    broadcast::send(None, "config file", action, &detail);
+    siem::record(SiemEvent::RuleChange {
+        peer: "config file",
+        action,
+        detail,
+    });
+}
+
+/// Report the filter rules of the configuration file that were
+/// ignored because they are invalid
+fn warn_invalid_rules(invalid: &[(&str, String)]) {
+    for (rule, e) in invalid {
+        warn!(target: diagnostics::TARGET, "config: ignoring invalid rule {rule}: {e}");
+    }
+}
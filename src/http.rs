@@ -0,0 +1,368 @@
+//! The HTTP admin API, for curl, dashboards and orchestration tools.
+//!
+//! The requests and responses are in JSON, except for the metrics:
+//!
+//! ```text
+//! GET    /filters          the filter rules
+//! PUT    /filters          {"rule":"vrf_id=1","options":{"ttl":"600"}}
+//! DELETE /filters/{field}  remove the rule on a field
+//! GET    /state            the whole filter state, as saved by SAVE
+//! PUT    /state            replace it, as LOAD does
+//! GET    /stats            the filter counters
+//! GET    /metrics          the record and filter counters, for
+//!                          Prometheus, see [`crate::metrics`]
+//! GET    /health           {"status":"ok"}
+//! GET    /ws/events?vrf_id=1   a WebSocket of the matching events
+//! ```
+//!
+//! `GET /` serves a dashboard using these, to see and change the
+//! filters, and to tail the events, from a browser.
+//!
+//! The filter state is in the schema of
+//! [`SavedFilters`](dynamic_field_filter::filter::SavedFilters), e.g.
+//! `{"current":{"rules":[{"field":"vrf_id","matcher":{"equals":"1"}}]}}`.
+//!
+//! Like those of the gRPC service, the changes go through the
+//! commands of the text protocol, and the token, if one is configured,
+//! is given in the `Authorization` header, as `Bearer <token>`.
+//!
+//! The WebSocket sends the events that go through the filters and
+//! whose fields, or those of their spans, have the values of the
+//! query, one formatted event per text message, after its sequence
+//! number, e.g. `seq=42 ...`, see [`crate::live`].
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use axum::extract::ws::Message;
+use axum::extract::ws::WebSocket;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::ConnectInfo;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::http::header::AUTHORIZATION;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::http::HeaderName;
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::response::Response as HttpResponse;
+use axum::routing::delete;
+use axum::routing::get;
+use axum::Json;
+use axum::Router;
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use dynamic_field_filter::filter::SavedFilters;
+use dynamic_field_filter::protocol;
+use dynamic_field_filter::protocol::Response;
+use dynamic_field_filter::stats::StatsReporter;
+use dynamic_field_filter::stats::StatsSnapshot;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Registry;
+
+use crate::config::Config;
+use crate::config::FmtLayer;
+use crate::control;
+use crate::control::Session;
+use crate::live;
+use crate::metrics;
+use crate::sink::ShardedSink;
+
+/// The HTTP admin API, acting on the same layers as the TCP control
+/// connection
+pub struct AdminApi<S, T, U> {
+    pub layer_handle: Handle<DynamicFieldFilter, S>,
+    pub level_handle: Handle<EnvFilter, U>,
+    pub sink_handle: Handle<ShardedSink, T>,
+    pub fmt_handle: Handle<FmtLayer, Registry>,
+    pub reporter: Arc<StatsReporter>,
+    pub config: Arc<RwLock<Config>>,
+}
+
+/// The body of `PUT /filters`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetFilter {
+    rule: String,
+    /// Options named like those of the FILTER command
+    #[serde(default)]
+    options: BTreeMap<String, String>,
+}
+
+/// A filter rule, as returned by `GET /filters`
+#[derive(Debug, Serialize)]
+struct Filter {
+    /// The rule, as printed by LIST
+    rule: String,
+    field: String,
+    active: bool,
+    expired: bool,
+    hits: u64,
+    /// Matches left in the rule's budget, if it has one
+    remaining: Option<u64>,
+    /// Seconds left before the rule expires, if it has a TTL
+    ttl_left_secs: Option<u64>,
+}
+
+/// A response, with its status code
+type Reply<T> = (StatusCode, Json<T>);
+
+impl<S: 'static, T: 'static, U: 'static> AdminApi<S, T, U> {
+    /// Serve the requests on `addr`, until the server fails
+    pub async fn serve(self, addr: SocketAddr) {
+        let app = Router::new()
+            .route("/", get(dashboard))
+            .route(
+                "/filters",
+                get(list_filters::<S, T, U>).put(set_filter::<S, T, U>),
+            )
+            .route("/filters/{field}", delete(remove_filter::<S, T, U>))
+            .route("/state", get(state::<S, T, U>).put(restore::<S, T, U>))
+            .route("/stats", get(stats::<S, T, U>))
+            .route("/metrics", get(metrics::<S, T, U>))
+            .route("/health", get(health::<S, T, U>))
+            .route("/ws/events", get(stream_events))
+            .with_state(Arc::new(self));
+        let result = async {
+            let listener = TcpListener::bind(addr).await?;
+            info!("HTTP admin API listening on {addr}");
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        };
+        if let Err(e) = result.await {
+            error!("HTTP admin API failed: {e}");
+        }
+    }
+
+    /// Open a session for a request, as a client of its own,
+    /// authenticated with the token of the request if any
+    fn session(
+        &self,
+        peer: SocketAddr,
+        headers: &HeaderMap,
+    ) -> Result<Session<S, T, U>, Reply<Response>> {
+        let mut session = Session::new(
+            format!("http {peer}"),
+            self.layer_handle.clone(),
+            self.level_handle.clone(),
+            self.sink_handle.clone(),
+            self.fmt_handle.clone(),
+            self.reporter.clone(),
+            self.config.clone(),
+        );
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(token) = token {
+            if let Err(e) = session.execute(&format!("AUTH {token}")) {
+                return Err((StatusCode::UNAUTHORIZED, Json(Response::from(Err(e)))));
+            }
+        }
+        Ok(session)
+    }
+
+    /// Run a command of the text protocol for a request
+    fn execute(&self, peer: SocketAddr, headers: &HeaderMap, command: &str) -> Reply<Response> {
+        let mut session = match self.session(peer, headers) {
+            Ok(session) => session,
+            Err(reply) => return reply,
+        };
+        let result = session.execute(command);
+        (status(&result), Json(Response::from(result)))
+    }
+}
+
+/// The status code of the reply to a command
+fn status<T>(result: &Result<T, String>) -> StatusCode {
+    match result {
+        Ok(_) => StatusCode::OK,
+        Err(e) if e == control::NOT_AUTHENTICATED => StatusCode::UNAUTHORIZED,
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+async fn list_filters<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+) -> Reply<Vec<Filter>> {
+    let filters = api.layer_handle.with_current(|layer| {
+        layer
+            .filters()
+            .into_iter()
+            .map(|rule| Filter {
+                rule: rule.to_string(),
+                field: rule.field.clone(),
+                active: rule.is_active(),
+                expired: rule.is_expired(),
+                hits: rule.hits(),
+                remaining: rule.remaining(),
+                ttl_left_secs: rule.time_left().map(|ttl| ttl.as_secs()),
+            })
+            .collect()
+    });
+    match filters {
+        Ok(filters) => (StatusCode::OK, Json(filters)),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, Json(Vec::new())),
+    }
+}
+
+async fn set_filter<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<SetFilter>,
+) -> Reply<Response> {
+    let filter = protocol::Request::Filter {
+        rule: body.rule,
+        options: body.options,
+    };
+    let command = filter.to_command().unwrap_or_default();
+    api.execute(peer, &headers, &command)
+}
+
+async fn remove_filter<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(field): Path<String>,
+) -> Reply<Response> {
+    let exists = api
+        .layer_handle
+        .with_current(|layer| layer.filters().iter().any(|rule| rule.field == field))
+        .unwrap_or_default();
+    if !exists {
+        let error = Err(format!("no filter on {field}"));
+        return (StatusCode::NOT_FOUND, Json(Response::from(error)));
+    }
+    api.execute(peer, &headers, &format!("UNFILTER {field}"))
+}
+
+async fn state<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+) -> Reply<SavedFilters> {
+    match api.layer_handle.with_current(|layer| layer.saved_filters()) {
+        Ok(saved) => (StatusCode::OK, Json(saved)),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(SavedFilters::default()),
+        ),
+    }
+}
+
+/// Replace the whole filter state, the profiles included
+async fn restore<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(saved): Json<SavedFilters>,
+) -> Reply<Response> {
+    let mut session = match api.session(peer, &headers) {
+        Ok(session) => session,
+        Err(reply) => return reply,
+    };
+    let result = session
+        .restore(saved, "the HTTP API")
+        .map(|()| String::new());
+    (status(&result), Json(Response::from(result)))
+}
+
+async fn stats<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+) -> Reply<StatsSnapshot> {
+    match api
+        .layer_handle
+        .with_current(|layer| layer.stats().snapshot())
+    {
+        Ok(stats) => (StatusCode::OK, Json(stats)),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(StatsSnapshot::default()),
+        ),
+    }
+}
+
+/// Serve the counters in the Prometheus text format
+async fn metrics<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+) -> (StatusCode, [(HeaderName, &'static str); 1], String) {
+    let filter = api.layer_handle.with_current(|layer| {
+        let rule_hits: Vec<_> = layer
+            .filters()
+            .into_iter()
+            .map(|rule| (rule.to_string(), rule.hits()))
+            .collect();
+        (layer.stats().snapshot(), rule_hits)
+    });
+    let content_type = [(CONTENT_TYPE, "text/plain; version=0.0.4")];
+    match filter {
+        Ok((stats, rule_hits)) => (
+            StatusCode::OK,
+            content_type,
+            metrics::render(&stats, &rule_hits),
+        ),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, content_type, String::new()),
+    }
+}
+
+/// Report whether the filter layer is still there to be changed
+async fn health<S: 'static, T: 'static, U: 'static>(
+    State(api): State<Arc<AdminApi<S, T, U>>>,
+) -> Reply<BTreeMap<&'static str, &'static str>> {
+    match api.layer_handle.with_current(|_| ()) {
+        Ok(()) => (StatusCode::OK, Json(BTreeMap::from([("status", "ok")]))),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(BTreeMap::from([("status", "unavailable")])),
+        ),
+    }
+}
+
+/// Serve the dashboard, a single page that only uses the API
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+/// Stream the events matching the query parameters over a WebSocket
+async fn stream_events(
+    upgrade: WebSocketUpgrade,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(query): Query<Vec<(String, String)>>,
+) -> HttpResponse {
+    upgrade.on_upgrade(move |socket| send_events(socket, peer, query))
+}
+
+/// Send the events matching `query` until the client goes away
+async fn send_events(mut socket: WebSocket, peer: SocketAddr, query: live::Query) {
+    info!("streaming events to {peer}");
+    let mut events = live::subscribe(query, Default::default());
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(live::StreamedEvent { seq, line }) = event else {
+                    break;
+                };
+                let line = format!("seq={seq} {line}");
+                if socket.send(Message::Text(line.into())).await.is_err() {
+                    break;
+                }
+            }
+            // Nothing is expected from the client, but reading is how
+            // a close is noticed
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    info!("stopped streaming events to {peer}");
+}
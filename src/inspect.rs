@@ -0,0 +1,85 @@
+//! Inspection of live spans, to debug filtering decisions on a
+//! running process.
+
+use std::fmt::Write;
+
+use dynamic_field_filter::format::SpanFields;
+use dynamic_field_filter::redact::RedactingFields;
+use tracing::dispatcher;
+use tracing::Id;
+use tracing_subscriber::fmt::FormattedFields;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::SpanRef;
+use tracing_subscriber::Registry;
+
+/// Run `f` on the span with the given ID, if it is still alive. This
+/// only works if the global subscriber is built on a `Registry`.
+fn with_span<T>(id: &Id, f: impl FnOnce(SpanRef<'_, Registry>) -> T) -> Option<T> {
+    let mut f = Some(f);
+    dispatcher::get_default(|dispatch| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        registry.span(id).map(f.take()?)
+    })
+}
+
+fn verdict(disabled: bool) -> &'static str {
+    if disabled {
+        "disabled"
+    } else {
+        "enabled"
+    }
+}
+
+/// Return a one-line summary of the span with the given ID
+pub fn summarize_span(id: &Id, is_disabled: impl Fn(&Id) -> bool) -> Option<String> {
+    with_span(id, |span_ref| {
+        format!(
+            "{} {} ({})",
+            id.into_u64(),
+            span_ref.name(),
+            verdict(is_disabled(id))
+        )
+    })
+}
+
+/// Return a multi-line description of the span with the given ID:
+/// its metadata, fields, extensions, filter verdict, and ancestry.
+/// `is_disabled` tells whether the filter disabled a given span.
+pub fn describe_span(id: &Id, is_disabled: impl Fn(&Id) -> bool) -> Option<String> {
+    with_span(id, |span_ref| {
+        let metadata = span_ref.metadata();
+        let mut out = String::new();
+        let _ = writeln!(out, "span {} {}", id.into_u64(), span_ref.name());
+        let _ = writeln!(out, "  target: {}", metadata.target());
+        let _ = writeln!(out, "  level: {}", metadata.level());
+        if let (Some(file), Some(line)) = (metadata.file(), metadata.line()) {
+            let _ = writeln!(out, "  location: {file}:{line}");
+        }
+
+        let extensions = span_ref.extensions();
+        let mut names = Vec::new();
+        if let Some(fields) = extensions.get::<FormattedFields<RedactingFields>>() {
+            let _ = writeln!(out, "  fields: {fields}");
+            names.push("FormattedFields");
+        }
+        if extensions.get::<SpanFields>().is_some() {
+            names.push("SpanFields");
+        }
+        let _ = writeln!(out, "  extensions: {}", names.join(", "));
+        drop(extensions);
+
+        let _ = writeln!(out, "  filter: {}", verdict(is_disabled(id)));
+        let _ = writeln!(out, "  ancestry:");
+        for ancestor in span_ref.scope().skip(1) {
+            let ancestor_id = ancestor.id();
+            let _ = writeln!(
+                out,
+                "    {} {} ({})",
+                ancestor_id.into_u64(),
+                ancestor.name(),
+                verdict(is_disabled(&ancestor_id))
+            );
+        }
+        out
+    })
+}
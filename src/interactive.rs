@@ -0,0 +1,153 @@
+//! The interactive mode of the control connection, for those typing
+//! the commands in netcat or telnet.
+//!
+//! After `INTERACTIVE`, the server prompts for each command, and the
+//! errors come with the usage of the command, as given by `HELP`, or
+//! with the commands whose name is close to a mistyped one. Command
+//! names may be typed in lower case.
+//!
+//! A command may span several lines: a line ending with `\` goes on
+//! with the next one, and so does a rule that isn't complete yet:
+//!
+//! ```text
+//! > FILTER busy_us
+//! ... > 5ms \
+//! ... TTL 600
+//! ```
+//!
+//! An empty line sends what was typed so far. `INTERACTIVE OFF` goes
+//! back to the plain protocol.
+
+use crate::control;
+use crate::control::COMMANDS;
+use crate::control::RULE_OPTIONS;
+
+/// The prompt for a command
+pub const PROMPT: &str = "> ";
+
+/// The prompt for the next line of a command
+pub const CONTINUATION_PROMPT: &str = "... ";
+
+/// The reply to `INTERACTIVE`
+pub const WELCOME: &str =
+    "interactive mode, type HELP for the commands, INTERACTIVE OFF to leave\n";
+
+/// The commands whose arguments start with a rule, and how many words
+/// come before it
+const RULE_COMMANDS: &[(&str, usize)] = &[("FILTER", 1), ("SHADOW", 1), ("SESSION", 2)];
+
+/// The lines of a command being typed
+#[derive(Debug, Default)]
+pub struct Input {
+    partial: String,
+}
+
+impl Input {
+    /// Add a line, and return the command once it is complete
+    pub fn push(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        let (line, continued) = match line.strip_suffix('\\') {
+            Some(line) => (line.trim_end(), true),
+            None => (line, false),
+        };
+        let sent = line.is_empty() && !self.partial.is_empty();
+        if !line.is_empty() {
+            if !self.partial.is_empty() {
+                self.partial.push(' ');
+            }
+            self.partial.push_str(line);
+        }
+        if !sent && (continued || is_incomplete(&self.partial)) {
+            return None;
+        }
+        let command = std::mem::take(&mut self.partial);
+        // Command names are case insensitive, their arguments aren't
+        Some(match command.split_once(' ') {
+            Some((name, args)) => format!("{} {args}", name.to_uppercase()),
+            None => command.to_uppercase(),
+        })
+    }
+}
+
+/// Whether a command is a rule that the next line should complete:
+/// the rule has no operator yet, or nothing after its operator, or an
+/// option is missing its value
+fn is_incomplete(command: &str) -> bool {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let Some(name) = words.first().map(|name| name.to_uppercase()) else {
+        return false;
+    };
+    let Some(&(_, skip)) = RULE_COMMANDS.iter().find(|(command, _)| *command == name) else {
+        return false;
+    };
+    // SHADOW CLEAR and the SESSION commands other than FILTER have no
+    // rule
+    match (name.as_str(), words.get(1)) {
+        ("SHADOW", Some(&"CLEAR")) => return false,
+        ("SESSION", Some(&"FILTER")) | ("SESSION", None) => {}
+        ("SESSION", Some(_)) => return false,
+        _ => {}
+    }
+    let args = words.get(skip..).unwrap_or_default();
+    // The scope of a rule, such as @thread=bgp, comes before it
+    let scopes = args.iter().take_while(|word| word.starts_with('@')).count();
+    let args = &args[scopes..];
+    let end = args
+        .iter()
+        .position(|word| RULE_OPTIONS.contains(word))
+        .unwrap_or(args.len());
+    let rule = args[..end].join(" ");
+    if let Some(position) = args[..end]
+        .iter()
+        .position(|word| *word == "contains" || *word == "within")
+    {
+        return position + 1 == end || args.last().is_some_and(|word| RULE_OPTIONS.contains(word));
+    }
+    let operator = rule.find(['<', '>', '=', '~']);
+    operator.is_none_or(|start| {
+        rule[start..]
+            .trim_start_matches(['<', '>', '=', '~'])
+            .is_empty()
+    }) || args.last().is_some_and(|word| RULE_OPTIONS.contains(word))
+}
+
+/// Explain why a command failed: give its usage along with the error,
+/// or suggest the commands the client may have meant
+pub fn explain(command: &str, error: &str) -> String {
+    let name = command.split_whitespace().next().unwrap_or_default();
+    if error.starts_with("usage:") {
+        return format!("error: {error}\n");
+    }
+    // Some commands are unknown with the wrong arguments, e.g. MUTE
+    // without CALLSITE
+    if let Some(command) = control::command(name) {
+        return format!("error: {error}\nusage: {}\n", command.usage());
+    }
+    let close: Vec<&str> = COMMANDS
+        .iter()
+        .map(|command| command.name)
+        .filter(|command| {
+            command.starts_with(name) || name.starts_with(command) || distance(command, name) <= 2
+        })
+        .collect();
+    if close.is_empty() {
+        format!("error: {error}, type HELP for the commands\n")
+    } else {
+        format!("error: {error}, did you mean {}?\n", close.join(" or "))
+    }
+}
+
+/// The edit distance between two words
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
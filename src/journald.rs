@@ -0,0 +1,41 @@
+//! Sending the records to the systemd journal, with the `[journald]`
+//! settings:
+//!
+//! ```toml
+//! [journald]
+//! enabled = true
+//! identifier = "loggingdemo"           # SYSLOG_IDENTIFIER, the name
+//!                                      # of the binary by default
+//! field_prefix = "F"                   # of the event fields, or ""
+//! ```
+//!
+//! The journald layer comes after the field filter, like the sharded
+//! sink, so the journal only gets the records that went through the
+//! filters. Each field of an event, and of its spans, is a field of
+//! the journal entry, e.g. `F_VRF_ID=2`, which `journalctl` can match
+//! on:
+//!
+//! ```text
+//! journalctl -t loggingdemo F_VRF_ID=2 -o verbose
+//! ```
+//!
+//! The fields are sent as they are, not redacted. The layer is set up
+//! at startup, and these settings only apply after a restart.
+
+use std::io;
+
+use crate::config::JournaldConfig;
+
+/// The journald layer, if it is enabled. Fails if the journal can't
+/// be reached, e.g. on systems without systemd.
+pub fn layer(config: &JournaldConfig) -> io::Result<Option<tracing_journald::Layer>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    let mut layer = tracing_journald::layer()?;
+    if let Some(identifier) = &config.identifier {
+        layer = layer.with_syslog_identifier(identifier.clone());
+    }
+    let prefix = Some(config.field_prefix.clone()).filter(|prefix| !prefix.is_empty());
+    Ok(Some(layer.with_field_prefix(prefix)))
+}
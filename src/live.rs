@@ -0,0 +1,151 @@
+//! The events that go through the filters, streamed live to the
+//! clients that asked for them.
+//!
+//! Each client gives the values that some fields must have, e.g.
+//! `vrf_id=1`, and gets the matching events, formatted like those of
+//! the sharded sink. A client that doesn't keep up misses events
+//! rather than slowing down the program. Each event sent to a client
+//! has a sequence number, counting from 1 the events matching its
+//! query, so that the client can tell which ones it missed.
+//!
+//! A client may also have filters of its own, that suppress events
+//! from its stream only, without changing what the others see.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+
+use dynamic_field_filter::format;
+use dynamic_field_filter::format::FieldValues;
+use dynamic_field_filter::matcher::Matcher;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tracing::span::Attributes;
+use tracing::span::Record;
+use tracing::Event;
+use tracing::Id;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Number of events kept for a client that doesn't read them fast
+/// enough, before the next ones are dropped
+const BACKLOG: usize = 1024;
+
+/// The clients subscribed
+static CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+
+/// The number of clients, to skip formatting when there are none
+static CLIENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The values some fields must have, as `(field, value)` pairs
+pub type Query = Vec<(String, String)>;
+
+/// The filter rules of a client, by field. The events where a field
+/// matches are left out of the client's stream. They are shared with
+/// the connection, so that they can change while it is subscribed.
+pub type SessionFilters = Arc<RwLock<BTreeMap<String, Matcher>>>;
+
+/// An event streamed to a client
+#[derive(Debug)]
+pub struct StreamedEvent {
+    /// The sequence number of the event, for this client
+    pub seq: u64,
+    pub line: String,
+}
+
+/// A client, with the field values it wants, its own filters, and
+/// where its events go
+#[derive(Debug)]
+struct Client {
+    query: Query,
+    filters: SessionFilters,
+    tx: Sender<StreamedEvent>,
+    /// The sequence number of the last event for the client, sent or
+    /// dropped
+    seq: u64,
+}
+
+/// Start receiving the events matching `query`, except those that
+/// `filters` suppress. The client is forgotten once the receiver is
+/// dropped.
+pub fn subscribe(query: Query, filters: SessionFilters) -> Receiver<StreamedEvent> {
+    let (tx, rx) = mpsc::channel(BACKLOG);
+    let mut clients = CLIENTS.lock().unwrap();
+    clients.push(Client {
+        query,
+        filters,
+        tx,
+        seq: 0,
+    });
+    CLIENT_COUNT.store(clients.len(), Ordering::Relaxed);
+    rx
+}
+
+/// A layer that sends the events to the clients whose query they
+/// match. It must come after the filters, so that it only sees the
+/// events that went through.
+#[derive(Debug, Default)]
+pub struct LiveEvents;
+
+impl<S> Layer<S> for LiveEvents
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // The span fields are always recorded, since a client may ask
+    // for the events of spans created before it connected
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        format::record_span_fields(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        format::update_span_fields(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if CLIENT_COUNT.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        let mut line = None;
+        let mut clients = CLIENTS.lock().unwrap();
+        clients.retain_mut(|client| {
+            if client.tx.is_closed() {
+                return false;
+            }
+            let lookup = |name: &str| format::lookup_field(name, event, &fields, &ctx);
+            let matches = client
+                .query
+                .iter()
+                .all(|(name, value)| lookup(name).as_deref() == Some(value));
+            let suppressed = || {
+                client
+                    .filters
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .any(|(name, matcher)| {
+                        lookup(name).is_some_and(|value| matcher.matches_text(name, &value))
+                    })
+            };
+            if matches && !suppressed() {
+                let line = line.get_or_insert_with(|| format::format_event(event, &fields, &ctx));
+                // The sequence number goes up even if the event is
+                // dropped, leaving a gap
+                client.seq += 1;
+                let _ = client.tx.try_send(StreamedEvent {
+                    seq: client.seq,
+                    line: line.clone(),
+                });
+            }
+            true
+        });
+        CLIENT_COUNT.store(clients.len(), Ordering::Relaxed);
+    }
+}
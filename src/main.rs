@@ -2,176 +2,517 @@
 #[macro_use]
 extern crate tracing;
 
-use std::collections::HashMap;
-use std::fmt;
-use std::io::Read;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::sync::mpsc;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::RwLock;
 use std::thread;
+use std::time::Duration;
 
-use tracing::field::Field;
-use tracing::field::ValueSet;
-use tracing::field::Visit;
-use tracing::span::Attributes;
-use tracing::subscriber::Interest;
-use tracing::Id;
-use tracing::Metadata;
-use tracing::Subscriber;
-use tracing_subscriber::layer::Context;
+use dynamic_field_filter::dedup;
+use dynamic_field_filter::diagnostics;
+use dynamic_field_filter::enrich;
+use dynamic_field_filter::explain;
+use dynamic_field_filter::filter::DynamicFieldFilter;
+use dynamic_field_filter::panics;
+use dynamic_field_filter::rate_limit;
+use dynamic_field_filter::replay;
+use dynamic_field_filter::stats::StatsReporter;
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio::time::MissedTickBehavior;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
-use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::reload;
-use tracing_subscriber::reload::Handle;
 use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::EnvFilter;
 use tracing_subscriber::Layer;
+use tracing_subscriber::Registry;
 
+use crate::chrome::ChromeTrace;
+use crate::config::Config;
+use crate::control::handle_tcp_client;
+use crate::control::Clients;
+use crate::control::Session;
+use crate::flame::FlameGraph;
+use crate::grpc::ControlService;
+use crate::hot_reload::Handles;
+use crate::http::AdminApi;
+use crate::live::LiveEvents;
+use crate::scenario::Scenario;
+use crate::sink::ShardedSink;
+
+mod audit;
+mod broadcast;
+mod channel;
+mod chrome;
+mod cli;
+mod colors;
+mod config;
+mod console;
+mod control;
+mod enrichment;
+mod expiry;
+mod flame;
+mod gelf;
+mod grpc;
+mod hot_reload;
+mod http;
+mod inspect;
+mod interactive;
+mod journald;
+mod live;
+mod metrics;
+mod nonblocking;
+mod otlp;
+mod persist;
+#[cfg(windows)]
+mod pipe;
+mod retry;
+mod rotate;
 mod router;
+mod routes;
+mod scenario;
+mod shutdown;
+mod siem;
+mod signals;
+mod sink;
+mod span_path;
+mod syslog;
+mod tee;
+mod vrfs;
+
+/// How long the shutdown waits for the control connections to close
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[tokio::main]
+async fn main() {
+    // Start counting the uptime reported by PING
+    LazyLock::force(&control::STARTED);
+
+    // Read the configuration file, if any, and the command-line
+    // arguments. Logging is not set up yet, so errors go to stderr.
+    let config = match Config::from_args() {
+        Ok(config) => Arc::new(RwLock::new(config)),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    // The events get the enrichment fields from the start, if they are
+    // on
+    enrich::set_enabled(config.read().unwrap().enrich);
+
+    // The fmt layer prints the records of some targets to files,
+    // which must be open before it is
+    routes::set_rotation(config.read().unwrap().rotation.clone());
+    if let Err(e) = routes::replace(&config.read().unwrap().routes) {
+        eprintln!("failed to open the routed log files: {e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = tee::set(config.read().unwrap().fmt.tee.as_deref()) {
+        eprintln!("failed to open the tee file: {e}");
+        std::process::exit(1);
+    }
 
-fn main() {
     // Construct a reloadable layer that filters span based on field
-    // values. The handle will be passed to the `handle_tcp_client`,
-    // so that the fields to filter on can be read from a TCP
-    // connection
-    let (field_filter, handle) = reload::Layer::new(DynamicFieldFilter::default());
-
-    let fmt_subcriber = tracing_subscriber::fmt()
-        .compact()
-        .with_line_number(true)
-        .with_ansi(false)
-        .with_env_filter(EnvFilter::from_default_env())
-        .finish();
-
-    // Compose the fmt subscriber with out custom layer
-    let subcriber = fmt_subcriber.with(field_filter);
-
-    // Install the subscriber
-    subcriber.init();
+    // values. The initial filters come from the configuration. The
+    // handle will be passed to the `handle_tcp_client`, so that the
+    // fields to filter on can be read from a TCP connection
+    let initial_config = config.read().unwrap().clone();
+    let mut initial_filter = DynamicFieldFilter::default();
+    if let Some(profile) = initial_config.filter.clone() {
+        initial_filter.apply_profile(profile);
+    }
+    // The invalid rules are reported once the subscriber is set up
+    let invalid_rules = initial_filter.add_rules(initial_config.filters.iter().map(String::as_str));
+    if let Some(directives) = &initial_config.directives {
+        // They were checked along with the configuration
+        let _ = initial_filter.add_directives(directives);
+    }
+    let (field_filter, handle) = reload::Layer::new(initial_filter);
+    // The sharded sink is also reloadable, so that it can be enabled
+    // and configured from the TCP connection too.
+    let (sharded_sink, sink_handle) = reload::Layer::new(ShardedSink::default());
 
-    // Start listening for incoming TCP connections. Clients should be
-    // able to specify fields they want to filter on.
-    thread::spawn(move || {
-        let listener = TcpListener::bind("127.0.0.1:8888").unwrap();
-        for stream in listener.incoming() {
-            handle_tcp_client(stream.unwrap(), handle.clone());
+    // Send the records to the systemd journal too, if configured
+    let journald = match journald::layer(&initial_config.journald) {
+        Ok(layer) => layer,
+        Err(e) => {
+            eprintln!("failed to connect to the systemd journal: {e}");
+            std::process::exit(1);
         }
-    });
+    };
 
-    // Start our fake router so that we start logging stuff
-    let (tx, rx) = mpsc::channel();
-    let bgp = router::Bgp::new(rx);
-    let rib = router::Rib::new(tx);
-    thread::spawn(move || bgp.run());
-    rib.run();
-}
+    // And to a syslog server
+    let syslog = match syslog::layer(&initial_config.syslog) {
+        Ok(layer) => layer,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-struct MatchStrVisitor<'a> {
-    field: &'a str,
-    value: &'a str,
-    matched: bool,
-}
+    // And to Graylog
+    let gelf = match gelf::layer(&initial_config.gelf) {
+        Ok(layer) => layer,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-impl Visit for MatchStrVisitor<'_> {
-    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
-    fn record_str(&mut self, field: &Field, value: &str) {
-        if field.name() == self.field && value == self.value {
-            self.matched = true;
+    // Export the spans over OTLP, those the field filter suppresses
+    // excepted
+    let suppressed_handle = handle.clone();
+    let slow_handle = handle.clone();
+    let (otlp, export_guard) = match otlp::start(
+        &initial_config.otlp,
+        Box::new(move |id| {
+            suppressed_handle
+                .with_current(|filter| filter.is_disabled(id) && !filter.dry_run())
+                .unwrap_or(false)
+        }),
+        Box::new(move || {
+            slow_handle
+                .with_current(|filter| filter.slow())
+                .ok()
+                .flatten()
+        }),
+    ) {
+        Ok(otlp) => otlp,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
         }
-    }
-}
+    };
+
+    // Count the events for Prometheus
+    let metrics = metrics::layer(&initial_config.metrics);
 
-/// Return `true` if the value set contains the given field with the
-/// given value.
-fn value_in_valueset(valueset: &ValueSet<'_>, field: &str, value: &str) -> bool {
-    let mut visitor = MatchStrVisitor {
-        field,
-        value,
-        matched: false,
+    // Print the records as configured. The fmt layer is reloadable,
+    // so that the configuration file can change its settings.
+    let (fmt_layer, fmt_handle) = reload::Layer::new(initial_config.fmt.layer());
+
+    // The level and target filtering from `RUST_LOG` is reloadable
+    // as well, so that directives can be changed from the TCP
+    // connection.
+    let (env_filter, level_handle) = reload::Layer::new(initial_config.env_filter());
+
+    // Compose the fmt layer with the env filter, then with our custom
+    // layers. The sink, the live events and the other outputs (the
+    // journal, syslog, GELF, OTLP, the Chrome trace, the flamegraph and
+    // the metrics) come after the filter, so that they only see the
+    // records that went through. They are boxed, so that the type of the
+    // subscriber doesn't grow with each of them. With the `console`
+    // feature, the console layer sees the tasks, and only them, while
+    // the layers writing the logs don't.
+    let outputs = vec![
+        journald.boxed(),
+        syslog.boxed(),
+        gelf.boxed(),
+        otlp.boxed(),
+        ChromeTrace.boxed(),
+        FlameGraph.boxed(),
+        metrics.boxed(),
+    ];
+    let subcriber = Registry::default()
+        .with(fmt_layer.with_filter(console::hide_tasks()))
+        .with(env_filter)
+        .with(field_filter)
+        .with(sharded_sink.with_filter(console::hide_tasks()))
+        .with(LiveEvents.with_filter(console::hide_tasks()))
+        .with(outputs.with_filter(console::hide_tasks()))
+        .with(console::layer());
+
+    // Install the subscriber. The records are written on a thread of
+    // their own until the guard is dropped, at the end of main.
+    let writer_guard = match nonblocking::start() {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("failed to start the log writer: {e}");
+            std::process::exit(1);
+        }
     };
-    valueset.record(&mut visitor);
-    visitor.matched
-}
+    subcriber.init();
+    for (rule, e) in invalid_rules {
+        warn!(target: diagnostics::TARGET, "ignoring invalid rule {rule} in the configuration: {e}");
+    }
 
-/// A layer that checks filters spans based their fields values
-#[derive(Debug, Default)]
-struct DynamicFieldFilter {
-    filters: HashMap<String, String>,
-}
+    // Report the panics in the logs, e.g. those of the router threads
+    panics::install_hook();
 
-/// A span extension that indicates that the span is disabled
-struct SpanExtDisable;
+    // Shut down gracefully on SIGINT and SIGTERM
+    thread::spawn(shutdown::handle_signals);
 
-impl<S> Layer<S> for DynamicFieldFilter
-where
-    S: Subscriber + for<'a> LookupSpan<'a>,
-{
-    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
-        Interest::sometimes()
+    // Open the SIEM changelog. This is independent from the
+    // subscriber, and must work even if logging is misconfigured.
+    if let Err(e) = siem::init_from_env() {
+        eprintln!("failed to open the SIEM sink: {e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = audit::init_from_env() {
+        eprintln!("failed to open the audit log: {e}");
+        std::process::exit(1);
     }
 
-    fn enabled(&self, _metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
-        eprintln!("ENABLED");
-        if let Some(span_ref) = ctx.lookup_current() {
-            span_ref.extensions().get::<SpanExtDisable>().is_none()
-        } else {
-            true
+    // Restore the filters saved by a previous run, if any
+    match persist::load_from_env() {
+        Ok(Some(saved)) => {
+            if retry::modify(&handle, |layer| layer.restore(saved)).is_ok() {
+                info!(
+                    target: diagnostics::TARGET,
+                    "filters restored from {}",
+                    persist::default_path().display()
+                );
+            }
         }
+        Ok(None) => {}
+        Err(e) => error!(
+            target: diagnostics::TARGET,
+            "failed to restore the filters from {}: {e}",
+            persist::default_path().display()
+        ),
     }
 
-    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
-        eprintln!("ON_NEW_SPAN CALLED");
-        // Lookup up the parents spans, see if an ancestor has the
-        // extension already. If so, add the extension for this span
-        // too.
-        let span_ref = ctx.span(id).unwrap();
-        if let Some(parent_span) = span_ref.parent() {
-            if parent_span.extensions().get::<SpanExtDisable>().is_some() {
-                span_ref.extensions_mut().insert(SpanExtDisable);
-                return;
-            }
+    // Drop the rules and callsite mutes once their TTL is over
+    tokio::spawn(expiry::run(handle.clone()));
+
+    // Periodically report the filter statistics. This is off until
+    // an interval is set from the TCP connection.
+    let reporter = Arc::new(StatsReporter::default());
+    tokio::spawn(every(StatsReporter::TICK, {
+        let reporter = reporter.clone();
+        let handle = handle.clone();
+        move || reporter.tick(|| handle.with_current(|layer| layer.stats().snapshot()).ok())
+    }));
+
+    // Periodically report the events suppressed by rate limiting
+    tokio::spawn(every(rate_limit::REPORT_INTERVAL, {
+        let handle = handle.clone();
+        move || {
+            rate_limit::report(
+                handle
+                    .with_current(|layer| layer.take_rate_limited())
+                    .unwrap_or_default(),
+            )
         }
+    }));
+
+    // Report the events collapsed by duplicate suppression
+    tokio::spawn(every(dedup::REPORT_INTERVAL, {
+        let handle = handle.clone();
+        move || {
+            dedup::report(
+                handle
+                    .with_current(|layer| layer.take_repeats())
+                    .unwrap_or_default(),
+            )
+        }
+    }));
+
+    // Report why spans and events are suppressed, in explain mode
+    let explain = tokio::task::spawn_blocking(|| explain::run_reporter(|| !shutdown::requested()));
+
+    // Emit the events held back by trigger and slow modes once they
+    // are released
+    let replay = tokio::task::spawn_blocking(|| replay::run_reporter(|| !shutdown::requested()));
+
+    // Apply the configuration file again when it changes, or on
+    // SIGHUP, which also reopens the files written to
+    let handles = Arc::new(Handles {
+        filter: handle.clone(),
+        level: level_handle.clone(),
+        fmt: fmt_handle.clone(),
+    });
+    tokio::spawn({
+        let config = config.clone();
+        let handles = handles.clone();
+        async move { hot_reload::watch(&config, &handles).await }
+    });
+    tokio::spawn({
+        let config = config.clone();
+        let sink_handle = sink_handle.clone();
+        async move { signals::handle_sighup(&config, &handles, &sink_handle).await }
+    });
 
-        // If the parent wasn't disabled or if there was no parent,
-        // check the fields
-        for (filtered_field, filtered_value) in self.filters.iter() {
-            if value_in_valueset(attrs.values(), filtered_field, filtered_value) {
-                span_ref.extensions_mut().insert(SpanExtDisable);
-                return;
+    // Start listening for incoming TCP connections. Clients should be
+    // able to specify fields they want to filter on. Each client gets
+    // its own task, and their changes apply one at a time through the
+    // reload handles.
+    let tls = match initial_config.control.tls() {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("failed to set up TLS for the control connection: {e}");
+            std::process::exit(1);
+        }
+    };
+    if tls.is_none() && !initial_config.listen.ip().is_loopback() {
+        warn!(
+            "the control connection on {} is not encrypted, set control.cert and control.key to use TLS",
+            initial_config.listen
+        );
+    }
+    let clients = Clients::new(initial_config.control.max_clients);
+    if let Some(name) = initial_config.control.pipe.clone() {
+        // The same commands are accepted on a named pipe, on Windows
+        // Connecting a pipe blocks, so it is waited for on a thread,
+        // and the connections are then served by tasks
+        #[cfg(windows)]
+        thread::spawn({
+            let runtime = tokio::runtime::Handle::current();
+            let handle = handle.clone();
+            let level_handle = level_handle.clone();
+            let sink_handle = sink_handle.clone();
+            let fmt_handle = fmt_handle.clone();
+            let reporter = reporter.clone();
+            let config = config.clone();
+            let clients = clients.clone();
+            move || {
+                pipe::serve(&name, |pipe, peer| {
+                    let Some(slot) = clients.add() else {
+                        warn!("too many control connections, closing the one on {peer}");
+                        return;
+                    };
+                    let session = Session::new(
+                        peer,
+                        handle.clone(),
+                        level_handle.clone(),
+                        sink_handle.clone(),
+                        fmt_handle.clone(),
+                        reporter.clone(),
+                        config.clone(),
+                    );
+                    runtime.spawn(async move {
+                        let pipe = control::Connection::Blocking(Box::new(pipe));
+                        control::handle_client(pipe, session).await;
+                        drop(slot);
+                    });
+                })
+            }
+        });
+        #[cfg(not(windows))]
+        warn!("not listening on the pipe {name}: named pipes are only supported on Windows");
+    }
+    // The gRPC control service, if enabled, runs the same commands on
+    // its own port
+    if let Some(addr) = initial_config.control.grpc {
+        let service = ControlService {
+            layer_handle: handle.clone(),
+            level_handle: level_handle.clone(),
+            sink_handle: sink_handle.clone(),
+            fmt_handle: fmt_handle.clone(),
+            reporter: reporter.clone(),
+            config: config.clone(),
+        };
+        tokio::spawn(service.serve(addr));
+    }
+    // And so does the HTTP admin API
+    if let Some(addr) = initial_config.control.http {
+        let api = AdminApi {
+            layer_handle: handle.clone(),
+            level_handle: level_handle.clone(),
+            sink_handle: sink_handle.clone(),
+            fmt_handle: fmt_handle.clone(),
+            reporter: reporter.clone(),
+            config: config.clone(),
+        };
+        tokio::spawn(api.serve(addr));
+    }
+    let listener = match TcpListener::bind(initial_config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "failed to listen on {} for control connections: {e}",
+                initial_config.listen
+            );
+            std::process::exit(1);
+        }
+    };
+    let listener = tokio::spawn({
+        let config = config.clone();
+        let clients = clients.clone();
+        async move {
+            loop {
+                let (stream, peer) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("failed to accept a control connection: {e}");
+                            continue;
+                        }
+                    },
+                    () = shutdown::wait() => break,
+                };
+                let Some(slot) = clients.add() else {
+                    warn!("too many control connections, closing the new one");
+                    continue;
+                };
+                let session = Session::new(
+                    peer.to_string(),
+                    handle.clone(),
+                    level_handle.clone(),
+                    sink_handle.clone(),
+                    fmt_handle.clone(),
+                    reporter.clone(),
+                    config.clone(),
+                );
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    handle_tcp_client(stream, tls, session).await;
+                    drop(slot);
+                });
             }
         }
+    });
+
+    // Start our fake router so that we start logging stuff
+    vrfs::set(&initial_config.simulator.names());
+    let (tx, rx) = channel::bounded(
+        initial_config.simulator.capacity,
+        initial_config.simulator.overflow,
+    );
+    let scenario = match Scenario::load(&initial_config.simulator) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let bgp = router::Bgp::new(rx);
+    let rib = router::Rib::new(tx, initial_config.simulator, scenario);
+    let bgp = router::spawn("bgp", bgp.run());
+    let rib = router::spawn("rib", rib.run());
+
+    // Run until the shutdown, then let the tasks finish what they are
+    // doing, and flush what they wrote
+    for task in [rib, bgp, listener] {
+        let _ = task.await;
+    }
+    if !clients.wait_closed(SHUTDOWN_TIMEOUT).await {
+        warn!("control connections still open after {SHUTDOWN_TIMEOUT:?}");
+    }
+    // Let the reporters emit what they were given
+    for task in [explain, replay] {
+        let _ = task.await;
     }
+    info!("shut down");
+    if let Err(e) = chrome::stop() {
+        eprintln!("{e}");
+    }
+    drop(export_guard);
+    drop(writer_guard);
+    let _ = io::stdout().flush();
 }
 
-fn handle_tcp_client<S>(mut stream: TcpStream, layer_handle: Handle<DynamicFieldFilter, S>) {
+/// Call `report` every `period`, until the shutdown
+async fn every(period: Duration, report: impl Fn()) {
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        let mut read_buf = [0_u8; 1024];
-        match stream.read(&mut read_buf[..]) {
-            Ok(n) => {
-                let s = String::from_utf8_lossy(&read_buf[..n]);
-                let mut words = s.split_whitespace();
-                match words.next() {
-                    Some("CLEAR") => {
-                        layer_handle.modify(|layer| layer.filters.clear()).unwrap();
-                    }
-                    // Filter on vrf_id=id
-                    Some("VRF") => {
-                        if let Some(id) = words.next() {
-                            layer_handle
-                                .modify(|layer| {
-                                    error!("setting filter for vrf_id = {id}");
-                                    layer.filters.insert("vrf_id".to_string(), id.to_string());
-                                })
-                                .unwrap();
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            Err(e) => {
-                warn!("TCP connection closed ({e})");
-                return;
-            }
+        tokio::select! {
+            _ = interval.tick() => report(),
+            () = shutdown::wait() => break,
         }
     }
 }
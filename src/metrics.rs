@@ -0,0 +1,396 @@
+//! Counting the records, for Prometheus, with the `[metrics]` settings:
+//!
+//! ```toml
+//! [metrics]
+//! fields = ["vrf_id", "peer"]          # the events are counted by
+//! max_values = 100                     # the values of these fields
+//! ```
+//!
+//! The counters are served in the Prometheus text format by the HTTP
+//! admin API, on `GET /metrics`, along with the counters of the field
+//! filter:
+//!
+//! ```text
+//! loggingdemo_events_total{level="INFO",target="loggingdemo::router"} 1312
+//! loggingdemo_field_events_total{field="vrf_id",value="2"} 640
+//! loggingdemo_filter_events_suppressed_total 2048
+//! loggingdemo_filter_rule_hits_total{rule="vrf_id=1"} 187
+//! loggingdemo_span_busy_seconds{name="add_path",quantile="0.95"} 0.0002
+//! ```
+//!
+//! The metrics layer comes after the field filter, so the events are
+//! those that went through the filters, and the suppressed ones are
+//! only in the filter counters. A field of the event hides those of its
+//! spans with the same name. The values are redacted like in the other
+//! outputs, and past `max_values` values of a field, the events with
+//! new values are counted under `__other__`, so that the number of
+//! series stays bounded. The layer is set up at startup, and these
+//! settings only apply after a restart.
+//!
+//! The time each span is busy, from the moments it is entered to those
+//! it is exited, is counted in fixed buckets by span name, from which
+//! the p50, p95 and p99 are estimated, both here and in `STATS`: an
+//! estimate is the upper bound of the bucket the quantile falls in, so
+//! it is at most 2.5 times too high.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use dynamic_field_filter::format;
+use dynamic_field_filter::format::FieldValues;
+use dynamic_field_filter::format::SpanFields;
+use dynamic_field_filter::stats::StatsSnapshot;
+use tracing::span::Attributes;
+use tracing::Event;
+use tracing::Id;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::MetricsConfig;
+
+/// The value the events are counted under, past the maximum number of
+/// values of a field
+const OTHER: &str = "__other__";
+
+/// The upper bounds of the buckets of the span busy times, in
+/// microseconds. The longer times go to a last bucket.
+const BUCKETS: [u64; 19] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000,
+    200_000, 500_000, 1_000_000,
+];
+
+/// The quantiles of the span busy times that are reported, and their
+/// names in `STATS`
+pub const QUANTILES: [(f64, &str); 3] = [(0.5, "p50"), (0.95, "p95"), (0.99, "p99")];
+
+/// The counters, since the start
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+    events: BTreeMap::new(),
+    fields: BTreeMap::new(),
+    spans: BTreeMap::new(),
+});
+
+struct Counters {
+    /// The events, by level and target
+    events: BTreeMap<(&'static str, &'static str), u64>,
+    /// The events, by field and value
+    fields: BTreeMap<String, BTreeMap<String, u64>>,
+    /// The busy times of the closed spans, by span name
+    spans: BTreeMap<&'static str, Histogram>,
+}
+
+/// The busy times of the spans with a given name
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// The number of spans in each bucket, the last one being for the
+    /// spans busy for longer than the last bound
+    buckets: [u64; BUCKETS.len() + 1],
+    /// The longest busy time, for the quantiles in the last bucket
+    max: Duration,
+    /// The total busy time
+    pub sum: Duration,
+    /// The number of spans
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS.len() + 1],
+            max: Duration::ZERO,
+            sum: Duration::ZERO,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, busy: Duration) {
+        let micros = busy.as_micros();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| micros <= u128::from(bound))
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.max = self.max.max(busy);
+        self.sum += busy;
+        self.count += 1;
+    }
+
+    /// Estimate a quantile, between 0 and 1, as the upper bound of the
+    /// bucket it falls in
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = (quantile * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return match BUCKETS.get(bucket) {
+                    Some(&bound) => Duration::from_micros(bound).min(self.max),
+                    None => self.max,
+                };
+            }
+        }
+        self.max
+    }
+}
+
+/// A span extension: how long the span was entered so far
+struct Busy {
+    entered: Option<Instant>,
+    busy: Duration,
+}
+
+/// The busy times of the spans closed so far, by span name
+pub fn span_busy_times() -> Vec<(&'static str, Histogram)> {
+    let counters = COUNTERS.lock().unwrap();
+    counters
+        .spans
+        .iter()
+        .map(|(name, histogram)| (*name, histogram.clone()))
+        .collect()
+}
+
+/// The metrics layer
+pub fn layer(config: &MetricsConfig) -> Metrics {
+    Metrics {
+        fields: config.fields.clone(),
+        max_values: config.max_values,
+    }
+}
+
+/// A layer counting the events
+#[derive(Debug)]
+pub struct Metrics {
+    fields: Vec<String>,
+    max_values: usize,
+}
+
+impl<S> Layer<S> for Metrics
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            span_ref.extensions_mut().insert(Busy {
+                entered: None,
+                busy: Duration::ZERO,
+            });
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            if let Some(busy) = span_ref.extensions_mut().get_mut::<Busy>() {
+                busy.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            if let Some(busy) = span_ref.extensions_mut().get_mut::<Busy>() {
+                if let Some(entered) = busy.entered.take() {
+                    busy.busy += entered.elapsed();
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span_ref) = ctx.span(&id) else {
+            return;
+        };
+        let Some(Busy { busy, .. }) = span_ref.extensions_mut().remove::<Busy>() else {
+            return;
+        };
+        let mut counters = COUNTERS.lock().unwrap();
+        counters
+            .spans
+            .entry(span_ref.name())
+            .or_default()
+            .record(busy);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+
+        // The values of the fields counted, those of the outermost
+        // spans first, so that those of the inner spans and of the
+        // event replace them
+        let mut values = BTreeMap::new();
+        if !self.fields.is_empty() {
+            let mut keep = |name: &str, value: &str| {
+                if self.fields.iter().any(|field| field == name) {
+                    values.insert(name.to_string(), format::redacted(name, value).into_owned());
+                }
+            };
+            if let Some(scope) = ctx.event_scope(event) {
+                for span_ref in scope.from_root() {
+                    if let Some(SpanFields(fields)) = span_ref.extensions().get::<SpanFields>() {
+                        for (name, value) in fields.values.iter() {
+                            keep(name, value);
+                        }
+                    }
+                }
+            }
+            let mut fields = FieldValues::default();
+            event.record(&mut fields);
+            for (name, value) in fields.values.iter() {
+                keep(name, value);
+            }
+        }
+
+        let mut counters = COUNTERS.lock().unwrap();
+        *counters
+            .events
+            .entry((metadata.level().as_str(), metadata.target()))
+            .or_default() += 1;
+        for (name, value) in values {
+            let counts = counters.fields.entry(name).or_default();
+            let value = if counts.contains_key(&value) || counts.len() < self.max_values {
+                value
+            } else {
+                OTHER.to_string()
+            };
+            *counts.entry(value).or_default() += 1;
+        }
+    }
+}
+
+/// The counters in the Prometheus text format, along with those of the
+/// field filter and the hits of its rules
+pub fn render(stats: &StatsSnapshot, rule_hits: &[(String, u64)]) -> String {
+    let mut text = String::new();
+    let counters = COUNTERS.lock().unwrap();
+
+    header(
+        &mut text,
+        "loggingdemo_events_total",
+        "Events that went through the filters, by level and target",
+    );
+    for ((level, target), count) in &counters.events {
+        let _ = writeln!(
+            text,
+            "loggingdemo_events_total{{level=\"{level}\",target=\"{}\"}} {count}",
+            label(target)
+        );
+    }
+
+    header(
+        &mut text,
+        "loggingdemo_field_events_total",
+        "Events that went through the filters, by value of the fields counted",
+    );
+    for (field, counts) in &counters.fields {
+        for (value, count) in counts {
+            let _ = writeln!(
+                text,
+                "loggingdemo_field_events_total{{field=\"{}\",value=\"{}\"}} {count}",
+                label(field),
+                label(value)
+            );
+        }
+    }
+
+    let name = "loggingdemo_span_busy_seconds";
+    let _ = writeln!(
+        text,
+        "# HELP {name} Time the spans were entered, by span name"
+    );
+    let _ = writeln!(text, "# TYPE {name} summary");
+    for (span, histogram) in &counters.spans {
+        let span = label(span);
+        for (quantile, _) in QUANTILES {
+            let _ = writeln!(
+                text,
+                "{name}{{name=\"{span}\",quantile=\"{quantile}\"}} {}",
+                histogram.quantile(quantile).as_secs_f64()
+            );
+        }
+        let _ = writeln!(
+            text,
+            "{name}_sum{{name=\"{span}\"}} {}",
+            histogram.sum.as_secs_f64()
+        );
+        let _ = writeln!(text, "{name}_count{{name=\"{span}\"}} {}", histogram.count);
+    }
+    drop(counters);
+
+    let filter_counters = [
+        (
+            "spans_evaluated",
+            "Spans checked against the filters",
+            stats.spans_evaluated,
+        ),
+        (
+            "spans_suppressed",
+            "Spans suppressed by the filters",
+            stats.spans_suppressed,
+        ),
+        (
+            "events_passed",
+            "Events that went through the filters",
+            stats.events_passed,
+        ),
+        (
+            "events_suppressed",
+            "Events suppressed by the filters",
+            stats.events_suppressed,
+        ),
+        (
+            "spans_dry_run",
+            "Spans the filters would have suppressed, in dry-run mode",
+            stats.spans_dry_run,
+        ),
+        (
+            "events_dry_run",
+            "Events the filters would have suppressed, in dry-run mode",
+            stats.events_dry_run,
+        ),
+    ];
+    for (name, help, value) in filter_counters {
+        let name = format!("loggingdemo_filter_{name}_total");
+        header(&mut text, &name, help);
+        let _ = writeln!(text, "{name} {value}");
+    }
+
+    header(
+        &mut text,
+        "loggingdemo_filter_rule_hits_total",
+        "Records matched by each filter rule",
+    );
+    for (rule, hits) in rule_hits {
+        let _ = writeln!(
+            text,
+            "loggingdemo_filter_rule_hits_total{{rule=\"{}\"}} {hits}",
+            label(rule)
+        );
+    }
+    text
+}
+
+/// Write the HELP and TYPE lines of a counter
+fn header(text: &mut String, name: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} counter");
+}
+
+/// A label value, with `\`, `"` and the line feeds escaped
+fn label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,163 @@
+//! Writing the printed records on a thread of their own.
+//!
+//! The fmt layer formats each record into a buffer, which is queued
+//! along with the writer it goes to, e.g. that of the routes. A
+//! worker thread writes them, so that a slow disk or terminal doesn't
+//! hold up the threads logging. `tracing_appender::non_blocking` does
+//! the same but has a single destination, while the routes pick one
+//! per record.
+//!
+//! The queue is bounded. When it is full, the record is dropped rather
+//! than blocking the logging thread, and counted, the count being
+//! reported by the STATS command. Until the worker is started, and once
+//! it stopped, the records are written directly.
+
+use std::io;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How many records the queue holds, as many lines as
+/// `tracing_appender` buffers by default
+const CAPACITY: usize = 128_000;
+
+enum Message {
+    Record(Box<dyn Write + Send>, Vec<u8>),
+    /// Flush the outputs and stop
+    Shutdown,
+}
+
+/// The queue of the worker, while it runs
+static QUEUE: Mutex<Option<SyncSender<Message>>> = Mutex::new(None);
+
+/// How many records were dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Return how many records were dropped because the queue was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Start the worker. The records queued are written until the
+/// returned guard is dropped.
+pub fn start() -> io::Result<WorkerGuard> {
+    let (sender, receiver) = mpsc::sync_channel(CAPACITY);
+    let worker = thread::Builder::new()
+        .name("log-writer".to_string())
+        .spawn(move || work(receiver))?;
+    *QUEUE.lock().unwrap() = Some(sender);
+    Ok(WorkerGuard {
+        worker: Some(worker),
+    })
+}
+
+/// Write the queued records until the shutdown
+fn work(receiver: Receiver<Message>) {
+    while let Ok(Message::Record(mut output, buf)) = receiver.recv() {
+        // There is nowhere to report the failure to
+        let _ = output.write_all(&buf);
+        let _ = output.flush();
+    }
+}
+
+/// Stops the worker when dropped, once it wrote the records queued so
+/// far
+#[derive(Debug)]
+pub struct WorkerGuard {
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if let Some(sender) = QUEUE.lock().unwrap().take() {
+            let _ = sender.send(Message::Shutdown);
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Queues each record for the worker, to be written with the writer
+/// of `M`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NonBlocking<M>(pub M);
+
+/// A record being formatted, queued when dropped
+pub struct Record {
+    output: Option<Box<dyn Write + Send>>,
+    buf: Vec<u8>,
+}
+
+impl Record {
+    fn new(output: impl Write + Send + 'static) -> Self {
+        Self {
+            output: Some(Box::new(output)),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for Record {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Record {
+    fn drop(&mut self) {
+        let Some(mut output) = self.output.take() else {
+            return;
+        };
+        if self.buf.is_empty() {
+            return;
+        }
+        let buf = std::mem::take(&mut self.buf);
+        let queue = QUEUE.lock().unwrap().clone();
+        let Some(queue) = queue else {
+            let _ = output.write_all(&buf);
+            return;
+        };
+        match queue.try_send(Message::Record(output, buf)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(Message::Record(mut output, buf))) => {
+                let _ = output.write_all(&buf);
+            }
+            Err(TrySendError::Disconnected(Message::Shutdown)) => {}
+        }
+    }
+}
+
+impl<'a, M> MakeWriter<'a> for NonBlocking<M>
+where
+    M: MakeWriter<'a>,
+    M::Writer: Send + 'static,
+{
+    type Writer = Record;
+
+    fn make_writer(&'a self) -> Record {
+        Record::new(self.0.make_writer())
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> Record {
+        Record::new(self.0.make_writer_for(metadata))
+    }
+}
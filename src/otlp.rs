@@ -0,0 +1,241 @@
+//! Exporting the spans to an OpenTelemetry collector, over OTLP/HTTP,
+//! with the `[otlp]` settings:
+//!
+//! ```toml
+//! [otlp]
+//! endpoint = "http://localhost:4318/v1/traces"
+//! service_name = "loggingdemo"
+//! ```
+//!
+//! The decisions of the field filter double as the sampling policy:
+//!
+//! - the spans it suppresses aren't exported, nor the spans within
+//!   them, which it doesn't let be created. Their events are
+//!   suppressed as well.
+//! - in slow mode, see `SLOW`, the spans busy for less than the
+//!   threshold aren't exported either. This is decided when the spans
+//!   end, from their `busy_ns` attribute.
+//!
+//! In dry-run mode, the spans the filter would suppress are exported
+//! too. The spans are exported in batches, from a thread of their own,
+//! and those not exported yet are flushed at shutdown. The layer is set
+//! up at startup, and these settings only apply after a restart.
+
+use std::any::TypeId;
+use std::fmt;
+use std::time::Duration;
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::Context as OtelContext;
+use opentelemetry::Value;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::BatchSpanProcessor;
+use opentelemetry_sdk::trace::SdkTracer;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::trace::Span as SdkSpan;
+use opentelemetry_sdk::trace::SpanData;
+use opentelemetry_sdk::trace::SpanProcessor;
+use opentelemetry_sdk::Resource;
+use tracing::span::Attributes;
+use tracing::span::Record;
+use tracing::subscriber::Interest;
+use tracing::Dispatch;
+use tracing::Event;
+use tracing::Id;
+use tracing::Metadata;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::OtlpConfig;
+
+/// Whether the field filter suppressed a span
+pub type Suppressed = Box<dyn Fn(&Id) -> bool + Send + Sync>;
+
+/// The threshold of the field filter's slow mode, if it is on
+pub type Slow = Box<dyn Fn() -> Option<Duration> + Send + Sync>;
+
+/// Start exporting the spans, if an endpoint is configured. The spans
+/// not exported yet are flushed when the returned guard is dropped.
+pub fn start<S>(
+    config: &OtlpConfig,
+    suppressed: Suppressed,
+    slow: Slow,
+) -> Result<(Option<Sampled<S>>, ExportGuard), String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(endpoint) = &config.endpoint else {
+        return Ok((None, ExportGuard { provider: None }));
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| format!("failed to set up the OTLP export to {endpoint}: {e}"))?;
+    let provider = SdkTracerProvider::builder()
+        .with_span_processor(TailSampler {
+            inner: BatchSpanProcessor::builder(exporter).build(),
+            slow,
+        })
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    let layer = Sampled {
+        inner: tracing_opentelemetry::layer().with_tracer(provider.tracer("loggingdemo")),
+        suppressed,
+    };
+    Ok((
+        Some(layer),
+        ExportGuard {
+            provider: Some(provider),
+        },
+    ))
+}
+
+/// Flushes the spans not exported yet when dropped
+#[derive(Debug)]
+pub struct ExportGuard {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            // There is nowhere to report the failure to
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Drops the spans that ended before the slow mode threshold, and
+/// hands the others over to the batch processor
+struct TailSampler {
+    inner: BatchSpanProcessor,
+    slow: Slow,
+}
+
+impl fmt::Debug for TailSampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TailSampler")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SpanProcessor for TailSampler {
+    fn on_start(&self, span: &mut SdkSpan, cx: &OtelContext) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if let Some(threshold) = (self.slow)() {
+            let busy = span
+                .attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == "busy_ns")
+                .and_then(|attribute| match attribute.value {
+                    Value::I64(busy) => u64::try_from(busy).ok(),
+                    _ => None,
+                });
+            if busy.is_some_and(|busy| Duration::from_nanos(busy) < threshold) {
+                return;
+            }
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// The OpenTelemetry layer, which leaves out the spans the field
+/// filter suppressed
+pub struct Sampled<S> {
+    inner: OpenTelemetryLayer<S, SdkTracer>,
+    suppressed: Suppressed,
+}
+
+impl<S> Layer<S> for Sampled<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.inner.on_register_dispatch(subscriber);
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, ctx)
+    }
+
+    // The OpenTelemetry layer ignores the spans it didn't see created
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !(self.suppressed)(id) {
+            self.inner.on_new_span(attrs, id, ctx);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(id, values, ctx);
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, S>) {
+        self.inner.on_follows_from(id, follows, ctx);
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.event_enabled(event, ctx)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.inner.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        self.inner.on_id_change(old, new, ctx);
+    }
+
+    // The OpenTelemetry layer is looked up by `OpenTelemetrySpanExt`
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            return Some(self as *const Self as *const ());
+        }
+        unsafe { self.inner.downcast_raw(id) }
+    }
+}
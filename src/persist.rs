@@ -0,0 +1,68 @@
+//! Persistence of the filter state across restarts.
+//!
+//! The rules, shadow rules, logger levels, rate limits and profiles
+//! are saved as JSON, with `SAVE [path]`, and loaded back with
+//! `LOAD [path]`, or as TOML when the path ends with `.toml`. The
+//! schema is that of [`SavedFilters`]. When the `FILTER_STATE`
+//! environment variable is set, it is the default path, and the file
+//! is loaded at startup if it exists.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use dynamic_field_filter::filter::SavedFilters;
+
+/// The default path, when `FILTER_STATE` is not set
+const DEFAULT_PATH: &str = "filters.json";
+
+/// Return the path to use when none is given
+pub fn default_path() -> PathBuf {
+    env::var_os("FILTER_STATE").map_or_else(|| PathBuf::from(DEFAULT_PATH), PathBuf::from)
+}
+
+/// Write the filter state to a file, replacing it atomically so that
+/// a crash can't leave a truncated file behind
+pub fn save(path: &Path, saved: &SavedFilters) -> io::Result<()> {
+    let data = if is_toml(path) {
+        toml::to_string_pretty(saved)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .into_bytes()
+    } else {
+        serde_json::to_vec_pretty(saved)?
+    };
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+/// Read the filter state from a file
+pub fn load(path: &Path) -> io::Result<SavedFilters> {
+    if is_toml(path) {
+        let text = fs::read_to_string(path)?;
+        return toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    let json = fs::read(path)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Whether the filter state is saved as TOML, rather than JSON
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "toml")
+}
+
+/// Read the filter state from the file given by `FILTER_STATE`, if it
+/// is set and the file exists
+pub fn load_from_env() -> io::Result<Option<SavedFilters>> {
+    if env::var_os("FILTER_STATE").is_none() {
+        return Ok(None);
+    }
+    match load(&default_path()) {
+        Ok(saved) => Ok(Some(saved)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
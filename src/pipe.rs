@@ -0,0 +1,87 @@
+//! The control connection over a Windows named pipe, such as
+//! `\\.\pipe\tracing-filter`. The commands are the same as over TCP,
+//! see [`crate::control`].
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::AsRawHandle;
+use std::os::windows::io::FromRawHandle;
+use std::ptr;
+
+use windows_sys::Win32::Foundation::ERROR_PIPE_CONNECTED;
+use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+use windows_sys::Win32::Storage::FileSystem::PIPE_ACCESS_DUPLEX;
+use windows_sys::Win32::System::Pipes::ConnectNamedPipe;
+use windows_sys::Win32::System::Pipes::CreateNamedPipeW;
+use windows_sys::Win32::System::Pipes::PIPE_READMODE_BYTE;
+use windows_sys::Win32::System::Pipes::PIPE_REJECT_REMOTE_CLIENTS;
+use windows_sys::Win32::System::Pipes::PIPE_TYPE_BYTE;
+use windows_sys::Win32::System::Pipes::PIPE_UNLIMITED_INSTANCES;
+use windows_sys::Win32::System::Pipes::PIPE_WAIT;
+
+/// Size of the pipe buffers, in bytes
+const BUFFER_SIZE: u32 = 4096;
+
+/// Accept connections on a named pipe, and pass them to `handle`
+/// along with a name for the client, until the process exits. The
+/// next client can connect once `handle` returns. Since the pipe has
+/// no read timeout, the notifications of the changes made by the other
+/// clients only reach a pipe client when it sends a command.
+pub fn serve(name: &str, mut handle: impl FnMut(File, String)) {
+    let wide_name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+    for client in 1.. {
+        let pipe = match create(&wide_name) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                error!("failed to create the pipe {name}: {e}");
+                return;
+            }
+        };
+        if let Err(e) = connect(&pipe) {
+            warn!("failed to accept a connection on {name}: {e}");
+            continue;
+        }
+        handle(pipe, format!("{name}#{client}"));
+    }
+}
+
+/// Create an instance of the pipe. The file handle works like a
+/// stream once a client is connected.
+fn create(wide_name: &[u16]) -> io::Result<File> {
+    // SAFETY: the name is NUL-terminated, and no security attributes
+    // are given
+    let handle = unsafe {
+        CreateNamedPipeW(
+            wide_name.as_ptr(),
+            PIPE_ACCESS_DUPLEX,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUFFER_SIZE,
+            BUFFER_SIZE,
+            0,
+            ptr::null(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the handle is valid, and owned by nothing else
+    Ok(unsafe { File::from_raw_handle(handle) })
+}
+
+/// Wait for a client to connect to the pipe
+fn connect(pipe: &File) -> io::Result<()> {
+    // SAFETY: the handle is valid for as long as `pipe` is, and the
+    // pipe is not in overlapped mode
+    if unsafe { ConnectNamedPipe(pipe.as_raw_handle(), ptr::null_mut()) } != 0 {
+        return Ok(());
+    }
+    // The client may have connected between the creation and the call
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(code) if code == ERROR_PIPE_CONNECTED as i32 => Ok(()),
+        _ => Err(e),
+    }
+}
@@ -0,0 +1,90 @@
+//! Changes made through reload handles, without unwrapping.
+//!
+//! A reload handle fails when the subscriber holding its layer was
+//! dropped, which is final, and panics when the layer's lock was
+//! poisoned by a panic in another thread. Both are turned into an
+//! error, `reload failed: ...`, for the control clients, so that a
+//! failed change doesn't take the control thread down with it.
+//! Neither failure clears up, a poisoned lock stays poisoned, so the
+//! changes aren't retried.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
+use dynamic_field_filter::diagnostics;
+use tracing_subscriber::reload;
+use tracing_subscriber::reload::Handle;
+
+/// Change the layer of a reload handle, see [`Handle::modify`]
+pub fn modify<L, S>(handle: &Handle<L, S>, change: impl FnOnce(&mut L)) -> Result<(), String> {
+    let change = RefCell::new(Some(change));
+    attempt(
+        || {
+            handle.modify(|layer| {
+                if let Some(change) = change.borrow_mut().take() {
+                    change(layer);
+                }
+            })
+        },
+        || change.borrow().is_none(),
+    )
+}
+
+/// Replace the layer of a reload handle, see [`Handle::reload`]
+pub fn reload<L, S>(handle: &Handle<L, S>, layer: impl Into<L>) -> Result<(), String> {
+    modify(handle, |current| *current = layer.into())
+}
+
+/// Read the layer of a reload handle, see [`Handle::with_current`]
+pub fn read<L, S, T>(handle: &Handle<L, S>, read: impl FnOnce(&L) -> T) -> Result<T, String> {
+    let read = RefCell::new(Some(read));
+    let output = RefCell::new(None);
+    attempt(
+        || {
+            handle.with_current(|layer| {
+                if let Some(read) = read.borrow_mut().take() {
+                    *output.borrow_mut() = Some(read(layer));
+                }
+            })
+        },
+        || read.borrow().is_none(),
+    )?;
+    output
+        .into_inner()
+        .ok_or_else(|| report("the read panicked"))
+}
+
+/// Run a change or a read through a handle, turning its failure or
+/// panic into an error. `ran` tells whether the closure given to the
+/// handle ran, in which case the closure itself panicked, rather than
+/// the handle.
+fn attempt(
+    once: impl FnOnce() -> Result<(), reload::Error>,
+    ran: impl Fn() -> bool,
+) -> Result<(), String> {
+    match panic::catch_unwind(AssertUnwindSafe(once)) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(report(&e.to_string())),
+        Err(_) if ran() => Err(report("the change panicked")),
+        Err(payload) => Err(report(&panic_message(payload))),
+    }
+}
+
+/// Log a failure, and return it as the error of a command
+fn report(reason: &str) -> String {
+    error!(target: diagnostics::TARGET, "reload failed: {reason}");
+    format!("reload failed: {reason}")
+}
+
+/// The message of a panic, if it has one
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panic".to_string(),
+        },
+    }
+}
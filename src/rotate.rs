@@ -0,0 +1,137 @@
+//! Size-based rotation of the files the records are printed to.
+//!
+//! Once a file would grow past the maximum size, it is renamed with
+//! the suffix `.1`, the previous generations are shifted to `.2`, `.3`
+//! and so on, the ones past the number to keep are deleted, and a new
+//! file is started. The rotated generations are optionally compressed
+//! with gzip, as `.1.gz` and so on. The compression happens on the
+//! writing thread, when the file rolls.
+
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::config::RotationConfig;
+
+/// A file appended to, rolled as its rotation says
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// The size of the file, as far as this one wrote it
+    size: u64,
+    rotation: RotationConfig,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: RotationConfig) -> io::Result<Self> {
+        let file = open_file(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            rotation,
+        })
+    }
+
+    /// Open the file again, e.g. after it was rotated by logrotate
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_file(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    /// Change when the file rolls, from the next write on
+    pub fn set_rotation(&mut self, rotation: RotationConfig) {
+        self.rotation = rotation;
+    }
+
+    /// The path of a rotated generation
+    fn generation(&self, n: usize, compressed: bool) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{n}"));
+        if compressed {
+            name.push(".gz");
+        }
+        PathBuf::from(name)
+    }
+
+    /// Shift the generations, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let keep = self.rotation.keep;
+        // The generations may have been compressed or not, depending
+        // on the rotation in place when they rolled
+        for compressed in [false, true] {
+            let oldest = self.generation(keep, compressed);
+            if keep > 0 && oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+        }
+        for n in (1..keep).rev() {
+            for compressed in [false, true] {
+                let from = self.generation(n, compressed);
+                if from.exists() {
+                    fs::rename(from, self.generation(n + 1, compressed))?;
+                }
+            }
+        }
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let rotated = self.generation(1, false);
+            fs::rename(&self.path, &rotated)?;
+            if self.rotation.compress {
+                compress(&rotated, &self.generation(1, true))?;
+            }
+        }
+        self.file = open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len() as u64;
+        // A record larger than the maximum still goes to a file of
+        // its own
+        if self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max)
+        {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Open a file for appending
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Compress a file with gzip, and remove the original
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(from)
+}
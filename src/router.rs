@@ -1,48 +1,161 @@
 use std::collections::hash_map::Entry;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::fmt;
+use std::future;
+use std::future::Future;
 use std::net::IpAddr;
-use std::sync::mpsc;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
+use dynamic_field_filter::hints;
 use ipnetwork::IpNetwork;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 use rand::Rng;
+use rand::SeedableRng;
+use serde::Deserialize;
+use tokio::runtime;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::Instrument;
+use tracing::Span;
+
+use crate::channel;
+use crate::channel::Closed;
+use crate::channel::Overflow;
+use crate::channel::Sent;
+use crate::config::SimulatorConfig;
+use crate::scenario::Scenario;
+use crate::shutdown;
+use crate::vrfs;
+
+/// How often the route updates dropped by the RIB are reported, at
+/// most
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the route updates injected by the control connections go,
+/// while the RIB runs
+static INJECTED: Mutex<Option<mpsc::UnboundedSender<RouteUpdate>>> = Mutex::new(None);
+
+/// How the RIB generates the route updates, as changed by `SIM`
+static PACE: Mutex<Pace> = Mutex::new(Pace {
+    interval: Duration::from_secs(1),
+    paused: false,
+});
+
+/// Notified when the pace changes
+static PACE_CHANGED: Notify = Notify::const_new();
+
+/// How the RIB generates the route updates
+#[derive(Debug, Clone, Copy)]
+pub struct Pace {
+    /// Time between two route updates
+    pub interval: Duration,
+    /// Whether the RIB stopped generating them, the injected ones
+    /// being sent still
+    pub paused: bool,
+}
+
+/// Return how the RIB generates the route updates
+pub fn pace() -> Pace {
+    *PACE.lock().unwrap()
+}
+
+/// Pause or resume the generation of the route updates
+pub fn set_paused(paused: bool) {
+    PACE.lock().unwrap().paused = paused;
+    PACE_CHANGED.notify_waiters();
+}
+
+/// Change the time between two route updates
+pub fn set_interval(interval: Duration) {
+    PACE.lock().unwrap().interval = interval;
+    PACE_CHANGED.notify_waiters();
+}
+
+/// Wait until the next route update is to be generated, after `delay`,
+/// that is forever while paused or without a delay
+async fn next_update(pace: Pace, delay: Option<Duration>) {
+    match delay {
+        Some(delay) if !pace.paused => time::sleep(delay).await,
+        _ => future::pending().await,
+    }
+}
+
+/// Have the RIB send a route update to BGP, along with those it
+/// generates, see `ADDROUTE` and `DELROUTE`
+pub fn inject(update: RouteUpdate) -> Result<(), String> {
+    let injected = INJECTED.lock().unwrap();
+    match injected.as_ref().map(|injector| injector.send(update)) {
+        Some(Ok(())) => Ok(()),
+        _ => Err("the RIB is not running".to_string()),
+    }
+}
+
+/// Run a part of the router on a thread of its own, named so that the
+/// rules can be restricted to it, e.g. `FILTER @thread=bgp vrf_id=1`.
+/// The task returned completes along with it.
+pub fn spawn(name: &str, task: impl Future<Output = ()> + Send + 'static) -> JoinHandle<()> {
+    let (done, finished) = oneshot::channel();
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            runtime.block_on(task);
+            let _ = done.send(());
+        })
+        .unwrap();
+    tokio::spawn(async move {
+        let _ = finished.await;
+    })
+}
 
 pub struct Bgp {
-    events: mpsc::Receiver<RibToBgpEvent>,
+    events: channel::Receiver<RibToBgpEvent>,
     local_rib: BgpLocalRib,
 }
 
 impl Bgp {
-    pub fn new(events: mpsc::Receiver<RibToBgpEvent>) -> Self {
+    pub fn new(events: channel::Receiver<RibToBgpEvent>) -> Self {
         Self {
             events,
             local_rib: Default::default(),
         }
     }
 
-    pub fn run(mut self) {
-        loop {
-            match self.events.recv() {
-                Ok(ev) => {
-                    self.handle_event(ev);
-                }
-                Err(mpsc::RecvError) => {
-                    warn!("BGP lost communication with the RIB");
-                }
-            }
+    /// Handle the events of the RIB, until the RIB stops
+    pub async fn run(mut self) {
+        while let Some(ev) = self.events.recv().await {
+            self.handle_event(ev);
+        }
+        if !shutdown::requested() {
+            warn!("BGP lost communication with the RIB");
         }
     }
 
+    /// Handle an event within a span carrying its correlation ID, so
+    /// that a rule such as `correlation_id=42` applies to everything
+    /// BGP logs about it. The span follows from the one the RIB sent
+    /// the event in, so that it is suppressed when that one is.
+    #[instrument(skip_all, fields(correlation_id = event.correlation_id))]
     fn handle_event(&mut self, event: RibToBgpEvent) {
-        match event {
-            RibToBgpEvent::RedistAdd(vrf_id, prefix, next_hop) => {
-                self.local_rib.add_path(vrf_id, prefix, next_hop);
+        Span::current().follows_from(&event.span);
+        match event.update {
+            RouteUpdate::RedistAdd(vrf_id, prefix, next_hop, attributes) => {
+                self.local_rib
+                    .add_path(vrf_id, prefix, next_hop, attributes);
             }
-            RibToBgpEvent::RedistDel(vrf_id, prefix) => {
-                self.local_rib.del_path(vrf_id, prefix);
+            RouteUpdate::RedistDel(vrf_id, prefix, next_hop) => {
+                self.local_rib.del_path(vrf_id, prefix, next_hop);
             }
         }
     }
@@ -54,20 +167,50 @@ struct BgpLocalRib {
 }
 
 impl BgpLocalRib {
-    #[instrument(skip(self), fields(vrf_id = %vrf_id, prefix = %prefix, next_hop = %next_hop))]
-    fn add_path(&mut self, vrf_id: u32, prefix: IpNetwork, next_hop: IpAddr) {
+    /// Add a path, its attributes being fields of the span, so that a
+    /// rule such as `as_path contains 65001` or `local_pref=200`
+    /// applies to it
+    #[instrument(
+        skip(self, attributes),
+        fields(
+            vrf_id = %vrf_id,
+            vrf_name = vrfs::name(vrf_id).as_deref(),
+            prefix = %prefix,
+            next_hop = %next_hop,
+            as_path = %attributes.as_path,
+            local_pref = attributes.local_pref,
+            med = attributes.med,
+            origin = %attributes.origin
+        )
+    )]
+    fn add_path(
+        &mut self,
+        vrf_id: u32,
+        prefix: IpNetwork,
+        next_hop: IpAddr,
+        attributes: PathAttributes,
+    ) {
         let table = self
             .tables
             .entry(vrf_id)
             .or_insert_with(BgpLocalRibTable::default);
-        table.add_route(prefix, next_hop);
+        table.add_route(prefix, next_hop, attributes);
+        // Dumping the table is expensive, don't bother if this VRF is
+        // filtered out
+        if hints::is_partition_enabled("vrf_id", &vrf_id.to_string()) {
+            debug!(paths = ?table.paths, "Table updated");
+        }
     }
 
-    #[instrument(skip(self), fields(vrf_id = %vrf_id, prefix = %prefix))]
-    fn del_path(&mut self, vrf_id: u32, prefix: IpNetwork) {
+    /// Remove a next hop of a path, or the whole path without one
+    #[instrument(
+        skip(self, next_hop),
+        fields(vrf_id = %vrf_id, vrf_name = vrfs::name(vrf_id).as_deref(), prefix = %prefix)
+    )]
+    fn del_path(&mut self, vrf_id: u32, prefix: IpNetwork, next_hop: Option<IpAddr>) {
         match self.tables.entry(vrf_id) {
             Entry::Occupied(mut entry) => {
-                entry.get_mut().del_route(prefix);
+                entry.get_mut().del_route(prefix, next_hop);
                 if entry.get().is_empty() {
                     info!("Table is now empty, dropping it");
                     entry.remove();
@@ -82,24 +225,40 @@ impl BgpLocalRib {
 
 #[derive(Debug, Default)]
 struct BgpLocalRibTable {
-    paths: HashMap<IpNetwork, IpAddr>,
+    paths: HashMap<IpNetwork, Path>,
+}
+
+/// A path of the local RIB, with its ECMP set: the next hops the
+/// traffic to the prefix is spread over
+#[derive(Debug)]
+struct Path {
+    next_hops: BTreeSet<IpAddr>,
+    attributes: PathAttributes,
 }
 
 impl BgpLocalRibTable {
     #[instrument(skip_all)]
-    fn add_route(&mut self, prefix: IpNetwork, next_hop: IpAddr) {
-        self.paths
-            .entry(prefix)
-            .and_modify(|nh| {
-                if nh != &next_hop {
-                    info!(old_next_hop = ?nh, "Updated path's next-hop");
-                    *nh = next_hop
+    fn add_route(&mut self, prefix: IpNetwork, next_hop: IpAddr, attributes: PathAttributes) {
+        match self.paths.entry(prefix) {
+            Entry::Occupied(mut entry) => {
+                let path = entry.get_mut();
+                if path.next_hops.insert(next_hop) {
+                    info!(next_hop = %next_hop, "Added next-hop");
+                    ecmp_changed(path.next_hops.len() - 1, path.next_hops.len());
+                }
+                if path.attributes != attributes {
+                    info!(old_attributes = ?path.attributes, "Updated path's attributes");
+                    path.attributes = attributes
                 }
-            })
-            .or_insert_with(|| {
+            }
+            Entry::Vacant(entry) => {
                 info!("New path");
-                next_hop
-            });
+                entry.insert(Path {
+                    next_hops: BTreeSet::from([next_hop]),
+                    attributes,
+                });
+            }
+        }
     }
 
     fn is_empty(&self) -> bool {
@@ -107,64 +266,307 @@ impl BgpLocalRibTable {
     }
 
     #[instrument(skip_all)]
-    fn del_route(&mut self, prefix: IpNetwork) {
-        match self.paths.remove(&prefix) {
-            Some(next_hop) => {
-                info!(next_hop = ?next_hop, "Removed path");
-            }
-            None => warn!("No path to remove (prefix not found in table)"),
+    fn del_route(&mut self, prefix: IpNetwork, next_hop: Option<IpAddr>) {
+        let Entry::Occupied(mut entry) = self.paths.entry(prefix) else {
+            warn!("No path to remove (prefix not found in table)");
+            return;
+        };
+        let Some(next_hop) = next_hop else {
+            info!(next_hops = ?entry.remove().next_hops, "Removed path");
+            return;
+        };
+        let next_hops = &mut entry.get_mut().next_hops;
+        if !next_hops.remove(&next_hop) {
+            warn!(next_hop = %next_hop, "No next-hop to remove (not in the ECMP set)");
+        } else if next_hops.is_empty() {
+            entry.remove();
+            info!(next_hop = %next_hop, "Removed path");
+        } else {
+            info!(next_hop = %next_hop, "Removed next-hop");
+            ecmp_changed(next_hops.len() + 1, next_hops.len());
         }
     }
 }
 
+/// Report that the ECMP set of a path grew or shrank
+fn ecmp_changed(old_size: usize, size: usize) {
+    info!(old_size, size, "ECMP set changed size");
+}
+
 #[derive(Debug)]
 pub struct Rib {
-    tx: mpsc::Sender<RibToBgpEvent>,
+    tx: channel::Sender<RibToBgpEvent>,
+    config: SimulatorConfig,
+    /// The scenario replayed, if the updates aren't random
+    scenario: Option<Scenario>,
 }
 
 impl Rib {
-    pub fn new(tx: mpsc::Sender<RibToBgpEvent>) -> Self {
-        Self { tx }
-    }
-
-    pub fn run(self) {
-        let prefixes = vec![
-            "1.0.0.0/8".parse().unwrap(),
-            "192.168.1.1/32".parse().unwrap(),
-            "10.10.1.0/24".parse().unwrap(),
-            "3.3.240.0/16".parse().unwrap(),
-        ];
-        let next_hops = vec![
-            "1.1.1.1".parse().unwrap(),
-            "11.22.33.44".parse().unwrap(),
-            "10.10.10.10".parse().unwrap(),
-        ];
-        let vrf_ids = vec![0, 1, 2, 3];
-        let mut routes: HashSet<(u32, IpNetwork)> = HashSet::new();
-        let mut rng = rand::thread_rng();
-        loop {
-            thread::sleep(Duration::from_secs(1));
-            let prefix = prefixes.choose(&mut rng).unwrap();
-            let next_hop = next_hops.choose(&mut rng).unwrap();
-            let vrf_id = vrf_ids.choose(&mut rng).unwrap();
-
-            let route = (*vrf_id, *prefix);
-            if routes.contains(&route) && rng.gen::<bool>() {
-                routes.remove(&route);
-                self.tx
-                    .send(RibToBgpEvent::RedistDel(*vrf_id, *prefix))
-                    .unwrap();
-            } else {
-                self.tx
-                    .send(RibToBgpEvent::RedistAdd(*vrf_id, *prefix, *next_hop))
-                    .unwrap();
+    pub fn new(
+        tx: channel::Sender<RibToBgpEvent>,
+        config: SimulatorConfig,
+        scenario: Option<Scenario>,
+    ) -> Self {
+        Self {
+            tx,
+            config,
+            scenario,
+        }
+    }
+
+    /// Send route updates to BGP, until the shutdown or until BGP
+    /// stops. When BGP is behind, the updates wait or are dropped
+    /// depending on the overflow policy of the channel.
+    pub async fn run(mut self) {
+        let mut scenario = self.scenario.take();
+        let SimulatorConfig { interval, seed, .. } = &self.config;
+        let topology = self.config.topology();
+        let mut drops = Drops::default();
+        let mut correlation_ids = 1..;
+        // The next hops of the routes sent, to pick those to withdraw
+        let mut routes: HashMap<(u32, IpNetwork), BTreeSet<IpAddr>> = HashMap::new();
+        // Without a seed, pick one, so that the run can be repeated
+        // anyway
+        let seed = seed.unwrap_or_else(rand::random);
+        if scenario.is_none() {
+            info!(
+                seed,
+                "generating route updates, --seed with this seed repeats them"
+            );
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (injector, mut injected) = mpsc::unbounded_channel();
+        *INJECTED.lock().unwrap() = Some(injector);
+        set_interval(*interval);
+        while !shutdown::requested() {
+            // The updates injected are sent right away, in between
+            // those generated. When the pace changes, wait again with
+            // the new one.
+            let pace = pace();
+            let delay = match &scenario {
+                Some(scenario) => scenario.delay(),
+                None => Some(pace.interval),
+            };
+            let update = tokio::select! {
+                () = next_update(pace, delay) => match &mut scenario {
+                    Some(scenario) => match scenario.next_update() {
+                        Some(update) => update,
+                        None => continue,
+                    },
+                    None => {
+                        let vrf = topology.choose_weighted(&mut rng, |vrf| vrf.weight).unwrap();
+                        let prefix = vrf.prefixes.choose(&mut rng).unwrap();
+                        let next_hops: Vec<_> = vrf
+                            .next_hops
+                            .iter()
+                            .filter(|next_hop| next_hop.is_ipv4() == prefix.is_ipv4())
+                            .collect();
+                        let next_hop = match next_hops.choose(&mut rng) {
+                            Some(next_hop) => *next_hop,
+                            None => vrf.next_hops.choose(&mut rng).unwrap(),
+                        };
+
+                        let sent = routes.get(&(vrf.id, *prefix));
+                        if let Some(sent) = sent.filter(|_| rng.gen_bool(vrf.withdraw)) {
+                            let sent: Vec<IpAddr> = sent.iter().copied().collect();
+                            let next_hop = sent.choose(&mut rng).copied();
+                            RouteUpdate::RedistDel(vrf.id, *prefix, next_hop)
+                        } else {
+                            let attributes = PathAttributes::random(&mut rng, &self.config.asns);
+                            RouteUpdate::RedistAdd(vrf.id, *prefix, *next_hop, attributes)
+                        }
+                    }
+                },
+                Some(update) = injected.recv() => update,
+                () = PACE_CHANGED.notified() => continue,
+                () = shutdown::wait() => break,
+            };
+            let (vrf_id, prefix) = update.route();
+            match &update {
+                RouteUpdate::RedistAdd(_, _, next_hop, _) => {
+                    routes
+                        .entry((vrf_id, prefix))
+                        .or_default()
+                        .insert(*next_hop);
+                }
+                RouteUpdate::RedistDel(_, _, None) => {
+                    routes.remove(&(vrf_id, prefix));
+                }
+                RouteUpdate::RedistDel(_, _, Some(next_hop)) => {
+                    if let Entry::Occupied(mut sent) = routes.entry((vrf_id, prefix)) {
+                        sent.get_mut().remove(next_hop);
+                        if sent.get().is_empty() {
+                            sent.remove();
+                        }
+                    }
+                }
+            }
+            let correlation_id = correlation_ids.next().unwrap();
+            let span = info_span!(
+                "send_update",
+                correlation_id,
+                vrf_id = %vrf_id,
+                vrf_name = vrfs::name(vrf_id).as_deref(),
+                prefix = %prefix
+            );
+            let event = RibToBgpEvent {
+                correlation_id,
+                update,
+                span: span.clone(),
+            };
+            let sent = async {
+                debug!(update = ?event.update, "Sending route update");
+                self.tx.send(event).await
+            }
+            .instrument(span)
+            .await;
+            match sent {
+                Ok(Sent::Queued) => {}
+                Ok(Sent::DroppedOldest | Sent::DroppedNewest) => drops.count(),
+                Err(Closed) => {
+                    if !shutdown::requested() {
+                        warn!("RIB lost communication with BGP");
+                    }
+                    return;
+                }
             }
+            drops.report(self.tx.overflow());
+        }
+    }
+}
+
+/// The route updates the RIB dropped because BGP was behind
+#[derive(Debug, Default)]
+struct Drops {
+    total: u64,
+    /// Those dropped since the last report
+    unreported: u64,
+    reported: Option<Instant>,
+}
+
+impl Drops {
+    fn count(&mut self) {
+        self.total += 1;
+        self.unreported += 1;
+    }
+
+    /// Report the drops not reported yet, at most once per
+    /// [`DROP_REPORT_INTERVAL`]
+    fn report(&mut self, overflow: Overflow) {
+        if self.unreported == 0
+            || self
+                .reported
+                .is_some_and(|reported| reported.elapsed() < DROP_REPORT_INTERVAL)
+        {
+            return;
         }
+        warn!(
+            dropped = self.unreported,
+            total = self.total,
+            %overflow,
+            "BGP is behind, route updates dropped"
+        );
+        self.unreported = 0;
+        self.reported = Some(Instant::now());
     }
 }
 
+/// A route update sent by the RIB to BGP
 #[derive(Debug)]
-pub enum RibToBgpEvent {
-    RedistAdd(u32, IpNetwork, IpAddr),
-    RedistDel(u32, IpNetwork),
+pub struct RibToBgpEvent {
+    /// Identifies the update in the logs of both the RIB and BGP, see
+    /// [`Bgp::handle_event`]
+    pub correlation_id: u64,
+    pub update: RouteUpdate,
+    /// The span the RIB sent the update in. It stays open until BGP
+    /// has handled the update, so that BGP can link to it.
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum RouteUpdate {
+    RedistAdd(u32, IpNetwork, IpAddr, PathAttributes),
+    /// Withdraw a next hop of a route, or the whole route without one
+    RedistDel(u32, IpNetwork, Option<IpAddr>),
+}
+
+impl RouteUpdate {
+    /// The VRF and the prefix of the route updated
+    fn route(&self) -> (u32, IpNetwork) {
+        match self {
+            RouteUpdate::RedistAdd(vrf_id, prefix, ..)
+            | RouteUpdate::RedistDel(vrf_id, prefix, _) => (*vrf_id, *prefix),
+        }
+    }
+}
+
+/// The BGP attributes of a path. In a scenario, those missing take
+/// their default value: an empty AS path, a local preference of 100, a
+/// MED of 0 and an incomplete origin, as for a redistributed route.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct PathAttributes {
+    pub as_path: AsPath,
+    pub local_pref: u32,
+    pub med: u32,
+    pub origin: Origin,
+}
+
+impl PathAttributes {
+    /// Attributes for a generated path, its AS path made of up to 4 of
+    /// the given ASNs
+    fn random(rng: &mut StdRng, asns: &[u32]) -> Self {
+        let len = rng.gen_range(1..=4);
+        Self {
+            as_path: AsPath(asns.choose_multiple(rng, len).copied().collect()),
+            local_pref: *[50, 100, 100, 200].choose(rng).unwrap(),
+            med: rng.gen_range(0..10) * 10,
+            origin: *[Origin::Igp, Origin::Egp, Origin::Incomplete]
+                .choose(rng)
+                .unwrap(),
+        }
+    }
+}
+
+impl Default for PathAttributes {
+    fn default() -> Self {
+        Self {
+            as_path: AsPath::default(),
+            local_pref: 100,
+            med: 0,
+            origin: Origin::Incomplete,
+        }
+    }
+}
+
+/// The ASes a path goes through, printed as their numbers separated by
+/// spaces, e.g. `65001 3356`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct AsPath(pub Vec<u32>);
+
+impl fmt::Display for AsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let asns: Vec<_> = self.0.iter().map(u32::to_string).collect();
+        f.write_str(&asns.join(" "))
+    }
+}
+
+/// Where a path comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    Igp,
+    Egp,
+    Incomplete,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Origin::Igp => "igp",
+            Origin::Egp => "egp",
+            Origin::Incomplete => "incomplete",
+        })
+    }
 }
@@ -0,0 +1,175 @@
+//! Routing of the printed records to files, by target.
+//!
+//! The fmt layer writes each record through [`RoutingWriter`], on the
+//! thread of [`crate::nonblocking`]. It picks the file of the route matching the record's target, or
+//! stdout when none does. A route is a logger name, matching targets
+//! as described in [`loggers::logger_matches`], and the most specific
+//! one wins:
+//!
+//! ```toml
+//! [routes]
+//! "router::bgp" = "bgp.log"
+//! "router::rib" = "rib.log"
+//! ```
+//!
+//! The empty name matches all the targets. The routes are set at
+//! startup from the configuration, and changed with the ROUTE command.
+//! Their files roll by size as configured, see [`crate::rotate`], and
+//! are reopened on SIGHUP.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Stdout;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::sync::RwLock;
+
+use dynamic_field_filter::loggers;
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::RotationConfig;
+use crate::rotate::RotatingFile;
+
+/// Where the records of a target go
+#[derive(Debug)]
+struct Route {
+    target: String,
+    path: PathBuf,
+    file: Arc<Mutex<RotatingFile>>,
+}
+
+/// The routes, the most specific first
+static ROUTES: RwLock<Vec<Route>> = RwLock::new(Vec::new());
+
+/// When the files of the routes roll
+static ROTATION: LazyLock<RwLock<RotationConfig>> = LazyLock::new(Default::default);
+
+/// Send the records of a target to a file, instead of where they
+/// went so far
+pub fn set(target: &str, path: &Path) -> io::Result<()> {
+    let mut routes = ROUTES.write().unwrap();
+    // The targets routed to the same file share it, so that their
+    // lines don't get interleaved
+    let file = match routes.iter().find(|route| route.path == path) {
+        Some(route) => route.file.clone(),
+        None => {
+            let rotation = ROTATION.read().unwrap().clone();
+            Arc::new(Mutex::new(RotatingFile::open(path, rotation)?))
+        }
+    };
+    routes.retain(|route| route.target != target);
+    routes.push(Route {
+        target: target.to_string(),
+        path: path.to_path_buf(),
+        file,
+    });
+    routes.sort_by_key(|route| std::cmp::Reverse(route.target.split("::").count()));
+    Ok(())
+}
+
+/// Stop routing the records of a target, return `false` if they
+/// weren't
+pub fn remove(target: &str) -> bool {
+    let mut routes = ROUTES.write().unwrap();
+    let before = routes.len();
+    routes.retain(|route| route.target != target);
+    routes.len() != before
+}
+
+/// Replace all the routes. Nothing changes if a file can't be opened.
+pub fn replace(table: &BTreeMap<String, PathBuf>) -> io::Result<()> {
+    let rotation = ROTATION.read().unwrap().clone();
+    for path in table.values() {
+        RotatingFile::open(path, rotation.clone())?;
+    }
+    ROUTES.write().unwrap().clear();
+    for (target, path) in table {
+        set(target, path)?;
+    }
+    Ok(())
+}
+
+/// The routes, by target
+pub fn list() -> BTreeMap<String, PathBuf> {
+    ROUTES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|route| (route.target.clone(), route.path.clone()))
+        .collect()
+}
+
+/// Open the files again, e.g. after they were rotated by logrotate
+pub fn reopen() -> io::Result<()> {
+    for route in ROUTES.read().unwrap().iter() {
+        route.file.lock().unwrap().reopen()?;
+    }
+    Ok(())
+}
+
+/// Change when the files roll, those open and those opened later
+pub fn set_rotation(rotation: RotationConfig) {
+    let mut current = ROTATION.write().unwrap();
+    for route in ROUTES.read().unwrap().iter() {
+        route.file.lock().unwrap().set_rotation(rotation.clone());
+    }
+    *current = rotation;
+}
+
+/// The writer of the fmt layer, sending each record where the routes
+/// say
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoutingWriter;
+
+/// Where a record is written
+pub enum Output {
+    Stdout(Stdout),
+    File(Arc<Mutex<RotatingFile>>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.lock().unwrap().write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.write_all(buf),
+            Output::File(file) => file.lock().unwrap().write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.lock().unwrap().flush(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for RoutingWriter {
+    type Writer = Output;
+
+    fn make_writer(&'a self) -> Output {
+        Output::Stdout(io::stdout())
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> Output {
+        let routes = ROUTES.read().unwrap();
+        match routes
+            .iter()
+            .find(|route| loggers::logger_matches(&route.target, metadata.target()))
+        {
+            Some(route) => Output::File(route.file.clone()),
+            None => self.make_writer(),
+        }
+    }
+}
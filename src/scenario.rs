@@ -0,0 +1,151 @@
+//! Replaying a scenario, rather than generating random route updates,
+//! for demos and for checking the filters against known traffic. The
+//! scenario is a JSON file listing the route updates, and when they
+//! are sent, from the start:
+//!
+//! ```json
+//! [
+//!   {"at": "0s", "event": "RedistAdd", "vrf_id": 1, "prefix": "10.10.1.0/24", "next_hop": "1.1.1.1"},
+//!   {"at": "1500ms", "event": "RedistDel", "vrf_id": 1, "prefix": "10.10.1.0/24"}
+//! ]
+//! ```
+//!
+//! The routes added may have BGP attributes, `"as_path": [65001, 3356]`,
+//! `"local_pref"`, `"med"` and `"origin"` (`"igp"`, `"egp"` or
+//! `"incomplete"`), see [`PathAttributes`]. Adding a route again with
+//! another next hop adds it to the ECMP set of the route, and a
+//! withdrawal with a `"next_hop"` only removes that one.
+//!
+//! It is set in the `[simulator]` settings, or with `--scenario`:
+//!
+//! ```toml
+//! [simulator]
+//! scenario = "flap.json"
+//! scenario_loop = true                 # start over at the end
+//! speed = 2.0                          # twice as fast
+//! ```
+//!
+//! `SIM PAUSE` and `SIM RESUME` apply to the replay too, but not
+//! `SIM RATE`. Once the scenario is over, unless it loops, the RIB only
+//! sends the route updates injected with `ADDROUTE` and `DELROUTE`.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+
+use crate::config;
+use crate::config::SimulatorConfig;
+use crate::router::PathAttributes;
+use crate::router::RouteUpdate;
+
+/// A route update of the scenario
+#[derive(Debug, Deserialize)]
+struct Step {
+    /// When the update is sent, from the start of the scenario
+    #[serde(deserialize_with = "config::duration")]
+    at: Duration,
+    #[serde(flatten)]
+    update: StepUpdate,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "event")]
+enum StepUpdate {
+    RedistAdd {
+        vrf_id: u32,
+        prefix: IpNetwork,
+        next_hop: IpAddr,
+        #[serde(flatten)]
+        attributes: PathAttributes,
+    },
+    RedistDel {
+        vrf_id: u32,
+        prefix: IpNetwork,
+        /// The next hop withdrawn, if not the whole route
+        #[serde(default)]
+        next_hop: Option<IpAddr>,
+    },
+}
+
+/// A scenario being replayed
+#[derive(Debug)]
+pub struct Scenario {
+    steps: Vec<Step>,
+    looping: bool,
+    speed: f64,
+    /// The index of the next step
+    next: usize,
+}
+
+impl Scenario {
+    /// Load the scenario of the simulator settings, if there is one
+    pub fn load(config: &SimulatorConfig) -> Result<Option<Self>, String> {
+        let Some(path) = &config.scenario else {
+            return Ok(None);
+        };
+        let steps = read(path)?;
+        Ok(Some(Self {
+            steps,
+            looping: config.scenario_loop,
+            speed: config.speed,
+            next: 0,
+        }))
+    }
+
+    /// How long to wait before the next route update, if the scenario
+    /// isn't over
+    pub fn delay(&self) -> Option<Duration> {
+        let step = self.steps.get(self.next)?;
+        let previous = match self.next {
+            0 => Duration::ZERO,
+            next => self.steps[next - 1].at,
+        };
+        Some((step.at - previous).div_f64(self.speed))
+    }
+
+    /// The next route update, starting over at the end if the scenario
+    /// loops
+    pub fn next_update(&mut self) -> Option<RouteUpdate> {
+        let step = self.steps.get(self.next)?;
+        let update = match step.update.clone() {
+            StepUpdate::RedistAdd {
+                vrf_id,
+                prefix,
+                next_hop,
+                attributes,
+            } => RouteUpdate::RedistAdd(vrf_id, prefix, next_hop, attributes),
+            StepUpdate::RedistDel {
+                vrf_id,
+                prefix,
+                next_hop,
+            } => RouteUpdate::RedistDel(vrf_id, prefix, next_hop),
+        };
+        self.next += 1;
+        if self.next == self.steps.len() {
+            if self.looping {
+                self.next = 0;
+            } else {
+                info!("scenario over");
+            }
+        }
+        Some(update)
+    }
+}
+
+/// Read the steps of a scenario, in the order they are sent
+fn read(path: &Path) -> Result<Vec<Step>, String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("invalid scenario {}: {e}", path.display());
+    let data = fs::read_to_string(path).map_err(|e| invalid(&e))?;
+    let steps: Vec<Step> = serde_json::from_str(&data).map_err(|e| invalid(&e))?;
+    if steps.is_empty() {
+        return Err(invalid(&"no route update"));
+    }
+    if steps.windows(2).any(|pair| pair[1].at < pair[0].at) {
+        return Err(invalid(&"the route updates are not in order"));
+    }
+    Ok(steps)
+}
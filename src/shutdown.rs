@@ -0,0 +1,60 @@
+//! Graceful shutdown, on SIGINT or SIGTERM.
+//!
+//! The signal sets a flag that the long-running loops check: the
+//! control listener stops accepting connections, the control
+//! connections close, and the RIB stops sending route updates, which
+//! makes BGP stop once it has handled those already sent. `main` then
+//! waits for these tasks and flushes the output, rather than having
+//! them killed in the middle of a write. A second signal exits right
+//! away.
+
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+use tokio::sync::Notify;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+static NOTIFY: Notify = Notify::const_new();
+
+/// Return `true` once the shutdown was requested
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Wait until the shutdown is requested
+pub async fn wait() {
+    let notified = NOTIFY.notified();
+    if requested() {
+        return;
+    }
+    notified.await;
+}
+
+/// Handle SIGINT and SIGTERM until the process exits
+pub fn handle_signals() {
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("failed to handle SIGINT and SIGTERM: {e}");
+            return;
+        }
+    };
+    for signal in signals.forever() {
+        let name = if signal == SIGINT {
+            "SIGINT"
+        } else {
+            "SIGTERM"
+        };
+        if REQUESTED.swap(true, Ordering::Relaxed) {
+            warn!("{name} received again, exiting now");
+            process::exit(128 + signal);
+        }
+        NOTIFY.notify_waiters();
+        info!("{name} received, shutting down");
+    }
+}
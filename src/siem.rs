@@ -0,0 +1,161 @@
+//! A structured changelog of the control plane, for external SIEM
+//! systems.
+//!
+//! Every authentication attempt, admin command and rule change is
+//! written as one JSON object per line, in a stable schema (see
+//! [`SCHEMA`]). The changelog doesn't go through tracing at all, so it
+//! can't be filtered, muted, or redirected by the log configuration.
+//!
+//! The destination is set with the `SIEM_SINK` environment variable,
+//! and nothing is written unless it is set:
+//!
+//! ```text
+//! file:<path>       append to a file
+//! udp:<host:port>   send each record as a UDP datagram
+//! ```
+
+use std::env;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::time::SystemTime;
+
+/// Identifies the schema of the records. This must change whenever
+/// the records change in a backward incompatible way.
+pub const SCHEMA: &str = "loggingdemo.siem.v1";
+
+/// Something that happened on the control plane
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SiemEvent<'a> {
+    /// A client gave a token with AUTH
+    AuthAttempt { peer: &'a str, success: bool },
+    /// A command was received on a control connection
+    AdminCommand { peer: &'a str, command: &'a str },
+    /// The filtering rules changed
+    RuleChange {
+        peer: &'a str,
+        action: &'a str,
+        detail: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct SiemRecord<'a> {
+    schema: &'static str,
+    /// Incremented for every record, so that gaps can be detected
+    seq: u64,
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a SiemEvent<'a>,
+}
+
+#[derive(Debug)]
+enum Destination {
+    File(PathBuf, File),
+    Udp(UdpSocket),
+}
+
+/// Open a file for appending
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[derive(Debug)]
+pub struct SiemSink {
+    destination: Mutex<Destination>,
+    seq: AtomicU64,
+}
+
+impl SiemSink {
+    /// Open the destination described by `spec` (see the module
+    /// documentation for the format)
+    pub fn open(spec: &str) -> io::Result<Self> {
+        let destination = match spec.split_once(':') {
+            Some(("file", path)) => Destination::File(path.into(), open_file(Path::new(path))?),
+            Some(("udp", addr)) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                Destination::Udp(socket)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid SIEM sink {spec}"),
+                ))
+            }
+        };
+        Ok(Self {
+            destination: Mutex::new(destination),
+            seq: AtomicU64::new(0),
+        })
+    }
+
+    /// Open the file again, e.g. after it was rotated
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Destination::File(path, file) = &mut *self.destination.lock().unwrap() {
+            *file = open_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn record(&self, event: &SiemEvent<'_>) -> io::Result<()> {
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        let record = SiemRecord {
+            schema: SCHEMA,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            timestamp,
+            event,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        match &mut *self.destination.lock().unwrap() {
+            Destination::File(_, file) => file.write_all(&line),
+            Destination::Udp(socket) => socket.send(&line).map(|_| ()),
+        }
+    }
+}
+
+static SINK: OnceLock<SiemSink> = OnceLock::new();
+
+/// Open the sink configured by the `SIEM_SINK` environment variable,
+/// if it is set
+pub fn init_from_env() -> io::Result<()> {
+    let Ok(spec) = env::var("SIEM_SINK") else {
+        return Ok(());
+    };
+    let sink = SiemSink::open(&spec)?;
+    let _ = SINK.set(sink);
+    Ok(())
+}
+
+/// Open the changelog file again, if it goes to a file
+pub fn reopen() -> io::Result<()> {
+    match SINK.get() {
+        Some(sink) => sink.reopen(),
+        None => Ok(()),
+    }
+}
+
+/// Write an event to the changelog. Failures are reported on stderr,
+/// since they must not go through the log pipeline either.
+pub fn record(event: SiemEvent<'_>) {
+    if let Some(sink) = SINK.get() {
+        if let Err(e) = sink.record(&event) {
+            eprintln!("failed to write SIEM record: {e}");
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Signal handling: on SIGHUP, the configuration file is read again,
+//! and the files written to are reopened, so that they can be rotated
+//! by logrotate. SIGINT and SIGTERM shut down, see [`crate::shutdown`].
+
+use std::sync::RwLock;
+
+use dynamic_field_filter::diagnostics;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tracing_subscriber::reload::Handle;
+
+use crate::audit;
+use crate::config::Config;
+use crate::hot_reload;
+use crate::hot_reload::Handles;
+use crate::routes;
+use crate::shutdown;
+use crate::siem;
+use crate::sink::ShardedSink;
+use crate::tee;
+
+/// Handle SIGHUP until the shutdown
+pub async fn handle_sighup<S, T, U, V>(
+    config: &RwLock<Config>,
+    handles: &Handles<S, T, U>,
+    sink_handle: &Handle<ShardedSink, V>,
+) {
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("failed to handle SIGHUP: {e}");
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            Some(()) = signals.recv() => {}
+            () = shutdown::wait() => break,
+        }
+        info!("SIGHUP received, reloading the configuration and reopening the files");
+        hot_reload::reload(config, handles);
+        let mut res = Ok(());
+        let _ = sink_handle.modify(|sink| res = sink.reopen());
+        if let Err(e) = res {
+            error!("failed to reopen the shard files: {e}");
+        }
+        let mut res = Ok(());
+        let _ = handles
+            .filter
+            .modify(|layer| res = layer.reopen_quarantine());
+        if let Err(e) = res {
+            error!(target: diagnostics::TARGET, "failed to reopen the quarantine file: {e}");
+        }
+        if let Err(e) = siem::reopen() {
+            error!("failed to reopen the SIEM changelog: {e}");
+        }
+        if let Err(e) = audit::reopen() {
+            error!("failed to reopen the audit log: {e}");
+        }
+        if let Err(e) = routes::reopen() {
+            error!("failed to reopen the routed log files: {e}");
+        }
+        if let Err(e) = tee::reopen() {
+            error!("failed to reopen the tee file: {e}");
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Outputs that write records somewhere else than the fmt layer's
+//! writer.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::hash::BuildHasherDefault;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use dynamic_field_filter::format;
+use dynamic_field_filter::format::FieldValues;
+use tracing::span::Attributes;
+use tracing::span::Record;
+use tracing::Event;
+use tracing::Id;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// A consistent hash ring mapping keys to shards. Each shard is
+/// placed on the ring several times (virtual nodes) so that keys are
+/// evenly spread, and so that changing the number of shards only
+/// moves a fraction of the keys.
+///
+/// The hash function is pluggable through the `H` parameter.
+#[derive(Debug, Clone)]
+pub struct HashRing<H = BuildHasherDefault<DefaultHasher>> {
+    hasher: H,
+    /// Ring positions and the shard they belong to, sorted by position
+    nodes: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// Number of positions each shard takes on the ring
+    const VIRTUAL_NODES: usize = 64;
+
+    pub fn new(shards: usize) -> Self {
+        Self::with_hasher(shards, Default::default())
+    }
+}
+
+impl<H: BuildHasher> HashRing<H> {
+    pub fn with_hasher(shards: usize, hasher: H) -> Self {
+        let mut nodes = Vec::with_capacity(shards * HashRing::VIRTUAL_NODES);
+        for shard in 0..shards {
+            for vnode in 0..HashRing::VIRTUAL_NODES {
+                nodes.push((hasher.hash_one((shard, vnode)), shard));
+            }
+        }
+        nodes.sort_unstable();
+        Self { hasher, nodes }
+    }
+
+    /// Return the number of shards on the ring
+    pub fn len(&self) -> usize {
+        self.nodes.len() / HashRing::VIRTUAL_NODES
+    }
+
+    /// Return the shard the given key belongs to, i.e. the shard of
+    /// the first ring position after the key's hash.
+    pub fn shard(&self, key: &str) -> usize {
+        let hash = self.hasher.hash_one(key);
+        let index = self.nodes.partition_point(|(position, _)| *position < hash);
+        self.nodes[index % self.nodes.len()].1
+    }
+}
+
+/// The files records are sharded into
+#[derive(Debug)]
+struct Shards {
+    field: String,
+    dir: PathBuf,
+    ring: HashRing,
+    files: Vec<File>,
+}
+
+/// A layer that shards records across several files, by hashing the
+/// value of a chosen field, so that they can be processed in
+/// parallel downstream. Records that don't have the field (neither
+/// themselves nor in their spans) all end up in the same shard.
+///
+/// The layer does nothing until sharding is enabled with
+/// [`ShardedSink::enable`].
+#[derive(Debug, Default)]
+pub struct ShardedSink {
+    shards: Option<Shards>,
+}
+
+impl ShardedSink {
+    /// Start sharding records across `count` files named
+    /// `shard-<n>.log` in `dir`, by hash of `field`.
+    pub fn enable(&mut self, field: &str, count: usize, dir: &Path) -> io::Result<()> {
+        if count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shard count must be at least 1",
+            ));
+        }
+        fs::create_dir_all(dir)?;
+        self.shards = Some(Shards {
+            field: field.to_string(),
+            dir: dir.to_path_buf(),
+            ring: HashRing::new(count),
+            files: open_shards(dir, count)?,
+        });
+        Ok(())
+    }
+
+    /// Open the shard files again, e.g. after they were rotated
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Some(shards) = &mut self.shards {
+            shards.files = open_shards(&shards.dir, shards.ring.len())?;
+        }
+        Ok(())
+    }
+
+    /// Stop sharding records
+    pub fn disable(&mut self) {
+        self.shards = None;
+    }
+
+    /// Return a one-line description of the current configuration
+    pub fn describe(&self) -> String {
+        match &self.shards {
+            Some(shards) => format!(
+                "sharding on {} across {} files in {}",
+                shards.field,
+                shards.ring.len(),
+                shards.dir.display()
+            ),
+            None => "sharding disabled".to_string(),
+        }
+    }
+}
+
+/// Open the `count` shard files in `dir`, for appending
+fn open_shards(dir: &Path, count: usize) -> io::Result<Vec<File>> {
+    (0..count)
+        .map(|n| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(format!("shard-{n}.log")))
+        })
+        .collect()
+}
+
+impl<S> Layer<S> for ShardedSink
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if self.shards.is_some() {
+            format::record_span_fields(attrs, id, &ctx);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if self.shards.is_some() {
+            format::update_span_fields(id, values, &ctx);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(shards) = &self.shards else {
+            return;
+        };
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        let key = format::lookup_field(&shards.field, event, &fields, &ctx).unwrap_or_default();
+        let mut line = format::format_event(event, &fields, &ctx);
+        line.push('\n');
+        let file = &shards.files[shards.ring.shard(&key)];
+        if let Err(e) = (&*file).write_all(line.as_bytes()) {
+            eprintln!("failed to write to shard file: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..10_000).map(|n| format!("cust-{n}"))
+    }
+
+    #[test]
+    fn keys_spread_evenly() {
+        for shards in [1, 2, 4, 7] {
+            let ring = HashRing::new(shards);
+            assert_eq!(ring.len(), shards);
+            let mut counts = vec![0; shards];
+            for key in keys() {
+                counts[ring.shard(&key)] += 1;
+            }
+            let fair = 10_000 / shards;
+            for (shard, count) in counts.into_iter().enumerate() {
+                assert!(
+                    count > fair / 2 && count < fair * 3 / 2,
+                    "{shards} shards: {count} keys in shard {shard}, expected about {fair}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn same_key_same_shard() {
+        let ring = HashRing::new(4);
+        let other = HashRing::new(4);
+        for key in keys() {
+            assert_eq!(ring.shard(&key), other.shard(&key));
+        }
+    }
+
+    #[test]
+    fn adding_a_shard_only_moves_keys_to_it() {
+        let before = HashRing::new(4);
+        let after = HashRing::new(5);
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (before.shard(&key), after.shard(&key));
+            if old != new {
+                assert_eq!(new, 4, "{key} moved from shard {old} to shard {new}");
+                moved += 1;
+            }
+        }
+        // About a fifth of the keys go to the new shard
+        assert!(moved > 1_000 && moved < 3_000, "{moved} keys moved");
+    }
+
+    #[test]
+    fn removing_a_shard_only_moves_its_keys() {
+        let before = HashRing::new(5);
+        let after = HashRing::new(4);
+        for key in keys() {
+            let old = before.shard(&key);
+            if old != 4 {
+                assert_eq!(after.shard(&key), old, "{key} moved from shard {old}");
+            }
+        }
+    }
+
+    #[test]
+    fn enable_rejects_zero_shards() {
+        let mut sink = ShardedSink::default();
+        let dir = std::env::temp_dir().join("sharded-sink-test");
+        assert!(sink.enable("vrf_id", 0, &dir).is_err());
+        assert_eq!(sink.describe(), "sharding disabled");
+    }
+}
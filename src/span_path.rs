@@ -0,0 +1,73 @@
+//! Prefixing the printed records with the path of their spans.
+//!
+//! The compact format only names the spans of an event, and prints
+//! their fields after those of the event, in a single list. With the
+//! `span_path` display option on, [`SpanPath`] prints before each event
+//! the spans it is in, from the outermost, along with their fields,
+//! those recorded after their creation included:
+//!
+//! ```text
+//! add_path{vrf_id=2 prefix=10.10.1.0/24}>add_route: 2023-05-17T09:47:12.345Z INFO ...
+//! ```
+//!
+//! so that each line tells where it comes from even when the lines
+//! around it were filtered out.
+
+use std::fmt;
+
+use tracing::Event;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::fmt::FormatEvent;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::fmt::FormattedFields;
+use tracing_subscriber::registry::LookupSpan;
+
+/// An event formatter printing the span path of the records before
+/// those of another one, if it is on
+#[derive(Debug, Clone)]
+pub struct SpanPath<F> {
+    inner: F,
+    on: bool,
+}
+
+impl<F> SpanPath<F> {
+    pub fn new(inner: F, on: bool) -> Self {
+        Self { inner, on }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for SpanPath<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if self.on {
+            if let Some(scope) = ctx.event_scope() {
+                for (n, span) in scope.from_root().enumerate() {
+                    if n > 0 {
+                        writer.write_char('>')?;
+                    }
+                    writer.write_str(span.name())?;
+                    // The fields as formatted by the fmt layer, redacted
+                    let extensions = span.extensions();
+                    if let Some(fields) = extensions.get::<FormattedFields<N>>() {
+                        if !fields.is_empty() {
+                            write!(writer, "{{{fields}}}")?;
+                        }
+                    }
+                }
+                writer.write_str(": ")?;
+            }
+        }
+        self.inner.format_event(ctx, writer, event)
+    }
+}
@@ -0,0 +1,287 @@
+//! Forwarding the records to a syslog server, in the format of
+//! RFC 5424, with the `[syslog]` settings:
+//!
+//! ```toml
+//! [syslog]
+//! address = "udp://127.0.0.1:514"      # or "unix:///dev/log"
+//! facility = "local0"                  # "user" by default
+//! app_name = "loggingdemo"
+//! structured_data = true               # the span fields, as SD-PARAMs
+//! ```
+//!
+//! Each event is sent in a datagram of its own, with the severity of
+//! its level, e.g. `err` for ERROR and `debug` for DEBUG and TRACE:
+//!
+//! ```text
+//! <134>1 2023-05-17T09:47:12.345678Z router1 loggingdemo 4242 - [meta sequenceId="7"][fields@32473 vrf_id="2" prefix="10.10.1.0/24"] add_path:add_route: loggingdemo::router: adding route next_hop=10.10.10.10
+//! ```
+//!
+//! The `sequenceId` of the `meta` element counts the messages from 1,
+//! so that the server can tell which ones were dropped.
+//!
+//! With `structured_data`, the span fields are in the structured data
+//! of the message, rather than at the end of its text. The field
+//! values are redacted like those of the other outputs.
+//!
+//! The syslog layer comes after the field filter, so the server only
+//! gets the records that went through the filters. The span fields are
+//! those recorded by [`crate::live::LiveEvents`]. A record is dropped
+//! rather than holding up the logging thread when the socket can't
+//! take it. The layer is set up at startup, and these settings only
+//! apply after a restart.
+
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use dynamic_field_filter::enrich;
+use dynamic_field_filter::format;
+use dynamic_field_filter::format::FieldValues;
+use dynamic_field_filter::format::SpanFields;
+use tracing::Event;
+use tracing::Level;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::time::SystemTime;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::SyslogConfig;
+
+/// The ID of the structured data element holding the span fields,
+/// with the private enterprise number reserved for documentation
+const SD_ID: &str = "fields@32473";
+
+/// The largest `sequenceId`, after which it starts over from 1
+const MAX_SEQUENCE_ID: u64 = 2_147_483_647;
+
+/// The facilities, by name, and their codes
+const FACILITIES: &[(&str, u8)] = &[
+    ("kern", 0),
+    ("user", 1),
+    ("mail", 2),
+    ("daemon", 3),
+    ("auth", 4),
+    ("syslog", 5),
+    ("lpr", 6),
+    ("news", 7),
+    ("uucp", 8),
+    ("cron", 9),
+    ("authpriv", 10),
+    ("ftp", 11),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+/// The syslog layer, if an address is configured
+pub fn layer(config: &SyslogConfig) -> Result<Option<Syslog>, String> {
+    let Some(address) = &config.address else {
+        return Ok(None);
+    };
+    let facility = FACILITIES
+        .iter()
+        .find(|(name, _)| *name == config.facility)
+        .map(|(_, code)| *code)
+        .ok_or_else(|| format!("unknown syslog facility {:?}", config.facility))?;
+    let socket = Socket::connect(address)
+        .map_err(|e| format!("failed to connect to syslog at {address}: {e}"))?;
+    Ok(Some(Syslog {
+        socket,
+        facility,
+        app_name: header_field(&config.app_name, 48),
+        structured_data: config.structured_data,
+        seq: AtomicU64::new(0),
+    }))
+}
+
+/// Where the messages are sent
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Socket {
+    /// Connect to an address, as `udp://<host>:<port>` or
+    /// `unix://<path>`
+    fn connect(address: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected udp://<host>:<port> or unix://<path>",
+            )
+        };
+        if let Some(host) = address.strip_prefix("udp://") {
+            let server = host.to_socket_addrs()?.next().ok_or_else(invalid)?;
+            let local = match server {
+                SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(server)?;
+            socket.set_nonblocking(true)?;
+            return Ok(Socket::Udp(socket));
+        }
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix://") {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            socket.set_nonblocking(true)?;
+            return Ok(Socket::Unix(socket));
+        }
+        Err(invalid())
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Udp(socket) => socket.send(buf),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(buf),
+        }
+    }
+}
+
+/// A layer sending the events to a syslog server
+#[derive(Debug)]
+pub struct Syslog {
+    socket: Socket,
+    facility: u8,
+    app_name: String,
+    structured_data: bool,
+    /// The number of messages sent, or dropped
+    seq: AtomicU64,
+}
+
+impl<S> Layer<S> for Syslog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = FieldValues::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+
+        // The header, then the structured data
+        let priority = self.facility * 8 + severity(metadata.level());
+        let mut line = format!("<{priority}>1 ");
+        let _ = SystemTime.format_time(&mut Writer::new(&mut line));
+        let hostname = enrich::value("hostname").unwrap_or_default();
+        let _ = write!(
+            line,
+            " {} {} {} - ",
+            header_field(&hostname, 255),
+            self.app_name,
+            process::id()
+        );
+        let mut spans = String::new();
+        let mut span_fields = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span_ref in scope.from_root() {
+                let _ = write!(spans, "{}:", span_ref.name());
+                if let Some(SpanFields(fields)) = span_ref.extensions().get::<SpanFields>() {
+                    span_fields.extend(
+                        fields.values.iter().map(|(name, value)| {
+                            (*name, format::redacted(name, value).into_owned())
+                        }),
+                    );
+                }
+            }
+            spans.push(' ');
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) % MAX_SEQUENCE_ID + 1;
+        let _ = write!(line, "[meta sequenceId=\"{seq}\"]");
+        if self.structured_data && !span_fields.is_empty() {
+            let _ = write!(line, "[{SD_ID}");
+            for (name, value) in &span_fields {
+                let _ = write!(line, " {}=\"{}\"", sd_name(name), sd_value(value));
+            }
+            line.push(']');
+        }
+
+        // The message, like the compact format
+        let _ = write!(line, " {spans}{}:", metadata.target());
+        if let Some(message) = fields.message.as_ref() {
+            let _ = write!(line, " {message}");
+        }
+        for (name, value) in fields.values.iter() {
+            let _ = write!(line, " {name}={}", format::redacted(name, value));
+        }
+        for (name, value) in enrich::values() {
+            let _ = write!(line, " {name}={}", format::redacted(name, &value));
+        }
+        if !self.structured_data {
+            for (name, value) in &span_fields {
+                let _ = write!(line, " {name}={value}");
+            }
+        }
+        // There is nowhere to report the failure to
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+/// The severity of a level: `err`, `warning`, `info` or `debug`
+pub fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// A header field, which is printable ASCII of a limited length, or
+/// `-` if empty
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+/// A field name as the name of an SD-PARAM, which can't have `=`,
+/// spaces, `]` or `"`
+fn sd_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '=' | ' ' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect()
+}
+
+/// A field value as the value of an SD-PARAM, with `"`, `\` and `]`
+/// escaped
+fn sd_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,154 @@
+//! Printing the records to a file as well as to stdout.
+//!
+//! [`Tee`] wraps the writer of the fmt layer, and writes each record
+//! it is given to the tee file too, if one is set:
+//!
+//! ```toml
+//! [fmt]
+//! ansi = true                          # colors on the terminal
+//! tee = "loggingdemo.log"
+//! tee_ansi = false                     # and none in the file
+//! ```
+//!
+//! The records are formatted once, with colors if either side wants
+//! them, and the colors are stripped from the side that doesn't. The
+//! file is set at startup from the configuration, and reopened on
+//! SIGHUP.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The tee file, and its path
+static FILE: RwLock<Option<(PathBuf, Arc<Mutex<File>>)>> = RwLock::new(None);
+
+/// Open a file for appending
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Set the file the records are copied to, or stop copying them with
+/// `None`. Nothing changes if the file can't be opened.
+pub fn set(path: Option<&Path>) -> io::Result<()> {
+    let file = match path {
+        Some(path) => Some((path.to_path_buf(), Arc::new(Mutex::new(open_file(path)?)))),
+        None => None,
+    };
+    *FILE.write().unwrap() = file;
+    Ok(())
+}
+
+/// Open the file again, e.g. after it was rotated
+pub fn reopen() -> io::Result<()> {
+    if let Some((path, file)) = FILE.read().unwrap().as_ref() {
+        *file.lock().unwrap() = open_file(path)?;
+    }
+    Ok(())
+}
+
+/// Duplicates what a writer writes to the tee file, with independent
+/// colors on each side
+#[derive(Debug, Clone, Copy)]
+pub struct Tee<M> {
+    inner: M,
+    /// Whether the colors are kept for the writer
+    ansi: bool,
+    /// Whether the colors are kept for the file
+    file_ansi: bool,
+}
+
+impl<M> Tee<M> {
+    pub fn new(inner: M, ansi: bool, file_ansi: bool) -> Self {
+        Self {
+            inner,
+            ansi,
+            file_ansi,
+        }
+    }
+
+    fn writer<W>(&self, inner: W) -> TeeWriter<W> {
+        TeeWriter {
+            inner,
+            ansi: self.ansi,
+            file: FILE.read().unwrap().as_ref().map(|(_, file)| file.clone()),
+            file_ansi: self.file_ansi,
+        }
+    }
+}
+
+/// The writer of a [`Tee`], for one record
+pub struct TeeWriter<W> {
+    inner: W,
+    ansi: bool,
+    file: Option<Arc<Mutex<File>>>,
+    file_ansi: bool,
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            file.lock()
+                .unwrap()
+                .write_all(&colors(buf, self.file_ansi))?;
+        }
+        self.inner.write_all(&colors(buf, self.ansi))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            file.lock().unwrap().flush()?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Tee<M> {
+    type Writer = TeeWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer(self.inner.make_writer())
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> Self::Writer {
+        self.writer(self.inner.make_writer_for(metadata))
+    }
+}
+
+/// Keep the colors of a record, or strip them, i.e. remove the ANSI
+/// escape sequences `ESC [ ... <letter>`
+fn colors(buf: &[u8], keep: bool) -> Cow<'_, [u8]> {
+    if keep || !buf.contains(&0x1b) {
+        return Cow::Borrowed(buf);
+    }
+    let mut plain = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte == 0x1b && bytes.peek() == Some(&b'[') {
+            bytes.next();
+            // Skip the parameters, up to and including the final byte
+            for byte in bytes.by_ref() {
+                if (0x40..=0x7e).contains(&byte) {
+                    break;
+                }
+            }
+        } else {
+            plain.push(byte);
+        }
+    }
+    Cow::Owned(plain)
+}
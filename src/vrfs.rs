@@ -0,0 +1,39 @@
+//! The names of the VRFs, from the `[simulator]` settings:
+//!
+//! ```toml
+//! [simulator]
+//! vrf_names = { mgmt = 0, cust-a = 1 }
+//! ```
+//!
+//! The spans of the router record the name of their VRF as `vrf_name`,
+//! alongside `vrf_id`, and the `VRF` command takes either. The VRFs
+//! without a name have no `vrf_name`.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// The VRF IDs, by name
+static NAMES: RwLock<BTreeMap<String, u32>> = RwLock::new(BTreeMap::new());
+
+/// Replace the names of the VRFs
+pub fn set(names: &BTreeMap<String, u32>) {
+    *NAMES.write().unwrap() = names.clone();
+}
+
+/// The name of a VRF, if it has one
+pub fn name(id: u32) -> Option<String> {
+    NAMES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(_, vrf_id)| **vrf_id == id)
+        .map(|(name, _)| name.clone())
+}
+
+/// Resolve a VRF given by ID or by name to its ID
+pub fn resolve(vrf: &str) -> Option<u32> {
+    match vrf.parse() {
+        Ok(id) => Some(id),
+        Err(_) => NAMES.read().unwrap().get(vrf).copied(),
+    }
+}